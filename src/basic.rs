@@ -0,0 +1,476 @@
+use std::collections::BTreeMap;
+
+// References:
+// - https://sinclair.wiki.zxnet.co.uk/wiki/ZX_Spectrum_character_set
+// - https://sinclair.wiki.zxnet.co.uk/wiki/Spectrum_BASIC
+
+/// Keywords of the tokens 0xA3 (SPECTRUM, 128K only) to 0xFF (COPY).
+#[rustfmt::skip]
+const KEYWORDS: [&str; 93] = [
+    "SPECTRUM", "PLAY", "RND", "INKEY$", "PI", "FN", "POINT", "SCREEN$", "ATTR", "AT", "TAB", "VAL$", "CODE", "VAL",
+    "LEN", "SIN", "COS", "TAN", "ASN", "ACS", "ATN", "LN", "EXP", "INT", "SQR", "SGN", "ABS", "PEEK", "IN", "USR",
+    "STR$", "CHR$", "NOT", "BIN", "OR", "AND", "<=", ">=", "<>", "LINE", "THEN", "TO", "STEP", "DEF FN", "CAT",
+    "FORMAT", "MOVE", "ERASE", "OPEN #", "CLOSE #", "MERGE", "VERIFY", "BEEP", "CIRCLE", "INK", "PAPER", "FLASH",
+    "BRIGHT", "INVERSE", "OVER", "OUT", "LPRINT", "LLIST", "STOP", "READ", "DATA", "RESTORE", "NEW", "BORDER",
+    "CONTINUE", "DIM", "REM", "FOR", "GO TO", "GO SUB", "INPUT", "LOAD", "LIST", "LET", "PAUSE", "NEXT", "POKE",
+    "PRINT", "PLOT", "RUN", "SAVE", "RANDOMIZE", "IF", "CLS", "DRAW", "CLEAR", "RETURN", "COPY",
+];
+const FIRST_TOKEN: u8 = 0xA3;
+const REM: u8 = 0xEA;
+/// marks the 5 byte binary form of the number just listed
+const NUMBER: u8 = 0x0E;
+const END_OF_LINE: u8 = 0x0D;
+
+/// Block graphics 0x80-0x8F, the bits of the code standing for the quarters.
+const BLOCK_GRAPHICS: [char; 16] = [
+    ' ', '▝', '▘', '▀', '▗', '▐', '▚', '▜', '▖', '▞', '▌', '▛', '▄', '▟', '▙', '█',
+];
+
+/// What a piece of a listed line is, for highlighting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Part {
+    Keyword,
+    Number,
+    /// string literal, with the quotes
+    Str,
+    /// REM statement comment
+    Rem,
+    /// anything else: variables, operators, punctuation
+    Other,
+}
+
+/// Program line as listed, in highlighted pieces.
+#[derive(Debug, PartialEq)]
+pub struct Line {
+    pub number: u16,
+    pub parts: Vec<(Part, String)>,
+}
+
+impl Line {
+    pub fn text(&self) -> String {
+        self.parts.iter().map(|(_, s)| s.as_str()).collect()
+    }
+
+    /// Appends the text, merging it with the last piece of the same kind.
+    fn push(&mut self, part: Part, s: &str) {
+        match self.parts.last_mut() {
+            Some((last, text)) if *last == part => text.push_str(s),
+            _ => self.parts.push((part, s.to_string())),
+        }
+    }
+
+    fn ends_with_space(&self) -> bool {
+        self.parts.last().is_none_or(|(_, s)| s.ends_with(' '))
+    }
+
+    /// Turns the digits just listed into a number, when the binary form of one follows.
+    fn mark_number(&mut self) {
+        let Some((Part::Other, text)) = self.parts.last_mut() else {
+            return;
+        };
+        let digits = text
+            .rfind(|c: char| !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E'))
+            .map_or(0, |pos| pos + 1);
+        // skip the exponent letters, if they're really the end of a variable name
+        let Some(start) = text[digits..].find(|c: char| c.is_ascii_digit() || c == '.') else {
+            return;
+        };
+        let number = text.split_off(digits + start);
+        if text.is_empty() {
+            self.parts.pop();
+        }
+        self.parts.push((Part::Number, number));
+    }
+}
+
+/// Lists the program (the BASIC area, without variables) as the Spectrum does, line by line,
+/// stopping at the first malformed line.
+pub fn detokenize(program: &[u8]) -> Vec<Line> {
+    let mut lines = vec![];
+    let mut pos = 0;
+    while pos + 4 <= program.len() {
+        let number = u16::from_be_bytes([program[pos], program[pos + 1]]);
+        let length = u16::from_le_bytes([program[pos + 2], program[pos + 3]]) as usize;
+        // line numbers go up to 9999, anything above ends the program (e.g. variables)
+        if number > 9999 || pos + 4 + length > program.len() {
+            break;
+        }
+        lines.push(detokenize_line(number, &program[pos + 4..pos + 4 + length]));
+        pos += 4 + length;
+    }
+    lines
+}
+
+fn detokenize_line(number: u16, data: &[u8]) -> Line {
+    let mut line = Line { number, parts: vec![] };
+    let (mut in_string, mut in_rem) = (false, false);
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        i += 1;
+        let part = if in_rem {
+            Part::Rem
+        } else if in_string || b == b'"' {
+            Part::Str
+        } else {
+            Part::Other
+        };
+        match b {
+            END_OF_LINE => break,
+            NUMBER if !in_string && !in_rem => {
+                line.mark_number();
+                i += 5;
+            }
+            // colour control codes with one parameter, AT and TAB with two
+            0x10..=0x15 => i += 1,
+            0x16 | 0x17 => i += 2,
+            FIRST_TOKEN.. => {
+                let keyword = KEYWORDS[(b - FIRST_TOKEN) as usize];
+                let operator = !keyword.starts_with(|c: char| c.is_ascii_alphabetic());
+                let keyword = if operator {
+                    keyword.to_string()
+                } else {
+                    format!("{} ", keyword)
+                };
+                if part == Part::Other {
+                    if !operator && !line.ends_with_space() {
+                        line.push(Part::Other, " ");
+                    }
+                    // keywords stay separate, for the analysis
+                    line.parts.push((Part::Keyword, keyword));
+                    in_rem = b == REM;
+                } else {
+                    if !operator && !line.ends_with_space() {
+                        line.push(part, " ");
+                    }
+                    line.push(part, &keyword);
+                }
+            }
+            _ => {
+                if b == b'"' && !in_rem {
+                    in_string = !in_string;
+                }
+                line.push(part, &character(b));
+            }
+        }
+    }
+    line
+}
+
+/// Spectrum text as printed, e.g. a file name: characters, tokens as their keywords, and
+/// control codes (colors in fancy names) escaped as \xNN.
+pub fn spectrum_text(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0x00..=0x1F => format!("\\x{:02X}", b),
+            FIRST_TOKEN.. => KEYWORDS[(b - FIRST_TOKEN) as usize].to_string(),
+            _ => character(b),
+        })
+        .collect()
+}
+
+/// Returns the character of the Spectrum code: ASCII with £ and ©, block graphics, and
+/// UDGs as \A to \S.
+fn character(b: u8) -> String {
+    match b {
+        0x60 => "£".to_string(),
+        0x7F => "©".to_string(),
+        0x20..=0x7E => (b as char).to_string(),
+        0x80..=0x8F => BLOCK_GRAPHICS[(b - 0x80) as usize].to_string(),
+        0x90..=0xA2 => format!("\\{}", (b'A' + b - 0x90) as char),
+        _ => "?".to_string(),
+    }
+}
+
+/// Reference to a line number: the line it's in, the keyword and the target.
+#[derive(Debug, PartialEq)]
+pub struct Jump {
+    pub from: u16,
+    pub keyword: &'static str,
+    pub target: u16,
+}
+
+/// Lines a variable is assigned (LET, FOR, INPUT, READ, DIM) and used in.
+#[derive(Debug, Default, PartialEq)]
+pub struct VarUsage {
+    pub assigned: Vec<u16>,
+    pub used: Vec<u16>,
+}
+
+/// Cross-reference of a program.
+#[derive(Debug, Default)]
+pub struct Xref {
+    /// GO TO, GO SUB, RUN and RESTORE with a line number
+    pub jumps: Vec<Jump>,
+    /// GO TO, GO SUB, RUN and RESTORE with an expression: line and keyword
+    pub computed: Vec<(u16, &'static str)>,
+    /// by name, lower case, string variables with $
+    pub variables: BTreeMap<String, VarUsage>,
+    /// lines never executed (other than DATA ones), None if computed jumps make it impossible to tell
+    pub unreachable: Option<Vec<u16>>,
+}
+
+const JUMP_KEYWORDS: [&str; 4] = ["GO TO", "GO SUB", "RUN", "RESTORE"];
+/// statements the next one isn't executed after
+const FINAL_KEYWORDS: [&str; 5] = ["GO TO", "RUN", "STOP", "RETURN", "NEW"];
+
+/// What the next variable names in the statement are.
+#[derive(Clone, Copy, PartialEq)]
+enum Naming {
+    Used,
+    /// the next one is assigned (LET, FOR, DIM)
+    AssignedNext,
+    /// all are assigned (INPUT, READ)
+    Assigned,
+    /// the next one is a function (FN, DEF FN)
+    Function,
+}
+
+impl Xref {
+    /// References to lines the program doesn't have. The Spectrum continues with the next
+    /// line then, usually not what was meant.
+    pub fn undefined(&self, lines: &[Line]) -> Vec<&Jump> {
+        self.jumps
+            .iter()
+            .filter(|j| !lines.iter().any(|l| l.number == j.target))
+            .collect()
+    }
+}
+
+/// Analyses the line number references, variable usage and reachability of lines (from the
+/// first line and the autostart one, if given).
+pub fn xref(lines: &[Line], autostart: Option<u16>) -> Xref {
+    let mut xref = Xref::default();
+    let mut falls_through = vec![];
+    for line in lines {
+        let (mut statement_start, mut last_statement, mut conditional) = (true, "", false);
+        let mut naming = Naming::Used;
+        for (i, (part, text)) in line.parts.iter().enumerate() {
+            match part {
+                Part::Keyword => {
+                    let Some(keyword) = KEYWORDS.iter().find(|&&k| k == text.trim_end()) else {
+                        continue;
+                    };
+                    if statement_start {
+                        last_statement = keyword;
+                        statement_start = false;
+                    }
+                    match *keyword {
+                        "IF" => conditional = true,
+                        // the statement executed if the condition holds
+                        "THEN" => statement_start = true,
+                        "LET" | "FOR" | "DIM" => naming = Naming::AssignedNext,
+                        "INPUT" | "READ" => naming = Naming::Assigned,
+                        "FN" | "DEF FN" => naming = Naming::Function,
+                        _ => {}
+                    }
+                    if JUMP_KEYWORDS.contains(keyword) {
+                        add_jump(&mut xref, line.number, keyword, &line.parts[i + 1..]);
+                    }
+                }
+                Part::Other => {
+                    let mut chars = text.chars().peekable();
+                    while let Some(c) = chars.next() {
+                        if c == ':' {
+                            statement_start = true;
+                            naming = Naming::Used;
+                        }
+                        if !c.is_ascii_alphabetic() {
+                            continue;
+                        }
+                        let mut name = c.to_ascii_lowercase().to_string();
+                        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '$') {
+                            name.push(c.to_ascii_lowercase());
+                        }
+                        let usage = xref.variables.entry(name).or_default();
+                        match naming {
+                            Naming::Function => {}
+                            Naming::AssignedNext | Naming::Assigned => usage.assigned.push(line.number),
+                            Naming::Used => usage.used.push(line.number),
+                        }
+                        if naming != Naming::Assigned {
+                            naming = Naming::Used;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        falls_through.push(conditional || !FINAL_KEYWORDS.contains(&last_statement));
+    }
+    xref.variables
+        .retain(|_, usage| !usage.assigned.is_empty() || !usage.used.is_empty());
+    for usage in xref.variables.values_mut() {
+        usage.assigned.dedup();
+        usage.used.dedup();
+    }
+    if xref.computed.is_empty() {
+        xref.unreachable = Some(unreachable(lines, &xref.jumps, &falls_through, autostart));
+    }
+    xref
+}
+
+/// Records the line number reference of the keyword, followed by the parts given.
+fn add_jump(xref: &mut Xref, from: u16, keyword: &'static str, rest: &[(Part, String)]) {
+    let ends_statement = |part: Option<&(Part, String)>| match part {
+        None => true,
+        Some((Part::Other, text)) => text.trim_start().starts_with(':'),
+        Some((part, _)) => *part == Part::Keyword,
+    };
+    match rest.first() {
+        Some((Part::Number, number)) if ends_statement(rest.get(1)) => match number.parse::<u16>() {
+            Ok(target) => xref.jumps.push(Jump { from, keyword, target }),
+            Err(_) => xref.computed.push((from, keyword)),
+        },
+        // RUN and RESTORE go to the first line
+        part if ends_statement(part) && (keyword == "RUN" || keyword == "RESTORE") => {}
+        _ => xref.computed.push((from, keyword)),
+    }
+}
+
+/// Follows the program flow from the first line and the autostart one, returns the numbers
+/// of lines never reached, except DATA ones.
+fn unreachable(lines: &[Line], jumps: &[Jump], falls_through: &[bool], autostart: Option<u16>) -> Vec<u16> {
+    // a missing line continues with the next one
+    let index = |number: u16| lines.iter().position(|l| l.number >= number);
+    let mut reached = vec![false; lines.len()];
+    let mut pending: Vec<usize> = [Some(0), autostart.and_then(index)].into_iter().flatten().collect();
+    while let Some(i) = pending.pop() {
+        if i >= lines.len() || reached[i] {
+            continue;
+        }
+        reached[i] = true;
+        if falls_through[i] {
+            pending.push(i + 1);
+        }
+        let targets = jumps
+            .iter()
+            .filter(|j| j.from == lines[i].number && j.keyword != "RESTORE")
+            .filter_map(|j| index(j.target));
+        pending.extend(targets);
+    }
+    lines
+        .iter()
+        .zip(reached)
+        .filter(|(line, reached)| !reached && line.parts.first().is_none_or(|(_, text)| text != "DATA "))
+        .map(|(line, _)| line.number)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detokenize, spectrum_text, xref, Jump, Part, VarUsage};
+
+    /// Builds the BASIC area of the lines (number and tokenized body).
+    fn program(lines: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut program = vec![];
+        for (number, body) in lines {
+            program.extend_from_slice(&number.to_be_bytes());
+            program.extend_from_slice(&(body.len() as u16 + 1).to_le_bytes());
+            program.extend_from_slice(body);
+            program.push(0x0D);
+        }
+        program
+    }
+
+    #[test]
+    fn test_detokenize() {
+        // 10 PRINT AT 0,1;"HI£":REM done PRINT
+        // 20 LET a1=a1+1.5: IF a1<>3 THEN GO TO 10
+        let mut program = program(&[
+            (
+                10,
+                b"\xF5\xAC0\x0E\0\0\0\0\0,1\x0E\0\0\x01\0\0;\"HI`\x16\x01\x02\":\xEA done \xF5",
+            ),
+            (
+                20,
+                b"\xF1a1=a1+1.5\x0E\x81\x40\0\0\0:\xFAa1\xC93\x0E\0\0\x03\0\0\xCB\xEC10\x0E\0\0\x0A\0\0",
+            ),
+        ]);
+        // variables area
+        program.extend_from_slice(b"\x61\0\0\0\0\0");
+
+        let lines = detokenize(&program);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].number, 10);
+        assert_eq!(lines[0].text(), "PRINT AT 0,1;\"HI£\": REM  done PRINT ");
+        assert_eq!(
+            lines[0].parts[..4],
+            [
+                (Part::Keyword, "PRINT ".to_string()),
+                (Part::Keyword, "AT ".to_string()),
+                (Part::Number, "0".to_string()),
+                (Part::Other, ",".to_string())
+            ]
+        );
+        assert_eq!(lines[0].parts[6], (Part::Str, "\"HI£\"".to_string()));
+        assert_eq!(lines[0].parts.last().unwrap(), &(Part::Rem, " done PRINT ".to_string()));
+
+        assert_eq!(lines[1].text(), "LET a1=a1+1.5: IF a1<>3 THEN GO TO 10");
+        assert!(lines[1].parts.contains(&(Part::Other, "a1=a1+".to_string())));
+        assert!(lines[1].parts.contains(&(Part::Number, "1.5".to_string())));
+        assert!(lines[1].parts.contains(&(Part::Keyword, "<>".to_string())));
+    }
+
+    #[test]
+    fn test_spectrum_text() {
+        assert_eq!(spectrum_text(b"Jet`\x7F"), "Jet£©");
+        assert_eq!(spectrum_text(b"\x10\x02Game\x8F\x90"), "\\x10\\x02Game█\\A");
+        assert_eq!(spectrum_text(b"\xEF\"\""), "LOAD\"\"");
+    }
+
+    #[test]
+    fn test_xref() {
+        let lines = detokenize(&program(&[
+            // LET x=5: GO SUB 100
+            (10, b"\xF1x=5\x0E\0\0\x05\0\0:\xED100\x0E\0\0\x64\0\0"),
+            // IF x THEN GO TO 40
+            (20, b"\xFAx\xCB\xEC40\x0E\0\0\x28\0\0"),
+            // PRINT y$: STOP
+            (30, b"\xF5y$:\xE2"),
+            // PRINT "dead"
+            (35, b"\xF5\"dead\""),
+            // GO TO 30
+            (40, b"\xEC30\x0E\0\0\x1E\0\0"),
+            // DATA 1
+            (50, b"\xE41\x0E\0\0\x01\0\0"),
+            // RETURN
+            (100, b"\xFE"),
+            // RUN 200
+            (110, b"\xF7200\x0E\0\0\xC8\0\0"),
+        ]));
+        let refs = xref(&lines, None);
+        assert_eq!(refs.jumps.len(), 4);
+        assert_eq!(
+            refs.undefined(&lines),
+            [&Jump {
+                from: 110,
+                keyword: "RUN",
+                target: 200
+            }]
+        );
+        assert!(refs.computed.is_empty());
+        assert_eq!(
+            refs.variables["x"],
+            VarUsage {
+                assigned: vec![10],
+                used: vec![20]
+            }
+        );
+        assert_eq!(
+            refs.variables["y$"],
+            VarUsage {
+                assigned: vec![],
+                used: vec![30]
+            }
+        );
+        assert_eq!(refs.unreachable, Some(vec![35, 110]));
+
+        // GO TO a*10
+        let lines = detokenize(&program(&[(10, b"\xECa*10\x0E\0\0\x0A\0\0"), (20, b"\xE2")]));
+        let refs = xref(&lines, None);
+        assert_eq!(refs.computed, [(10, "GO TO")]);
+        assert_eq!(refs.unreachable, None);
+    }
+}
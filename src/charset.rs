@@ -0,0 +1,103 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// 8-bit character sets used for Polish text files.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Charset {
+    /// Mazovia, code page 437 with Polish letters (common on Elwro Junior and Polish PCs)
+    Mazovia,
+    /// Code page 852 (DOS Latin-2)
+    Cp852,
+    /// ISO 8859-2
+    Latin2,
+}
+
+// Characters of bytes 0x80-0xFF (0xA0-0xFF for ISO 8859-2, the rest are C1 controls).
+const MAZOVIA: &str = "ÇüéâäàąçêëèïîćÄĄĘęłôöĆûùŚÖÜ¢Ł¥śƒŹŻóÓńŃźż¿⌐¬½¼¡«»\
+    ░▒▓│┤╡╢╖╕╣║╗╝╜╛┐└┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀\
+    αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+const CP852: &str = "ÇüéâäůćçłëŐőîŹÄĆÉĹĺôöĽľŚśÖÜŤťŁ×čáíóúĄąŽžĘę¬źČş«»\
+    ░▒▓│┤ÁÂĚŞ╣║╗╝Żż┐└┴┬├─┼Ăă╚╔╩╦╠═╬¤đĐĎËďŇÍÎě┘┌█▄ŢŮ▀\
+    ÓßÔŃńňŠšŔÚŕŰýÝţ´\u{ad}˝˛ˇ˘§÷¸°¨˙űŘř■\u{a0}";
+const LATIN2: &str = "\u{a0}Ą˘Ł¤ĽŚ§¨ŠŞŤŹ\u{ad}ŽŻ°ą˛ł´ľśˇ¸šşťź˝žż\
+    ŔÁÂĂÄĹĆÇČÉĘËĚÍÎĎĐŃŇÓÔŐÖ×ŘŮÚŰÜÝŢßŕáâăäĺćçčéęëěíîďđńňóôőö÷řůúűüýţ˙";
+
+impl Charset {
+    /// Returns the first byte covered by the table, and the table.
+    fn table(self) -> (u8, &'static str) {
+        match self {
+            Charset::Mazovia => (0x80, MAZOVIA),
+            Charset::Cp852 => (0x80, CP852),
+            Charset::Latin2 => (0xA0, LATIN2),
+        }
+    }
+
+    /// Converts text to UTF-8.
+    pub fn decode(self, data: &[u8]) -> String {
+        let (first, table) = self.table();
+        let table: Vec<char> = table.chars().collect();
+        data.iter()
+            .map(|&b| match b {
+                b if b >= first => table[(b - first) as usize],
+                b => b as char,
+            })
+            .collect()
+    }
+
+    /// Converts UTF-8 text to the charset. Characters that can't be represented are replaced
+    /// with '?', returns their number too.
+    pub fn encode(self, data: &[u8]) -> Result<(Vec<u8>, usize)> {
+        let text = std::str::from_utf8(data).context("Text is not valid UTF-8")?;
+        let (first, table) = self.table();
+        let mut unmapped = 0;
+        let encoded = text
+            .chars()
+            .map(|c| {
+                if (c as u32) < first as u32 {
+                    return c as u8;
+                }
+                match table.chars().position(|t| t == c) {
+                    Some(idx) => first + idx as u8,
+                    None => {
+                        unmapped += 1;
+                        b'?'
+                    }
+                }
+            })
+            .collect();
+        Ok((encoded, unmapped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Charset, CP852, LATIN2, MAZOVIA};
+
+    #[test]
+    fn test_tables() {
+        assert_eq!(MAZOVIA.chars().count(), 128);
+        assert_eq!(CP852.chars().count(), 128);
+        assert_eq!(LATIN2.chars().count(), 96);
+    }
+
+    #[test]
+    fn test_conversion() {
+        let text = "Zażółć gęślą jaźń, ZAŻÓŁĆ GĘŚLĄ JAŹŃ";
+        let cases: [(Charset, &[u8]); 3] = [
+            (Charset::Mazovia, b"Za\xa7\xa2\x92\x8d"),
+            (Charset::Cp852, b"Za\xbe\xa2\x88\x86"),
+            (Charset::Latin2, b"Za\xbf\xf3\xb3\xe6"),
+        ];
+        for (charset, zazolc) in cases {
+            let (encoded, unmapped) = charset.encode(text.as_bytes()).unwrap();
+            assert_eq!(unmapped, 0);
+            assert_eq!(&encoded[0..6], zazolc);
+            assert_eq!(charset.decode(&encoded), text);
+        }
+
+        assert_eq!(Charset::Mazovia.encode("a€b".as_bytes()).unwrap(), (b"a?b".to_vec(), 1));
+        assert!(Charset::Latin2.encode(b"\xff").is_err());
+    }
+}
@@ -1,5 +1,12 @@
-use anyhow::Result;
-use clap::{Args, Subcommand};
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand, ValueEnum};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::basic::{detokenize, spectrum_text, xref, Line, Part};
+use crate::speccy_files::{SpeccyFileHeader, SpeccyFileType, HEADER_SIZE};
+use crate::util::html_escape;
 
 #[derive(Args)]
 pub struct BasicArgs {
@@ -10,22 +17,257 @@ pub struct BasicArgs {
 #[derive(Subcommand)]
 pub enum BasicCommands {
     /// Dump BASIC program
-    Dump,
+    Dump(DumpArgs),
+    /// Cross-reference: line number references, variables and unreachable lines
+    Xref(XrefArgs),
     /// Tokenize BASIC program
     Tokenize,
 }
 
+#[derive(Args)]
+pub struct DumpArgs {
+    /// program file, with the 17 byte Spectrum header (as on Junior disks) or without one
+    file: String,
+    /// listing format
+    #[arg(short, long, value_enum, default_value_t = DumpFormat::Text)]
+    format: DumpFormat,
+    /// file to write the listing to, rather than stdout
+    #[arg(short, long)]
+    out: Option<String>,
+}
+
+#[derive(Args)]
+pub struct XrefArgs {
+    /// program file, with the 17 byte Spectrum header (as on Junior disks) or without one
+    file: String,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum DumpFormat {
+    /// Plain text, as the Spectrum lists it
+    Text,
+    /// HTML page with keywords, numbers, strings and REMs highlighted
+    Html,
+    /// Markdown with the highlighted listing as a <pre> block
+    Markdown,
+}
+
 pub fn basic(args: BasicArgs) -> Result<()> {
     match args.command {
-        BasicCommands::Dump => dump(),
+        BasicCommands::Dump(dump_args) => dump(dump_args),
+        BasicCommands::Xref(xref_args) => print_xref(xref_args),
         BasicCommands::Tokenize => tokenize(),
     }
 }
 
-fn dump() -> Result<()> {
+/// Lists the program, highlighted for publishing in the HTML and Markdown formats.
+fn dump(args: DumpArgs) -> Result<()> {
+    let program = Program::load(&args.file)?;
+    let lines = detokenize(&program.data);
+    let listing = match args.format {
+        DumpFormat::Text => text_listing(&lines),
+        DumpFormat::Html => html_listing(&program.title, &lines),
+        DumpFormat::Markdown => markdown_listing(&program.title, &lines),
+    };
+
+    match &args.out {
+        Some(out) => {
+            let mut f = File::create(out).with_context(|| format!("Can't create {}", out))?;
+            f.write_all(listing.as_bytes())?;
+            println!("{} lines listed to {}.", lines.len(), out);
+        }
+        None => io::stdout().write_all(listing.as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Program read from a file.
+struct Program {
+    /// name from the header, or the file name
+    title: String,
+    /// the BASIC area, without variables
+    data: Vec<u8>,
+    autostart: Option<u16>,
+}
+
+impl Program {
+    fn load(file: &str) -> Result<Self> {
+        let mut data = std::fs::read(file).with_context(|| format!("Can't read {}", file))?;
+        match SpeccyFileHeader::from_bytes(&data) {
+            Some(header) if header.file_type == SpeccyFileType::Program => {
+                // the variables follow the program
+                let end = (HEADER_SIZE + header.param2.min(header.length) as usize).min(data.len());
+                Ok(Self {
+                    title: spectrum_text(header.name()),
+                    data: data.drain(HEADER_SIZE..end).collect(),
+                    autostart: Some(header.param1).filter(|&line| line < 0x4000),
+                })
+            }
+            _ => Ok(Self {
+                title: Path::new(file)
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                data,
+                autostart: None,
+            }),
+        }
+    }
+}
+
+/// Reports the line number references, variables and unreachable lines, as a lint before
+/// editing a recovered program.
+fn print_xref(args: XrefArgs) -> Result<()> {
+    let program = Program::load(&args.file)?;
+    let lines = detokenize(&program.data);
+    let xref = xref(&lines, program.autostart);
+
+    println!("Line references:");
+    let mut targets: Vec<u16> = xref.jumps.iter().map(|j| j.target).collect();
+    targets.sort_unstable();
+    targets.dedup();
+    for target in targets {
+        let from: Vec<_> = xref
+            .jumps
+            .iter()
+            .filter(|j| j.target == target)
+            .map(|j| format!("{} ({})", j.from, j.keyword))
+            .collect();
+        println!("  {:>4} <- {}", target, from.join(", "));
+    }
+
+    let undefined = xref.undefined(&lines);
+    if !undefined.is_empty() {
+        println!("Undefined lines:");
+        for jump in undefined {
+            println!("  {:>4}: {} {}", jump.from, jump.keyword, jump.target);
+        }
+    }
+    if !xref.computed.is_empty() {
+        println!("Computed line numbers:");
+        for (from, keyword) in &xref.computed {
+            println!("  {:>4}: {}", from, keyword);
+        }
+    }
+
+    println!("Variables:");
+    let list = |lines: &[u16]| lines.iter().map(|l| l.to_string()).collect::<Vec<_>>().join(", ");
+    for (name, usage) in &xref.variables {
+        let mut desc = vec![];
+        if !usage.assigned.is_empty() {
+            desc.push(format!("assigned {}", list(&usage.assigned)));
+        }
+        if !usage.used.is_empty() {
+            desc.push(format!("used {}", list(&usage.used)));
+        }
+        if usage.assigned.is_empty() {
+            desc.push("never assigned".to_string());
+        }
+        println!("  {:<8} {}", name, desc.join(", "));
+    }
+
+    match &xref.unreachable {
+        Some(unreachable) if !unreachable.is_empty() => println!("Unreachable lines: {}", list(unreachable)),
+        Some(_) => println!("All lines are reachable."),
+        None => println!("Reachability not checked, the program has computed line numbers."),
+    }
     Ok(())
 }
 
+fn text_listing(lines: &[Line]) -> String {
+    lines
+        .iter()
+        .map(|line| format!("{:>4} {}\n", line.number, line.text()))
+        .collect()
+}
+
+const STYLE: &str = "pre { background: #cdcdcd; padding: 1em; }\n\
+    .ln { color: #0000cd; }\n\
+    .kw { color: #000000; font-weight: bold; }\n\
+    .num { color: #cd00cd; }\n\
+    .str { color: #00cd00; }\n\
+    .rem { color: #606060; font-style: italic; }\n";
+
+fn html_listing(title: &str, lines: &[Line]) -> String {
+    let body = highlighted(lines, "<span class=\"ln\">", "</span>", |part| match part {
+        Part::Keyword => Some(("<span class=\"kw\">", "</span>")),
+        Part::Number => Some(("<span class=\"num\">", "</span>")),
+        Part::Str => Some(("<span class=\"str\">", "</span>")),
+        Part::Rem => Some(("<span class=\"rem\">", "</span>")),
+        Part::Other => None,
+    });
+    let title = html_escape(title);
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title>\n<style>\n{STYLE}</style></head>\n\
+         <body>\n<h1>{title}</h1>\n<pre>\n{body}</pre>\n</body></html>\n"
+    )
+}
+
+/// Markdown renderers drop styles, so the listing uses plain tags: line numbers as keys,
+/// keywords in bold, strings as code and REMs in italics.
+fn markdown_listing(title: &str, lines: &[Line]) -> String {
+    let body = highlighted(lines, "<kbd>", "</kbd>", |part| match part {
+        Part::Keyword => Some(("<b>", "</b>")),
+        Part::Str => Some(("<code>", "</code>")),
+        Part::Rem => Some(("<i>", "</i>")),
+        Part::Number | Part::Other => None,
+    });
+    format!("# {}\n\n<pre>\n{}</pre>\n", title, body)
+}
+
+/// Formats the lines as HTML, wrapping line numbers and the parts in the tags given.
+fn highlighted(
+    lines: &[Line],
+    number_start: &str,
+    number_end: &str,
+    tags: impl Fn(Part) -> Option<(&'static str, &'static str)>,
+) -> String {
+    let mut out = String::new();
+    for line in lines {
+        out += &format!("{}{:>4}{} ", number_start, line.number, number_end);
+        for (part, text) in &line.parts {
+            match tags(*part) {
+                Some((start, end)) => out += &format!("{}{}{}", start, html_escape(text), end),
+                None => out += &html_escape(text),
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
 fn tokenize() -> Result<()> {
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{html_listing, markdown_listing, text_listing};
+    use crate::basic::{Line, Part};
+
+    #[test]
+    fn test_listings() {
+        let lines = [Line {
+            number: 10,
+            parts: vec![
+                (Part::Keyword, "PRINT ".to_string()),
+                (Part::Str, "\"<&>\"".to_string()),
+                (Part::Other, ":".to_string()),
+                (Part::Keyword, " GO TO ".to_string()),
+                (Part::Number, "10".to_string()),
+            ],
+        }];
+        assert_eq!(text_listing(&lines), "  10 PRINT \"<&>\": GO TO 10\n");
+        let html = html_listing("demo", &lines);
+        assert!(html.contains(
+            "<span class=\"ln\">  10</span> <span class=\"kw\">PRINT </span>\
+             <span class=\"str\">&quot;&lt;&amp;&gt;&quot;</span>:"
+        ));
+        assert!(html.contains("<span class=\"num\">10</span>\n"));
+        assert_eq!(
+            markdown_listing("demo", &lines),
+            "# demo\n\n<pre>\n<kbd>  10</kbd> <b>PRINT </b><code>&quot;&lt;&amp;&gt;&quot;</code>:<b> GO TO </b>10\n</pre>\n"
+        );
+    }
+}
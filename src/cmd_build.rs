@@ -0,0 +1,229 @@
+use anyhow::{bail, Context, Result};
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::cmd_dsk::{check_overwrite, new_fs, save_new_image};
+use crate::cpm::{CpmFs, CpmVersion, FileId, FilenameMode};
+use crate::error::{ErrorKind, Failure};
+use crate::file_arg::DEFAULT_USER;
+use crate::profile::{Profile, DEFAULT_PROFILE};
+use crate::util::thousands;
+
+#[derive(Args)]
+pub struct BuildArgs {
+    /// manifest file (TOML), paths in it are relative to its directory
+    pub manifest: PathBuf,
+    /// image file to create
+    pub image_file: String,
+    /// overwrite the image file if it exists
+    #[arg(short, long)]
+    pub force: bool,
+    /// don't report copied files
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+/// Image description, e.g.:
+///
+/// ```toml
+/// profile = "junior"
+/// label = "RELEASE"
+/// boot = "build/boot.bin"
+///
+/// [[file]]
+/// src = "build/game.com"
+/// dst = "0:GAME.COM"
+///
+/// [[file]]
+/// src = "README.md"
+/// dst = "README.TXT"
+/// text = true
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// disk format profile (default junior)
+    profile: Option<String>,
+    /// CP/M version, "2.2" or "3" (defaults to the format's, labels need 3)
+    cpm_version: Option<String>,
+    label: Option<String>,
+    /// system binary, written to the system area
+    boot: Option<PathBuf>,
+    /// offset of the Disk Parameter Block copy in the system area
+    dpb_offset: Option<usize>,
+    #[serde(default, rename = "file")]
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    /// local file
+    src: PathBuf,
+    /// NAME.EXT or N:NAME.EXT
+    dst: String,
+    /// text mode (terminate with ^Z)
+    #[serde(default)]
+    text: bool,
+}
+
+/// Builds an image from a manifest. Files are written in the manifest order, so the same
+/// sources always give the same image.
+pub fn build(args: BuildArgs) -> Result<()> {
+    let text = fs::read_to_string(&args.manifest).with_context(|| format!("Can't read {}", args.manifest.display()))?;
+    let manifest: Manifest = toml::from_str(&text).map_err(|e| {
+        Failure::new(
+            ErrorKind::Usage,
+            format!("Invalid manifest {}: {}", args.manifest.display(), e),
+        )
+    })?;
+    let base = args.manifest.parent().unwrap_or(Path::new(""));
+
+    check_overwrite(&args.image_file, args.force)?;
+    let mut fs = build_fs(&manifest, base, args.quiet)?;
+    save_new_image(&mut fs, &args.image_file)?;
+    if !args.quiet {
+        println!(
+            "{} created, {} files, {} blocks free.",
+            args.image_file,
+            manifest.files.len(),
+            fs.free_blocks()
+        );
+    }
+    Ok(())
+}
+
+fn build_fs(manifest: &Manifest, base: &Path, quiet: bool) -> Result<CpmFs> {
+    let profile = Profile::find(manifest.profile.as_deref().unwrap_or(DEFAULT_PROFILE))?;
+    let version = match &manifest.cpm_version {
+        Some(v) => CpmVersion::from_str(v, true)
+            .map_err(|_| Failure::new(ErrorKind::Usage, format!("Invalid CP/M version: {}", v)))?,
+        None => profile.params.version,
+    };
+    let boot = manifest.boot.as_ref().map(|b| base.join(b));
+    let mut fs = new_fs(
+        profile,
+        version,
+        boot.as_deref(),
+        manifest.dpb_offset,
+        manifest.label.as_deref(),
+    )?;
+
+    for file in &manifest.files {
+        let (user, name) = match file.dst.split_once(':') {
+            Some((user, name)) => {
+                let user = user
+                    .parse()
+                    .map_err(|_| Failure::new(ErrorKind::Usage, format!("Invalid user in {}", file.dst)))?;
+                (user, name)
+            }
+            None => (DEFAULT_USER, file.dst.as_str()),
+        };
+        if name.is_empty() {
+            bail!(Failure::new(
+                ErrorKind::Usage,
+                format!("Missing file name in {}", file.dst)
+            ));
+        }
+        let id = FileId::new_with_filename(user, name, FilenameMode::Normalized)
+            .with_context(|| format!("Invalid destination {}", file.dst))?;
+
+        let src = base.join(&file.src);
+        let mut lf = File::open(&src).with_context(|| format!("Can't open {}", src.display()))?;
+        fs.write_file(&id, &mut lf, file.text)
+            .with_context(|| format!("Can't store {} on the image", src.display()))?;
+        if !quiet {
+            let size = lf.metadata()?.len() as usize;
+            println!(
+                "{} -> {}:{}, {} bytes",
+                file.src.display(),
+                id.user,
+                id.filename(),
+                thousands(size)
+            );
+        }
+    }
+    Ok(fs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, BuildArgs};
+    use crate::cpm::{CpmFs, CpmVersion, LsMode};
+    use crate::profile::Profile;
+    use std::fs::File;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_build() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_build");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("boot.bin"), [0xC3, 0x00, 0x01]).unwrap();
+        std::fs::write(dir.join("game.com"), vec![0x55; 3000]).unwrap();
+        std::fs::write(dir.join("readme"), "Hello\n").unwrap();
+        std::fs::write(
+            dir.join("image.toml"),
+            r#"
+cpm_version = "3"
+label = "RELEASE"
+boot = "boot.bin"
+
+[[file]]
+src = "game.com"
+dst = "GAME.COM"
+
+[[file]]
+src = "readme"
+dst = "3:README.TXT"
+text = true
+"#,
+        )
+        .unwrap();
+
+        let images: Vec<Vec<u8>> = ["a.dsk", "b.dsk"]
+            .iter()
+            .map(|name| {
+                let image_file = dir.join(name).display().to_string();
+                build(BuildArgs {
+                    manifest: dir.join("image.toml"),
+                    image_file: image_file.clone(),
+                    force: true,
+                    quiet: true,
+                })
+                .unwrap();
+                std::fs::read(image_file).unwrap()
+            })
+            .collect();
+        assert_eq!(images[0], images[1]);
+
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V3);
+        let fs = CpmFs::load(&mut File::open(dir.join("a.dsk")).unwrap(), params).unwrap();
+        assert_eq!(fs.label().as_deref(), Some("RELEASE"));
+        assert_eq!(fs.read_system_area().unwrap()[0..3], [0xC3, 0x00, 0x01]);
+        let mut files: Vec<_> = fs
+            .list_files(LsMode::All)
+            .unwrap()
+            .into_iter()
+            .map(|f| (f.user, f.name, f.size))
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                (Some(0), "GAME.COM".to_string(), 3072),
+                (Some(3), "README.TXT".to_string(), 128)
+            ]
+        );
+
+        std::fs::write(dir.join("bad.toml"), "[[file]]\nsrc = \"x\"\n").unwrap();
+        let result = build(BuildArgs {
+            manifest: dir.join("bad.toml"),
+            image_file: dir.join("c.dsk").display().to_string(),
+            force: true,
+            quiet: true,
+        });
+        assert!(format!("{:#}", result.unwrap_err()).contains("missing field `dst`"));
+    }
+}
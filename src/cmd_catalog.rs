@@ -0,0 +1,182 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::cmd_dsk::{image_digest, load_image_fs, STDIO};
+use crate::config::default_format;
+use crate::cpm::{CpmVersion, LsMode};
+use crate::error::{ErrorKind, Failure};
+use crate::profile::Profile;
+use crate::util::{glob_files, hex, thousands};
+
+#[derive(Args)]
+pub struct CatalogArgs {
+    /// image files, a glob (e.g. 'disks/**/*.dsk', ** matches any number of directories)
+    pub images: String,
+    /// catalog file to write (JSON), - for stdout
+    #[arg(short, long, default_value = STDIO)]
+    pub out: String,
+    /// disk format of the images, defaults to $JUDIM_FORMAT or default_format of the config file,
+    /// if set, otherwise to what the disk specification record of +3/PCW disks gives, or the best
+    /// matching known format
+    #[arg(long)]
+    pub disk_format: Option<String>,
+    /// CP/M version, determines how the directory is interpreted, defaults to the disk format's
+    #[arg(long, value_enum)]
+    pub cpm_version: Option<CpmVersion>,
+}
+
+#[derive(Serialize)]
+struct Catalog {
+    images: Vec<ImageEntry>,
+}
+
+#[derive(Serialize, Default)]
+struct ImageEntry {
+    path: String,
+    /// SHA-256 of the sector data, as printed by `dsk hash` (DSK images only)
+    sha256: Option<String>,
+    cylinders: Option<u8>,
+    sides: Option<u8>,
+    label: Option<String>,
+    free_bytes: Option<usize>,
+    files: Vec<FileEntry>,
+    /// why the image couldn't be cataloged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FileEntry {
+    user: u8,
+    name: String,
+    size: usize,
+    /// SHA-256 of the contents, None if the file can't be read (e.g. truncated)
+    sha256: Option<String>,
+}
+
+/// Writes a JSON catalog of the images: their metadata and files with checksums. Images that
+/// can't be loaded are recorded with the error.
+pub fn catalog(args: CatalogArgs) -> Result<()> {
+    let disk_format = match args.disk_format {
+        Some(format) => Some(format),
+        None => default_format()?,
+    };
+    let profile = disk_format.as_deref().map(Profile::find).transpose()?;
+    let paths = glob_files(&args.images)?;
+    if paths.is_empty() {
+        bail!(Failure::new(
+            ErrorKind::NoMatch,
+            format!("No image files match {}", args.images)
+        ));
+    }
+
+    let images: Vec<ImageEntry> = paths
+        .iter()
+        .map(|path| catalog_image(path, profile, args.cpm_version))
+        .collect();
+    let catalog = Catalog { images };
+    if args.out == STDIO {
+        serde_json::to_writer_pretty(io::stdout().lock(), &catalog)?;
+        println!();
+    } else {
+        let mut f = File::create(&args.out).with_context(|| format!("Can't create {}", args.out))?;
+        serde_json::to_writer_pretty(&mut f, &catalog)?;
+        writeln!(f)?;
+        let files: usize = catalog.images.iter().map(|i| i.files.len()).sum();
+        let failed = catalog.images.iter().filter(|i| i.error.is_some()).count();
+        println!(
+            "{} images ({} failed), {} files cataloged to {}.",
+            catalog.images.len(),
+            failed,
+            thousands(files),
+            args.out
+        );
+    }
+    Ok(())
+}
+
+fn catalog_image(path: &Path, profile: Option<&Profile>, version: Option<CpmVersion>) -> ImageEntry {
+    let mut entry = ImageEntry {
+        path: path.display().to_string(),
+        ..Default::default()
+    };
+    if let Err(e) = fill_image_entry(&mut entry, path, profile, version) {
+        eprintln!("Warning: {}: {:#}", entry.path, e);
+        entry.error = Some(format!("{:#}", e));
+    }
+    entry
+}
+
+fn fill_image_entry(
+    entry: &mut ImageEntry,
+    path: &Path,
+    profile: Option<&Profile>,
+    version: Option<CpmVersion>,
+) -> Result<()> {
+    let mut f = File::open(path).context("Can't open image file")?;
+    let fs = load_image_fs(&mut f, profile, version).context("Error loading image file")?;
+    let disk = fs.disk();
+    entry.sha256 = disk.as_dsk().map(image_digest).transpose()?.map(|d| hex(&d));
+    entry.cylinders = Some(disk.num_cylinders());
+    entry.sides = Some(disk.num_sides());
+    entry.label = fs.label();
+    entry.free_bytes = Some(fs.free_space());
+
+    let mut files = fs.list_files(LsMode::All)?;
+    files.sort_by(|a, b| a.listing_cmp(b));
+    for file in files {
+        let mut data = vec![];
+        let sha256 = fs
+            .read_file(&file, &mut data, false)
+            .ok()
+            .map(|_| hex(&Sha256::digest(&data)));
+        entry.files.push(FileEntry {
+            user: file.user.unwrap_or_default(),
+            name: file.name,
+            size: file.size,
+            sha256,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{catalog, CatalogArgs};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_catalog() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_catalog");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let base = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+        std::fs::copy(base.join("03.dsk"), dir.join("sub/03.dsk")).unwrap();
+        std::fs::write(dir.join("broken.dsk"), b"not an image").unwrap();
+
+        let out = dir.join("catalog.json");
+        catalog(CatalogArgs {
+            images: format!("{}/**/*.dsk", dir.display()),
+            out: out.display().to_string(),
+            disk_format: Some("junior".to_string()),
+            cpm_version: None,
+        })
+        .unwrap();
+
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&out).unwrap()).unwrap();
+        let images = json["images"].as_array().unwrap();
+        assert_eq!(images.len(), 2);
+        assert!(images[0]["path"].as_str().unwrap().ends_with("broken.dsk"));
+        assert!(images[0]["error"].is_string());
+        assert_eq!(images[1]["cylinders"], 80);
+        let files = images[1]["files"].as_array().unwrap();
+        assert_eq!(files.len(), 64);
+        let bdos = files.iter().find(|f| f["name"] == "BDOS.MAC").unwrap();
+        assert_eq!((bdos["user"].clone(), bdos["size"].clone()), (0.into(), 21120.into()));
+        assert_eq!(bdos["sha256"].as_str().unwrap().len(), 64);
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+
+use crate::error::{ErrorKind, Failure};
+use crate::speccy_files::{SpeccyFileHeader, SpeccyFileType, HEADER_SIZE};
+use crate::util::{hexdump, parse_number, thousands};
+
+#[derive(Args)]
+pub struct CodeinfoArgs {
+    /// Code file, with the 17 byte Spectrum header (as on Junior disks) or without one
+    file: String,
+    /// load address, for files without a header or to override the one in the header
+    #[arg(short, long, value_parser = parse_number)]
+    address: Option<usize>,
+    /// only describe the file, without the hexdump
+    #[arg(short, long)]
+    summary: bool,
+}
+
+/// Areas of the 48K memory map worth pointing out, with inclusive ends.
+const MEMORY_MAP: [(u16, u16, &str); 5] = [
+    (0x0000, 0x3FFF, "ROM"),
+    (0x4000, 0x57FF, "screen bitmap"),
+    (0x5800, 0x5AFF, "screen attributes"),
+    (0x5B00, 0x5BFF, "printer buffer"),
+    (0x5C00, 0x5CB5, "system variables"),
+];
+
+const SCREEN_SIZE: usize = 6912;
+const BITMAP_SIZE: usize = 6144;
+/// PROG system variable, the start of the BASIC program
+const PROG: usize = 0x5C53;
+const SYSVARS_END: usize = 0x5CB6;
+
+/// LD BC,0xFFFD and LD BC,0xBFFD, the AY register select and data ports
+const AY_SELECT: &[u8] = &[0x01, 0xFD, 0xFF];
+const AY_DATA: &[u8] = &[0x01, 0xFD, 0xBF];
+/// Names music trackers leave in their players
+const TRACKERS: [&str; 5] = [
+    "ProTracker",
+    "Vortex Tracker",
+    "Sound Tracker",
+    "ASC Sound Master",
+    "Fast Tracker",
+];
+
+/// Hexdumps a Code file at the addresses it loads to, marking the areas of the memory map it
+/// overlaps and describing what the blob likely is.
+pub fn codeinfo(args: CodeinfoArgs) -> Result<()> {
+    let data = std::fs::read(&args.file).with_context(|| format!("Can't read {}", args.file))?;
+    let (address, mut data) = match SpeccyFileHeader::from_bytes(&data) {
+        Some(header) if header.file_type == SpeccyFileType::Code => {
+            println!("{}, {} bytes", header, thousands(header.length as usize));
+            let end = (HEADER_SIZE + header.length as usize).min(data.len());
+            (
+                args.address.unwrap_or(header.param1 as usize),
+                data[HEADER_SIZE..end].to_vec(),
+            )
+        }
+        Some(header) => bail!(Failure::new(
+            ErrorKind::Usage,
+            format!("{} is a {}, not a Code file", args.file, header.file_type)
+        )),
+        None => match args.address {
+            Some(address) => (address, data),
+            None => bail!(Failure::new(
+                ErrorKind::Usage,
+                format!("{} has no header, give the load address with --address", args.file)
+            )),
+        },
+    };
+    if address > 0xFFFF {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            format!("Invalid load address 0x{:X}", address)
+        ));
+    }
+    if address + data.len() > 0x10000 {
+        eprintln!(
+            "Warning: {} bytes past 0xFFFF not shown",
+            thousands(address + data.len() - 0x10000)
+        );
+        data.truncate(0x10000 - address);
+    }
+    if data.is_empty() {
+        println!("No data.");
+        return Ok(());
+    }
+
+    let end = address + data.len() - 1;
+    println!("Loads to 0x{:04X}-0x{:04X}", address, end);
+    for (start, area_end, name) in overlaps(address, end) {
+        println!("  overlaps {} 0x{:04X}-0x{:04X}", name, start, area_end);
+    }
+    match describe(address, &data).as_slice() {
+        [] => println!("No known signature."),
+        findings => findings.iter().for_each(|f| println!("Likely {}.", f)),
+    }
+    if args.summary {
+        return Ok(());
+    }
+
+    println!();
+    let mut marked = vec![];
+    for (idx, line) in hexdump(&data, address).into_iter().enumerate() {
+        let line_start = address + idx * 16;
+        let line_end = (line_start + 15).min(end);
+        for (start, area_end, name) in overlaps(line_start, line_end) {
+            if !marked.contains(&name) {
+                println!("; {} 0x{:04X}-0x{:04X}", name, start, area_end);
+                marked.push(name);
+            }
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Areas of the memory map overlapping the address range (inclusive).
+fn overlaps(start: usize, end: usize) -> Vec<(u16, u16, &'static str)> {
+    MEMORY_MAP
+        .into_iter()
+        .filter(|&(area_start, area_end, _)| start <= area_end as usize && end >= area_start as usize)
+        .collect()
+}
+
+/// What the data loaded at the address looks like: a screen, a memory dump with the BASIC
+/// system variables, an AY music player.
+fn describe(address: usize, data: &[u8]) -> Vec<String> {
+    let mut findings = vec![];
+    if address == 0x4000 && data.len() == SCREEN_SIZE {
+        findings.push("a SCREEN$ (bitmap and attributes)".to_string());
+    } else if address == 0x4000 && data.len() == BITMAP_SIZE {
+        findings.push("a screen bitmap without attributes".to_string());
+    }
+
+    if address <= 0x5C00 && address + data.len() >= SYSVARS_END {
+        let prog = u16::from_le_bytes([data[PROG - address], data[PROG + 1 - address]]) as usize;
+        if (SYSVARS_END..0x10000).contains(&prog) {
+            findings.push(format!(
+                "a memory dump with the BASIC system variables (program at 0x{:04X})",
+                prog
+            ));
+        } else {
+            findings.push("a loader overwriting the system variables".to_string());
+        }
+    }
+
+    let contains = |pattern: &[u8]| data.windows(pattern.len()).any(|w| w == pattern);
+    if contains(AY_SELECT) && contains(AY_DATA) {
+        let tracker = TRACKERS.iter().find(|name| contains(name.as_bytes()));
+        match tracker {
+            Some(name) => findings.push(format!("an AY music player ({})", name)),
+            None => findings.push("an AY music player (writes ports 0xFFFD and 0xBFFD)".to_string()),
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, overlaps};
+
+    #[test]
+    fn test_overlaps() {
+        let names = |start, end| overlaps(start, end).into_iter().map(|(_, _, n)| n).collect::<Vec<_>>();
+        assert_eq!(names(0x4000, 0x5AFF), ["screen bitmap", "screen attributes"]);
+        assert_eq!(names(0x57F8, 0x5807), ["screen bitmap", "screen attributes"]);
+        assert_eq!(names(0x5CB0, 0x5CCB), ["system variables"]);
+        assert!(names(0x8000, 0xFFFF).is_empty());
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(0x4000, &[0; 6912]), ["a SCREEN$ (bitmap and attributes)"]);
+        assert!(describe(0x4001, &[0; 6912]).is_empty());
+
+        let mut dump = vec![0; 0x10000 - 0x4000];
+        dump[0x5C53 - 0x4000..][..2].copy_from_slice(&[0xCB, 0x5C]);
+        assert_eq!(
+            describe(0x4000, &dump),
+            ["a memory dump with the BASIC system variables (program at 0x5CCB)"]
+        );
+        assert_eq!(
+            describe(0x5B00, &[0; 0x200]),
+            ["a loader overwriting the system variables"]
+        );
+
+        let mut player = b"\x01\xFD\xFF\xED\x79\x01\xFD\xBF\xED\x61\xC9".to_vec();
+        assert_eq!(
+            describe(0xC000, &player),
+            ["an AY music player (writes ports 0xFFFD and 0xBFFD)"]
+        );
+        player.extend_from_slice(b"Vortex Tracker II 1.0 module");
+        assert_eq!(describe(0xC000, &player), ["an AY music player (Vortex Tracker)"]);
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+
+use crate::config::default_format;
+use crate::cpm::{CpmVersion, Dpb};
+use crate::profile::{Profile, DEFAULT_PROFILE};
+
+#[derive(Args)]
+pub struct DpbArgs {
+    /// disk format, defaults to $JUDIM_FORMAT or default_format of the config file, if set,
+    /// otherwise to junior
+    #[arg(long, alias = "disk-format")]
+    format: Option<String>,
+    /// CP/M version, defaults to the disk format's. CP/M Plus DPBs have the physical record shift
+    /// and mask (PSH, PHM) too
+    #[arg(long, value_enum)]
+    cpm_version: Option<CpmVersion>,
+    /// language of the tables
+    #[arg(short, long, value_enum, default_value_t = Emit::Asm)]
+    emit: Emit,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum Emit {
+    /// Z80 assembler, DEFB/DEFW
+    Asm,
+    /// C byte arrays
+    C,
+    /// Rust byte arrays
+    Rust,
+}
+
+/// DPB field: name, value, true for words, description.
+type Field = (&'static str, u16, bool, &'static str);
+
+/// Prints the Disk Parameter Block and the skew table of a format as source code, for BIOS
+/// and emulator writers.
+pub fn dpb(args: DpbArgs) -> Result<()> {
+    let format = match args.format {
+        Some(format) => format,
+        None => default_format()?.unwrap_or(DEFAULT_PROFILE.to_string()),
+    };
+    let profile = Profile::find(&format)?;
+    let version = args.cpm_version.unwrap_or(profile.params.version);
+    print!("{}", tables(profile, version, args.emit));
+    Ok(())
+}
+
+/// The DPB fields in the BIOS order, PSH and PHM for CP/M Plus only.
+fn fields(dpb: &Dpb, version: CpmVersion) -> Vec<Field> {
+    let mut fields = vec![
+        ("SPT", dpb.spt, true, "128-byte records per track"),
+        ("BSH", dpb.bsh as u16, false, "block shift factor"),
+        ("BLM", dpb.blm as u16, false, "block mask"),
+        ("EXM", dpb.exm as u16, false, "extent mask"),
+        ("DSM", dpb.dsm, true, "number of the last block"),
+        ("DRM", dpb.drm, true, "number of the last directory entry"),
+        ("AL0", dpb.al >> 8, false, "directory blocks allocation bitmap"),
+        (
+            "AL1",
+            dpb.al & 0xFF,
+            false,
+            "directory blocks allocation bitmap, continued",
+        ),
+        ("CKS", dpb.cks, true, "size of the directory check vector"),
+        ("OFF", dpb.off, true, "number of reserved tracks"),
+    ];
+    if version == CpmVersion::V3 {
+        fields.push(("PSH", dpb.psh as u16, false, "physical record shift factor"));
+        fields.push(("PHM", dpb.phm as u16, false, "physical record mask"));
+    }
+    fields
+}
+
+/// Little endian bytes of the field, as hex literals of the language.
+fn field_bytes((_, value, word, _): &Field, hex: impl Fn(u8) -> String) -> Vec<String> {
+    let bytes = value.to_le_bytes();
+    let len = if *word { 2 } else { 1 };
+    bytes[..len].iter().map(|&b| hex(b)).collect()
+}
+
+/// Formats the DPB and the skew table (sector IDs in the physical order of a track) as source
+/// code.
+fn tables(profile: &Profile, version: CpmVersion, emit: Emit) -> String {
+    let fields = fields(&profile.dpb(version), version);
+    let size: usize = fields.iter().map(|f| if f.2 { 2 } else { 1 }).sum();
+    let version_name = match version {
+        CpmVersion::V22 => "CP/M 2.2",
+        CpmVersion::V3 => "CP/M Plus",
+    };
+    let title = format!("{}, {}", profile.description, version_name);
+    let ident = profile.name.to_ascii_uppercase().replace('-', "_");
+    let skew: Vec<String> = profile.sector_ids.iter().map(|id| id.to_string()).collect();
+    let skew = skew.join(", ");
+    let num_sectors = profile.sector_ids.len();
+
+    let mut out = String::new();
+    match emit {
+        Emit::Asm => {
+            out += &format!(
+                "; {}\n; Disk Parameter Block, generated by judim\nDPB_{}:\n",
+                title, ident
+            );
+            for f in &fields {
+                let directive = if f.2 { "DEFW" } else { "DEFB" };
+                // the allocation bitmap reads better in hex
+                let value = match f.0.starts_with("AL") {
+                    true => format!("0{:02X}H", f.1),
+                    false => f.1.to_string(),
+                };
+                out += &format!("    {} {:<8}; {} {}\n", directive, value, f.0, f.3);
+            }
+            out += &format!(
+                "\n; sector IDs in the physical order of a track\nSKEW_{}:\n    DEFB {}\n",
+                ident, skew
+            );
+        }
+        Emit::C | Emit::Rust => {
+            let c = emit == Emit::C;
+            let comment = |text: &str| match c {
+                true => format!("/* {} */", text),
+                false => format!("// {}", text),
+            };
+            let array = |name: &str, len: usize| match c {
+                true => format!(
+                    "static const unsigned char {}_{}[{}] =",
+                    name.to_lowercase(),
+                    ident.to_lowercase(),
+                    len
+                ),
+                false => format!("pub const {}_{}: [u8; {}] =", name, ident, len),
+            };
+            let (open, close) = if c { ("{", "}") } else { ("[", "]") };
+
+            out += &format!(
+                "{}\n{}\n",
+                comment(&title),
+                comment("Disk Parameter Block, generated by judim")
+            );
+            out += &format!("{} {}\n", array("DPB", size), open);
+            for f in &fields {
+                let bytes = field_bytes(f, |b| format!("0x{:02X},", b)).join(" ");
+                out += &format!("    {:<12}{}\n", bytes, comment(&format!("{} {}: {}", f.0, f.1, f.3)));
+            }
+            out += &format!("{};\n\n", close);
+            out += &format!("{}\n", comment("sector IDs in the physical order of a track"));
+            out += &format!("{} {}{}{};\n", array("SKEW", num_sectors), open, skew, close);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{field_bytes, fields, tables, Emit};
+    use crate::cpm::CpmVersion;
+    use crate::profile::Profile;
+
+    #[test]
+    fn test_tables() {
+        let junior = Profile::find("junior").unwrap();
+        for version in [CpmVersion::V22, CpmVersion::V3] {
+            let dpb = junior.dpb(version);
+            let bytes: Vec<u8> = fields(&dpb, version)
+                .iter()
+                .flat_map(|f| field_bytes(f, |b| b.to_string()))
+                .map(|b| b.parse::<u8>().unwrap())
+                .collect();
+            assert_eq!(bytes, dpb.to_bytes(version));
+        }
+
+        let rust = tables(junior, CpmVersion::V3, Emit::Rust);
+        assert!(rust.contains("pub const DPB_JUNIOR: [u8; 17] = [\n    0x24, 0x00, // SPT 36: "));
+        assert!(rust.contains("pub const SKEW_JUNIOR: [u8; 9] = [1, 6, 2, 7, 3, 8, 4, 9, 5];"));
+        let c = tables(junior, CpmVersion::V22, Emit::C);
+        assert!(c.contains("static const unsigned char dpb_junior[15] = {"));
+        assert!(c.contains("    0xF0,       /* AL0 240: "));
+        let asm = tables(Profile::find("cpc-data").unwrap(), CpmVersion::V22, Emit::Asm);
+        assert!(asm.contains("DPB_CPC_DATA:\n    DEFW 36      ; SPT 128-byte records per track\n"));
+        assert!(asm.contains("    DEFB 0C0H    ; AL0 "));
+        assert!(asm.contains("    DEFB 3       ; BSH "));
+        assert!(asm.contains("SKEW_CPC_DATA:\n    DEFB 193, 198,"));
+    }
+}
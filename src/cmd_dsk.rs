@@ -1,30 +1,120 @@
+mod batch;
+mod boot;
+mod browse;
+mod cmp;
+mod dedup;
+mod dir;
+mod edit;
+mod export;
+mod fsdump;
+mod hash;
+mod imgdiff;
+mod import;
+mod info;
+mod interleave;
+mod map;
+mod mkfs;
+mod reformat;
+mod scan;
+mod serial;
+mod serve;
+mod stat;
+mod sync;
+mod undelete;
+mod users;
+mod volumes;
+
 use anyhow::{bail, Context, Result};
 use clap::{Args, Subcommand, ValueEnum};
-use prettytable::{format, row, Table};
-use std::fs::File;
-use std::path::Path;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-use crate::cpm::{CpmFs, FileItem, LsMode, Params};
-use crate::file_arg::FileArg;
+use crate::charset::Charset;
+use crate::color::Style;
+use crate::config::{default_format, Config, TransferRule};
+use crate::cpm::{
+    CpmFs, CpmVersion, EntryStatus, FileId, FileItem, FilenameMode, LsMode, Params, Placement, MAX_USER_ID, RECORD_SIZE,
+};
+use crate::dsk::{DiskBackend, DskImage, RawImage, CHS};
+use crate::error::{ErrorKind, Failure};
+use crate::file_arg::{FileArg, ImageGlob, UserList, DEFAULT_USER};
+use crate::mdos::MdosFs;
+use crate::mgt::MgtFs;
+use crate::output::{self, Listing};
+use crate::profile::{Profile, SpecRecord, DEFAULT_PROFILE, PROFILES};
+use crate::retro_fs::{self, file_exists, FsFile, FsFileId, Recoverability, RetroFs, Select};
+use crate::speccy_files::{SpeccyFileHeader, HEADER_SIZE};
+use crate::stats;
+use crate::util::{human_size, parse_number, parse_ranges, safe_filename, thousands, unique_filename};
+use boot::BootArgs;
+use browse::BrowseArgs;
+use cmp::CmpArgs;
+use dedup::DedupArgs;
+use dir::DirArgs;
+use edit::EditArgs;
+use export::ExportArgs;
 use fast_glob::glob_match;
+use fsdump::FsdumpArgs;
+pub use hash::image_digest;
+use hash::HashArgs;
+use imgdiff::ImgdiffArgs;
+use import::ImportArgs;
+use interleave::InterleaveArgs;
+use map::MapArgs;
+use mkfs::MkfsArgs;
+pub use mkfs::{check_overwrite, new_fs, save_new_image};
+use reformat::ReformatArgs;
+use serial::SerialArgs;
+use serve::ServeArgs;
+use stat::StatArgs;
+use sync::SyncArgs;
+use undelete::UndeleteArgs;
+use volumes::{JoinArgs, SplitArgs};
+
+/// Local path (or image file) standing for stdin or stdout.
+pub const STDIO: &str = "-";
 
-#[derive(Args)]
+/// Disk format name of +D/DISCiPLE disks, which have the MGT filesystem rather than CP/M.
+const MGT_FORMAT: &str = "mgt";
+const MDOS_FORMAT: &str = "mdos";
+
+#[derive(Args, Clone)]
 pub struct DskArgs {
-    /// The disk image file
+    /// The disk image file, - to read it from stdin (read-only commands only), or a glob
+    /// (e.g. 'disks/*.dsk') to run a read-only command on every matching image
     pub image_file: String,
 
+    /// Disk format (geometry and filesystem parameters), mgt for +D/DISCiPLE disks (.mgt or .img),
+    /// mdos for Didaktik 40/80 ones. Defaults to $JUDIM_FORMAT or default_format of the config
+    /// file, if set, otherwise to mdos for .d40/.d80 images, to what the disk specification
+    /// record of +3/PCW disks gives, or the best matching known format
+    #[arg(long)]
+    pub disk_format: Option<String>,
+
+    /// CP/M version, determines how the directory is interpreted. Defaults to the disk format's:
+    /// 2.2 for Junior (CP/J) and CPC disks, 3 for +3/PCW ones
+    #[arg(long, value_enum)]
+    pub cpm_version: Option<CpmVersion>,
+
+    /// When writing, use never used blocks and directory entries before the ones of deleted files
+    #[arg(long)]
+    pub preserve_deleted: bool,
+
     #[command(subcommand)]
     pub command: DskCommands,
 }
 
-#[derive(Subcommand)]
+#[derive(Subcommand, Clone)]
 pub enum DskCommands {
     #[command(
         about = "List files stored in the disk image.",
         long_about = "The 'ls' command lists the files present in the disk image. \
            \n\n\
            By default files all files are listed, except deleted ones. Use the --user option to\n\
-           filter by the user number. Use the --deleted option to include deleted files.\n\n\
+           filter by the user number (or numbers, e.g. --user 0-3,15), or prefix the globs with\n\
+           it (e.g. '3:*.PAS'). Use the --deleted option to include deleted files.\n\n\
            Note: CP/M uses 0xE5 as a user number to mark unused directory entries.\n\
            Hence --deleted and --user options are mutually exclusive."
     )]
@@ -37,6 +127,201 @@ pub enum DskCommands {
     /// Copy files
     #[command(about = "Copy file or files to/from the disk image")]
     Cp(CpArgs),
+
+    /// Store files
+    #[command(about = "Copy local files into the disk image")]
+    Put(PutArgs),
+
+    /// Compare two files
+    #[command(about = "Compare two files byte by byte, in two user areas (cmp 0:PROG.COM 3:PROG.COM) or two images")]
+    Cmp(CmpArgs),
+
+    /// List user areas
+    #[command(about = "List the user areas holding files, with file, byte and block counts")]
+    Users,
+
+    /// Find identical files
+    #[command(about = "List groups of files with identical contents, optionally deleting all but the first copy")]
+    Dedup(DedupArgs),
+
+    /// Show disk image information
+    #[command(about = "Show disk image information (geometry, label, free space)")]
+    Info,
+
+    /// Show file details
+    #[command(about = "Show a file's size, blocks, flags and time stamps, or its raw directory entries (--raw)")]
+    Stat(StatArgs),
+
+    /// Show the block map
+    #[command(about = "Show the map of all blocks (directory, used, free, bad), or where a single file is stored")]
+    Map(MapArgs),
+
+    /// Scan for bad sectors
+    #[command(
+        about = "Check every sector for read errors recorded in the image, listing the files stored in bad ones"
+    )]
+    Scan,
+
+    /// Split a file across images
+    #[command(about = "Split a file into parts (NAME.001, ...) filling the free space of other images, in order")]
+    Split(SplitArgs),
+
+    /// Join a split file
+    #[command(about = "Join the parts of a split file (NAME.001, ...) found on other images into a single file")]
+    Join(JoinArgs),
+
+    /// Raw directory access
+    #[command(about = "Inspect or tidy up the directory: dump all slots, sort or compact the entries")]
+    Dir(DirArgs),
+
+    /// Export all files
+    #[command(about = "Export all files into a zip or tar archive (as userN/NAME.EXT)")]
+    Export(ExportArgs),
+
+    /// Dump the whole filesystem with a manifest
+    #[command(
+        about = "Extract all files, the system area and a JSON manifest (parameters, flags, sizes, block layout) into a directory"
+    )]
+    Fsdump(FsdumpArgs),
+
+    /// Import an archive
+    #[command(about = "Import all files of a zip or tar archive (userN/ directories select the user)")]
+    Import(ImportArgs),
+
+    /// Mirror a local directory
+    #[command(about = "Copy new and changed files between a local directory and a user area of the image")]
+    Sync(SyncArgs),
+
+    /// Serial transfer to and from a real machine
+    #[command(about = "Send or receive files over a serial port (XMODEM/YMODEM)")]
+    Serial(SerialArgs),
+
+    /// HTTP server
+    #[command(about = "Serve the image over HTTP: JSON API (list, download, upload, delete) and an HTML index")]
+    Serve(ServeArgs),
+
+    /// Interactive two-pane browser
+    #[command(about = "Browse the image and a local directory side by side, copy, rename, delete and view files")]
+    Browse(BrowseArgs),
+
+    /// Checksum of the sector data
+    #[command(about = "Print SHA-256 of the sector data, independent of the image layout")]
+    Hash(HashArgs),
+
+    /// Sector order analysis
+    #[command(about = "Show the sector interleave and skew of every track, flagging irregular tracks")]
+    Interleave(InterleaveArgs),
+
+    /// Sector hex editor
+    #[command(about = "Edit a sector in a hex editor (e.g. edit 0 0 1), move between sectors, save when done")]
+    Edit(EditArgs),
+
+    /// Compare images sector by sector
+    #[command(about = "Compare raw sector data with another image, track by track")]
+    Imgdiff(ImgdiffArgs),
+
+    /// Show or set the disk label
+    #[command(about = "Show or set the disk label (CP/M Plus directory label)")]
+    Label(LabelArgs),
+
+    /// Delete files
+    #[command(about = "Delete files from the disk image")]
+    Rm(RmArgs),
+
+    /// Bring back deleted files
+    #[command(about = "Bring a deleted file back, or save what's left of a partially overwritten one (--partial)")]
+    Undelete(UndeleteArgs),
+
+    /// Move files to another user
+    #[command(about = "Move files to another user area, e.g. chuser '2:*' 0")]
+    Chuser(ChuserArgs),
+
+    /// Create empty files
+    #[command(about = "Create empty (zero-length) files in the disk image")]
+    Touch(TouchArgs),
+
+    /// Change the image size
+    #[command(about = "Grow or shrink the image, adding or removing cylinders")]
+    Resize(ResizeArgs),
+
+    /// Convert to another format
+    #[command(about = "Copy the image contents to a new image of another format (geometry, block size)")]
+    Reformat(ReformatArgs),
+
+    /// System area (boot tracks) operations
+    #[command(about = "Read or write the system area (reserved boot tracks)")]
+    Boot(BootArgs),
+
+    /// Create a new image
+    #[command(about = "Create a new, empty disk image (optionally with the system and the DPB)")]
+    Mkfs(MkfsArgs),
+}
+
+impl DskCommands {
+    /// Returns true for commands which can run on multiple images: read-only, non-interactive
+    /// ones, not producing a fixed set of output files.
+    fn batch_allowed(&self) -> bool {
+        !self.modifies_image()
+            && !self.writes_local_path()
+            && !matches!(
+                self,
+                DskCommands::Mkfs(_)
+                    | DskCommands::Edit(_)
+                    | DskCommands::Browse(_)
+                    | DskCommands::Serve(_)
+                    | DskCommands::Serial(_)
+                    | DskCommands::Sync(_)
+                    | DskCommands::Split(_)
+            )
+    }
+
+    /// Returns true for commands writing images other than the one given (split volumes,
+    /// reformat output).
+    fn writes_other_images(&self) -> bool {
+        matches!(self, DskCommands::Split(_) | DskCommands::Reformat(_))
+    }
+
+    /// Returns true for commands writing to a local path given on the command line (other than
+    /// stdout), which every image of a batch would overwrite.
+    fn writes_local_path(&self) -> bool {
+        match self {
+            DskCommands::Get(args) => args.local_path != STDIO,
+            DskCommands::Cp(args) => matches!(&args.dst_file, FileArg::Local { path } if path != Path::new(STDIO)),
+            DskCommands::Boot(args) => args.writes_local_path(),
+            DskCommands::Undelete(args) => args.partial.is_some(),
+            DskCommands::Export(_) | DskCommands::Fsdump(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true for commands which need to write the image back.
+    fn modifies_image(&self) -> bool {
+        match self {
+            DskCommands::Boot(args) => args.modifies_image(),
+            DskCommands::Dir(args) => args.modifies_image(),
+            DskCommands::Serial(args) => args.modifies_image(),
+            DskCommands::Serve(args) => !args.read_only,
+            DskCommands::Sync(args) => !args.from_image && !args.dry_run,
+            DskCommands::Import(args) => !args.dry_run,
+            DskCommands::Label(args) => args.label.is_some() || args.clear,
+            DskCommands::Touch(_) | DskCommands::Resize(_) | DskCommands::Browse(_) | DskCommands::Join(_) => true,
+            DskCommands::Rm(args) => !args.dry_run,
+            DskCommands::Undelete(args) => args.partial.is_none(),
+            DskCommands::Dedup(args) => args.delete,
+            DskCommands::Chuser(args) => !args.dry_run,
+            DskCommands::Put(args) => !args.dry_run,
+            DskCommands::Cp(args) => !args.dry_run && !args.dst_file.is_local(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum LsSort {
+    /// By user, then by name (deleted files last)
+    Name,
+    /// Directory order, as stored on the disk
+    None,
 }
 
 #[derive(Clone, ValueEnum, Debug, PartialEq)]
@@ -49,40 +334,149 @@ pub enum LsFormat {
     Verbose,
 }
 
-#[derive(Args)]
+/// What to do when a file being written to the image already exists there.
+#[derive(Clone, Copy, ValueEnum, Debug, PartialEq)]
+pub enum OnConflict {
+    /// Stop with an error
+    Fail,
+    /// Leave the existing file, don't copy
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Store under a numbered name (GAME1.COM, GAME2.COM, ...)
+    Rename,
+    /// Ask for each conflicting file
+    Ask,
+}
+
+#[derive(Args, Clone)]
+#[command(disable_help_flag = true)]
 pub struct LsArgs {
-    /// Include deleted files
+    /// Print help
+    #[arg(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
+    /// Show sizes with units, e.g. 12.5K
+    #[arg(short = 'h', long)]
+    human_readable: bool,
+    /// Include deleted files, telling if their blocks are still free (recoverable), partially
+    /// or fully reused by other files
     #[arg(short, long)]
     deleted: bool,
-    /// Filter by the user number
+    /// List only deleted files, with their status as for --deleted
+    #[arg(short = 'D', long, conflicts_with_all = ["deleted", "user"])]
+    deleted_only: bool,
+    /// Filter by the user number, or a list of numbers and ranges (e.g. 0-3,15)
     #[arg(short, long)]
-    user: Option<u8>,
+    user: Option<UserList>,
+    /// Show the number of 128 byte records and the allocated size (whole blocks) of files
+    #[arg(short, long)]
+    allocation: bool,
+    /// Show the ZX Spectrum header (type, name, autostart or load address) found at the start of files
+    #[arg(short, long)]
+    speccy: bool,
     /// Output format
     #[arg(short, long, value_enum, default_value_t = LsFormat::Default)]
     format: LsFormat,
-    /// Glob expression to filter the files
-    glob: Option<String>,
+    /// Sort order
+    #[arg(long, value_enum, default_value_t = LsSort::Name)]
+    sort: LsSort,
+    /// Exclude files matching the glob (may be repeated)
+    #[arg(short = 'x', long)]
+    exclude: Vec<String>,
+    /// Take the filters as regular expressions rather than globs, e.g. '^(GAME|DEMO)[0-9]\.COM$'
+    #[arg(short = 'E', long)]
+    regex: bool,
+    /// Glob expressions to filter the files, N:GLOB for user N, *:GLOB for all users (without
+    /// deleted files)
+    globs: Vec<ImageGlob>,
+    /// image name to prefix the output with, when listing multiple images
+    #[arg(skip)]
+    batch_image: Option<String>,
 }
 
-#[derive(Args)]
+#[derive(Args, Clone)]
 pub struct GetArgs {
     /// user number (default 0)
     #[arg(short, long)]
     user: Option<u8>,
-    /// text mode (trim at ^Z)
+    /// text mode (trim at ^Z) for all files, rather than as configured by the transfer rules
     #[arg(short, long)]
     text: bool,
-    /// file or glob
-    image_file: String,
-    /// local file name or path
+    /// binary mode for all files, rather than as configured by the transfer rules
+    #[arg(short, long, conflicts_with = "text")]
+    binary: bool,
+    /// convert text from this character set to UTF-8 (text files only)
+    #[arg(long, value_enum)]
+    charset: Option<Charset>,
+    /// exclude files matching the glob (may be repeated)
+    #[arg(short = 'x', long)]
+    exclude: Vec<String>,
+    /// extract what's stored for truncated files (with a warning), instead of failing
+    #[arg(long)]
+    lenient: bool,
+    /// write whole 128 byte records, regardless of the last record byte count, transfer rules
+    /// and text mode
+    #[arg(long, conflicts_with_all = ["text", "charset"])]
+    raw: bool,
+    /// skip the first N bytes (0x prefix for hex), regardless of transfer rules and text mode
+    #[arg(long, value_name = "N", value_parser = parse_number, conflicts_with_all = ["text", "charset"])]
+    offset: Option<usize>,
+    /// write exactly N bytes of the file (of what's stored, rather than the recorded size),
+    /// regardless of transfer rules and text mode
+    #[arg(long, value_name = "N", value_parser = parse_number, conflicts_with_all = ["text", "charset", "raw"])]
+    length: Option<usize>,
+    /// don't set modification times of extracted files from CP/M Plus time stamps
+    #[arg(long)]
+    no_preserve_times: bool,
+    /// don't report copied files
+    #[arg(short, long)]
+    quiet: bool,
+    /// list the matching files and ask which ones to extract
+    #[arg(short, long)]
+    interactive: bool,
+    /// take the file patterns as regular expressions rather than globs, e.g. '^(GAME|DEMO)[0-9]\.COM$'
+    #[arg(short = 'E', long)]
+    regex: bool,
+    /// files or globs, N:GLOB for user N, *:GLOB for all users
+    #[arg(required = true)]
+    image_files: Vec<ImageGlob>,
+    /// local file name or path, - for stdout
     local_path: String,
 }
 
-#[derive(Args)]
+#[derive(Args, Clone)]
 pub struct CpArgs {
-    /// text mode (trim at ^Z)
+    /// text mode (trim at ^Z) for all files, rather than as configured by the transfer rules
     #[arg(short, long)]
     text: bool,
+    /// binary mode for all files, rather than as configured by the transfer rules
+    #[arg(short, long, conflicts_with = "text")]
+    binary: bool,
+    /// character set of text on the image, converted from/to UTF-8 (text files only)
+    #[arg(long, value_enum)]
+    charset: Option<Charset>,
+    /// exclude files matching the glob (may be repeated)
+    #[arg(short = 'x', long)]
+    exclude: Vec<String>,
+    /// only show what would be copied, don't modify anything
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// what to do if a file copied to the image already exists
+    #[arg(long, value_enum, default_value_t = OnConflict::Fail)]
+    on_conflict: OnConflict,
+    /// extract what's stored for truncated files (with a warning), instead of failing
+    #[arg(long)]
+    lenient: bool,
+    /// don't set modification times of extracted files from CP/M Plus time stamps
+    #[arg(long)]
+    no_preserve_times: bool,
+    /// set the R/O flag of files copied to the image which aren't writable locally (extracted
+    /// R/O files are always made read-only)
+    #[arg(long)]
+    preserve_read_only: bool,
+    /// don't report copied files
+    #[arg(short, long)]
+    quiet: bool,
     /// source files
     #[arg(required = true)]
     src_files: Vec<FileArg>,
@@ -91,123 +485,970 @@ pub struct CpArgs {
     dst_file: FileArg,
 }
 
-pub fn dsk(args: DskArgs) -> Result<()> {
-    let mut file = File::open(&args.image_file).context("Can't open image file")?;
+#[derive(Args, Clone)]
+pub struct PutArgs {
+    /// user number (default 0, unless destination is given as N:)
+    #[arg(short, long)]
+    user: Option<u8>,
+    /// text mode (terminate with ^Z) for all files, rather than as configured by the transfer rules
+    #[arg(short, long)]
+    text: bool,
+    /// binary mode for all files, rather than as configured by the transfer rules
+    #[arg(short, long, conflicts_with = "text")]
+    binary: bool,
+    /// convert text from UTF-8 to this character set (text files only)
+    #[arg(long, value_enum)]
+    charset: Option<Charset>,
+    /// only show what would be copied, don't modify the image
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// what to do if the file already exists on the image
+    #[arg(long, value_enum, default_value_t = OnConflict::Fail)]
+    on_conflict: OnConflict,
+    /// start the file at block N, fail if it isn't free (single file only)
+    #[arg(long, value_name = "N")]
+    at_block: Option<u16>,
+    /// store every file in consecutive blocks, fail if there's no long enough run of free ones
+    #[arg(long)]
+    contiguous: bool,
+    /// set the R/O flag of files which aren't writable locally
+    #[arg(long)]
+    preserve_read_only: bool,
+    /// don't report copied files
+    #[arg(short, long)]
+    quiet: bool,
+    /// local files, - for stdin
+    #[arg(required = true)]
+    local_files: Vec<PathBuf>,
+    /// destination on the image (:NAME.EXT for a single file, : or N: to keep local names)
+    #[arg(required = true)]
+    dst_file: FileArg,
+}
+
+impl PutArgs {
+    fn placement(&self) -> Result<Placement> {
+        if self.at_block.is_some() && self.local_files.len() > 1 {
+            bail!(Failure::new(ErrorKind::Usage, "--at-block places a single file."));
+        }
+        Ok(Placement {
+            first_block: self.at_block,
+            contiguous: self.contiguous,
+        })
+    }
+}
 
-    let params = Params {
-        sectors_per_track: 9,
-        reserved_tracks: 2,
-        sector_size: 512,
-        sectors_per_block: 4,
-        dir_blocks: 4,
+#[derive(Args, Clone)]
+pub struct LabelArgs {
+    /// new label (8.3 name, extension is optional)
+    #[arg(conflicts_with = "clear")]
+    label: Option<String>,
+    /// remove the label
+    #[arg(short, long)]
+    clear: bool,
+}
+
+#[derive(Args, Clone)]
+pub struct RmArgs {
+    /// user number (default 0)
+    #[arg(short, long)]
+    user: Option<u8>,
+    /// only show what would be deleted, don't modify the image
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// take the file patterns as regular expressions rather than globs, e.g. '^(GAME|DEMO)[0-9]\.COM$'
+    #[arg(short = 'E', long)]
+    regex: bool,
+    /// files or globs, N:GLOB for user N, *:GLOB for all users
+    #[arg(required = true)]
+    globs: Vec<ImageGlob>,
+}
+
+#[derive(Args, Clone)]
+pub struct ChuserArgs {
+    /// user number of the files given without N: (default 0)
+    #[arg(short, long)]
+    user: Option<u8>,
+    /// only show what would be moved, don't modify the image
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+    /// files or globs, N:GLOB for user N, *:GLOB for all users
+    #[arg(required = true)]
+    globs: Vec<ImageGlob>,
+    /// user to move the files to
+    #[arg(value_parser = clap::value_parser!(u8).range(0..=MAX_USER_ID as i64))]
+    new_user: u8,
+}
+
+#[derive(Args, Clone)]
+pub struct TouchArgs {
+    /// user number (default 0, unless given as N:NAME.EXT)
+    #[arg(short, long)]
+    user: Option<u8>,
+    /// files to create (:NAME.EXT or N:NAME.EXT)
+    #[arg(required = true)]
+    files: Vec<FileArg>,
+}
+
+#[derive(Args, Clone)]
+pub struct ResizeArgs {
+    /// new number of cylinders
+    #[arg(short, long)]
+    cylinders: u8,
+}
+
+pub fn dsk(mut args: DskArgs, read_only: bool) -> Result<()> {
+    if let DskCommands::Serve(serve_args) = &mut args.command {
+        serve_args.read_only |= read_only;
+    }
+    let writes = args.command.modifies_image() || matches!(args.command, DskCommands::Mkfs(_) | DskCommands::Edit(_));
+    if writes {
+        check_writable(&args.image_file, read_only)?;
+    }
+    if read_only && args.command.writes_other_images() {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            "The command would write other images, not allowed with --read-only."
+        ));
+    }
+    if args.disk_format.is_none() {
+        args.disk_format = default_format()?;
+    }
+    match batch::batch_images(&args.image_file)? {
+        Some(images) => batch::batch(args, &images),
+        None => dsk_image(args),
+    }
+}
+
+/// Parses the dsk command line (image file, options and command), for tests.
+#[cfg(test)]
+fn parse_args(args: &[&str]) -> DskArgs {
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        dsk: DskArgs,
+    }
+    <Cli as clap::Parser>::parse_from(["judim"].iter().chain(args)).dsk
+}
+
+/// Fails for a command writing the image in the read-only mode, or if the image file is
+/// read-only, before anything is written.
+fn check_writable(image_file: &str, read_only: bool) -> Result<()> {
+    if read_only {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            "The command would modify the image, not allowed with --read-only."
+        ));
+    }
+    if std::fs::metadata(image_file).is_ok_and(|m| m.permissions().readonly()) {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            format!("Image file {} is read-only, the command would modify it.", image_file)
+        ));
+    }
+    Ok(())
+}
+
+/// Runs the command on a single image.
+fn dsk_image(args: DskArgs) -> Result<()> {
+    if args
+        .disk_format
+        .as_deref()
+        .is_some_and(|f| f.eq_ignore_ascii_case(MGT_FORMAT))
+    {
+        return mgt_image(args);
+    }
+    if is_mdos_image(&args) {
+        return mdos_image(args);
+    }
+    let given_profile = args.disk_format.as_deref().map(Profile::find).transpose()?;
+    let profile = given_profile.unwrap_or(Profile::find(DEFAULT_PROFILE)?);
+
+    // mkfs creates the image, rather than opening an existing one, imgdiff, hash, interleave and edit don't
+    // need the filesystem
+    let command = match args.command {
+        DskCommands::Mkfs(cmd_args) => {
+            let version = args.cpm_version.unwrap_or(profile.params.version);
+            return mkfs::mkfs(&args.image_file, profile, version, cmd_args);
+        }
+        DskCommands::Imgdiff(cmd_args) => return imgdiff::imgdiff(&args.image_file, cmd_args),
+        DskCommands::Hash(cmd_args) => return hash::hash(&args.image_file, cmd_args),
+        DskCommands::Interleave(cmd_args) => return interleave::interleave(&args.image_file, cmd_args),
+        DskCommands::Edit(cmd_args) => return edit::edit(&args.image_file, cmd_args),
+        command => command,
     };
-    let fs = CpmFs::load(&mut file, params).context("Error loading image file")?;
+
+    let modifies_image = command.modifies_image();
+    let mut file = None;
+    let loaded = if args.image_file == STDIO {
+        if modifies_image {
+            bail!(Failure::new(
+                ErrorKind::Usage,
+                "Image read from stdin can't be modified, use an image file."
+            ));
+        }
+        load_image_fs(&mut read_stdin_image()?, given_profile, args.cpm_version)
+    } else {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(modifies_image)
+            .open(&args.image_file)
+            .context("Can't open image file")?;
+        load_image_fs(file.insert(f), given_profile, args.cpm_version)
+    };
+    let mut fs = loaded.context(Failure::new(ErrorKind::Filesystem, "Error loading image file"))?;
+    report_warnings(&args.image_file, fs.disk());
+    report_diagnostics(&args.image_file, &fs);
+    fs.set_preserve_deleted(args.preserve_deleted);
+
+    match command {
+        DskCommands::Ls(cmd_args) => ls(&fs, cmd_args),
+        DskCommands::Get(cmd_args) => get_files(&fs, cmd_args),
+        DskCommands::Cp(cmd_args) => cp_files(&mut fs, cmd_args),
+        DskCommands::Put(cmd_args) => {
+            fs.set_placement(cmd_args.placement()?);
+            put_files(&mut fs, cmd_args)
+        }
+        DskCommands::Cmp(cmd_args) => cmp::cmp(&fs, *fs.params(), cmd_args),
+        DskCommands::Users => users::users(&fs),
+        DskCommands::Dedup(cmd_args) => dedup::dedup(&mut fs, cmd_args),
+        DskCommands::Info => info::info(&fs),
+        DskCommands::Map(cmd_args) => map::map(&fs, cmd_args),
+        DskCommands::Scan => scan::scan(&fs),
+        DskCommands::Stat(cmd_args) => stat::stat(&fs, cmd_args),
+        DskCommands::Dir(cmd_args) => dir::dir(&mut fs, cmd_args),
+        DskCommands::Split(cmd_args) => volumes::split(&fs, profile, fs.params().version, cmd_args),
+        DskCommands::Join(cmd_args) => {
+            let version = fs.params().version;
+            volumes::join(&mut fs, profile, version, cmd_args)
+        }
+        DskCommands::Label(cmd_args) => label(&mut fs, cmd_args),
+        DskCommands::Rm(cmd_args) => rm(&mut fs, cmd_args),
+        DskCommands::Undelete(cmd_args) => undelete::undelete(&mut fs, cmd_args),
+        DskCommands::Chuser(cmd_args) => chuser(&mut fs, cmd_args),
+        DskCommands::Touch(cmd_args) => touch(&mut fs, cmd_args),
+        DskCommands::Browse(cmd_args) => browse::browse(&mut fs, cmd_args),
+        DskCommands::Serial(cmd_args) => serial::serial(&mut fs, cmd_args),
+        DskCommands::Serve(cmd_args) => serve::serve(&mut fs, image_file(&mut file)?, cmd_args),
+        DskCommands::Sync(cmd_args) => sync::sync(&mut fs, image_file(&mut file)?, cmd_args),
+        DskCommands::Export(cmd_args) => export::export(&fs, cmd_args),
+        DskCommands::Fsdump(cmd_args) => fsdump::fsdump(&fs, cmd_args),
+        DskCommands::Import(cmd_args) => import::import(&mut fs, cmd_args),
+        DskCommands::Resize(cmd_args) => resize(&mut fs, cmd_args),
+        DskCommands::Reformat(cmd_args) => reformat::reformat(&fs, cmd_args),
+        DskCommands::Boot(cmd_args) => boot::boot(&mut fs, cmd_args),
+        DskCommands::Mkfs(_)
+        | DskCommands::Imgdiff(_)
+        | DskCommands::Hash(_)
+        | DskCommands::Interleave(_)
+        | DskCommands::Edit(_) => {
+            unreachable!()
+        }
+    }?;
+
+    if modifies_image {
+        let f = image_file(&mut file)?;
+        stats::time("image save", || fs.save(f)).context("Error saving image file")?;
+    }
+    Ok(())
+}
+
+/// Runs a file command on a +D/DISCiPLE (MGT filesystem) image.
+fn mgt_image(args: DskArgs) -> Result<()> {
+    let modifies_image = args.command.modifies_image();
+    // .img images hold all tracks of side 0 before side 1
+    let sides_first = Path::new(&args.image_file)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("img"));
+    let mut file = None;
+    let loaded = if args.image_file == STDIO {
+        if modifies_image {
+            bail!(Failure::new(
+                ErrorKind::Usage,
+                "Image read from stdin can't be modified, use an image file."
+            ));
+        }
+        stats::time("image load", || MgtFs::load(&mut read_stdin_image()?, sides_first))
+    } else {
+        let f = OpenOptions::new()
+            .read(true)
+            .write(modifies_image)
+            .open(&args.image_file)
+            .context("Can't open image file")?;
+        stats::time("image load", || MgtFs::load(file.insert(f), sides_first))
+    };
+    let mut fs = loaded.context(Failure::new(ErrorKind::Filesystem, "Error loading image file"))?;
+    report_warnings(&args.image_file, fs.disk());
 
     match args.command {
         DskCommands::Ls(cmd_args) => ls(&fs, cmd_args),
         DskCommands::Get(cmd_args) => get_files(&fs, cmd_args),
-        DskCommands::Cp(cmd_args) => cp_files(&fs, cmd_args),
+        DskCommands::Cp(cmd_args) => cp_files(&mut fs, cmd_args),
+        DskCommands::Put(cmd_args) if cmd_args.at_block.is_some() || cmd_args.contiguous => bail!(Failure::new(
+            ErrorKind::Usage,
+            "--at-block and --contiguous don't work on MGT disks."
+        )),
+        DskCommands::Put(cmd_args) => put_files(&mut fs, cmd_args),
+        DskCommands::Rm(cmd_args) => rm(&mut fs, cmd_args),
+        _ => bail!(Failure::new(
+            ErrorKind::Usage,
+            "Only ls, get, cp, put and rm commands work on MGT disks."
+        )),
+    }?;
+
+    if modifies_image {
+        let f = image_file(&mut file)?;
+        stats::time("image save", || fs.save(f)).context("Error saving image file")?;
+    }
+    Ok(())
+}
+
+/// Returns true for the MDOS format, or no format and a .d40/.d80 image.
+fn is_mdos_image(args: &DskArgs) -> bool {
+    match args.disk_format.as_deref() {
+        Some(format) => format.eq_ignore_ascii_case(MDOS_FORMAT),
+        None => Path::new(&args.image_file)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("d40") || ext.eq_ignore_ascii_case("d80")),
+    }
+}
+
+/// Runs the file listing and extraction commands on a Didaktik (MDOS) image.
+fn mdos_image(args: DskArgs) -> Result<()> {
+    if args.command.modifies_image() {
+        bail!(Failure::new(ErrorKind::Usage, "MDOS disks are read-only."));
+    }
+    let loaded = if args.image_file == STDIO {
+        stats::time("image load", || MdosFs::load(&mut read_stdin_image()?))
+    } else {
+        let mut f = File::open(&args.image_file).context("Can't open image file")?;
+        stats::time("image load", || MdosFs::load(&mut f))
+    };
+    let fs = loaded.context(Failure::new(ErrorKind::Filesystem, "Error loading image file"))?;
+    report_warnings(&args.image_file, fs.disk());
+
+    match args.command {
+        DskCommands::Ls(cmd_args) => ls(&fs, cmd_args),
+        DskCommands::Get(cmd_args) => get_files(&fs, cmd_args),
+        _ => bail!(Failure::new(
+            ErrorKind::Usage,
+            "Only ls and get commands work on MDOS disks."
+        )),
+    }
+}
+
+/// Loads the disk image without the filesystem, from stdin for "-".
+fn load_disk(image_file: &str) -> Result<DskImage> {
+    let disk = if image_file == STDIO {
+        DskImage::load(&mut read_stdin_image()?)
+    } else {
+        let mut f = File::open(image_file).with_context(|| format!("Can't open image file {}", image_file))?;
+        DskImage::load(&mut f)
+    };
+    let disk = disk.context(Failure::new(ErrorKind::Filesystem, "Error loading image file"))?;
+    report_warnings(image_file, &disk);
+    Ok(disk)
+}
+
+/// Loads the filesystem from a DSK image or, if there's no DSK signature, from a raw sector
+/// dump laid out as the disk format.
+///
+/// The parameters come from the disk specification record of +3/PCW disks, if the profile allows
+/// for one or none is given, otherwise from the profile. Without a profile, the known ones are
+/// tried and the one the directory makes the most sense with is used.
+pub fn load_image_fs(
+    f: &mut (impl Read + Seek),
+    profile: Option<&Profile>,
+    version: Option<CpmVersion>,
+) -> Result<CpmFs> {
+    stats::count("image bytes read", f.seek(SeekFrom::End(0))? as usize);
+    f.seek(SeekFrom::Start(0))?;
+    let Some(profile) = profile else {
+        return probe_image_fs(f, version);
+    };
+    let disk = load_backend(f, profile, profile.spec_record)?;
+    let params = match spec_record(disk.as_ref()).filter(|_| profile.spec_record) {
+        Some(spec) => spec.params(version.unwrap_or(SpecRecord::VERSION))?,
+        None => profile.params_or_default(version),
+    };
+    CpmFs::from_disk(disk, params)
+}
+
+/// Loads the image again, e.g. after other programs changed it, in the container format and with
+/// the filesystem parameters of the one loaded before.
+pub fn reload_image_fs(f: &mut (impl Read + Seek), fs: &CpmFs) -> Result<CpmFs> {
+    // raw images are read with the geometry of the loaded one
+    let geometry = Profile {
+        name: "reloaded",
+        description: "",
+        cylinders: fs.disk().num_cylinders(),
+        sides: fs.disk().num_sides(),
+        sector_ids: &[],
+        gap3: 0,
+        filler: 0,
+        params: *fs.params(),
+        spec_record: false,
+    };
+    f.seek(SeekFrom::Start(0))?;
+    CpmFs::from_disk(load_backend(f, &geometry, false)?, *fs.params())
+}
+
+/// Loads the image of an unknown format: by the disk specification record, if there's one,
+/// otherwise with every profile, keeping the most plausible directory. Reports the chosen
+/// profile.
+fn probe_image_fs(f: &mut (impl Read + Seek), version: Option<CpmVersion>) -> Result<CpmFs> {
+    let mut data = vec![];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_to_end(&mut data)?;
+
+    // the record makes the profile irrelevant, any one finds it
+    let default = Profile::find(DEFAULT_PROFILE)?;
+    if let Ok(disk) = load_backend(&mut Cursor::new(&data), default, true) {
+        if let Some(spec) = spec_record(disk.as_ref()) {
+            return CpmFs::from_disk(disk, spec.params(version.unwrap_or(SpecRecord::VERSION))?);
+        }
     }
+
+    let mut best: Option<(ProbeScore, &Profile, CpmFs)> = None;
+    let mut first_error = None;
+    for profile in PROFILES {
+        let loaded = load_backend(&mut Cursor::new(&data), profile, false)
+            .and_then(|disk| CpmFs::from_disk(disk, profile.params_or_default(version)));
+        let fs = match loaded {
+            Ok(fs) => fs,
+            Err(e) => {
+                first_error.get_or_insert(e);
+                continue;
+            }
+        };
+        let score = ProbeScore::new(&fs, profile)?;
+        // on a tie, the earlier profile wins
+        if best.as_ref().is_none_or(|(best_score, _, _)| score > *best_score) {
+            best = Some((score, profile, fs));
+        }
+    }
+    match (best, first_error) {
+        (Some((_, profile, fs)), _) => {
+            eprintln!("Detected disk format: {}", profile.name);
+            Ok(fs)
+        }
+        (None, Some(e)) => Err(e.context("No known disk format matches the image")),
+        (None, None) => unreachable!(),
+    }
+}
+
+/// How well the directory read with a profile makes sense, compared field by field.
+#[derive(PartialEq, PartialOrd)]
+struct ProbeScore {
+    /// per mille of directory slots holding file extents, labels, time stamps or unused ones,
+    /// minus the truncated files
+    plausible: usize,
+    /// the image has the cylinders and sides of the profile
+    geometry: bool,
+    /// extents of existing files
+    extents: usize,
+}
+
+impl ProbeScore {
+    fn new(fs: &CpmFs, profile: &Profile) -> Result<Self> {
+        let (mut plausible, mut extents, mut slots) = (0usize, 0, 0);
+        for entry in fs.dir_entries() {
+            slots += 1;
+            match entry.status() {
+                EntryStatus::File => {
+                    plausible += 1;
+                    extents += 1;
+                }
+                EntryStatus::Other => {}
+                _ => plausible += 1,
+            }
+        }
+        let truncated = fs
+            .list_files(LsMode::All)?
+            .iter()
+            .filter(|f| fs.stored_size(f) < f.size)
+            .count();
+        let disk = fs.disk();
+        Ok(Self {
+            plausible: plausible.saturating_sub(truncated) * 1000 / slots.max(1),
+            geometry: disk.num_cylinders() == profile.cylinders && disk.num_sides() == profile.sides,
+            extents,
+        })
+    }
+}
+
+/// Loads the image container, a raw image laid out as the disk specification record says, if
+/// allowed and there's one, otherwise as the profile.
+fn load_backend(f: &mut (impl Read + Seek), profile: &Profile, use_spec_record: bool) -> Result<Box<dyn DiskBackend>> {
+    stats::time("image load", || load_container(f, profile, use_spec_record))
+}
+
+fn load_container(
+    f: &mut (impl Read + Seek),
+    profile: &Profile,
+    use_spec_record: bool,
+) -> Result<Box<dyn DiskBackend>> {
+    let mut signature = [0u8; SpecRecord::SIZE];
+    let read = f.read_exact(&mut signature);
+    f.seek(SeekFrom::Start(0))?;
+    let disk: Box<dyn DiskBackend> = match &signature[..8] {
+        _ if read.is_err() => bail!("Image file is too short"),
+        b"EXTENDED" => Box::new(DskImage::load(f)?),
+        b"MV - CPC" => bail!("Standard (non-extended) DSK images are not supported"),
+        _ => {
+            // a raw image starts with the record, if it has one
+            let spec = SpecRecord::from_bytes(&signature).filter(|_| use_spec_record);
+            let spec_disk = spec.and_then(|s| {
+                RawImage::load(f, s.sides, s.sectors_per_track, s.sector_size)
+                    .ok()
+                    .filter(|disk| s.matches(disk))
+            });
+            match spec_disk {
+                Some(disk) => Box::new(disk),
+                None => {
+                    let mut disk = RawImage::load(
+                        f,
+                        profile.sides,
+                        profile.params.sectors_per_track,
+                        profile.params.sector_size,
+                    )?;
+                    disk.set_first_sector_id(profile.params.first_sector_id);
+                    Box::new(disk)
+                }
+            }
+        }
+    };
+    Ok(disk)
+}
+
+/// Returns the disk specification record in the first sector, if it describes the disk.
+fn spec_record(disk: &dyn DiskBackend) -> Option<SpecRecord> {
+    disk.sector_as_slice(CHS {
+        cylinder: 0,
+        head: 0,
+        sector: 1,
+    })
+    .ok()
+    .and_then(SpecRecord::from_bytes)
+    .filter(|s| s.matches(disk))
+}
+
+/// Prints the format deviations tolerated while loading the image.
+fn report_warnings(image_file: &str, disk: &dyn DiskBackend) {
+    for warning in disk.warnings() {
+        eprintln!("Warning: {}: {}.", image_file, warning);
+    }
+}
+
+/// Prints the directory oddities found when loading the image, with --verbose only.
+fn report_diagnostics(image_file: &str, fs: &CpmFs) {
+    if output::verbose() {
+        for diagnostic in fs.diagnostics() {
+            eprintln!("Note: {}: {}.", image_file, diagnostic);
+        }
+    }
+}
+
+/// Reads the whole image from stdin.
+pub fn read_stdin_image() -> Result<Cursor<Vec<u8>>> {
+    let mut data = Vec::new();
+    io::stdin()
+        .lock()
+        .read_to_end(&mut data)
+        .context("Can't read image from stdin")?;
+    Ok(Cursor::new(data))
+}
+
+/// Loads the filesystem of another image, e.g. the second one of a comparison.
+fn load_fs(f: &mut File, image_file: &str, params: Params) -> Result<CpmFs> {
+    let fs = CpmFs::load(f, params).context(Failure::new(
+        ErrorKind::Filesystem,
+        format!("Error loading image file {}", image_file),
+    ))?;
+    report_warnings(image_file, fs.disk());
+    report_diagnostics(image_file, &fs);
+    Ok(fs)
 }
 
-fn ls(fs: &CpmFs, args: LsArgs) -> Result<()> {
+/// Returns the user and the normalized name (as stored on the image) of a single file argument.
+fn image_file_name(file: &FileArg) -> Result<(u8, String)> {
+    let (user, name) = match file {
+        FileArg::Image {
+            owner,
+            name: Some(name),
+        } => (owner.unwrap_or(DEFAULT_USER), name.clone()),
+        FileArg::Image { name: None, .. } => bail!(Failure::new(ErrorKind::Usage, "File name is missing.")),
+        FileArg::Local { path } => (DEFAULT_USER, path.to_string_lossy().to_string()),
+    };
+    let id = FileId::new_with_filename(user, &name, FilenameMode::Normalized)?;
+    Ok((user, id.filename()))
+}
+
+fn find_file(fs: &CpmFs, user: u8, name: &str) -> Result<FileItem> {
+    let name = name.to_ascii_uppercase();
+    let Some(file) = fs
+        .list_files(LsMode::OwnedBy(user))?
+        .into_iter()
+        .find(|f| f.name == name)
+    else {
+        bail!(Failure::new(
+            ErrorKind::NoMatch,
+            format!("File {}:{} not found.", user, name)
+        ));
+    };
+    Ok(file)
+}
+
+/// The opened image file, for the commands that need more than the loaded filesystem.
+fn image_file(file: &mut Option<File>) -> Result<&mut File> {
+    file.as_mut().context(Failure::new(
+        ErrorKind::Usage,
+        "This command needs an image file, not stdin.",
+    ))
+}
+
+fn ls(fs: &dyn RetroFs, args: LsArgs) -> Result<()> {
     if args.deleted && args.user.is_some() {
         bail!("--deleted and --user options are mutually exclusive");
     }
 
-    let mode = if args.deleted {
-        LsMode::Deleted
-    } else if let Some(user) = args.user {
-        LsMode::OwnedBy(user)
+    let select = if args.deleted_only {
+        Select::DeletedOnly
+    } else if args.deleted {
+        Select::WithDeleted
+    } else if let Some(UserList(users)) = args.user {
+        Select::Users(users)
     } else {
-        LsMode::All
+        Select::All
     };
 
-    let mut files = fs.list_files(mode)?;
-    if let Some(glob) = args.glob {
-        files = files.into_iter().filter(|file| glob_match(&glob, &file.name)).collect();
+    let mut files = fs.list(select)?;
+    let globs = regex_globs(args.globs, args.regex)?;
+    files.retain(|file| {
+        let included = globs.iter().any(|g| g.matches_listed(file.user, &file.name));
+        (globs.is_empty() || included) && !matches_any(&args.exclude, &file.name)
+    });
+    if args.sort == LsSort::Name {
+        files.sort_by(FsFile::listing_cmp);
+    }
+    if args.batch_image.is_some() && files.is_empty() {
+        // skipped by the batch, which only fails if no image has matching files
+        bail!(Failure::new(ErrorKind::NoMatch, "No files matched."));
     }
-    files.sort_by(|a, b| a.name.cmp(&b.name));
 
     match args.format {
         LsFormat::Simple => {
             for f in files {
-                println!("{}", f.name);
+                match &args.batch_image {
+                    Some(image) => println!("{}: {}", image, f.name),
+                    None => println!("{}", f.name),
+                }
             }
         }
         LsFormat::Default | LsFormat::Verbose => {
-            let mut table = Table::new();
-            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            if let Some(image) = &args.batch_image {
+                println!("{}:", image);
+            }
+            if let Some(label) = fs.label() {
+                println!("Label: {}\n", label);
+            }
 
+            let mut titles = vec!["User", "Name", "Size", "Flags", "Ext"];
+            if args.allocation {
+                titles.extend(["Records", "Allocated"]);
+            }
             if args.format == LsFormat::Verbose {
-                table.set_titles(row!["User", "Name", "Size", "Blocks"]);
-            } else {
-                table.set_titles(row!["User", "Name", "Size",]);
+                titles.push("Blocks");
+            }
+            if args.deleted || args.deleted_only {
+                titles.push("Status");
+            }
+            if args.speccy {
+                titles.push("Spectrum");
             }
+            let mut listing = Listing::new(&titles);
 
+            let total: usize = files.iter().map(|f| f.size).sum();
+            let count = files.len();
             for f in files {
                 let user = if let Some(u) = f.user {
                     u.to_string()
                 } else {
                     "-".to_string()
                 };
+                let size = if args.human_readable {
+                    human_size(f.size)
+                } else {
+                    f.size.to_string()
+                };
+                let mut cells = vec![user, f.name.clone(), size, f.flags(), f.entries.to_string()];
+                if args.allocation {
+                    let allocated = fs.stat(&f)?.stored_size;
+                    cells.push(f.size.div_ceil(RECORD_SIZE).to_string());
+                    cells.push(if args.human_readable {
+                        human_size(allocated)
+                    } else {
+                        allocated.to_string()
+                    });
+                }
                 if args.format == LsFormat::Verbose {
-                    let blocks = f.block_list.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",");
-                    table.add_row(row![user, f.name, f.size, blocks]);
+                    cells.push(block_list_str(&f.location));
+                }
+                if args.deleted || args.deleted_only {
+                    cells.push(deleted_status(fs, &f)?);
+                }
+                if args.speccy {
+                    cells.push(speccy_header(fs, &f).map(|h| h.to_string()).unwrap_or_default());
+                }
+                listing.add_row(cells, listing_style(&f));
+            }
+            listing.print()?;
+            let bytes = |n: usize| {
+                if args.human_readable {
+                    human_size(n)
                 } else {
-                    table.add_row(row![user, f.name, f.size]);
+                    format!("{} bytes", thousands(n))
                 }
+            };
+            println!(
+                "{} file{}, {}, {} free",
+                count,
+                if count == 1 { "" } else { "s" },
+                bytes(total),
+                bytes(fs.free_space().bytes)
+            );
+            if args.batch_image.is_some() {
+                println!();
             }
-            table.printstd();
         }
     };
 
     Ok(())
 }
 
-fn get_files(fs: &CpmFs, args: GetArgs) -> Result<()> {
-    let files: Vec<FileItem> = fs
-        .list_files(LsMode::OwnedBy(args.user.unwrap_or(0)))?
-        .into_iter()
-        .filter(|file| glob_match(&args.image_file, &file.name))
-        .collect();
-    let target_path = Path::new(&args.local_path);
+/// Tells if the blocks of a deleted file are still free, partially or fully reused, empty
+/// for existing files.
+fn deleted_status(fs: &dyn RetroFs, file: &FsFile) -> Result<String> {
+    if file.user.is_some() {
+        return Ok(String::new());
+    }
+    let stat = fs.stat(file)?;
+    let blocks = file.location.len();
+    Ok(match stat.recoverability(blocks) {
+        Recoverability::Free => "recoverable".to_string(),
+        Recoverability::PartiallyReused => {
+            format!("partially reused ({} of {} blocks)", stat.reused_blocks, blocks)
+        }
+        Recoverability::Reused => "reused".to_string(),
+    })
+}
+
+/// Returns how the file is highlighted in listings: deleted files, system files, executables.
+fn listing_style(file: &FsFile) -> Option<Style> {
+    if file.user.is_none() {
+        Some(Style::Deleted)
+    } else if file.attributes.system {
+        Some(Style::System)
+    } else if file.name.ends_with(".COM") {
+        Some(Style::Executable)
+    } else {
+        None
+    }
+}
+
+/// Looks for a ZX Spectrum header at the start of the file, consistent with the file size.
+/// Only the header is read.
+fn speccy_header(fs: &dyn RetroFs, file: &FsFile) -> Option<SpeccyFileHeader> {
+    let data = fs.read_prefix(file, HEADER_SIZE).ok()?;
+    SpeccyFileHeader::from_bytes(&data).filter(|h| HEADER_SIZE + h.length as usize <= file.size)
+}
+
+fn label(fs: &mut CpmFs, args: LabelArgs) -> Result<()> {
+    if args.clear {
+        fs.set_label(None)
+    } else if let Some(label) = args.label {
+        fs.set_label(Some(&label))
+    } else {
+        println!("{}", fs.label().unwrap_or_else(|| "No label.".to_string()));
+        Ok(())
+    }
+}
 
-    match files.len() {
-        0 => {
-            bail!("No files on the image matches {}.", args.image_file);
+fn rm(fs: &mut dyn RetroFs, args: RmArgs) -> Result<()> {
+    let globs = regex_globs(args.globs, args.regex)?;
+    let files = matching_files(fs, &globs, args.user.unwrap_or(DEFAULT_USER), &[])?;
+    for f in &files {
+        fs.delete(f)?;
+        if args.dry_run {
+            println!(
+                "Would delete {}:{} ({} bytes, blocks: {})",
+                f.user.unwrap_or_default(),
+                f.name,
+                f.size,
+                block_list_str(&f.location)
+            );
         }
-        1 => {
-            let f = &files[0];
-            let local_file = if target_path.is_dir() {
-                target_path.join(&f.name)
+    }
+
+    if args.dry_run {
+        print_dry_run_summary(fs);
+    }
+    Ok(())
+}
+
+fn touch(fs: &mut CpmFs, args: TouchArgs) -> Result<()> {
+    let default_user = args.user.unwrap_or(DEFAULT_USER);
+    for f in &args.files {
+        let (user, name) = match f {
+            FileArg::Image {
+                owner,
+                name: Some(name),
+            } => (owner.unwrap_or(default_user), name.clone()),
+            FileArg::Image { name: None, .. } => bail!("File name is missing."),
+            FileArg::Local { path } => (default_user, path.to_string_lossy().to_string()),
+        };
+
+        let id = FileId::new_with_filename(user, &name, FilenameMode::Normalized)?;
+        if !fs.file_exists(&id) {
+            fs.create_empty_file(&id)?;
+        }
+    }
+    Ok(())
+}
+
+fn resize(fs: &mut CpmFs, args: ResizeArgs) -> Result<()> {
+    let old_blocks = fs.num_blocks();
+    fs.resize(args.cylinders)?;
+    println!(
+        "Resized to {} cylinders, {} blocks (was {}), {} free.",
+        args.cylinders,
+        fs.num_blocks(),
+        old_blocks,
+        fs.free_blocks()
+    );
+    println!(
+        "Note: the BIOS Disk Parameter Block must match the new size (DSM = {}).",
+        fs.dpb().dsm
+    );
+    Ok(())
+}
+
+fn chuser(fs: &mut CpmFs, args: ChuserArgs) -> Result<()> {
+    let files = matching_files(fs, &args.globs, args.user.unwrap_or(DEFAULT_USER), &[])?;
+    let mut moved = 0;
+    for f in files.iter().filter(|f| f.user != Some(args.new_user)) {
+        let id = FileId::new_with_filename(args.new_user, &f.name, FilenameMode::AsIs)?;
+        fs.rename_file(&f.into(), &id)
+            .with_context(|| format!("Can't move {}:{}", f.user.unwrap_or_default(), f.name))?;
+        println!(
+            "{}{}:{} -> {}:{}",
+            if args.dry_run { "Would move " } else { "" },
+            f.user.unwrap_or_default(),
+            f.name,
+            args.new_user,
+            f.name
+        );
+        moved += 1;
+    }
+    println!(
+        "{} files {}moved to user {}.",
+        moved,
+        if args.dry_run { "would be " } else { "" },
+        args.new_user
+    );
+    Ok(())
+}
+
+fn get_files(fs: &dyn RetroFs, args: GetArgs) -> Result<()> {
+    let globs = regex_globs(args.image_files.clone(), args.regex)?;
+    let files = matching_files(fs, &globs, args.user.unwrap_or(DEFAULT_USER), &args.exclude)?;
+    let files = if args.interactive { select_files(files)? } else { files };
+    if files.is_empty() {
+        println!("No files selected.");
+        return Ok(());
+    }
+    if args.length.is_some() && files.len() > 1 {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            format!("{} files match, --length applies to a single file.", files.len())
+        ));
+    }
+
+    // raw data is written as is, with the size adjusted to the end of the range
+    let raw = args.raw || args.length.is_some() || args.offset.is_some();
+    let offset = args.offset.unwrap_or_default();
+    let files = if raw {
+        let mut ranged = vec![];
+        for f in files {
+            let size = if args.raw {
+                f.size.next_multiple_of(RECORD_SIZE)
             } else {
-                target_path.to_owned()
+                f.size
             };
-            let mut lf = File::create(local_file)?;
-            fs.read_file(f, &mut lf, args.text)
-        }
-        _ => {
-            if !target_path.is_dir() {
-                bail!("Multiple files match, target must be a directory.");
+            if args.length.is_none() && offset > size {
+                bail!(Failure::new(
+                    ErrorKind::Usage,
+                    format!("Offset {} is beyond the end of {} ({} bytes).", offset, f.name, size)
+                ));
             }
-            for f in &files {
-                let mut lf = File::create(&target_path.join(&f.name))?;
-                fs.read_file(f, &mut lf, args.text)?;
-            }
-            Ok(())
+            let Some(end) = offset.checked_add(args.length.unwrap_or(size - offset)) else {
+                bail!(Failure::new(
+                    ErrorKind::Usage,
+                    format!("Offset {} plus the length is out of range.", offset)
+                ));
+            };
+            ranged.push(FsFile { size: end, ..f });
         }
-    }
+        ranged
+    } else {
+        files
+    };
+    let opts = CopyOptions {
+        text: if raw {
+            Some(false)
+        } else {
+            forced_mode(args.text, args.binary)
+        },
+        charset: args.charset,
+        rules: if raw { vec![] } else { Config::load()?.rules },
+        dry_run: false,
+        quiet: args.quiet,
+        lenient: args.lenient,
+        preserve_times: !args.no_preserve_times,
+        preserve_read_only: true,
+        on_conflict: OnConflict::Fail,
+        offset,
+    };
+    copy_from_image(fs, &files, Path::new(&args.local_path), &opts)
 }
 
-fn cp_files(fs: &CpmFs, args: CpArgs) -> Result<()> {
+fn put_files(fs: &mut dyn RetroFs, args: PutArgs) -> Result<()> {
+    let FileArg::Image { owner, name } = &args.dst_file else {
+        bail!("Destination must be on the image (:NAME.EXT, : or N:).");
+    };
+    let user = owner.or(args.user).unwrap_or(DEFAULT_USER);
+    let opts = CopyOptions {
+        text: forced_mode(args.text, args.binary),
+        charset: args.charset,
+        rules: Config::load()?.rules,
+        dry_run: args.dry_run,
+        quiet: args.quiet,
+        lenient: false,
+        preserve_times: false,
+        preserve_read_only: args.preserve_read_only,
+        on_conflict: args.on_conflict,
+        offset: 0,
+    };
+    copy_to_image(fs, &args.local_files, user, name.as_deref(), &opts)
+}
+
+fn cp_files(fs: &mut dyn RetroFs, args: CpArgs) -> Result<()> {
     match &args.dst_file {
-        FileArg::Local { path } => cp_files_from_image(fs, &path, &args),
+        FileArg::Local { path } => cp_files_from_image(fs, path, &args),
         FileArg::Image { .. } => cp_files_to_image(fs, &args),
     }
 }
 
-fn cp_files_from_image(fs: &CpmFs, dst: &Path, args: &CpArgs) -> Result<()> {
+fn cp_files_from_image(fs: &dyn RetroFs, dst: &Path, args: &CpArgs) -> Result<()> {
     let sources = args
         .src_files
         .iter()
@@ -220,10 +1461,10 @@ fn cp_files_from_image(fs: &CpmFs, dst: &Path, args: &CpArgs) -> Result<()> {
                 bail!("Source argument is missing the file name.");
             };
 
-            let files: Vec<FileItem> = fs
-                .list_files(LsMode::OwnedBy(*owner))?
+            let files: Vec<FsFile> = fs
+                .list(Select::User(owner.unwrap_or(DEFAULT_USER)))?
                 .into_iter()
-                .filter(|file| glob_match(name, &file.name))
+                .filter(|file| glob_match(name, &file.name) && !matches_any(&args.exclude, &file.name))
                 .collect();
 
             Ok(files)
@@ -235,27 +1476,533 @@ fn cp_files_from_image(fs: &CpmFs, dst: &Path, args: &CpArgs) -> Result<()> {
             })
         })?;
 
-    if sources.len() > 1 && !dst.is_dir() {
+    let opts = CopyOptions {
+        text: forced_mode(args.text, args.binary),
+        charset: args.charset,
+        rules: Config::load()?.rules,
+        dry_run: args.dry_run,
+        quiet: args.quiet,
+        lenient: args.lenient,
+        preserve_times: !args.no_preserve_times,
+        preserve_read_only: true,
+        on_conflict: OnConflict::Fail,
+        offset: 0,
+    };
+    copy_from_image(fs, &sources, dst, &opts)
+}
+
+fn cp_files_to_image(fs: &mut dyn RetroFs, args: &CpArgs) -> Result<()> {
+    let FileArg::Image { owner, name } = &args.dst_file else {
+        unreachable!()
+    };
+
+    let sources = args
+        .src_files
+        .iter()
+        .map(|f| match f {
+            FileArg::Local { path } => Ok(path.clone()),
+            FileArg::Image { .. } => {
+                bail!("All sources must be on the local filesystem if copying to the image.")
+            }
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| !matches_any(&args.exclude, &path.file_name().unwrap_or_default().to_string_lossy()))
+        .collect::<Vec<_>>();
+
+    let user = owner.unwrap_or(DEFAULT_USER);
+    let opts = CopyOptions {
+        text: forced_mode(args.text, args.binary),
+        charset: args.charset,
+        rules: Config::load()?.rules,
+        dry_run: args.dry_run,
+        quiet: args.quiet,
+        lenient: false,
+        preserve_times: false,
+        preserve_read_only: args.preserve_read_only,
+        on_conflict: args.on_conflict,
+        offset: 0,
+    };
+    copy_to_image(fs, &sources, user, name.as_deref(), &opts)
+}
+
+/// Options shared by the commands copying files to/from the image.
+struct CopyOptions {
+    /// text mode (^Z handling) forced by the command line options, rules apply otherwise
+    text: Option<bool>,
+    /// character set of text files on the image, converted from/to UTF-8
+    charset: Option<Charset>,
+    /// configured transfer rules
+    rules: Vec<TransferRule>,
+    /// only report what would be done
+    dry_run: bool,
+    /// don't report copied files
+    quiet: bool,
+    /// extract truncated files (only what's stored) instead of failing
+    lenient: bool,
+    /// set local modification time from CP/M time stamps
+    preserve_times: bool,
+    /// map the R/O flag to local write permissions: extracted R/O files aren't writable, files
+    /// not writable locally get the flag on the image
+    preserve_read_only: bool,
+    /// name clash handling when writing to the image
+    on_conflict: OnConflict,
+    /// bytes of the extracted files to skip (binary mode only)
+    offset: usize,
+}
+
+impl CopyOptions {
+    /// Returns the text mode and charset of a file: the command line options if given,
+    /// otherwise the first matching transfer rule. Binary is the default.
+    fn mode(&self, name: &str) -> (bool, Option<Charset>) {
+        let rule = self.rules.iter().find(|r| r.matches(name));
+        let text = self.text.or(rule.map(|r| r.text)).unwrap_or(false);
+        let charset = self.charset.or(rule.and_then(|r| r.charset));
+        (text, charset.filter(|_| text))
+    }
+}
+
+/// Text mode given by the --text or --binary option.
+fn forced_mode(text: bool, binary: bool) -> Option<bool> {
+    match (text, binary) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Keeps track of copied files, reports them unless in quiet mode.
+struct CopyReport {
+    quiet: bool,
+    files: usize,
+    bytes: usize,
+}
+
+impl CopyReport {
+    fn new(opts: &CopyOptions) -> Self {
+        Self {
+            quiet: opts.quiet,
+            files: 0,
+            bytes: 0,
+        }
+    }
+
+    fn add(&mut self, src: &str, dst: &str, bytes: usize) {
+        self.files += 1;
+        self.bytes += bytes;
+        if !self.quiet {
+            println!("{} -> {}, {} bytes", src, dst, thousands(bytes));
+        }
+    }
+
+    fn summary(&self) {
+        if !self.quiet {
+            let files = if self.files == 1 { "file" } else { "files" };
+            println!("{} {} copied, {} bytes.", self.files, files, thousands(self.bytes));
+        }
+    }
+}
+
+/// Copies files from the image to a local file or directory.
+fn copy_from_image(fs: &dyn RetroFs, files: &[FsFile], dst: &Path, opts: &CopyOptions) -> Result<()> {
+    let to_stdout = dst == Path::new(STDIO);
+    if files.len() > 1 && !dst.is_dir() && !to_stdout {
         bail!("Multiple source files match, target must be a directory.");
     }
 
-    for s in &sources {
-        let local_file = if dst.is_dir() {
-            dst.join(&s.name)
+    let mut report = CopyReport::new(opts);
+    // reports would be mixed with the data
+    report.quiet |= to_stdout;
+    let mut used_names = HashSet::new();
+    for f in files {
+        let local_file = if dst.is_dir() && !to_stdout {
+            let name = safe_filename(&f.name).with_context(|| format!("Can't extract {}", f.name))?;
+            dst.join(unique_filename(name, &mut used_names))
         } else {
             dst.to_owned()
         };
-        let mut lf = File::create(local_file)?;
-        fs.read_file(s, &mut lf, args.text)?
+        if opts.dry_run {
+            println!(
+                "Would copy {}:{} -> {} ({} bytes)",
+                f.user.unwrap_or_default(),
+                f.name,
+                local_file.display(),
+                f.size
+            );
+            continue;
+        }
+
+        let stat = fs.stat(f)?;
+        let stored = stat.stored_size;
+        let truncated;
+        let f = if opts.lenient && stored < f.size {
+            eprintln!(
+                "Warning: {} is truncated, extracting {} of {} bytes.",
+                f.name, stored, f.size
+            );
+            truncated = FsFile {
+                size: stored,
+                ..f.clone()
+            };
+            &truncated
+        } else {
+            f
+        };
+
+        if to_stdout {
+            let mut stdout = io::stdout().lock();
+            read_converted(fs, f, &mut stdout, opts)?;
+            stdout.flush()?;
+            continue;
+        }
+        let mut lf = File::create(&local_file).with_context(|| format!("Can't create {}", local_file.display()))?;
+        let bytes = read_converted(fs, f, &mut lf, opts)?;
+        if opts.preserve_times {
+            if let Some(updated) = stat.updated {
+                lf.set_modified(updated)
+                    .with_context(|| format!("Can't set modification time of {}", local_file.display()))?;
+            }
+        }
+        if opts.preserve_read_only && f.attributes.read_only {
+            let mut perms = lf.metadata()?.permissions();
+            perms.set_readonly(true);
+            lf.set_permissions(perms)
+                .with_context(|| format!("Can't make {} read-only", local_file.display()))?;
+        }
+        report.add(&f.name, &local_file.display().to_string(), bytes);
     }
 
+    if !opts.dry_run {
+        report.summary();
+    }
     Ok(())
 }
 
-fn cp_files_to_image(fs: &CpmFs, args: &CpArgs) -> Result<()> {
-    if (&args.src_files).iter().any(|f| !f.is_local()) {
-        bail!("All sources must be on the local filesystem if copying to the image.")
+/// Reads the file, converting text to UTF-8 if a charset is given. Returns the number of bytes written.
+fn read_converted(fs: &dyn RetroFs, file: &FsFile, w: &mut dyn Write, opts: &CopyOptions) -> Result<usize> {
+    let size = stats::time("data transfer", || convert_file(fs, file, w, opts))?;
+    stats::count("bytes read from image", size);
+    Ok(size)
+}
+
+fn convert_file(fs: &dyn RetroFs, file: &FsFile, w: &mut dyn Write, opts: &CopyOptions) -> Result<usize> {
+    let (text, charset) = opts.mode(&file.name);
+    if opts.offset > 0 {
+        let mut skipping = SkippingWriter { w, skip: opts.offset };
+        return Ok(fs.read(file, &mut skipping, text)?.saturating_sub(opts.offset));
     }
+    let Some(charset) = charset else {
+        return fs.read(file, w, text);
+    };
+    let mut data = Vec::with_capacity(file.size);
+    fs.read(file, &mut data, text)?;
+    let text = charset.decode(&data);
+    w.write_all(text.as_bytes())?;
+    Ok(text.len())
+}
 
+/// Writer dropping the first bytes written.
+struct SkippingWriter<'a> {
+    w: &'a mut dyn Write,
+    skip: usize,
+}
+
+impl Write for SkippingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skipped = self.skip.min(buf.len());
+        self.skip -= skipped;
+        self.w.write_all(&buf[skipped..])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Copies local files to the image, either keeping their names (if name is None),
+/// or storing a single file under a given name.
+fn copy_to_image(
+    fs: &mut dyn RetroFs,
+    sources: &[PathBuf],
+    user: u8,
+    name: Option<&str>,
+    opts: &CopyOptions,
+) -> Result<()> {
+    if name.is_some() && sources.len() > 1 {
+        bail!("Multiple source files, destination must be a user area (: or N:).");
+    }
+
+    let mut report = CopyReport::new(opts);
+    for src in sources {
+        let from_stdin = src.as_os_str() == STDIO;
+        let name = match name {
+            Some(name) => name.to_string(),
+            None if from_stdin => bail!("Reading from stdin, destination must include the file name (:NAME.EXT)."),
+            None => src
+                .file_name()
+                .with_context(|| format!("Invalid source file {}", src.display()))?
+                .to_string_lossy()
+                .to_string(),
+        };
+        let id = fs
+            .file_id(user, &name)
+            .with_context(|| format!("Can't store {} on the image", src.display()))?;
+        let Some(id) = resolve_conflict(fs, id.clone(), opts.on_conflict)? else {
+            if !opts.quiet {
+                println!("Skipping {}, {} already exists", src.display(), id);
+            }
+            continue;
+        };
+
+        let open = || -> Result<File> {
+            let lf = File::open(src).with_context(|| format!("Can't open {}", src.display()))?;
+            if lf.metadata()?.is_dir() {
+                bail!("{} is a directory", src.display());
+            }
+            Ok(lf)
+        };
+        let (text, charset) = opts.mode(&id.name);
+        let (blocks, size) = if from_stdin || charset.is_some() {
+            let mut data = Vec::new();
+            if from_stdin {
+                io::stdin().lock().read_to_end(&mut data).context("Can't read stdin")?;
+            } else {
+                open()?.read_to_end(&mut data)?;
+            }
+            let size = data.len();
+            if let Some(charset) = charset {
+                let (encoded, unmapped) = charset
+                    .encode(&data)
+                    .with_context(|| format!("Can't convert {}", src.display()))?;
+                if unmapped > 0 {
+                    eprintln!(
+                        "Warning: {} characters of {} not available in {:?}, replaced with '?'.",
+                        unmapped,
+                        src.display(),
+                        charset
+                    );
+                }
+                data = encoded;
+            }
+            (
+                stats::time("data transfer", || fs.write(&id, &mut data.as_slice(), text))?,
+                size,
+            )
+        } else {
+            let mut lf = open()?;
+            let blocks = stats::time("data transfer", || fs.write(&id, &mut lf, text))?;
+            (blocks, lf.metadata()?.len() as usize)
+        };
+        if opts.preserve_read_only && !from_stdin && std::fs::metadata(src)?.permissions().readonly() {
+            fs.set_read_only(&id, true)?;
+        }
+        stats::count("bytes written to image", size);
+        if opts.dry_run {
+            println!(
+                "Would copy {} -> {} ({} bytes, blocks: {})",
+                src.display(),
+                id,
+                size,
+                block_list_str(&blocks)
+            );
+        } else {
+            report.add(&src.display().to_string(), &id.to_string(), size);
+        }
+    }
+
+    if opts.dry_run {
+        print_dry_run_summary(fs);
+    } else {
+        report.summary();
+    }
     Ok(())
 }
+
+/// Applies the conflict policy if the file exists on the image.
+///
+/// Returns the ID to write the file under, or None if it should be skipped.
+fn resolve_conflict(fs: &mut dyn RetroFs, id: FsFileId, policy: OnConflict) -> Result<Option<FsFileId>> {
+    if !file_exists(fs, &id)? {
+        return Ok(Some(id));
+    }
+
+    let policy = if policy == OnConflict::Ask {
+        ask_conflict(&id)?
+    } else {
+        policy
+    };
+    match policy {
+        // write_file() reports the error
+        OnConflict::Fail | OnConflict::Ask => Ok(Some(id)),
+        OnConflict::Skip => Ok(None),
+        OnConflict::Overwrite => {
+            if let Some(existing) = retro_fs::find_file(fs, &id)? {
+                fs.delete(&existing)?;
+            }
+            Ok(Some(id))
+        }
+        OnConflict::Rename => {
+            for renamed in (1..).map_while(|n| fs.numbered(&id, n)) {
+                if !file_exists(fs, &renamed)? {
+                    return Ok(Some(renamed));
+                }
+            }
+            bail!("No free name for {}", id.name)
+        }
+    }
+}
+
+fn ask_conflict(id: &FsFileId) -> Result<OnConflict> {
+    loop {
+        let prompt = format!("{} already exists. [s]kip, [o]verwrite, [r]ename? ", id);
+        match ask(&prompt)?.as_str() {
+            "s" | "skip" => return Ok(OnConflict::Skip),
+            "o" | "overwrite" => return Ok(OnConflict::Overwrite),
+            "r" | "rename" => return Ok(OnConflict::Rename),
+            _ => continue,
+        }
+    }
+}
+
+/// Lists numbered files and asks which ones to keep: a list of numbers and ranges,
+/// all, none, or confirming each file separately.
+fn select_files(mut files: Vec<FsFile>) -> Result<Vec<FsFile>> {
+    files.sort_by(FsFile::listing_cmp);
+    for (idx, f) in files.iter().enumerate() {
+        println!("{:4}  {:<12}  {:>9} bytes", idx + 1, f.name, thousands(f.size));
+    }
+    loop {
+        let answer = ask("Extract which files? Numbers (e.g. 1,3-5), [a]ll, [c]onfirm each, [n]one: ")?;
+        match answer.as_str() {
+            "a" | "all" => return Ok(files),
+            "n" | "none" => return Ok(vec![]),
+            "c" | "confirm" => break,
+            _ => match parse_ranges(&answer, 1, files.len()) {
+                Ok(selected) => {
+                    return Ok(files
+                        .into_iter()
+                        .enumerate()
+                        .filter(|(idx, _)| selected.contains(&(idx + 1)))
+                        .map(|(_, f)| f)
+                        .collect())
+                }
+                Err(e) => println!("{}", e),
+            },
+        }
+    }
+
+    let mut selected = vec![];
+    for f in files {
+        loop {
+            match ask(&format!("Extract {}? [y]es, [n]o, [q]uit: ", f.name))?.as_str() {
+                "y" | "yes" => selected.push(f),
+                "n" | "no" => {}
+                "q" | "quit" => return Ok(selected),
+                _ => continue,
+            }
+            break;
+        }
+    }
+    Ok(selected)
+}
+
+/// Prints the prompt and reads a trimmed, lowercase answer from stdin.
+fn ask(prompt: &str) -> Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        bail!("No answer, aborting.");
+    }
+    Ok(answer.trim().to_ascii_lowercase())
+}
+
+fn print_dry_run_summary(fs: &dyn RetroFs) {
+    let free = fs.free_space();
+    println!(
+        "Dry run, image not modified. Free space left would be: {} blocks ({} bytes), {} directory entries.",
+        free.blocks, free.bytes, free.dir_entries
+    );
+}
+
+fn block_list_str(blocks: &[u32]) -> String {
+    blocks.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",")
+}
+
+/// Returns the files matching any of the image globs and none of the exclude ones, in the
+/// listing order. Fails if there are none.
+fn matching_files(fs: &dyn RetroFs, globs: &[ImageGlob], default_user: u8, exclude: &[String]) -> Result<Vec<FsFile>> {
+    let mut files: Vec<FsFile> = fs
+        .list(Select::All)?
+        .into_iter()
+        .filter(|f| globs.iter().any(|g| g.matches(default_user, f.user, &f.name)) && !matches_any(exclude, &f.name))
+        .collect();
+    files.sort_by(FsFile::listing_cmp);
+    if files.is_empty() {
+        let globs: Vec<String> = globs.iter().map(|g| g.to_string()).collect();
+        bail!(Failure::new(
+            ErrorKind::NoMatch,
+            format!("No files on the image match {}.", globs.join(" "))
+        ));
+    }
+    Ok(files)
+}
+
+/// Returns true if name matches any of the glob patterns.
+fn matches_any(globs: &[String], name: &str) -> bool {
+    globs.iter().any(|glob| glob_match(glob, name))
+}
+
+/// Takes the patterns as regular expressions with --regex, as globs otherwise.
+fn regex_globs(globs: Vec<ImageGlob>, regex: bool) -> Result<Vec<ImageGlob>> {
+    if !regex {
+        return Ok(globs);
+    }
+    globs.into_iter().map(ImageGlob::into_regex).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dsk, load_image_fs, parse_args, reload_image_fs};
+    use crate::cpm::{CpmVersion, FileId, FilenameMode, LsMode};
+    use crate::error::{error_kind, ErrorKind};
+    use crate::profile::Profile;
+    use std::fs::File;
+
+    #[test]
+    fn test_get_range_overflow() {
+        let length = usize::MAX.to_string();
+        let args = [
+            "tests/03.dsk",
+            "get",
+            "--offset",
+            "2",
+            "--length",
+            &length,
+            ":BDOSI.MAC",
+            "tests/out_range.bin",
+        ];
+        let err = dsk(parse_args(&args), false).unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Usage);
+    }
+
+    #[test]
+    fn test_reload_raw_image() {
+        let path = "tests/out_reload.img";
+        std::fs::write(path, vec![0xE5; 80 * 2 * 9 * 512]).unwrap();
+        let profile = Profile::find("pcw720").unwrap();
+        let fs = load_image_fs(&mut File::open(path).unwrap(), Some(profile), None).unwrap();
+
+        // another program adds a file
+        let mut other = load_image_fs(&mut File::open(path).unwrap(), Some(profile), None).unwrap();
+        let id = FileId::new_with_filename(0, "NEW.TXT", FilenameMode::Normalized).unwrap();
+        other.write_file(&id, &mut &b"new"[..], false).unwrap();
+        other.save(&mut File::create(path).unwrap()).unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 80 * 2 * 9 * 512);
+
+        let fs = reload_image_fs(&mut File::open(path).unwrap(), &fs).unwrap();
+        assert_eq!(fs.params().version, CpmVersion::V3);
+        assert_eq!(fs.list_files(LsMode::All).unwrap()[0].name, "NEW.TXT");
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{bail, Result};
+use std::path::Path;
+
+use super::{dsk_image, DskArgs, DskCommands, STDIO};
+use crate::error::{error_kind, ErrorKind, Failure};
+use crate::util::{glob_files, WILDCARDS};
+
+/// Returns the image files matching the image argument if it's a glob, None for a plain
+/// image file.
+pub fn batch_images(image_file: &str) -> Result<Option<Vec<String>>> {
+    if image_file == STDIO || !image_file.contains(WILDCARDS) || Path::new(image_file).exists() {
+        return Ok(None);
+    }
+    let images: Vec<String> = glob_files(image_file)?
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect();
+    if images.is_empty() {
+        bail!(Failure::new(
+            ErrorKind::NoMatch,
+            format!("No image files match {}", image_file)
+        ));
+    }
+    Ok(Some(images))
+}
+
+/// Runs a read-only command on every image, its output headed by the image name. Images with
+/// no matching files are skipped, other failures are reported and don't stop the batch.
+pub fn batch(args: DskArgs, images: &[String]) -> Result<()> {
+    if !args.command.batch_allowed() {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            "Only read-only, non-interactive commands can run on multiple images, writing to stdout \
+             rather than local files every image would overwrite."
+        ));
+    }
+
+    let mut matched = 0;
+    let mut failures = vec![];
+    for image in images {
+        let mut command = args.command.clone();
+        match &mut command {
+            // ls prints the image name itself, and nothing if no files match
+            DskCommands::Ls(ls_args) => ls_args.batch_image = Some(image.clone()),
+            _ => println!("{}:", image),
+        }
+        let image_args = DskArgs {
+            image_file: image.clone(),
+            command,
+            ..args.clone()
+        };
+        match dsk_image(image_args) {
+            Ok(()) => matched += 1,
+            Err(e) if error_kind(&e) == ErrorKind::NoMatch => {}
+            Err(e) => {
+                eprintln!("Error: {}: {:#}", image, e);
+                failures.push(error_kind(&e));
+            }
+        }
+        if !matches!(args.command, DskCommands::Ls(_)) {
+            println!();
+        }
+    }
+
+    if let Some(&kind) = failures.first() {
+        bail!(Failure::new(
+            kind,
+            format!("{} of {} images failed.", failures.len(), images.len())
+        ));
+    }
+    if matched == 0 {
+        bail!(Failure::new(ErrorKind::NoMatch, "No files matched in any image."));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch, batch_images};
+    use crate::cmd_dsk::parse_args;
+    use crate::error::{error_kind, ErrorKind};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_batch_images() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_batch");
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["b.dsk", "a.dsk", "c.tap"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let pattern = |glob: &str| dir.join(glob).display().to_string();
+
+        let images = batch_images(&pattern("*.dsk")).unwrap().unwrap();
+        assert_eq!(images, [pattern("a.dsk"), pattern("b.dsk")]);
+        assert!(batch_images(&pattern("a.dsk")).unwrap().is_none());
+        assert!(batch_images("-").unwrap().is_none());
+        assert!(batch_images(&pattern("*.img")).is_err());
+    }
+
+    #[test]
+    fn test_batch_local_output() {
+        let images = ["tests/03.dsk".to_string(), "tests/03.dsk".to_string()];
+        let run = |args: &[&str]| {
+            let args: Vec<&str> = ["tests/*.dsk"].iter().chain(args).copied().collect();
+            batch(parse_args(&args), &images)
+        };
+        for args in [
+            &["export", "--zip", "tests/out_batch/all.zip"][..],
+            &["boot", "get", "tests/out_batch/boot.bin"],
+            &["get", "BDOS.MAC", "tests/out_batch"],
+            &["cp", "BDOS.MAC", "tests/out_batch/bdos.mac"],
+            &["fsdump", "tests/out_batch/dump"],
+            &["undelete", "X", "--partial", "tests/out_batch/x"],
+        ] {
+            let err = run(args).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::Usage, "{:?}", args);
+        }
+        let get = parse_args(&["tests/*.dsk", "get", "BDOS.MAC", "-"]);
+        assert!(get.command.batch_allowed());
+        assert!(run(&["info"]).is_ok());
+    }
+}
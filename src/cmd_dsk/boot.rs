@@ -0,0 +1,322 @@
+use anyhow::{bail, Context, Result};
+use clap::{Args, Subcommand};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use crate::cpm::CpmFs;
+use crate::error::{ErrorKind, Failure};
+use crate::util::{hexdump, parse_number};
+
+#[derive(Args, Clone)]
+pub struct BootArgs {
+    #[command(subcommand)]
+    pub command: BootCommands,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum BootCommands {
+    /// Save the system area (reserved tracks) to a local file
+    Get(BootGetArgs),
+    /// Write a local file onto the system area (reserved tracks)
+    Put(BootPutArgs),
+    /// Hexdump the first sector of the system area, with known fields annotated
+    Show,
+    /// Patch bytes in the system area in place
+    Patch(BootPatchArgs),
+}
+
+#[derive(Args, Clone)]
+pub struct BootGetArgs {
+    /// local file name
+    local_file: String,
+}
+
+#[derive(Args, Clone)]
+pub struct BootPutArgs {
+    /// local file with the system binary
+    local_file: String,
+}
+
+#[derive(Args, Clone)]
+pub struct BootPatchArgs {
+    /// patches in OFFSET=BYTES form, e.g. 0x10=C3,00,01 (offset relative to the system area start)
+    #[arg(required = true)]
+    patches: Vec<Patch>,
+}
+
+#[derive(Clone, Debug)]
+struct Patch {
+    offset: usize,
+    bytes: Vec<u8>,
+}
+
+impl FromStr for Patch {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((offset, bytes)) = s.split_once('=') else {
+            bail!("Patch must be in OFFSET=BYTES form: {}", s);
+        };
+        let offset = parse_number(offset)?;
+
+        // bytes are hex, optionally separated by commas or spaces
+        let digits: String = bytes.chars().filter(|c| *c != ',' && !c.is_whitespace()).collect();
+        if digits.is_empty() || !digits.len().is_multiple_of(2) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Invalid patch bytes: {}", bytes);
+        }
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect();
+
+        Ok(Self { offset, bytes })
+    }
+}
+
+/// Known field of the first system sector.
+struct Field {
+    offset: usize,
+    size: usize,
+    description: &'static str,
+}
+
+/// CP/J system tracks start with CP/M 2.2 CCP image, as it gets loaded into memory.
+const CCP_FIELDS: [Field; 6] = [
+    Field {
+        offset: 0x00,
+        size: 3,
+        description: "CCP entry",
+    },
+    Field {
+        offset: 0x03,
+        size: 3,
+        description: "CCP entry (clear command buffer)",
+    },
+    Field {
+        offset: 0x06,
+        size: 1,
+        description: "Command buffer size",
+    },
+    Field {
+        offset: 0x07,
+        size: 1,
+        description: "Command buffer length",
+    },
+    Field {
+        offset: 0x08,
+        size: 128,
+        description: "Command buffer (sign-on message on CP/J)",
+    },
+    Field {
+        offset: 0x88,
+        size: 2,
+        description: "Command buffer pointer",
+    },
+];
+
+/// Opcodes a boot loader plausibly starts with: JP, JR, DI, LD SP,nn, XOR A, LD HL,nn.
+const LOADER_START: [u8; 6] = [0xC3, 0x18, 0xF3, 0x31, 0xAF, 0x21];
+
+/// What the system area seems to hold.
+#[derive(Debug, PartialEq)]
+pub struct SystemStatus {
+    /// bytes up to the end of the last sector not filled with a single value
+    pub used: usize,
+    /// recognized system, if any
+    pub system: Option<&'static str>,
+    /// a known system or a plausible boot loader is there
+    pub bootable: bool,
+}
+
+/// Inspects the system area: sectors filled with a single value (formatter filler, zeros)
+/// are considered unused, the first sector tells the system (CP/J's CCP) or a loader.
+pub fn system_status(system: &[u8], sector_size: usize) -> SystemStatus {
+    let is_filler = |sector: &[u8]| sector.iter().all(|&b| b == sector[0]);
+    let used = system
+        .chunks(sector_size)
+        .rposition(|sector| !is_filler(sector))
+        .map_or(0, |idx| ((idx + 1) * sector_size).min(system.len()));
+    let first = &system[0..sector_size.min(system.len())];
+
+    let system = if is_ccp(first) {
+        let buffer = String::from_utf8_lossy(&first[0x08..0x88]);
+        Some(if buffer.contains("CP/J") {
+            "CP/J"
+        } else {
+            "CP/M 2.2 (CCP)"
+        })
+    } else {
+        None
+    };
+    let bootable = used > 0 && (system.is_some() || (!is_filler(first) && LOADER_START.contains(&first[0])));
+    SystemStatus { used, system, bootable }
+}
+
+/// Both entries being JP instructions is a good hint we deal with CCP.
+fn is_ccp(sector: &[u8]) -> bool {
+    sector.len() >= 0x8A && sector[0] == 0xC3 && sector[3] == 0xC3
+}
+
+impl BootArgs {
+    pub fn modifies_image(&self) -> bool {
+        matches!(self.command, BootCommands::Put(_) | BootCommands::Patch(_))
+    }
+
+    pub fn writes_local_path(&self) -> bool {
+        matches!(self.command, BootCommands::Get(_))
+    }
+}
+
+pub fn boot(fs: &mut CpmFs, args: BootArgs) -> Result<()> {
+    match args.command {
+        BootCommands::Get(cmd_args) => get(fs, cmd_args),
+        BootCommands::Put(cmd_args) => put(fs, cmd_args),
+        BootCommands::Show => show(fs),
+        BootCommands::Patch(cmd_args) => patch(fs, cmd_args),
+    }
+}
+
+fn get(fs: &CpmFs, args: BootGetArgs) -> Result<()> {
+    let data = fs.read_system_area()?;
+    let mut lf = File::create(&args.local_file).context("Can't create local file")?;
+    lf.write_all(&data)?;
+    Ok(())
+}
+
+fn put(fs: &mut CpmFs, args: BootPutArgs) -> Result<()> {
+    let mut data = Vec::new();
+    File::open(&args.local_file)
+        .context("Can't open local file")?
+        .read_to_end(&mut data)?;
+    fs.write_system_area(&data)?;
+    println!(
+        "{} bytes written to the system area ({} bytes available).",
+        data.len(),
+        fs.system_area_size()
+    );
+    Ok(())
+}
+
+fn show(fs: &CpmFs) -> Result<()> {
+    let system = fs.read_system_area()?;
+    let sector = &system[0..fs.sector_size().min(system.len())];
+    for line in hexdump(sector, 0) {
+        println!("{}", line);
+    }
+    println!();
+
+    if !is_ccp(sector) {
+        println!("No known structure recognized.");
+        return Ok(());
+    }
+
+    println!("CP/M 2.2 CCP image:");
+    for field in &CCP_FIELDS {
+        let bytes = &sector[field.offset..field.offset + field.size];
+        let value = match field.size {
+            1 => format!("{}", bytes[0]),
+            2 => format!("0x{:04X}", u16::from_le_bytes([bytes[0], bytes[1]])),
+            3 => format!("JP 0x{:04X}", u16::from_le_bytes([bytes[1], bytes[2]])),
+            _ => {
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                format!("\"{}\"", String::from_utf8_lossy(&bytes[0..end]))
+            }
+        };
+        println!("  0x{:02X}  {:<42} {}", field.offset, field.description, value);
+    }
+
+    // command buffer pointer refers to the buffer at offset 8
+    let ccp_base = u16::from_le_bytes([sector[0x88], sector[0x89]]).wrapping_sub(8);
+    println!("  CCP load address: 0x{:04X}", ccp_base);
+    Ok(())
+}
+
+fn patch(fs: &mut CpmFs, args: BootPatchArgs) -> Result<()> {
+    let mut system = fs.read_system_area()?;
+    for p in &args.patches {
+        let Some(end) = p.offset.checked_add(p.bytes.len()).filter(|&end| end <= system.len()) else {
+            bail!(Failure::new(
+                ErrorKind::Usage,
+                format!(
+                    "Patch at 0x{:04X} ({} bytes) exceeds the system area ({} bytes)",
+                    p.offset,
+                    p.bytes.len(),
+                    system.len()
+                )
+            ));
+        };
+        system[p.offset..end].copy_from_slice(&p.bytes);
+    }
+    fs.write_system_area(&system)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{patch, system_status, BootPatchArgs, Patch, SystemStatus};
+    use crate::cpm::{CpmFs, CpmVersion};
+    use crate::error::{error_kind, ErrorKind};
+    use crate::profile::Profile;
+    use std::fs::File;
+
+    #[test]
+    fn test_patch_parse() {
+        let p: Patch = "0x10=C3,00,01".parse().unwrap();
+        assert_eq!(p.offset, 16);
+        assert_eq!(p.bytes, vec![0xC3, 0x00, 0x01]);
+
+        let p: Patch = "5=abcd".parse().unwrap();
+        assert_eq!(p.offset, 5);
+        assert_eq!(p.bytes, vec![0xAB, 0xCD]);
+
+        assert!("5".parse::<Patch>().is_err());
+        assert!("5=".parse::<Patch>().is_err());
+        assert!("5=ABC".parse::<Patch>().is_err());
+        assert!("5=XY".parse::<Patch>().is_err());
+    }
+
+    #[test]
+    fn test_patch_bounds() {
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V22);
+        let mut fs = CpmFs::load(&mut File::open("tests/03.dsk").unwrap(), params).unwrap();
+        let size = fs.read_system_area().unwrap().len();
+        let args = |offset| BootPatchArgs {
+            patches: vec![Patch {
+                offset,
+                bytes: vec![0xC3, 0x00],
+            }],
+        };
+        for offset in [size - 1, usize::MAX] {
+            let err = patch(&mut fs, args(offset)).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::Usage);
+        }
+        patch(&mut fs, args(size - 2)).unwrap();
+        assert_eq!(fs.read_system_area().unwrap()[size - 2..], [0xC3, 0x00]);
+    }
+
+    #[test]
+    fn test_system_status() {
+        let mut system = vec![0xE5; 4 * 512];
+        assert_eq!(
+            system_status(&system, 512),
+            SystemStatus {
+                used: 0,
+                system: None,
+                bootable: false
+            }
+        );
+
+        system[0..6].copy_from_slice(&[0xC3, 0xF3, 0xBA, 0xC3, 0xF3, 0xBA]);
+        system[0x08..0x0C].copy_from_slice(b"CP/J");
+        system[1024] = 0x00;
+        let status = system_status(&system, 512);
+        assert_eq!(
+            (status.used, status.system, status.bootable),
+            (1536, Some("CP/J"), true)
+        );
+
+        // data, but no loader
+        system[0] = 0x41;
+        let status = system_status(&system, 512);
+        assert_eq!((status.used, status.system, status.bootable), (1536, None, false));
+    }
+}
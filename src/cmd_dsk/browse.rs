@@ -0,0 +1,563 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::widgets::{Block, Clear, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+use crate::cpm::{CpmFs, FileId, FileItem, FilenameMode, LsMode};
+use crate::file_arg::DEFAULT_USER;
+use crate::util::{hexdump, safe_filename, thousands};
+
+const KEYS_HELP: &str = "Tab pane  Enter open  F3/v view  F5/c copy  F6/r rename  F8/d delete  F10/q quit";
+
+#[derive(Args, Clone)]
+pub struct BrowseArgs {
+    /// local directory shown in the right pane (default: the current one)
+    #[arg(short, long)]
+    dir: Option<PathBuf>,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Pane {
+    Image,
+    Local,
+}
+
+enum Mode {
+    Browse,
+    View {
+        title: String,
+        lines: Vec<String>,
+        scroll: usize,
+    },
+    Rename {
+        input: String,
+    },
+    ConfirmDelete,
+}
+
+struct LocalEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+}
+
+/// Two-pane browser state: image files on the left, local directory on the right.
+struct App<'a> {
+    fs: &'a mut CpmFs,
+    image_files: Vec<FileItem>,
+    image_state: ListState,
+    local_dir: PathBuf,
+    local_entries: Vec<LocalEntry>,
+    local_state: ListState,
+    active: Pane,
+    mode: Mode,
+    message: String,
+    /// number of list rows visible, for paging
+    page: usize,
+    quit: bool,
+}
+
+/// Runs the interactive browser, changes are saved to the image on exit.
+pub fn browse(fs: &mut CpmFs, args: BrowseArgs) -> Result<()> {
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => std::env::current_dir()?,
+    };
+    let mut app = App::new(fs, dir)?;
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl<'a> App<'a> {
+    fn new(fs: &'a mut CpmFs, local_dir: PathBuf) -> Result<Self> {
+        let mut app = Self {
+            fs,
+            image_files: vec![],
+            image_state: ListState::default(),
+            local_dir: fs::canonicalize(&local_dir).with_context(|| format!("Can't open {}", local_dir.display()))?,
+            local_entries: vec![],
+            local_state: ListState::default(),
+            active: Pane::Image,
+            mode: Mode::Browse,
+            message: String::new(),
+            page: 10,
+            quit: false,
+        };
+        app.refresh_image()?;
+        app.refresh_local()?;
+        Ok(app)
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.handle_key(key.code);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, code: KeyCode) {
+        let result = match self.mode {
+            Mode::Browse => self.browse_key(code),
+            Mode::View { .. } => {
+                self.view_key(code);
+                Ok(())
+            }
+            Mode::Rename { .. } => self.rename_key(code),
+            Mode::ConfirmDelete => self.delete_key(code),
+        };
+        if let Err(e) = result {
+            self.mode = Mode::Browse;
+            self.message = format!("Error: {:#}", e);
+        }
+    }
+
+    fn browse_key(&mut self, code: KeyCode) -> Result<()> {
+        self.message.clear();
+        match code {
+            KeyCode::Tab | KeyCode::Left | KeyCode::Right => {
+                self.active = match self.active {
+                    Pane::Image => Pane::Local,
+                    Pane::Local => Pane::Image,
+                }
+            }
+            KeyCode::Up => self.move_selection(-1),
+            KeyCode::Down => self.move_selection(1),
+            KeyCode::PageUp => self.move_selection(-(self.page as isize)),
+            KeyCode::PageDown => self.move_selection(self.page as isize),
+            KeyCode::Home => self.move_selection(isize::MIN / 2),
+            KeyCode::End => self.move_selection(isize::MAX / 2),
+            KeyCode::Enter => match self.selected_local() {
+                Some(entry) if self.active == Pane::Local && entry.is_dir => {
+                    let dir = self.local_dir.join(&entry.name);
+                    self.local_dir = fs::canonicalize(&dir).with_context(|| format!("Can't open {}", dir.display()))?;
+                    self.local_state.select(None);
+                    self.refresh_local()?;
+                }
+                _ => self.view()?,
+            },
+            KeyCode::F(3) | KeyCode::Char('v') => self.view()?,
+            KeyCode::F(5) | KeyCode::Char('c') => self.copy()?,
+            KeyCode::F(6) | KeyCode::Char('r') => {
+                if let Some(name) = self.selected_name() {
+                    self.mode = Mode::Rename { input: name };
+                }
+            }
+            KeyCode::F(8) | KeyCode::Char('d') | KeyCode::Delete if self.selected_name().is_some() => {
+                self.mode = Mode::ConfirmDelete
+            }
+            KeyCode::F(10) | KeyCode::Char('q') | KeyCode::Esc => self.quit = true,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn view_key(&mut self, code: KeyCode) {
+        let page = self.page;
+        let Mode::View { lines, scroll, .. } = &mut self.mode else {
+            return;
+        };
+        let last = lines.len().saturating_sub(1);
+        *scroll = match code {
+            KeyCode::Up => scroll.saturating_sub(1),
+            KeyCode::Down => (*scroll + 1).min(last),
+            KeyCode::PageUp => scroll.saturating_sub(page),
+            KeyCode::PageDown => (*scroll + page).min(last),
+            KeyCode::Home => 0,
+            KeyCode::End => last,
+            KeyCode::Esc | KeyCode::Enter | KeyCode::F(3) | KeyCode::Char('q') | KeyCode::Char('v') => {
+                self.mode = Mode::Browse;
+                return;
+            }
+            _ => *scroll,
+        };
+    }
+
+    fn rename_key(&mut self, code: KeyCode) -> Result<()> {
+        let Mode::Rename { input } = &mut self.mode else {
+            return Ok(());
+        };
+        match code {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Esc => self.mode = Mode::Browse,
+            KeyCode::Enter => {
+                let new_name = input.clone();
+                self.mode = Mode::Browse;
+                self.rename(&new_name)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn delete_key(&mut self, code: KeyCode) -> Result<()> {
+        self.mode = Mode::Browse;
+        if matches!(code, KeyCode::Char('y') | KeyCode::Char('Y')) {
+            self.delete()?;
+        } else {
+            self.message = "Not deleted.".to_string();
+        }
+        Ok(())
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let (state, len) = match self.active {
+            Pane::Image => (&mut self.image_state, self.image_files.len()),
+            Pane::Local => (&mut self.local_state, self.local_entries.len()),
+        };
+        if len > 0 {
+            let current = state.selected().unwrap_or(0) as isize;
+            state.select(Some(current.saturating_add(delta).clamp(0, len as isize - 1) as usize));
+        }
+    }
+
+    fn selected_image(&self) -> Option<&FileItem> {
+        self.image_state.selected().and_then(|idx| self.image_files.get(idx))
+    }
+
+    fn selected_local(&self) -> Option<&LocalEntry> {
+        self.local_state.selected().and_then(|idx| self.local_entries.get(idx))
+    }
+
+    /// Name of the selected item in the active pane (never the parent directory).
+    fn selected_name(&self) -> Option<String> {
+        match self.active {
+            Pane::Image => self.selected_image().map(|f| f.name.clone()),
+            Pane::Local => self.selected_local().filter(|e| e.name != "..").map(|e| e.name.clone()),
+        }
+    }
+
+    fn view(&mut self) -> Result<()> {
+        let (title, data) = match self.active {
+            Pane::Image => {
+                let Some(f) = self.selected_image() else {
+                    return Ok(());
+                };
+                let mut data = Vec::with_capacity(f.size);
+                self.fs.read_file(f, &mut data, false)?;
+                (format!("{}:{}", f.user.unwrap_or_default(), f.name), data)
+            }
+            Pane::Local => {
+                let Some(entry) = self.selected_local().filter(|e| !e.is_dir) else {
+                    return Ok(());
+                };
+                let path = self.local_dir.join(&entry.name);
+                let data = fs::read(&path).with_context(|| format!("Can't read {}", path.display()))?;
+                (path.display().to_string(), data)
+            }
+        };
+        self.mode = Mode::View {
+            title: format!(" {} ({} bytes) ", title, thousands(data.len())),
+            lines: hexdump(&data, 0),
+            scroll: 0,
+        };
+        Ok(())
+    }
+
+    fn copy(&mut self) -> Result<()> {
+        match self.active {
+            Pane::Image => {
+                let Some(f) = self.selected_image() else {
+                    return Ok(());
+                };
+                let path = self.local_dir.join(safe_filename(&f.name)?);
+                if path.exists() {
+                    bail!("{} already exists", path.display());
+                }
+                let mut lf = File::create(&path).with_context(|| format!("Can't create {}", path.display()))?;
+                let bytes = self.fs.read_file(f, &mut lf, false)?;
+                self.message = format!("Copied {} -> {}, {} bytes", f.name, path.display(), thousands(bytes));
+                self.refresh_local()
+            }
+            Pane::Local => {
+                let Some(name) = self.selected_local().filter(|e| !e.is_dir).map(|e| e.name.clone()) else {
+                    return Ok(());
+                };
+                let path = self.local_dir.join(&name);
+                let id = FileId::new_with_filename(DEFAULT_USER, &name, FilenameMode::Normalized)?;
+                let mut lf = File::open(&path).with_context(|| format!("Can't open {}", path.display()))?;
+                self.fs.write_file(&id, &mut lf, false)?;
+                self.message = format!("Copied {} -> {}:{}", name, id.user, id.filename());
+                self.refresh_image()
+            }
+        }
+    }
+
+    fn rename(&mut self, new_name: &str) -> Result<()> {
+        match self.active {
+            Pane::Image => {
+                let Some(f) = self.selected_image().cloned() else {
+                    return Ok(());
+                };
+                let id = FileId::new_with_filename(f.user.unwrap_or_default(), new_name, FilenameMode::Normalized)?;
+                self.fs.rename_file(&f, &id)?;
+                self.message = format!("Renamed {} to {}", f.name, id.filename());
+                self.refresh_image()
+            }
+            Pane::Local => {
+                let Some(old_name) = self.selected_name() else {
+                    return Ok(());
+                };
+                if new_name.contains(['/', '\\']) {
+                    bail!("Invalid file name {}", new_name);
+                }
+                let new_path = self.local_dir.join(new_name);
+                if new_path.exists() {
+                    bail!("{} already exists", new_path.display());
+                }
+                fs::rename(self.local_dir.join(&old_name), &new_path)?;
+                self.message = format!("Renamed {} to {}", old_name, new_name);
+                self.refresh_local()
+            }
+        }
+    }
+
+    fn delete(&mut self) -> Result<()> {
+        match self.active {
+            Pane::Image => {
+                let Some(f) = self.selected_image().cloned() else {
+                    return Ok(());
+                };
+                self.fs.delete_file(&f)?;
+                self.message = format!("Deleted {}:{}", f.user.unwrap_or_default(), f.name);
+                self.refresh_image()
+            }
+            Pane::Local => {
+                let Some(entry) = self.selected_local().filter(|e| e.name != "..") else {
+                    return Ok(());
+                };
+                if entry.is_dir {
+                    bail!("Not deleting directory {}", entry.name);
+                }
+                fs::remove_file(self.local_dir.join(&entry.name))?;
+                self.message = format!("Deleted {}", entry.name);
+                self.refresh_local()
+            }
+        }
+    }
+
+    fn refresh_image(&mut self) -> Result<()> {
+        self.image_files = self.fs.list_files(LsMode::All)?;
+        self.image_files.sort_by(FileItem::listing_cmp);
+        Self::clamp_selection(&mut self.image_state, self.image_files.len());
+        Ok(())
+    }
+
+    fn refresh_local(&mut self) -> Result<()> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.local_dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            entries.push(LocalEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+            });
+        }
+        // directories first
+        entries.sort_by(|a, b| (!a.is_dir, &a.name).cmp(&(!b.is_dir, &b.name)));
+        if self.local_dir.parent().is_some() {
+            entries.insert(
+                0,
+                LocalEntry {
+                    name: "..".to_string(),
+                    is_dir: true,
+                    size: 0,
+                },
+            );
+        }
+        self.local_entries = entries;
+        Self::clamp_selection(&mut self.local_state, self.local_entries.len());
+        Ok(())
+    }
+
+    fn clamp_selection(state: &mut ListState, len: usize) {
+        if len == 0 {
+            state.select(None);
+        } else {
+            state.select(Some(state.selected().unwrap_or(0).min(len - 1)));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status, keys] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(1), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(main);
+        self.page = (main.height as usize).saturating_sub(3).max(1);
+
+        let items: Vec<ListItem> = self
+            .image_files
+            .iter()
+            .map(|f| {
+                ListItem::new(format!(
+                    "{:>2}:{:<12} {:>9}",
+                    f.user.unwrap_or_default(),
+                    f.name,
+                    f.size
+                ))
+            })
+            .collect();
+        let title = match self.fs.label() {
+            Some(label) => format!(" Image [{}] ", label),
+            None => " Image ".to_string(),
+        };
+        let active = self.active == Pane::Image;
+        Self::draw_list(frame, left, items, title, active, &mut self.image_state);
+
+        let items: Vec<ListItem> = self
+            .local_entries
+            .iter()
+            .map(|e| {
+                let size = if e.is_dir {
+                    "<DIR>".to_string()
+                } else {
+                    e.size.to_string()
+                };
+                ListItem::new(format!("{:<24} {:>9}", e.name, size))
+            })
+            .collect();
+        let title = format!(" {} ", self.local_dir.display());
+        let active = self.active == Pane::Local;
+        Self::draw_list(frame, right, items, title, active, &mut self.local_state);
+
+        let status_line = match &self.mode {
+            Mode::Rename { input } => format!("Rename to: {}_", input),
+            Mode::ConfirmDelete => format!("Delete {}? (y/n)", self.selected_name().unwrap_or_default()),
+            _ if !self.message.is_empty() => self.message.clone(),
+            _ => {
+                let (_, free_dents) = self.fs.dir_slots();
+                format!(
+                    "{} blocks free ({} bytes), {} directory entries free",
+                    self.fs.free_blocks(),
+                    thousands(self.fs.free_blocks() * self.fs.block_size()),
+                    free_dents
+                )
+            }
+        };
+        frame.render_widget(Paragraph::new(status_line).reversed(), status);
+        frame.render_widget(Paragraph::new(KEYS_HELP).dim(), keys);
+
+        if let Mode::View { title, lines, scroll } = &self.mode {
+            let text: Vec<_> = lines
+                .iter()
+                .skip(*scroll)
+                .map(|l| l.as_str().into())
+                .collect::<Vec<_>>();
+            frame.render_widget(Clear, main);
+            frame.render_widget(
+                Paragraph::new(text).block(Block::bordered().title(title.as_str()).title_bottom(" Esc close ")),
+                main,
+            );
+        }
+    }
+
+    fn draw_list(
+        frame: &mut Frame,
+        area: Rect,
+        items: Vec<ListItem>,
+        title: String,
+        active: bool,
+        state: &mut ListState,
+    ) {
+        let (border, highlight) = if active {
+            (Style::new().yellow(), Style::new().reversed())
+        } else {
+            (Style::new(), Style::new().bold())
+        };
+        let list = List::new(items)
+            .block(Block::bordered().title(title).border_style(border))
+            .highlight_style(highlight);
+        frame.render_stateful_widget(list, area, state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{App, Pane};
+    use crate::cpm::{CpmFs, CpmVersion, LsMode};
+    use crate::profile::Profile;
+    use ratatui::backend::TestBackend;
+    use ratatui::crossterm::event::KeyCode;
+    use ratatui::Terminal;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_copy_rename_delete() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_browse");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hello.txt"), b"hello").unwrap();
+
+        let mut fs: CpmFs = Profile::find("junior").unwrap().format(CpmVersion::V3).unwrap();
+        let mut app = App::new(&mut fs, dir.clone()).unwrap();
+        assert!(app.image_files.is_empty());
+
+        // copy to the image
+        app.handle_key(KeyCode::Tab);
+        assert!(app.active == Pane::Local);
+        let idx = app.local_entries.iter().position(|e| e.name == "hello.txt").unwrap();
+        app.local_state.select(Some(idx));
+        app.handle_key(KeyCode::F(5));
+        assert_eq!(app.image_files.len(), 1);
+        assert_eq!(app.image_files[0].name, "HELLO.TXT");
+
+        // rename it on the image
+        app.handle_key(KeyCode::Tab);
+        app.handle_key(KeyCode::F(6));
+        for _ in 0.."HELLO.TXT".len() {
+            app.handle_key(KeyCode::Backspace);
+        }
+        "hi.txt".chars().for_each(|c| app.handle_key(KeyCode::Char(c)));
+        app.handle_key(KeyCode::Enter);
+        assert_eq!(app.image_files[0].name, "HI.TXT");
+
+        // copy back and delete from the image
+        app.handle_key(KeyCode::F(5));
+        assert_eq!(std::fs::read(dir.join("HI.TXT")).unwrap()[0..5], *b"hello");
+        app.handle_key(KeyCode::F(8));
+        app.handle_key(KeyCode::Char('n'));
+        assert_eq!(app.image_files.len(), 1);
+        app.handle_key(KeyCode::F(8));
+        app.handle_key(KeyCode::Char('y'));
+        assert!(app.image_files.is_empty());
+
+        // errors end up in the status line
+        app.handle_key(KeyCode::Tab);
+        app.local_state
+            .select(Some(app.local_entries.iter().position(|e| e.name == "HI.TXT").unwrap()));
+        app.handle_key(KeyCode::F(5));
+        app.handle_key(KeyCode::Tab);
+        app.handle_key(KeyCode::Tab);
+        app.handle_key(KeyCode::F(5));
+        assert!(app.message.starts_with("Error:"));
+
+        // rendering, including the hex viewer
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        app.handle_key(KeyCode::Tab);
+        app.handle_key(KeyCode::F(3));
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains("68 65 6C 6C 6F 00"));
+        app.handle_key(KeyCode::Esc);
+
+        app.handle_key(KeyCode::Char('q'));
+        assert!(app.quit);
+        drop(app);
+        assert_eq!(fs.list_files(LsMode::All).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::fs::File;
+
+use super::{find_file, image_file_name, load_fs};
+use crate::color::{paint, Style};
+use crate::cpm::{CpmFs, Params};
+use crate::error::{ErrorKind, Failure};
+use crate::file_arg::FileArg;
+use crate::util::thousands;
+
+#[derive(Args, Clone)]
+pub struct CmpArgs {
+    /// first file, e.g. 0:PROG.COM
+    file1: FileArg,
+    /// second file, e.g. 3:PROG.COM
+    file2: FileArg,
+    /// image holding the second file (of the same format), rather than the same image
+    #[arg(short, long)]
+    other_image: Option<String>,
+    /// text mode: compare up to the ^Z end of text only
+    #[arg(short, long)]
+    text: bool,
+}
+
+/// Compares two files byte by byte, reporting the first difference. Fails with the "differ"
+/// exit code if the files aren't identical.
+pub fn cmp(fs: &CpmFs, params: Params, args: CmpArgs) -> Result<()> {
+    let other = match &args.other_image {
+        Some(image_file) => {
+            let mut f = File::open(image_file).with_context(|| format!("Can't open image file {}", image_file))?;
+            Some(load_fs(&mut f, image_file, params)?)
+        }
+        None => None,
+    };
+    let (data1, name1) = read(fs, &args.file1, args.text)?;
+    let (data2, name2) = read(other.as_ref().unwrap_or(fs), &args.file2, args.text)?;
+
+    let Some(offset) = first_difference(&data1, &data2) else {
+        println!(
+            "{} and {} are identical, {} bytes.",
+            name1,
+            name2,
+            thousands(data1.len())
+        );
+        return Ok(());
+    };
+    match (data1.get(offset), data2.get(offset)) {
+        (Some(a), Some(b)) => println!(
+            "First difference at offset {} (0x{:04X}): {} vs {}, {} bytes differ.",
+            thousands(offset),
+            offset,
+            paint(format!("0x{:02X}", a), Style::Mismatch),
+            paint(format!("0x{:02X}", b), Style::Mismatch),
+            thousands(data1.iter().zip(&data2).filter(|(a, b)| a != b).count())
+        ),
+        _ => println!(
+            "Files are identical up to offset {} (0x{:04X}).",
+            thousands(offset),
+            offset
+        ),
+    }
+    if data1.len() != data2.len() {
+        println!(
+            "Sizes differ: {} bytes vs {} bytes.",
+            thousands(data1.len()),
+            thousands(data2.len())
+        );
+    }
+    bail!(Failure::new(
+        ErrorKind::Differ,
+        format!("{} and {} differ.", name1, name2)
+    ))
+}
+
+/// Reads the whole file, returns it with its display name.
+fn read(fs: &CpmFs, file: &FileArg, text: bool) -> Result<(Vec<u8>, String)> {
+    let (user, name) = image_file_name(file)?;
+    let item = find_file(fs, user, &name)?;
+    let mut data = Vec::with_capacity(item.size);
+    fs.read_file(&item, &mut data, text)?;
+    Ok((data, format!("{}:{}", user, name)))
+}
+
+/// Returns the offset of the first differing byte, or the length of the shorter data if one
+/// is a prefix of the other. None if the data is identical.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(a, b)| a != b) {
+        Some(offset) => Some(offset),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::first_difference;
+
+    #[test]
+    fn test_first_difference() {
+        assert_eq!(first_difference(b"ABCD", b"ABCD"), None);
+        assert_eq!(first_difference(b"ABCD", b"ABXD"), Some(2));
+        assert_eq!(first_difference(b"ABCD", b"AB"), Some(2));
+        assert_eq!(first_difference(b"", b"A"), Some(0));
+        assert_eq!(first_difference(b"", b""), None);
+    }
+}
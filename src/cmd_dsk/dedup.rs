@@ -0,0 +1,135 @@
+use anyhow::Result;
+use clap::Args;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::retro_fs::{FsFile, RetroFs, Select};
+use crate::util::thousands;
+
+#[derive(Args, Clone)]
+pub struct DedupArgs {
+    /// only list the groups of identical files (the default)
+    #[arg(long, conflicts_with = "delete")]
+    report: bool,
+    /// delete the duplicates, keeping the first copy (by user, then by name)
+    #[arg(long)]
+    pub delete: bool,
+}
+
+/// Lists groups of files with identical contents, e.g. the same program in several user
+/// areas, optionally deleting all but the first copy of each.
+pub fn dedup(fs: &mut dyn RetroFs, args: DedupArgs) -> Result<()> {
+    let groups = duplicates(fs)?;
+    if groups.is_empty() {
+        println!("No duplicate files.");
+        return Ok(());
+    }
+
+    let (mut copies, mut bytes) = (0, 0);
+    for group in &groups {
+        let names: Vec<_> = group.iter().map(display_name).collect();
+        println!(
+            "{} copies, {} bytes: {}",
+            group.len(),
+            thousands(group[0].size),
+            names.join(" ")
+        );
+        for file in &group[1..] {
+            if args.delete {
+                fs.delete(file)?;
+                println!("  deleted {}", display_name(file));
+            }
+            copies += 1;
+            bytes += file.size;
+        }
+    }
+    println!(
+        "{} duplicate files ({} bytes) in {} groups{}.",
+        copies,
+        thousands(bytes),
+        groups.len(),
+        if args.delete { " deleted" } else { "" }
+    );
+    Ok(())
+}
+
+/// Returns groups of two or more non-empty files with the same contents, the files and the
+/// groups in the listing order.
+fn duplicates(fs: &dyn RetroFs) -> Result<Vec<Vec<FsFile>>> {
+    let mut files = fs.list(Select::All)?;
+    files.sort_by(FsFile::listing_cmp);
+
+    let mut groups: Vec<Vec<FsFile>> = vec![];
+    let mut by_digest: HashMap<Vec<u8>, usize> = HashMap::new();
+    for file in files.into_iter().filter(|f| f.size > 0) {
+        let mut data = Vec::with_capacity(file.size);
+        fs.read(&file, &mut data, false)?;
+        let digest = Sha256::digest(&data).to_vec();
+        match by_digest.get(&digest) {
+            Some(&index) => groups[index].push(file),
+            None => {
+                by_digest.insert(digest, groups.len());
+                groups.push(vec![file]);
+            }
+        }
+    }
+    groups.retain(|g| g.len() > 1);
+    Ok(groups)
+}
+
+fn display_name(file: &FsFile) -> String {
+    format!("{}:{}", file.user.unwrap_or_default(), file.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dedup, duplicates, DedupArgs};
+    use crate::cpm::{CpmFs, CpmVersion, FileId, FilenameMode, LsMode};
+    use crate::profile::Profile;
+    use std::fs::File;
+
+    #[test]
+    fn test_dedup() {
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V3);
+        let mut fs = CpmFs::load(&mut File::open("tests/03.dsk").unwrap(), params).unwrap();
+        let before = fs.list_files(LsMode::All).unwrap().len();
+        let bdos = fs
+            .list_files(LsMode::All)
+            .unwrap()
+            .into_iter()
+            .find(|f| f.name == "BDOS.MAC")
+            .unwrap();
+        let mut data = vec![];
+        fs.read_file(&bdos, &mut data, false).unwrap();
+        for user in [3, 1] {
+            let id = FileId::new_with_filename(user, "COPY.MAC", FilenameMode::Normalized).unwrap();
+            fs.write_file(&id, &mut data.as_slice(), false).unwrap();
+        }
+
+        let groups = duplicates(&fs).unwrap();
+        let group = groups.iter().find(|g| g[0].name == "BDOS.MAC").unwrap();
+        let names: Vec<_> = group.iter().map(|f| (f.user, f.name.as_str())).collect();
+        // the image has .RES copies of the .MAC sources already
+        assert_eq!(
+            names,
+            [
+                (Some(0), "BDOS.MAC"),
+                (Some(0), "BDOS.RES"),
+                (Some(1), "COPY.MAC"),
+                (Some(3), "COPY.MAC")
+            ]
+        );
+
+        let copies: usize = groups.iter().map(|g| g.len() - 1).sum();
+        dedup(
+            &mut fs,
+            DedupArgs {
+                report: false,
+                delete: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(fs.list_files(LsMode::All).unwrap().len(), before + 2 - copies);
+        assert!(duplicates(&fs).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::Result;
+use clap::{Args, Subcommand};
+
+use crate::cpm::{CpmFs, EntryStatus, MAX_USER_ID};
+use crate::util::hexdump;
+
+#[derive(Args, Clone)]
+pub struct DirArgs {
+    #[command(subcommand)]
+    pub command: DirCommands,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum DirCommands {
+    /// List the directory entries (extents), one per line, in the directory order
+    List(DirListArgs),
+    /// Hexdump all directory slots (including unused ones), each with its fields decoded
+    Dump,
+    /// Sort the directory by user, name and extent, moving unused and deleted entries to the end
+    Sort,
+    /// Wipe deleted entries (they can't be recovered afterwards), extra labels and unused date stamps
+    Compact(DirCompactArgs),
+}
+
+#[derive(Args, Clone)]
+pub struct DirListArgs {
+    /// include unused slots
+    #[arg(short, long)]
+    all: bool,
+}
+
+#[derive(Args, Clone)]
+pub struct DirCompactArgs {
+    /// only show how many slots would be freed, don't modify the image
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+}
+
+impl DirArgs {
+    pub fn modifies_image(&self) -> bool {
+        match &self.command {
+            DirCommands::List(_) | DirCommands::Dump => false,
+            DirCommands::Sort => true,
+            DirCommands::Compact(args) => !args.dry_run,
+        }
+    }
+}
+
+pub fn dir(fs: &mut CpmFs, args: DirArgs) -> Result<()> {
+    match args.command {
+        DirCommands::List(cmd_args) => list(fs, cmd_args),
+        DirCommands::Dump => dump(fs),
+        DirCommands::Sort => sort(fs),
+        DirCommands::Compact(cmd_args) => compact(fs, cmd_args),
+    }
+}
+
+fn list(fs: &CpmFs, args: DirListArgs) -> Result<()> {
+    for e in fs.dir_entries() {
+        let id = e.file_id();
+        let kind = match e.status() {
+            EntryStatus::File => format!("{}:{}", id.user, id.filename()),
+            EntryStatus::Deleted => format!("(deleted) {}", id.filename()),
+            EntryStatus::Unused if !args.all => continue,
+            EntryStatus::Unused => "unused".to_string(),
+            EntryStatus::Label => format!("label {}", id.filename()),
+            EntryStatus::Timestamps => "date stamps".to_string(),
+            EntryStatus::Other => format!("unknown entry type 0x{:02X}", id.user),
+        };
+        if !matches!(e.status(), EntryStatus::File | EntryStatus::Deleted) {
+            println!("{:4}  {}", e.slot(), kind);
+            continue;
+        }
+
+        // F1'-F4' as digits, then R/O, SYS and ARC
+        let attributes = (0..4u8).map(|bit| (e.attributes() & (1 << bit) != 0, (b'1' + bit) as char));
+        let flags: String = attributes
+            .chain([(e.read_only(), 'R'), (e.system_file(), 'S'), (e.archived(), 'A')])
+            .map(|(set, c)| if set { c } else { '-' })
+            .collect();
+        let blocks: Vec<String> = e.blocks().iter().map(|b| b.to_string()).collect();
+        println!(
+            "{:4}  {:<22} ext {:<3} {:>3} rec  {}  {}",
+            e.slot(),
+            kind,
+            e.extent(),
+            e.record_count(),
+            flags,
+            blocks.join(",")
+        );
+    }
+    Ok(())
+}
+
+fn compact(fs: &mut CpmFs, args: DirCompactArgs) -> Result<()> {
+    let stats = fs.compact_directory()?;
+    println!(
+        "{} {} deleted entries, {} extra labels, {} date stamp slots.",
+        if args.dry_run { "Would wipe" } else { "Wiped" },
+        stats.deleted,
+        stats.labels,
+        stats.stamps
+    );
+    let (slots, free) = fs.dir_slots();
+    println!("{} of {} directory slots free.", free, slots);
+    Ok(())
+}
+
+fn sort(fs: &mut CpmFs) -> Result<()> {
+    match fs.sort_directory()? {
+        0 => println!("Directory already sorted."),
+        changed => println!("Directory sorted, {} slots rewritten.", changed),
+    }
+    Ok(())
+}
+
+fn dump(fs: &CpmFs) -> Result<()> {
+    let (slots, _) = fs.dir_slots();
+    let slots_per_sector = fs.sector_size() / 32;
+    let sectors_per_block = fs.block_size() / fs.sector_size();
+    let wide_blocks = fs.wide_blocks();
+    for slot in 0..slots {
+        let data = fs.read_dir_slot(slot)?;
+        let sector = slot / slots_per_sector;
+        let chs = fs.block_chs((sector / sectors_per_block) as u16)[sector % sectors_per_block];
+        println!("Slot {} ({} +0x{:03X})", slot, chs, slot % slots_per_sector * 32);
+        for line in hexdump(&data, slot * 32) {
+            println!("{}", line);
+        }
+        println!("      {}", decode_slot(&data, wide_blocks));
+        println!();
+    }
+    Ok(())
+}
+
+/// Describes the raw directory slot, field by field, without any validation.
+pub fn decode_slot(data: &[u8; 32], wide_blocks: bool) -> String {
+    let name: String = data[1..12].iter().map(|&b| (b & 0x7F) as char).collect();
+    let name = format!("{}.{}", name[0..8].trim_end(), name[8..11].trim_end());
+    let kind = match data[0] {
+        user if user <= MAX_USER_ID => format!("user {}", user),
+        0xE5 if data.iter().all(|&b| b == 0xE5) => return "unused (never written)".to_string(),
+        0xE5 => "deleted".to_string(),
+        0x20 => return format!("directory label {}", name),
+        0x21 => return "date stamps (SFCB) of the 3 preceding slots".to_string(),
+        other => return format!("unknown entry type 0x{:02X}", other),
+    };
+
+    let flags: Vec<&str> = [
+        (1, "F1'"),
+        (2, "F2'"),
+        (3, "F3'"),
+        (4, "F4'"),
+        (9, "R/O"),
+        (10, "SYS"),
+        (11, "ARC"),
+    ]
+    .iter()
+    .filter(|(idx, _)| data[*idx] & 0x80 != 0)
+    .map(|(_, flag)| *flag)
+    .collect();
+    let blocks: Vec<u16> = if wide_blocks {
+        data[16..32]
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect()
+    } else {
+        data[16..32].iter().map(|&b| b as u16).collect()
+    };
+    let used = blocks.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1);
+    let blocks: Vec<String> = blocks[0..used].iter().map(|b| b.to_string()).collect();
+    format!(
+        "{} {}, extent {} (EX {}, S2 {}), RC {}, S1 {}, blocks [{}]{}",
+        kind,
+        name,
+        ((data[14] as u16) << 8) + data[12] as u16,
+        data[12],
+        data[14],
+        data[15],
+        data[13],
+        blocks.join(" "),
+        if flags.is_empty() {
+            String::new()
+        } else {
+            format!(", flags {}", flags.join(" "))
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_slot;
+
+    #[test]
+    fn test_decode_slot() {
+        let mut slot = [0u8; 32];
+        slot[0] = 3;
+        slot[1..12].copy_from_slice(b"GAME    COM");
+        slot[9] |= 0x80;
+        slot[12] = 1;
+        slot[15] = 0x80;
+        slot[16..20].copy_from_slice(&[0x10, 0x00, 0x11, 0x01]);
+        assert_eq!(
+            decode_slot(&slot, true),
+            "user 3 GAME.COM, extent 1 (EX 1, S2 0), RC 128, S1 0, blocks [16 273], flags R/O"
+        );
+
+        slot[0] = 0xE5;
+        assert!(decode_slot(&slot, true).starts_with("deleted GAME.COM"));
+        assert_eq!(decode_slot(&[0xE5; 32], true), "unused (never written)");
+    }
+}
@@ -0,0 +1,380 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::fs::{File, OpenOptions};
+use std::path::PathBuf;
+
+use super::report_warnings;
+use crate::dsk::{DskImage, CHS};
+use crate::error::{ErrorKind, Failure};
+use crate::util::parse_number;
+
+const KEYS_HELP: &str = "0-9 A-F edit  arrows move  PgUp/p PgDn/n sector  F2/s save  F10/q quit";
+const BYTES_PER_ROW: usize = 16;
+
+#[derive(Args, Clone)]
+pub struct EditArgs {
+    /// cylinder
+    #[arg(value_parser = parse_byte)]
+    cylinder: u8,
+    /// head (side)
+    #[arg(value_parser = parse_byte)]
+    head: u8,
+    /// sector ID, e.g. 1 or 0xC1
+    #[arg(value_parser = parse_byte)]
+    sector: u8,
+}
+
+fn parse_byte(s: &str) -> Result<u8> {
+    let n = parse_number(s)?;
+    u8::try_from(n).with_context(|| format!("Number out of range: {}", s))
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Edit,
+    ConfirmSave,
+    ConfirmQuit,
+}
+
+/// Hex editor state: the image is edited in memory, written to the file on save.
+struct App {
+    disk: DskImage,
+    image_file: PathBuf,
+    /// all sectors, by cylinder, head and sector ID
+    sectors: Vec<CHS>,
+    current: usize,
+    /// byte offset within the sector
+    cursor: usize,
+    /// the high nibble of the byte at the cursor has been entered
+    low_nibble: bool,
+    /// contents of the modified sectors as last saved
+    originals: Vec<(CHS, Vec<u8>)>,
+    mode: Mode,
+    message: String,
+    quit: bool,
+}
+
+/// Opens the hex editor on the sector.
+pub fn edit(image_file: &str, args: EditArgs) -> Result<()> {
+    let mut f = File::open(image_file).with_context(|| format!("Can't open image file {}", image_file))?;
+    let disk = DskImage::load(&mut f).context(Failure::new(ErrorKind::Filesystem, "Error loading image file"))?;
+    report_warnings(image_file, &disk);
+    let chs = CHS {
+        cylinder: args.cylinder,
+        head: args.head,
+        sector: args.sector,
+    };
+    let mut app = App::new(disk, PathBuf::from(image_file), chs)?;
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl App {
+    fn new(disk: DskImage, image_file: PathBuf, chs: CHS) -> Result<Self> {
+        let mut sectors = vec![];
+        for t in disk.tracks() {
+            let mut ids: Vec<u8> = t.sectors().iter().map(|s| s.sector_id).collect();
+            ids.sort_unstable();
+            sectors.extend(ids.into_iter().map(|sector| CHS {
+                cylinder: t.cylinder(),
+                head: t.head(),
+                sector,
+            }));
+        }
+        let Some(current) = sectors.iter().position(|&s| s == chs) else {
+            bail!(Failure::new(
+                ErrorKind::Usage,
+                format!("No sector {} on the image", chs)
+            ));
+        };
+        Ok(Self {
+            disk,
+            image_file,
+            sectors,
+            current,
+            cursor: 0,
+            low_nibble: false,
+            originals: vec![],
+            mode: Mode::Edit,
+            message: String::new(),
+            quit: false,
+        })
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.handle_key(key.code);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, code: KeyCode) {
+        let result = match self.mode {
+            Mode::Edit => self.edit_key(code),
+            Mode::ConfirmSave => self.save_key(code),
+            Mode::ConfirmQuit => {
+                self.mode = Mode::Edit;
+                self.quit = matches!(code, KeyCode::Char('y') | KeyCode::Char('Y'));
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            self.mode = Mode::Edit;
+            self.message = format!("Error: {:#}", e);
+        }
+    }
+
+    fn edit_key(&mut self, code: KeyCode) -> Result<()> {
+        self.message.clear();
+        let size = self.sector()?.len();
+        match code {
+            KeyCode::Char(c) if c.is_ascii_hexdigit() => self.set_nibble(c.to_digit(16).unwrap() as u8)?,
+            KeyCode::Left => self.move_cursor(-1, size),
+            KeyCode::Right => self.move_cursor(1, size),
+            KeyCode::Up => self.move_cursor(-(BYTES_PER_ROW as isize), size),
+            KeyCode::Down => self.move_cursor(BYTES_PER_ROW as isize, size),
+            KeyCode::Home => self.move_cursor(isize::MIN / 2, size),
+            KeyCode::End => self.move_cursor(isize::MAX / 2, size),
+            KeyCode::PageUp | KeyCode::Char('p') => self.move_sector(-1)?,
+            KeyCode::PageDown | KeyCode::Char('n') => self.move_sector(1)?,
+            KeyCode::F(2) | KeyCode::Char('s') => {
+                if self.originals.is_empty() {
+                    self.message = "No changes.".to_string();
+                } else {
+                    self.mode = Mode::ConfirmSave;
+                }
+            }
+            KeyCode::F(10) | KeyCode::Char('q') | KeyCode::Esc => {
+                if self.originals.is_empty() {
+                    self.quit = true;
+                } else {
+                    self.mode = Mode::ConfirmQuit;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn save_key(&mut self, code: KeyCode) -> Result<()> {
+        self.mode = Mode::Edit;
+        if !matches!(code, KeyCode::Char('y') | KeyCode::Char('Y')) {
+            self.message = "Not saved.".to_string();
+            return Ok(());
+        }
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(&self.image_file)
+            .with_context(|| format!("Can't open image file {}", self.image_file.display()))?;
+        self.disk.save(&mut f)?;
+        self.message = format!(
+            "{} sector(s) written to {}",
+            self.originals.len(),
+            self.image_file.display()
+        );
+        self.originals.clear();
+        Ok(())
+    }
+
+    fn chs(&self) -> CHS {
+        self.sectors[self.current]
+    }
+
+    fn sector(&self) -> Result<&[u8]> {
+        self.disk.sector_as_slice(self.chs())
+    }
+
+    fn original(&self) -> Option<&[u8]> {
+        let chs = self.chs();
+        self.originals
+            .iter()
+            .find(|(c, _)| *c == chs)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    fn set_nibble(&mut self, value: u8) -> Result<()> {
+        let chs = self.chs();
+        if self.original().is_none() {
+            let data = self.sector()?.to_vec();
+            self.originals.push((chs, data));
+        }
+        let (cursor, low_nibble) = (self.cursor, self.low_nibble);
+        let sector = self.disk.sector_as_slice_mut(chs)?;
+        sector[cursor] = if low_nibble {
+            sector[cursor] & 0xF0 | value
+        } else {
+            sector[cursor] & 0x0F | value << 4
+        };
+        let size = sector.len();
+        if low_nibble {
+            self.move_cursor(1, size);
+        } else {
+            self.low_nibble = true;
+        }
+        Ok(())
+    }
+
+    fn move_cursor(&mut self, delta: isize, size: usize) {
+        self.cursor = (self.cursor as isize).saturating_add(delta).clamp(0, size as isize - 1) as usize;
+        self.low_nibble = false;
+    }
+
+    fn move_sector(&mut self, delta: isize) -> Result<()> {
+        self.current = (self.current as isize + delta).clamp(0, self.sectors.len() as isize - 1) as usize;
+        self.cursor = self.cursor.min(self.sector()?.len() - 1);
+        self.low_nibble = false;
+        Ok(())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status, keys] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(1), Constraint::Length(1)]).areas(frame.area());
+
+        let data = self.sector().unwrap_or_default();
+        let original = self.original();
+        let byte_style = |offset: usize| {
+            let mut style = Style::new();
+            if original.is_some_and(|o| o[offset] != data[offset]) {
+                style = style.yellow().bold();
+            }
+            if offset == self.cursor {
+                style = style.reversed();
+            }
+            style
+        };
+        let lines: Vec<Line> = data
+            .chunks(BYTES_PER_ROW)
+            .enumerate()
+            .map(|(row, bytes)| {
+                let start = row * BYTES_PER_ROW;
+                let mut spans = vec![Span::raw(format!("{:04X}  ", start))];
+                for (i, b) in bytes.iter().enumerate() {
+                    spans.push(Span::styled(format!("{:02X}", b), byte_style(start + i)));
+                    spans.push(Span::raw(" "));
+                }
+                spans.push(Span::raw(" |"));
+                for (i, &b) in bytes.iter().enumerate() {
+                    let c = if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    };
+                    spans.push(Span::styled(c.to_string(), byte_style(start + i)));
+                }
+                spans.push(Span::raw("|"));
+                Line::from(spans)
+            })
+            .collect();
+        // keep the cursor row visible
+        let cursor_row = self.cursor / BYTES_PER_ROW;
+        let visible = (main.height as usize).saturating_sub(2).max(1);
+        let scroll = (cursor_row + 1).saturating_sub(visible);
+        let title = format!(
+            " {} ({} of {}){} ",
+            self.chs(),
+            self.current + 1,
+            self.sectors.len(),
+            if original.is_some() { " modified" } else { "" }
+        );
+        frame.render_widget(
+            Paragraph::new(lines)
+                .scroll((scroll as u16, 0))
+                .block(Block::bordered().title(title)),
+            main,
+        );
+
+        let status_line = match self.mode {
+            Mode::ConfirmSave => format!(
+                "Write {} modified sector(s) to {}? (y/n)",
+                self.originals.len(),
+                self.image_file.display()
+            ),
+            Mode::ConfirmQuit => "Quit without saving the changes? (y/n)".to_string(),
+            Mode::Edit if !self.message.is_empty() => self.message.clone(),
+            Mode::Edit => format!(
+                "Offset 0x{:03X} ({}), {} sector(s) modified",
+                self.cursor,
+                self.cursor,
+                self.originals.len()
+            ),
+        };
+        frame.render_widget(Paragraph::new(status_line).reversed(), status);
+        frame.render_widget(Paragraph::new(KEYS_HELP).dim(), keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{App, Mode};
+    use crate::dsk::{DskImage, CHS};
+    use ratatui::backend::TestBackend;
+    use ratatui::crossterm::event::KeyCode;
+    use ratatui::Terminal;
+    use std::fs::File;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_edit() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_edit.dsk");
+        let disk = DskImage::format(2, 1, 512, &[1, 2, 3], 0x2A, 0xE5);
+        disk.save(&mut File::create(&path).unwrap()).unwrap();
+        let chs = |cylinder, sector| CHS {
+            cylinder,
+            head: 0,
+            sector,
+        };
+        assert!(App::new(
+            DskImage::format(2, 1, 512, &[1, 2, 3], 0x2A, 0xE5),
+            path.clone(),
+            chs(0, 4)
+        )
+        .is_err());
+
+        let mut app = App::new(disk, path.clone(), chs(0, 3)).unwrap();
+        "C3".chars().for_each(|c| app.handle_key(KeyCode::Char(c)));
+        app.handle_key(KeyCode::Down);
+        "0a".chars().for_each(|c| app.handle_key(KeyCode::Char(c)));
+        assert_eq!(app.cursor, 18);
+        // next sector is on the next cylinder
+        app.handle_key(KeyCode::PageDown);
+        assert_eq!(app.chs(), chs(1, 1));
+        app.handle_key(KeyCode::End);
+        "7".chars().for_each(|c| app.handle_key(KeyCode::Char(c)));
+
+        let mut terminal = Terminal::new(TestBackend::new(90, 40)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen = format!("{:?}", terminal.backend().buffer());
+        assert!(screen.contains("C1/H0/R1 (4 of 6) modified"));
+
+        // quitting asks first
+        app.handle_key(KeyCode::Char('q'));
+        assert!(app.mode == Mode::ConfirmQuit);
+        app.handle_key(KeyCode::Char('n'));
+        assert!(!app.quit);
+
+        app.handle_key(KeyCode::F(2));
+        app.handle_key(KeyCode::Char('y'));
+        assert!(app.originals.is_empty());
+        app.handle_key(KeyCode::Char('q'));
+        assert!(app.quit);
+
+        let disk = DskImage::load(&mut File::open(&path).unwrap()).unwrap();
+        let sector = disk.sector_as_slice(chs(0, 3)).unwrap();
+        assert_eq!((sector[0], sector[1], sector[16], sector[17]), (0xC3, 0xE5, 0xE5, 0x0A));
+        assert_eq!(disk.sector_as_slice(chs(1, 1)).unwrap()[511], 0x75);
+        assert_eq!(disk.sector_as_slice(chs(1, 2)).unwrap()[511], 0xE5);
+    }
+}
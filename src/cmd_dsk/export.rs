@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+use crate::cpm::{CpmFs, FileItem, LsMode, Timestamp};
+use crate::util::{safe_filename, thousands, unique_filename};
+
+#[derive(Args, Clone)]
+pub struct ExportArgs {
+    /// write a zip archive
+    #[arg(long, required_unless_present = "tar", conflicts_with = "tar")]
+    zip: Option<PathBuf>,
+    /// write a tar archive
+    #[arg(long)]
+    tar: Option<PathBuf>,
+    /// text mode (trim at ^Z) for all files
+    #[arg(short, long)]
+    text: bool,
+    /// text mode only for files with these extensions (comma separated, e.g. TXT,ASM,BAS)
+    #[arg(long, value_delimiter = ',')]
+    text_ext: Vec<String>,
+    /// don't report exported files
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+/// A file to be stored in the archive.
+struct Entry {
+    path: String,
+    data: Vec<u8>,
+    /// modification time from CP/M Plus date stamps
+    updated: Option<Timestamp>,
+}
+
+/// Exports all files into a zip or tar archive, as userN/NAME.EXT.
+pub fn export(fs: &CpmFs, args: ExportArgs) -> Result<()> {
+    let mut files = fs.list_files(LsMode::All)?;
+    files.sort_by(FileItem::listing_cmp);
+
+    let text_ext: Vec<String> = args.text_ext.iter().map(|e| e.to_ascii_uppercase()).collect();
+    let mut used_names: HashMap<u8, HashSet<String>> = HashMap::new();
+    let mut entries = Vec::with_capacity(files.len());
+    for f in &files {
+        let user = f.user.unwrap_or_default();
+        let name = safe_filename(&f.name).with_context(|| format!("Can't export {}", f.name))?;
+        let name = unique_filename(name, used_names.entry(user).or_default());
+        let mut data = Vec::with_capacity(f.size);
+        fs.read_file(f, &mut data, args.text || is_text(f, &text_ext))?;
+        let updated = fs.file_times(f)?.and_then(|t| t.updated);
+        entries.push(Entry {
+            path: format!("user{}/{}", user, name),
+            data,
+            updated,
+        });
+    }
+
+    let archive = args.zip.as_ref().or(args.tar.as_ref()).expect("Archive path required");
+    let file = File::create(archive).with_context(|| format!("Can't create {}", archive.display()))?;
+    if args.zip.is_some() {
+        write_zip(file, &entries)
+    } else {
+        write_tar(file, &entries)
+    }
+    .with_context(|| format!("Can't write {}", archive.display()))?;
+
+    if !args.quiet {
+        for e in &entries {
+            println!("{}, {} bytes", e.path, thousands(e.data.len()));
+        }
+        let bytes = entries.iter().map(|e| e.data.len()).sum();
+        println!(
+            "{} files exported to {}, {} bytes.",
+            entries.len(),
+            archive.display(),
+            thousands(bytes)
+        );
+    }
+    Ok(())
+}
+
+fn is_text(file: &FileItem, text_ext: &[String]) -> bool {
+    let ext = file.name.rsplit_once('.').map(|(_, ext)| ext).unwrap_or_default();
+    text_ext.iter().any(|e| e == ext)
+}
+
+fn write_zip(file: impl Write + Seek, entries: &[Entry]) -> Result<()> {
+    let mut zip = ZipWriter::new(file);
+    for e in entries {
+        let mut options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        if let Some(t) = e.updated {
+            let (y, m, d) = t.date();
+            if let Ok(time) = DateTime::from_date_and_time(y, m, d, t.hour, t.minute, 0) {
+                options = options.last_modified_time(time);
+            }
+        }
+        zip.start_file(e.path.as_str(), options)?;
+        zip.write_all(&e.data)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+fn write_tar(file: impl Write, entries: &[Entry]) -> Result<()> {
+    let mut tar = tar::Builder::new(file);
+    for e in entries {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(e.data.len() as u64);
+        header.set_mode(0o644);
+        if let Some(t) = e.updated {
+            let mtime = t.to_system_time().duration_since(UNIX_EPOCH).unwrap_or_default();
+            header.set_mtime(mtime.as_secs());
+        }
+        tar.append_data(&mut header, &e.path, e.data.as_slice())?;
+    }
+    tar.into_inner()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, ExportArgs};
+    use crate::cpm::{CpmVersion, FileId, FilenameMode};
+    use crate::profile::Profile;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_export() {
+        let mut fs = Profile::find("junior").unwrap().format(CpmVersion::V3).unwrap();
+        for (user, name, data) in [(0, "README.TXT", &b"hello\x1A"[..]), (3, "GAME.COM", &[0xC3; 200])] {
+            let id = FileId::new_with_filename(user, name, FilenameMode::Normalized).unwrap();
+            fs.write_file(&id, &mut &data[..], false).unwrap();
+        }
+
+        let out = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+        let args = |zip, tar| ExportArgs {
+            zip,
+            tar,
+            text: false,
+            text_ext: vec!["txt".to_string()],
+            quiet: true,
+        };
+        export(&fs, args(Some(out.join("out_export.zip")), None)).unwrap();
+        export(&fs, args(None, Some(out.join("out_export.tar")))).unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(out.join("out_export.zip")).unwrap()).unwrap();
+        let mut names: Vec<_> = zip.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, ["user0/README.TXT", "user3/GAME.COM"]);
+        let mut data = vec![];
+        zip.by_name("user0/README.TXT").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+
+        let mut tar = tar::Archive::new(File::open(out.join("out_export.tar")).unwrap());
+        let entries: Vec<_> = tar
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                (e.path().unwrap().display().to_string(), e.size())
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [("user0/README.TXT".to_string(), 5), ("user3/GAME.COM".to_string(), 256)]
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::cpm::{CpmFs, CpmVersion, FileItem, LsMode, Timestamp};
+use crate::error::{ErrorKind, Failure};
+use crate::util::{hex, safe_filename, thousands, unique_filename};
+
+const MANIFEST: &str = "manifest.json";
+const BOOT: &str = "boot.bin";
+
+#[derive(Args, Clone)]
+pub struct FsdumpArgs {
+    /// directory to dump to, created if missing
+    out_dir: PathBuf,
+    /// dump into a directory which isn't empty, overwriting files of the same names
+    #[arg(short, long)]
+    force: bool,
+    /// don't report dumped files
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+/// Everything about the image but the file contents. Holds nothing the image doesn't, so
+/// dumps of the same image are identical.
+#[derive(Serialize)]
+struct Manifest {
+    params: ManifestParams,
+    label: Option<String>,
+    /// system area (reserved tracks) contents, None if the format has no system area
+    boot: Option<ManifestBlob>,
+    files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize)]
+struct ManifestParams {
+    cylinders: u8,
+    sides: u8,
+    sectors_per_track: u8,
+    first_sector_id: u8,
+    sector_size: u16,
+    reserved_tracks: u8,
+    block_size: usize,
+    dir_blocks: u8,
+    blocks: u16,
+    dir_entries: usize,
+    /// "2.2" or "3"
+    cpm_version: &'static str,
+    /// Disk Parameter Block as the BIOS has it, hex
+    dpb: String,
+}
+
+#[derive(Serialize)]
+struct ManifestBlob {
+    /// path relative to the manifest
+    path: String,
+    size: usize,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct ManifestFile {
+    user: u8,
+    name: String,
+    #[serde(flatten)]
+    data: ManifestBlob,
+    read_only: bool,
+    system: bool,
+    archived: bool,
+    created: Option<String>,
+    updated: Option<String>,
+    extents: Vec<ManifestExtent>,
+}
+
+#[derive(Serialize)]
+struct ManifestExtent {
+    /// directory slot of the entry
+    slot: usize,
+    extent: u16,
+    records: u8,
+    blocks: Vec<u16>,
+}
+
+/// Extracts all the files (binary, exact sizes) as userN/NAME.EXT, the system area as boot.bin,
+/// and writes a JSON manifest of the disk parameters and the files' metadata and block layout.
+pub fn fsdump(fs: &CpmFs, args: FsdumpArgs) -> Result<()> {
+    let out_dir = &args.out_dir;
+    let is_empty = match std::fs::read_dir(out_dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(_) => true,
+    };
+    if !is_empty && !args.force {
+        bail!(Failure::new(
+            ErrorKind::Exists,
+            format!("{} is not empty, use --force to dump into it anyway", out_dir.display())
+        ));
+    }
+    std::fs::create_dir_all(out_dir).with_context(|| format!("Can't create {}", out_dir.display()))?;
+
+    let boot = fs.read_system_area()?;
+    let boot = match boot.is_empty() {
+        true => None,
+        false => Some(write_blob(out_dir, BOOT.to_string(), &boot)?),
+    };
+
+    let mut files = fs.list_files(LsMode::All)?;
+    files.sort_by(FileItem::listing_cmp);
+    let mut used_names: HashMap<u8, HashSet<String>> = HashMap::new();
+    let mut manifest_files = Vec::with_capacity(files.len());
+    for f in &files {
+        let user = f.user.unwrap_or_default();
+        let name = safe_filename(&f.name).with_context(|| format!("Can't dump {}", f.name))?;
+        let name = unique_filename(name, used_names.entry(user).or_default());
+        std::fs::create_dir_all(out_dir.join(format!("user{}", user)))?;
+
+        let mut data = Vec::with_capacity(f.size);
+        fs.read_file(f, &mut data, false)
+            .with_context(|| format!("Can't read {}:{}", user, f.name))?;
+        let times = fs.file_times(f)?.unwrap_or_default();
+        let stamp = |t: Option<Timestamp>| t.map(|t| t.to_string());
+        manifest_files.push(ManifestFile {
+            user,
+            name: f.name.clone(),
+            data: write_blob(out_dir, format!("user{}/{}", user, name), &data)?,
+            read_only: f.read_only,
+            system: f.system_file,
+            archived: f.archived,
+            created: stamp(times.created),
+            updated: stamp(times.updated),
+            extents: fs
+                .file_extents(f)
+                .into_iter()
+                .map(|e| ManifestExtent {
+                    slot: e.slot,
+                    extent: e.extent,
+                    records: e.record_count,
+                    blocks: e.blocks,
+                })
+                .collect(),
+        });
+        if !args.quiet {
+            println!(
+                "{}:{} -> user{}/{}, {} bytes",
+                user,
+                f.name,
+                user,
+                name,
+                thousands(data.len())
+            );
+        }
+    }
+
+    let manifest = Manifest {
+        params: manifest_params(fs),
+        label: fs.label(),
+        boot,
+        files: manifest_files,
+    };
+    let json = serde_json::to_string_pretty(&manifest)? + "\n";
+    let path = out_dir.join(MANIFEST);
+    std::fs::write(&path, json).with_context(|| format!("Can't write {}", path.display()))?;
+    if !args.quiet {
+        println!("{} files dumped to {}.", files.len(), out_dir.display());
+    }
+    Ok(())
+}
+
+fn manifest_params(fs: &CpmFs) -> ManifestParams {
+    let params = fs.params();
+    ManifestParams {
+        cylinders: fs.disk().num_cylinders(),
+        sides: fs.disk().num_sides(),
+        sectors_per_track: params.sectors_per_track,
+        first_sector_id: params.first_sector_id,
+        sector_size: params.sector_size,
+        reserved_tracks: params.reserved_tracks,
+        block_size: params.block_size(),
+        dir_blocks: params.dir_blocks,
+        blocks: fs.num_blocks(),
+        dir_entries: params.dir_entries(),
+        cpm_version: match params.version {
+            CpmVersion::V22 => "2.2",
+            CpmVersion::V3 => "3",
+        },
+        dpb: hex(&fs.dpb().to_bytes(params.version)),
+    }
+}
+
+/// Writes the data to the path (relative to the output directory), returns its manifest entry.
+fn write_blob(out_dir: &Path, path: String, data: &[u8]) -> Result<ManifestBlob> {
+    let local = out_dir.join(&path);
+    std::fs::write(&local, data).with_context(|| format!("Can't write {}", local.display()))?;
+    Ok(ManifestBlob {
+        path,
+        size: data.len(),
+        sha256: hex(&Sha256::digest(data)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fsdump, FsdumpArgs};
+    use crate::cpm::{CpmFs, CpmVersion};
+    use crate::profile::Profile;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_fsdump() {
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V3);
+        let fs = CpmFs::load(&mut File::open("tests/03.dsk").unwrap(), params).unwrap();
+        let out_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_fsdump");
+        let _ = fs::remove_dir_all(&out_dir);
+        let args = |force| FsdumpArgs {
+            out_dir: out_dir.clone(),
+            force,
+            quiet: true,
+        };
+        fsdump(&fs, args(false)).unwrap();
+        let manifest = fs::read_to_string(out_dir.join("manifest.json")).unwrap();
+
+        // dumping again needs --force, and gives the same manifest
+        assert!(fsdump(&fs, args(false)).is_err());
+        fsdump(&fs, args(true)).unwrap();
+        assert_eq!(fs::read_to_string(out_dir.join("manifest.json")).unwrap(), manifest);
+
+        let json: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(json["params"]["block_size"], 2048);
+        assert_eq!(json["boot"]["size"], 2 * 9 * 512);
+        let files = json["files"].as_array().unwrap();
+        assert_eq!(files.len(), 64);
+        let bdos = files.iter().find(|f| f["name"] == "BDOS.MAC").unwrap();
+        let path = out_dir.join(bdos["path"].as_str().unwrap());
+        assert_eq!(fs::metadata(path).unwrap().len(), bdos["size"].as_u64().unwrap());
+        assert!(!bdos["extents"][0]["blocks"].as_array().unwrap().is_empty());
+    }
+}
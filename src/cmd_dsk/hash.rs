@@ -0,0 +1,90 @@
+use anyhow::Result;
+use clap::Args;
+use sha2::{Digest, Sha256};
+
+use super::load_disk;
+use crate::dsk::{DskImage, SectorInfo};
+use crate::util::hex;
+
+#[derive(Args, Clone)]
+pub struct HashArgs {
+    /// print a checksum of every track too
+    #[arg(long)]
+    per_track: bool,
+}
+
+/// SHA-256 digests of the tracks (cylinder, head, digest) and of the whole image.
+type Digests = (Vec<(u8, u8, Vec<u8>)>, Vec<u8>);
+
+/// Prints SHA-256 of the sector data, ignoring container metadata and interleave.
+pub fn hash(image_file: &str, args: HashArgs) -> Result<()> {
+    let disk = load_disk(image_file)?;
+
+    let (tracks, image) = digests(&disk)?;
+    if args.per_track {
+        for (cylinder, head, digest) in tracks {
+            println!("{}  C{}/H{}", hex(&digest), cylinder, head);
+        }
+    }
+    println!("{}  {}", hex(&image), image_file);
+    Ok(())
+}
+
+/// Hashes sectors of every track in the logical order (sorted by sector ID).
+fn digests(disk: &DskImage) -> Result<Digests> {
+    let mut tracks = vec![];
+    let mut image = Sha256::new();
+    for t in disk.tracks() {
+        let mut track = Sha256::new();
+        let mut sectors: Vec<(&SectorInfo, &[u8])> = t.sector_data().collect();
+        sectors.sort_unstable_by_key(|(info, _)| info.sector_id);
+        for (_, data) in sectors {
+            track.update(data);
+            image.update(data);
+        }
+        tracks.push((t.cylinder(), t.head(), track.finalize().to_vec()));
+    }
+    Ok((tracks, image.finalize().to_vec()))
+}
+
+/// Returns SHA-256 of the image's sector data, as printed by `hash`.
+pub fn image_digest(disk: &DskImage) -> Result<Vec<u8>> {
+    Ok(digests(disk)?.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::digests;
+    use crate::dsk::{DskImage, CHS};
+
+    #[test]
+    fn test_digests_ignore_layout() {
+        let mut a = DskImage::format(2, 2, 512, &[1, 6, 2, 7, 3, 8, 4, 9, 5], 0x2A, 0xE5);
+        let mut b = DskImage::format(2, 2, 512, &[1, 2, 3, 4, 5, 6, 7, 8, 9], 0x4E, 0xE5);
+        for disk in [&mut a, &mut b] {
+            let chs = CHS {
+                cylinder: 1,
+                head: 0,
+                sector: 3,
+            };
+            disk.sector_as_slice_mut(chs).unwrap()[0] = 0x42;
+        }
+        let (tracks_a, image_a) = digests(&a).unwrap();
+        let (tracks_b, image_b) = digests(&b).unwrap();
+        assert_eq!(tracks_a, tracks_b);
+        assert_eq!(image_a, image_b);
+        assert_eq!(tracks_a.len(), 4);
+        assert_ne!(tracks_a[0].2, tracks_a[2].2);
+
+        b.sector_as_slice_mut(CHS {
+            cylinder: 0,
+            head: 1,
+            sector: 9,
+        })
+        .unwrap()[511] = 0;
+        let (tracks_b, image_b) = digests(&b).unwrap();
+        assert_ne!(image_a, image_b);
+        assert_eq!(tracks_a[0], tracks_b[0]);
+        assert_ne!(tracks_a[1], tracks_b[1]);
+    }
+}
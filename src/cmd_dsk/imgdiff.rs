@@ -0,0 +1,185 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::fs::File;
+
+use super::{read_stdin_image, report_warnings, STDIO};
+use crate::color::{paint, Style};
+use crate::dsk::{DskImage, CHS};
+use crate::error::{ErrorKind, Failure};
+
+#[derive(Args, Clone)]
+pub struct ImgdiffArgs {
+    /// only print the summary
+    #[arg(short, long)]
+    quiet: bool,
+    /// the image to compare with
+    other_image: String,
+}
+
+/// Difference found between two images.
+#[derive(Debug, PartialEq)]
+enum Difference {
+    /// track present only in one of the images (true for the first one)
+    Track { cylinder: u8, head: u8, in_first: bool },
+    /// sector present only in one of the images
+    Sector { chs: CHS, in_first: bool },
+    /// sector data differs: first and last differing offsets, number of differing bytes
+    Data {
+        chs: CHS,
+        first: usize,
+        last: usize,
+        count: usize,
+    },
+}
+
+pub fn imgdiff(image_file: &str, args: ImgdiffArgs) -> Result<()> {
+    let a = load(image_file)?;
+    let b = load(&args.other_image)?;
+
+    let diffs = compare(&a, &b);
+    if !args.quiet {
+        for d in &diffs {
+            match d {
+                Difference::Track {
+                    cylinder,
+                    head,
+                    in_first,
+                } => println!("track C{}/H{} only in {}", cylinder, head, which(*in_first)),
+                Difference::Sector { chs, in_first } => println!("{}  only in {}", chs, which(*in_first)),
+                Difference::Data {
+                    chs,
+                    first,
+                    last,
+                    count,
+                } => println!(
+                    "{}  0x{:03X}-0x{:03X}, {} bytes differ",
+                    chs,
+                    first,
+                    last,
+                    paint(count, Style::Mismatch)
+                ),
+            }
+        }
+    }
+
+    if diffs.is_empty() {
+        println!("Images are identical (sector data).");
+        return Ok(());
+    }
+    bail!(Failure::new(
+        ErrorKind::Differ,
+        format!("Images differ: {} difference(s).", diffs.len())
+    ))
+}
+
+fn load(image_file: &str) -> Result<DskImage> {
+    let disk = if image_file == STDIO {
+        DskImage::load(&mut read_stdin_image()?)
+    } else {
+        let mut f = File::open(image_file).with_context(|| format!("Can't open image file {}", image_file))?;
+        DskImage::load(&mut f)
+    };
+    let disk = disk.context(Failure::new(
+        ErrorKind::Filesystem,
+        format!("Error loading image file {}", image_file),
+    ))?;
+    report_warnings(image_file, &disk);
+    Ok(disk)
+}
+
+fn which(in_first: bool) -> &'static str {
+    if in_first {
+        "the first image"
+    } else {
+        "the second image"
+    }
+}
+
+/// Compares sector data of both images, track by track, ignoring the container metadata.
+fn compare(a: &DskImage, b: &DskImage) -> Vec<Difference> {
+    let mut diffs = vec![];
+    for cylinder in 0..a.num_cylinders().max(b.num_cylinders()) {
+        for head in 0..a.num_sides().max(b.num_sides()) {
+            let (ids_a, ids_b) = match (a.sector_ids(cylinder, head), b.sector_ids(cylinder, head)) {
+                (Ok(ids_a), Ok(ids_b)) => (ids_a, ids_b),
+                (ids_a, _) => {
+                    let in_first = ids_a.is_ok();
+                    diffs.push(Difference::Track {
+                        cylinder,
+                        head,
+                        in_first,
+                    });
+                    continue;
+                }
+            };
+
+            let chs = |sector| CHS { cylinder, head, sector };
+            // sectors in the physical order of the first image, then the ones found only in the second one
+            for &id in ids_a.iter().chain(ids_b.iter().filter(|id| !ids_a.contains(id))) {
+                let (data_a, data_b) = match (a.sector_as_slice(chs(id)), b.sector_as_slice(chs(id))) {
+                    (Ok(data_a), Ok(data_b)) => (data_a, data_b),
+                    (data_a, _) => {
+                        let in_first = data_a.is_ok();
+                        diffs.push(Difference::Sector { chs: chs(id), in_first });
+                        continue;
+                    }
+                };
+
+                let len = data_a.len().max(data_b.len());
+                let differing: Vec<usize> = (0..len).filter(|&i| data_a.get(i) != data_b.get(i)).collect();
+                if let (Some(&first), Some(&last)) = (differing.first(), differing.last()) {
+                    diffs.push(Difference::Data {
+                        chs: chs(id),
+                        first,
+                        last,
+                        count: differing.len(),
+                    });
+                }
+            }
+        }
+    }
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, Difference};
+    use crate::dsk::{DskImage, CHS};
+
+    #[test]
+    fn test_compare() {
+        let ids = [1, 6, 2, 7, 3, 8, 4, 9, 5];
+        let a = DskImage::format(40, 1, 512, &ids, 0x2A, 0xE5);
+        let mut b = DskImage::format(40, 1, 512, &ids, 0x4E, 0xE5);
+        // container metadata (GAP#3) doesn't matter
+        assert!(compare(&a, &b).is_empty());
+
+        let chs = || CHS {
+            cylinder: 3,
+            head: 0,
+            sector: 7,
+        };
+        let sector = b.sector_as_slice_mut(chs()).unwrap();
+        sector[0x10] = 0;
+        sector[0x1F0] = 0;
+        assert_eq!(
+            compare(&a, &b),
+            vec![Difference::Data {
+                chs: chs(),
+                first: 0x10,
+                last: 0x1F0,
+                count: 2
+            }]
+        );
+
+        let c = DskImage::format(41, 1, 512, &ids, 0x2A, 0xE5);
+        assert_eq!(
+            compare(&a, &c),
+            vec![Difference::Track {
+                cylinder: 40,
+                head: 0,
+                in_first: false
+            }]
+        );
+    }
+}
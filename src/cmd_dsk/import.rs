@@ -0,0 +1,187 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use super::{print_dry_run_summary, resolve_conflict, OnConflict};
+use crate::cpm::{CpmFs, MAX_USER_ID};
+use crate::error::{error_kind, ErrorKind};
+use crate::file_arg::DEFAULT_USER;
+use crate::retro_fs::RetroFs;
+use crate::util::thousands;
+
+#[derive(Args, Clone)]
+pub struct ImportArgs {
+    /// user number for files not in a userN/ directory (default 0)
+    #[arg(short, long)]
+    user: Option<u8>,
+    /// text mode (terminate with ^Z) for all files
+    #[arg(short, long)]
+    text: bool,
+    /// text mode only for files with these extensions (comma separated, e.g. TXT,ASM,BAS)
+    #[arg(long, value_delimiter = ',')]
+    text_ext: Vec<String>,
+    /// only show what would be imported, don't modify the image
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+    /// what to do if a file already exists on the image (ask is not supported)
+    #[arg(long, value_enum, default_value_t = OnConflict::Fail)]
+    on_conflict: OnConflict,
+    /// don't report imported files
+    #[arg(short, long)]
+    quiet: bool,
+    /// zip or tar archive
+    archive: PathBuf,
+}
+
+/// Imports all files of a zip or tar archive. Files in userN/ directories go to user N,
+/// names are converted to 8.3 if needed. Files that don't fit are reported and skipped.
+pub fn import(fs: &mut CpmFs, args: ImportArgs) -> Result<()> {
+    if args.on_conflict == OnConflict::Ask {
+        bail!("--on-conflict ask is not supported by import");
+    }
+    let entries = read_archive(&args.archive).with_context(|| format!("Can't read {}", args.archive.display()))?;
+    let text_ext: Vec<String> = args.text_ext.iter().map(|e| e.to_ascii_uppercase()).collect();
+
+    let (mut files, mut bytes, mut renamed, mut skipped) = (0, 0, 0, vec![]);
+    for (path, data) in &entries {
+        let (user, name) = cpm_name(path, args.user.unwrap_or(DEFAULT_USER))?;
+        let id = fs
+            .file_id(user, &name)
+            .with_context(|| format!("Can't import {}", path))?;
+        let Some(id) = resolve_conflict(fs, id.clone(), args.on_conflict)? else {
+            skipped.push(format!("{} ({} already exists)", path, id));
+            continue;
+        };
+
+        let text = args.text || text_ext.iter().any(|e| name.ends_with(&format!(".{}", e)));
+        match fs.write(&id, &mut data.as_slice(), text) {
+            Ok(_) => {}
+            Err(e) if error_kind(&e) == ErrorKind::DiskFull => {
+                skipped.push(format!("{} ({:#})", path, e));
+                continue;
+            }
+            Err(e) => return Err(e.context(format!("Can't import {}", path))),
+        }
+
+        let dst = id.to_string();
+        let original = path.rsplit(['/', '\\']).next().unwrap_or_default();
+        let was_renamed = original.to_ascii_uppercase() != id.name.trim_end_matches('.');
+        renamed += was_renamed as usize;
+        files += 1;
+        bytes += data.len();
+        if !args.quiet {
+            println!(
+                "{}{} -> {}, {} bytes{}",
+                if args.dry_run { "Would import " } else { "" },
+                path,
+                dst,
+                thousands(data.len()),
+                if was_renamed { " (renamed)" } else { "" }
+            );
+        }
+    }
+
+    for s in &skipped {
+        eprintln!("Skipped {}", s);
+    }
+    if args.dry_run {
+        print_dry_run_summary(fs);
+    } else if !args.quiet {
+        println!(
+            "{} files imported ({} renamed, {} skipped), {} bytes.",
+            files,
+            renamed,
+            skipped.len(),
+            thousands(bytes)
+        );
+    }
+    Ok(())
+}
+
+/// Reads all regular files of a zip or tar archive (told apart by the zip signature).
+fn read_archive(path: &Path) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    let is_zip = file.read_exact(&mut magic).is_ok() && magic[0..2] == *b"PK";
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut entries = vec![];
+    if is_zip {
+        let mut zip = zip::ZipArchive::new(file)?;
+        for idx in 0..zip.len() {
+            let mut entry = zip.by_index(idx)?;
+            if entry.is_file() {
+                let mut data = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut data)?;
+                entries.push((entry.name().to_string(), data));
+            }
+        }
+    } else {
+        let mut tar = tar::Archive::new(file);
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type().is_file() {
+                let path = entry.path()?.to_string_lossy().to_string();
+                let mut data = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut data)?;
+                entries.push((path, data));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Maps an archive path to a user and an 8.3 name. The last userN/ directory selects the user,
+/// characters not allowed by CP/M are replaced with '_', name and extension are truncated.
+fn cpm_name(path: &str, default_user: u8) -> Result<(u8, String)> {
+    let mut components: Vec<&str> = path.split(['/', '\\']).filter(|c| !c.is_empty()).collect();
+    let file_name = components.pop().with_context(|| format!("Invalid path {}", path))?;
+    let user = components
+        .iter()
+        .rev()
+        .find_map(|c| c.to_ascii_lowercase().strip_prefix("user")?.parse::<u8>().ok())
+        .unwrap_or(default_user);
+    if user > MAX_USER_ID {
+        bail!("Invalid user {} in {}", user, path);
+    }
+
+    let convert = |s: &str, len: usize| -> String {
+        s.chars()
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "!#$%&'()-@^_{}~".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .take(len)
+            .collect()
+    };
+    let (name, ext) = file_name.rsplit_once('.').unwrap_or((file_name, ""));
+    let (name, ext) = if name.is_empty() { (ext, "") } else { (name, ext) };
+    Ok((user, format!("{}.{}", convert(name, 8), convert(ext, 3))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cpm_name;
+
+    #[test]
+    fn test_cpm_name() {
+        assert_eq!(cpm_name("user3/GAME.COM", 0).unwrap(), (3, "GAME.COM".to_string()));
+        assert_eq!(
+            cpm_name("disk/USER12/x/read me.text", 0).unwrap(),
+            (12, "READ_ME.TEX".to_string())
+        );
+        assert_eq!(
+            cpm_name("verylongname.tar.gz", 2).unwrap(),
+            (2, "VERYLONG.GZ".to_string())
+        );
+        assert_eq!(cpm_name("Makefile", 0).unwrap(), (0, "MAKEFILE.".to_string()));
+        assert_eq!(cpm_name(".profile", 0).unwrap(), (0, "PROFILE.".to_string()));
+        assert!(cpm_name("user16/A.COM", 0).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::Result;
+
+use super::boot::system_status;
+use crate::cpm::CpmFs;
+use crate::util::thousands;
+
+pub fn info(fs: &CpmFs) -> Result<()> {
+    let params = fs.params();
+    let disk = fs.disk();
+    let (dir_slots, free_slots) = fs.dir_slots();
+    let free_blocks = fs.free_blocks();
+
+    println!("Label:        {}", fs.label().unwrap_or_else(|| "-".to_string()));
+    println!(
+        "Geometry:     {} cylinders, {} side(s), {} sectors of {} bytes per track",
+        disk.num_cylinders(),
+        disk.num_sides(),
+        params.sectors_per_track,
+        params.sector_size
+    );
+    println!(
+        "System area:  {} track(s), {} bytes",
+        params.reserved_tracks,
+        fs.system_area_size()
+    );
+    let system = fs.read_system_area()?;
+    if system.is_empty() {
+        println!("Bootable:     no (no system area)");
+    } else {
+        let status = system_status(&system, fs.sector_size());
+        println!(
+            "Bootable:     {}{}, {} of {} bytes of the system area used",
+            if status.bootable { "yes" } else { "no" },
+            status.system.map(|s| format!(" ({})", s)).unwrap_or_default(),
+            thousands(status.used),
+            thousands(system.len())
+        );
+    }
+    println!("Block size:   {} bytes", fs.block_size());
+    println!(
+        "Blocks:       {} total ({} directory), {} free ({} bytes)",
+        fs.num_blocks(),
+        params.dir_blocks,
+        free_blocks,
+        free_blocks * fs.block_size()
+    );
+    println!("Directory:    {} entries, {} free", dir_slots, free_slots);
+    let dpb = fs.dpb();
+    println!(
+        "DPB:          SPT {}, BSH {}, BLM {}, EXM {}, DSM {}, DRM {}, AL0 {:#04x}, AL1 {:#04x}, CKS {}, OFF {}",
+        dpb.spt,
+        dpb.bsh,
+        dpb.blm,
+        dpb.exm,
+        dpb.dsm,
+        dpb.drm,
+        dpb.al >> 8,
+        dpb.al & 0xFF,
+        dpb.cks,
+        dpb.off
+    );
+
+    let Some(dsk) = disk.as_dsk() else {
+        println!("Container:    raw sector dump");
+        return Ok(());
+    };
+    let extended_tracks = dsk.tracks().filter(|t| t.extension().iter().any(|&b| b != 0)).count();
+    let header_extended = dsk.header_extension().iter().any(|&b| b != 0);
+    if extended_tracks > 0 || header_extended || !dsk.trailer().is_empty() {
+        println!(
+            "Extensions:   {} bytes after the tracks, {} track(s) with extension data{}",
+            thousands(dsk.trailer().len()),
+            extended_tracks,
+            if header_extended { ", header extension data" } else { "" }
+        );
+    }
+    Ok(())
+}
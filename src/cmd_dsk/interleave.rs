@@ -0,0 +1,175 @@
+use anyhow::Result;
+use clap::Args;
+use std::collections::HashMap;
+
+use super::load_disk;
+use crate::dsk::DskImage;
+
+#[derive(Args, Clone)]
+pub struct InterleaveArgs {
+    /// only list tracks that differ from the most common layout, or are irregular
+    #[arg(short, long)]
+    brief: bool,
+}
+
+/// Sector layout of a single track.
+struct TrackLayout {
+    cylinder: u8,
+    head: u8,
+    /// sector IDs in the physical order
+    ids: Vec<u8>,
+    /// physical distance between logically consecutive sectors, None if not regular
+    interleave: Option<usize>,
+    /// rotation of the lowest sector ID relative to the previous track
+    skew: Option<usize>,
+    issues: Vec<String>,
+}
+
+/// Reports the sector ID order of every track: interleave, skew between tracks, and
+/// tracks that don't follow the pattern of the others.
+pub fn interleave(image_file: &str, args: InterleaveArgs) -> Result<()> {
+    let disk = load_disk(image_file)?;
+    let tracks = analyze(&disk)?;
+
+    for t in tracks.iter().filter(|t| !args.brief || !t.issues.is_empty()) {
+        let ids = t.ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(" ");
+        println!(
+            "C{}/H{}  {:2} sectors  interleave {:>2}  skew {:>2}  [{}]{}",
+            t.cylinder,
+            t.head,
+            t.ids.len(),
+            opt_str(t.interleave),
+            opt_str(t.skew),
+            ids,
+            if t.issues.is_empty() {
+                String::new()
+            } else {
+                format!("  ! {}", t.issues.join(", "))
+            }
+        );
+    }
+
+    if let Some(common) = most_common(&tracks, |t| t.ids.clone()) {
+        let interleave = most_common(&tracks, |t| t.interleave).flatten();
+        let skew = most_common(&tracks, |t| t.skew).flatten();
+        println!(
+            "Most tracks: {} sectors, order [{}], interleave {}, skew {}.",
+            common.len(),
+            common.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(" "),
+            opt_str(interleave),
+            opt_str(skew)
+        );
+    }
+    let irregular = tracks.iter().filter(|t| !t.issues.is_empty()).count();
+    println!("{} of {} tracks irregular.", irregular, tracks.len());
+    Ok(())
+}
+
+fn analyze(disk: &DskImage) -> Result<Vec<TrackLayout>> {
+    let mut tracks: Vec<TrackLayout> = vec![];
+    for t in disk.tracks() {
+        let ids: Vec<u8> = t.sectors().iter().map(|s| s.sector_id).collect();
+        let skew = tracks.last().and_then(|prev| skew(&prev.ids, &ids));
+        tracks.push(TrackLayout {
+            cylinder: t.cylinder(),
+            head: t.head(),
+            interleave: interleave_factor(&ids),
+            skew,
+            ids,
+            issues: vec![],
+        });
+    }
+
+    let common_count = most_common(&tracks, |t| t.ids.len());
+    let common_interleave = most_common(&tracks, |t| t.interleave);
+    for t in tracks.iter_mut() {
+        let mut sorted = t.ids.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        if t.ids.is_empty() {
+            t.issues.push("no sectors".to_string());
+            continue;
+        }
+        if sorted.len() != t.ids.len() {
+            t.issues.push("duplicate sector IDs".to_string());
+        }
+        if (sorted[sorted.len() - 1] - sorted[0]) as usize + 1 != sorted.len() {
+            t.issues.push("sector IDs not contiguous".to_string());
+        }
+        if t.interleave.is_none() {
+            t.issues.push("irregular interleave".to_string());
+        } else if Some(t.interleave) != common_interleave {
+            t.issues.push("interleave differs from most tracks".to_string());
+        }
+        if Some(t.ids.len()) != common_count {
+            t.issues.push("sector count differs from most tracks".to_string());
+        }
+    }
+    Ok(tracks)
+}
+
+/// Returns the physical distance between sectors with consecutive IDs, if it's the same
+/// for all of them (1 means no interleave). When the factor divides the sector count, the
+/// layout moves one position further on every wrap, so such steps are accepted too.
+fn interleave_factor(ids: &[u8]) -> Option<usize> {
+    let n = ids.len();
+    let mut positions: Vec<(u8, usize)> = ids.iter().enumerate().map(|(pos, &id)| (id, pos)).collect();
+    positions.sort_unstable();
+    let mut distances = positions.windows(2).map(|w| (w[1].1 + n - w[0].1) % n);
+    let first = distances.next()?;
+    distances.all(|d| d == first || d == first + 1).then_some(first)
+}
+
+/// Returns how many positions the lowest sector ID moved relative to the previous track.
+fn skew(prev: &[u8], ids: &[u8]) -> Option<usize> {
+    if prev.len() != ids.len() || ids.is_empty() {
+        return None;
+    }
+    let lowest = |ids: &[u8]| ids.iter().enumerate().min_by_key(|(_, &id)| id).map(|(pos, _)| pos);
+    Some((lowest(ids)? + ids.len() - lowest(prev)?) % ids.len())
+}
+
+/// Returns the most frequent value of the key (the first one on ties).
+fn most_common<K: Eq + std::hash::Hash + Clone>(tracks: &[TrackLayout], key: impl Fn(&TrackLayout) -> K) -> Option<K> {
+    let mut counts: HashMap<K, usize> = HashMap::new();
+    for t in tracks {
+        *counts.entry(key(t)).or_default() += 1;
+    }
+    let max = counts.values().copied().max()?;
+    tracks.iter().map(&key).find(|k| counts[k] == max)
+}
+
+fn opt_str(value: Option<usize>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, interleave_factor, skew};
+    use crate::dsk::DskImage;
+
+    #[test]
+    fn test_interleave_factor() {
+        assert_eq!(interleave_factor(&[1, 2, 3, 4, 5, 6, 7, 8, 9]), Some(1));
+        assert_eq!(interleave_factor(&[1, 6, 2, 7, 3, 8, 4, 9, 5]), Some(2));
+        assert_eq!(
+            interleave_factor(&[0xC1, 0xC4, 0xC7, 0xC2, 0xC5, 0xC8, 0xC3, 0xC6, 0xC9]),
+            Some(3)
+        );
+        assert_eq!(interleave_factor(&[1, 3, 2, 4]), Some(2));
+        assert_eq!(interleave_factor(&[1, 2, 4, 3]), None);
+        assert_eq!(interleave_factor(&[]), None);
+
+        assert_eq!(skew(&[1, 2, 3, 4], &[3, 4, 1, 2]), Some(2));
+        assert_eq!(skew(&[1, 2, 3], &[1, 2]), None);
+    }
+
+    #[test]
+    fn test_analyze() {
+        let disk = DskImage::format(2, 2, 512, &[1, 6, 2, 7, 3, 8, 4, 9, 5], 0x2A, 0xE5);
+        let tracks = analyze(&disk).unwrap();
+        assert_eq!(tracks.len(), 4);
+        assert!(tracks.iter().all(|t| t.interleave == Some(2) && t.issues.is_empty()));
+        assert_eq!(tracks[1].skew, Some(0));
+    }
+}
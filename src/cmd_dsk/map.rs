@@ -0,0 +1,147 @@
+use anyhow::Result;
+use clap::Args;
+
+use super::find_file;
+use crate::cpm::{CpmFs, FileItem};
+use crate::dsk::CHS;
+use crate::file_arg::DEFAULT_USER;
+use crate::util::thousands;
+
+const BLOCKS_PER_ROW: u16 = 32;
+
+#[derive(Args, Clone)]
+pub struct MapArgs {
+    /// user number of the file (default 0)
+    #[arg(short, long)]
+    user: Option<u8>,
+    /// highlight blocks of this file
+    #[arg(long, conflicts_with = "file")]
+    highlight: Option<String>,
+    /// show extents, blocks and sectors of this file instead of the map
+    file: Option<String>,
+}
+
+pub fn map(fs: &CpmFs, args: MapArgs) -> Result<()> {
+    let user = args.user.unwrap_or(DEFAULT_USER);
+    if let Some(name) = &args.file {
+        return map_file(fs, &find_file(fs, user, name)?);
+    }
+    let highlighted = match &args.highlight {
+        Some(name) => find_file(fs, user, name)?.block_list,
+        None => vec![],
+    };
+
+    let dir_blocks = fs.params().dir_blocks as u16;
+    let mut counts = [0usize; 4];
+    for row_start in (0..fs.num_blocks()).step_by(BLOCKS_PER_ROW as usize) {
+        let row_end = (row_start + BLOCKS_PER_ROW).min(fs.num_blocks());
+        let row: String = (row_start..row_end)
+            .map(|b| {
+                let (c, idx) = if fs.block_is_bad(b) {
+                    ('X', 3)
+                } else if b < dir_blocks {
+                    ('D', 0)
+                } else if fs.block_is_used(b) {
+                    ('#', 1)
+                } else {
+                    ('.', 2)
+                };
+                counts[idx] += 1;
+                if highlighted.contains(&b) {
+                    '*'
+                } else {
+                    c
+                }
+            })
+            .collect();
+        println!("{:4}  {}", row_start, row);
+    }
+
+    println!();
+    println!(
+        "D directory ({}), # used ({}), . free ({}), X bad ({})",
+        counts[0], counts[1], counts[2], counts[3]
+    );
+    if let Some(name) = &args.highlight {
+        println!("* {} ({} blocks)", name.to_ascii_uppercase(), highlighted.len());
+    }
+    Ok(())
+}
+
+/// Lists the file's extents and their blocks, with the sectors each block is stored in.
+fn map_file(fs: &CpmFs, file: &FileItem) -> Result<()> {
+    let extents = fs.file_extents(file);
+    println!(
+        "{}:{}, {} bytes, {} blocks in {} extents",
+        file.user.unwrap_or_default(),
+        file.name,
+        thousands(file.size),
+        file.block_list.len(),
+        extents.len()
+    );
+    for e in &extents {
+        println!();
+        println!(
+            "Extent {} (directory slot {}, {} records)",
+            e.extent, e.slot, e.record_count
+        );
+        for &block in &e.blocks {
+            let sectors = fs.block_chs(block);
+            let bad: Vec<String> = sectors
+                .iter()
+                .filter(|&&chs| !fs.disk().sector_ok(chs))
+                .map(|chs| chs.to_string())
+                .collect();
+            println!(
+                "  block {:5}  {}{}",
+                block,
+                sector_runs(&sectors),
+                if bad.is_empty() {
+                    String::new()
+                } else {
+                    format!("  ! bad: {}", bad.join(", "))
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Formats sector addresses, joining consecutive sectors of a track into ranges (C2/H0/R1-4).
+fn sector_runs(sectors: &[CHS]) -> String {
+    let mut runs: Vec<(CHS, u8)> = vec![];
+    for &chs in sectors {
+        match runs.last_mut() {
+            Some((first, last))
+                if first.cylinder == chs.cylinder && first.head == chs.head && *last + 1 == chs.sector =>
+            {
+                *last = chs.sector
+            }
+            _ => runs.push((chs, chs.sector)),
+        }
+    }
+    runs.iter()
+        .map(|(first, last)| match first.sector == *last {
+            true => first.to_string(),
+            false => format!("{}-{}", first, last),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sector_runs;
+    use crate::dsk::CHS;
+
+    #[test]
+    fn test_sector_runs() {
+        let chs = |cylinder, head, sector| CHS { cylinder, head, sector };
+        assert_eq!(
+            sector_runs(&[chs(2, 0, 7), chs(2, 0, 8), chs(2, 0, 9), chs(2, 1, 1)]),
+            "C2/H0/R7-9, C2/H1/R1"
+        );
+        assert_eq!(sector_runs(&[chs(0, 0, 3), chs(0, 0, 5)]), "C0/H0/R3, C0/H0/R5");
+        assert_eq!(sector_runs(&[]), "");
+    }
+}
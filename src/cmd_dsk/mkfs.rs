@@ -0,0 +1,130 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::cpm::{CpmFs, CpmVersion};
+use crate::error::{ErrorKind, Failure};
+use crate::profile::Profile;
+use crate::util::parse_number;
+
+#[derive(Args, Clone)]
+pub struct MkfsArgs {
+    /// local file with the system binary, written to the system area (reserved tracks)
+    #[arg(short, long)]
+    boot: Option<PathBuf>,
+    /// write the Disk Parameter Block at this offset of the boot sector (the first sector of the
+    /// system area)
+    #[arg(long, value_parser = parse_number)]
+    dpb_offset: Option<usize>,
+    /// disk label
+    #[arg(short, long)]
+    label: Option<String>,
+    /// overwrite the image file if it exists
+    #[arg(short, long)]
+    force: bool,
+}
+
+/// Creates a new, empty image file.
+pub fn mkfs(image_file: &str, profile: &Profile, version: CpmVersion, args: MkfsArgs) -> Result<()> {
+    check_overwrite(image_file, args.force)?;
+    let mut fs = new_fs(
+        profile,
+        version,
+        args.boot.as_deref(),
+        args.dpb_offset,
+        args.label.as_deref(),
+    )?;
+    save_new_image(&mut fs, image_file)
+}
+
+/// Formats a new filesystem, with optional system binary, DPB copy and label.
+pub fn new_fs(
+    profile: &Profile,
+    version: CpmVersion,
+    boot: Option<&Path>,
+    dpb_offset: Option<usize>,
+    label: Option<&str>,
+) -> Result<CpmFs> {
+    let mut fs = profile.format(version)?;
+
+    let mut system = fs.read_system_area()?;
+    if let Some(boot) = boot {
+        let mut data = Vec::new();
+        File::open(boot)
+            .with_context(|| format!("Can't open {}", boot.display()))?
+            .read_to_end(&mut data)?;
+        if data.len() > system.len() {
+            bail!(
+                "System image too large: {} bytes, {} available",
+                data.len(),
+                system.len()
+            );
+        }
+        system[0..data.len()].copy_from_slice(&data);
+    }
+    if let Some(offset) = dpb_offset {
+        let dpb = fs.dpb().to_bytes(version);
+        // the BIOS finds it in the boot sector, the first one of the system area
+        let boot_sector = system.len().min(fs.params().sector_size as usize);
+        let Some(end) = offset.checked_add(dpb.len()).filter(|&end| end <= boot_sector) else {
+            bail!(Failure::new(
+                ErrorKind::Usage,
+                format!(
+                    "DPB at 0x{:04X} ({} bytes) exceeds the boot sector ({} bytes)",
+                    offset,
+                    dpb.len(),
+                    boot_sector
+                )
+            ));
+        };
+        system[offset..end].copy_from_slice(&dpb);
+    }
+    fs.write_system_area(&system)?;
+
+    if let Some(label) = label {
+        fs.set_label(Some(label))?;
+    }
+    Ok(fs)
+}
+
+/// Fails if the image file exists, unless it's to be overwritten.
+pub fn check_overwrite(image_file: &str, force: bool) -> Result<()> {
+    if !force && Path::new(image_file).exists() {
+        bail!(Failure::new(
+            ErrorKind::Exists,
+            format!("{} already exists, use --force to overwrite it", image_file)
+        ));
+    }
+    Ok(())
+}
+
+pub fn save_new_image(fs: &mut CpmFs, image_file: &str) -> Result<()> {
+    let mut file = File::create(image_file).with_context(|| format!("Can't create image file {}", image_file))?;
+    fs.save(&mut file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::new_fs;
+    use crate::cpm::CpmVersion;
+    use crate::error::{error_kind, ErrorKind};
+    use crate::profile::Profile;
+
+    #[test]
+    fn test_dpb_offset() {
+        let profile = Profile::find("junior").unwrap();
+        let fs = new_fs(profile, CpmVersion::V22, None, Some(0x80), None).unwrap();
+        let dpb = fs.dpb().to_bytes(CpmVersion::V22);
+        assert_eq!(fs.read_system_area().unwrap()[0x80..0x80 + dpb.len()], dpb);
+
+        // past the 512 bytes boot sector, or overflowing
+        for offset in [512 - dpb.len() + 1, usize::MAX] {
+            let Err(err) = new_fs(profile, CpmVersion::V22, None, Some(offset), None) else {
+                panic!("DPB at 0x{:X} accepted", offset);
+            };
+            assert_eq!(error_kind(&err), ErrorKind::Usage);
+        }
+    }
+}
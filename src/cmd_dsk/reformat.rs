@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use clap::Args;
+
+use super::mkfs::{check_overwrite, save_new_image};
+use crate::cpm::{CpmFs, FileId, FilenameMode, LsMode};
+use crate::profile::Profile;
+
+#[derive(Args, Clone)]
+pub struct ReformatArgs {
+    /// target disk format
+    #[arg(long)]
+    to: String,
+    /// overwrite the output image if it exists
+    #[arg(short, long)]
+    force: bool,
+    /// output image file
+    out_file: String,
+}
+
+/// Creates a new image of the target format, copying the system area, the label and all the files
+/// with their R/O, SYS and ARC flags. Time stamps can't be copied, the new image has none.
+pub fn reformat(fs: &CpmFs, args: ReformatArgs) -> Result<()> {
+    check_overwrite(&args.out_file, args.force)?;
+    let profile = Profile::find(&args.to)?;
+    let mut out = profile.format(fs.params().version)?;
+
+    // system area content is format specific, but copy it if it fits
+    let system = fs.read_system_area()?;
+    if system.len() <= out.system_area_size() {
+        out.write_system_area(&system)?;
+    } else {
+        eprintln!(
+            "Warning: system area not copied, {} bytes don't fit in {} bytes.",
+            system.len(),
+            out.system_area_size()
+        );
+    }
+
+    if let Some(label) = fs.label() {
+        out.set_label(Some(&label))?;
+    }
+
+    let files = fs.list_files(LsMode::All)?;
+    let mut stamped = 0;
+    for f in &files {
+        let user = f.user.unwrap_or_default();
+        let mut data = Vec::with_capacity(f.size);
+        fs.read_file(f, &mut data, false)?;
+        let id = FileId::new_with_filename(user, &f.name, FilenameMode::AsIs)?;
+        out.write_file(&id, &mut data.as_slice(), false)
+            .with_context(|| format!("Can't copy {}:{} to the new image", user, f.name))?;
+        out.copy_flags(&id, f)?;
+        if fs
+            .file_times(f)?
+            .is_some_and(|t| t.created.is_some() || t.updated.is_some())
+        {
+            stamped += 1;
+        }
+    }
+    if stamped > 0 {
+        eprintln!("Warning: time stamps of {} files not copied.", stamped);
+    }
+
+    save_new_image(&mut out, &args.out_file)?;
+    println!(
+        "{} files copied to {} ({}), {} blocks free.",
+        files.len(),
+        args.out_file,
+        profile.name,
+        out.free_blocks()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reformat, ReformatArgs};
+    use crate::cpm::{CpmFs, CpmVersion, FileId, FilenameMode, LsMode};
+    use crate::profile::Profile;
+    use std::fs::File;
+
+    #[test]
+    fn test_reformat_flags() {
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V22);
+        let mut fs = CpmFs::load(&mut File::open("tests/03.dsk").unwrap(), params).unwrap();
+        let mut flagged = fs.list_files(LsMode::OwnedBy(0)).unwrap().remove(0);
+        (flagged.read_only, flagged.system_file, flagged.archived) = (true, true, true);
+        let id = FileId::new_with_filename(0, &flagged.name, FilenameMode::AsIs).unwrap();
+        fs.copy_flags(&id, &flagged).unwrap();
+
+        let out_file = "tests/out_reformat.dsk";
+        let args = ReformatArgs {
+            to: "pcw720".to_string(),
+            force: true,
+            out_file: out_file.to_string(),
+        };
+        reformat(&fs, args).unwrap();
+
+        let params = Profile::find("pcw720").unwrap().params(CpmVersion::V22);
+        let out = CpmFs::load(&mut File::open(out_file).unwrap(), params).unwrap();
+        let flags = |fs: &CpmFs| -> Vec<(String, String)> {
+            let mut files = fs.list_files(LsMode::All).unwrap();
+            files.sort_by(|a, b| a.listing_cmp(b));
+            files.iter().map(|f| (f.name.clone(), f.flags())).collect()
+        };
+        assert_eq!(flags(&out), flags(&fs));
+        assert!(flags(&out).contains(&(flagged.name, "RSA".to_string())));
+    }
+}
@@ -0,0 +1,280 @@
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+
+use crate::cpm::{CpmFs, FileItem, LsMode};
+use crate::dsk::{DskImage, CHS};
+use crate::error::{ErrorKind, Failure};
+
+/// uPD765 ST1 bits, as stored in the sector info.
+const ST1_FLAGS: [(u8, &str); 6] = [
+    (0x80, "end of cylinder"),
+    (0x20, "CRC error"),
+    (0x10, "overrun"),
+    (0x04, "no data"),
+    (0x02, "write protected"),
+    (0x01, "missing address mark"),
+];
+
+/// uPD765 ST2 bits, as stored in the sector info.
+const ST2_FLAGS: [(u8, &str); 5] = [
+    (0x40, "deleted data mark"),
+    (0x20, "CRC error in data field"),
+    (0x10, "wrong cylinder"),
+    (0x02, "bad cylinder"),
+    (0x01, "missing data address mark"),
+];
+
+/// Sector the disk couldn't be read from properly when imaged.
+struct BadSector {
+    chs: CHS,
+    /// position of the sector's data in the image file, None for missing sectors
+    offset: Option<usize>,
+    problem: Problem,
+}
+
+#[derive(Debug, PartialEq)]
+enum Problem {
+    /// FDC status registers with error bits set
+    Fdc { st1: u8, st2: u8 },
+    /// less data stored than the sector size (none for zero-length sectors)
+    Short { length: u16, size: u16 },
+    /// sector of the format not on the track
+    Missing,
+}
+
+impl Problem {
+    /// Character of the sector in the track map.
+    fn symbol(&self) -> char {
+        match self {
+            Problem::Fdc { .. } => 'X',
+            Problem::Short { .. } => 's',
+            Problem::Missing => '-',
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Problem::Fdc { st1, st2 } => {
+                let flags: Vec<&str> = ST1_FLAGS
+                    .iter()
+                    .filter(|(bit, _)| st1 & bit != 0)
+                    .chain(ST2_FLAGS.iter().filter(|(bit, _)| st2 & bit != 0))
+                    .map(|(_, name)| *name)
+                    .collect();
+                format!("{} (ST1 {:#04x}, ST2 {:#04x})", flags.join(", "), st1, st2)
+            }
+            Problem::Short { length: 0, .. } => "no data stored".to_string(),
+            Problem::Short { length, size } => format!("{} of {} bytes stored", length, size),
+            Problem::Missing => "missing".to_string(),
+        }
+    }
+}
+
+/// Checks every sector of the image for read errors the imaging recorded, prints a map of the
+/// tracks and the bad sectors with the files stored in them.
+pub fn scan(fs: &CpmFs) -> Result<()> {
+    let Some(dsk) = fs.disk().as_dsk() else {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            "Raw sector dumps don't record read errors, only DSK images can be scanned."
+        ));
+    };
+    let bad = bad_sectors(dsk, fs);
+
+    // a row per cylinder, the sides next to each other, sectors in the ID order
+    let mut rows: Vec<Vec<String>> = vec![];
+    for t in dsk.tracks() {
+        let (cylinder, head) = (t.cylinder(), t.head());
+        let ids: BTreeSet<u8> = t
+            .sectors()
+            .iter()
+            .map(|s| s.sector_id)
+            .chain(expected_ids(fs))
+            .collect();
+        let track: String = ids
+            .into_iter()
+            .map(|sector| {
+                let chs = CHS { cylinder, head, sector };
+                bad.iter().find(|b| b.chs == chs).map_or('.', |b| b.problem.symbol())
+            })
+            .collect();
+        match head {
+            0 => rows.push(vec![track]),
+            _ => rows.last_mut().unwrap().push(track),
+        }
+    }
+    for (cylinder, row) in rows.iter().enumerate() {
+        println!("{:4}  {}", cylinder, row.join("  "));
+    }
+    println!();
+    println!(". ok, X FDC error, s short, - missing");
+    println!();
+
+    if bad.is_empty() {
+        println!("No bad sectors found.");
+        return Ok(());
+    }
+    let files = fs.list_files(LsMode::All)?;
+    let mut damaged: Vec<(&FileItem, Vec<u16>)> = vec![];
+    println!("Bad sectors:");
+    for b in &bad {
+        let offset = b.offset.map_or(String::new(), |o| format!(" at {:#x}", o));
+        println!(
+            "  {}{}: {}, {}",
+            b.chs,
+            offset,
+            b.problem.describe(),
+            contents(fs, &files, b.chs)
+        );
+
+        let Some(block) = fs.chs_block(b.chs) else {
+            continue;
+        };
+        for f in files.iter().filter(|f| f.block_list.contains(&block)) {
+            match damaged.iter_mut().find(|(d, _)| std::ptr::eq(*d, f)) {
+                Some((_, blocks)) if !blocks.contains(&block) => blocks.push(block),
+                Some(_) => {}
+                None => damaged.push((f, vec![block])),
+            }
+        }
+    }
+
+    println!();
+    if damaged.is_empty() {
+        println!("No files are damaged.");
+    } else {
+        println!("Damaged files:");
+        for (f, blocks) in &damaged {
+            let blocks: Vec<String> = blocks.iter().map(|b| b.to_string()).collect();
+            println!(
+                "  {}:{}  {} of {} blocks ({})",
+                f.user.unwrap_or_default(),
+                f.name,
+                blocks.len(),
+                f.block_list.len(),
+                blocks.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sector IDs every track of the format has.
+fn expected_ids(fs: &CpmFs) -> impl Iterator<Item = u8> {
+    let (first, count) = (fs.params().first_sector_id, fs.params().sectors_per_track);
+    (0..count).map(move |i| first + i)
+}
+
+/// Walks the sectors of all tracks in the ID order, collecting the ones with FDC errors, short
+/// ones and the sectors of the format missing.
+fn bad_sectors(dsk: &DskImage, fs: &CpmFs) -> Vec<BadSector> {
+    let mut bad = vec![];
+    for t in dsk.tracks() {
+        let (cylinder, head) = (t.cylinder(), t.head());
+        let mut sectors: Vec<_> = t.sectors().iter().collect();
+        sectors.sort_unstable_by_key(|s| s.sector_id);
+        for s in sectors {
+            let chs = CHS {
+                cylinder,
+                head,
+                sector: s.sector_id,
+            };
+            let problem = if s.fdc_st1 != 0 || s.fdc_st2 != 0 {
+                Problem::Fdc {
+                    st1: s.fdc_st1,
+                    st2: s.fdc_st2,
+                }
+            } else if s.actual_data_length < s.sector_size {
+                Problem::Short {
+                    length: s.actual_data_length,
+                    size: s.sector_size,
+                }
+            } else {
+                continue;
+            };
+            bad.push(BadSector {
+                chs,
+                offset: dsk.sector_offset(chs),
+                problem,
+            });
+        }
+        for sector in expected_ids(fs) {
+            let chs = CHS { cylinder, head, sector };
+            if dsk.sector_info(chs).is_none() {
+                bad.push(BadSector {
+                    chs,
+                    offset: None,
+                    problem: Problem::Missing,
+                });
+            }
+        }
+    }
+    bad
+}
+
+/// Describes what the sector holds: the system tracks, the directory, files or free space.
+fn contents(fs: &CpmFs, files: &[FileItem], chs: CHS) -> String {
+    let Some(block) = fs.chs_block(chs) else {
+        let track = chs.cylinder as usize * fs.disk().num_sides() as usize + chs.head as usize;
+        return match track < fs.params().reserved_tracks as usize {
+            true => "system track".to_string(),
+            false => "outside the filesystem".to_string(),
+        };
+    };
+    if block < fs.params().dir_blocks as u16 {
+        return format!("block {} (directory)", block);
+    }
+    let names: Vec<String> = files
+        .iter()
+        .filter(|f| f.block_list.contains(&block))
+        .map(|f| format!("{}:{}", f.user.unwrap_or_default(), f.name))
+        .collect();
+    if names.is_empty() {
+        format!("block {} (free)", block)
+    } else {
+        format!("block {} ({})", block, names.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bad_sectors, Problem};
+    use crate::cpm::{CpmFs, CpmVersion};
+    use crate::dsk::CHS;
+    use crate::profile::Profile;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_bad_sectors() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/03.dsk");
+        let mut data = std::fs::read(path).unwrap();
+        // sector info list of the track C3/H1: CRC error in the first sector, nothing stored
+        // for the second one
+        let info = 0x8600 + 0x18;
+        data[info + 4] = 0x20;
+        data[info + 5] = 0x20;
+        data[info + 8 + 6..info + 8 + 8].copy_from_slice(&[0, 0]);
+
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V3);
+        let fs = CpmFs::load(&mut Cursor::new(data), params).unwrap();
+        let bad = bad_sectors(fs.disk().as_dsk().unwrap(), &fs);
+        assert_eq!(bad.len(), 2);
+        assert_eq!(
+            bad[0].chs,
+            CHS {
+                cylinder: 3,
+                head: 1,
+                sector: 1
+            }
+        );
+        assert_eq!(bad[0].problem, Problem::Fdc { st1: 0x20, st2: 0x20 });
+        assert_eq!(
+            bad[0].problem.describe(),
+            "CRC error, CRC error in data field (ST1 0x20, ST2 0x20)"
+        );
+        assert_eq!(bad[0].offset, Some(0x8700));
+        assert_eq!(bad[1].problem, Problem::Short { length: 0, size: 512 });
+        assert!(fs.chs_block(bad[0].chs).is_some());
+    }
+}
@@ -0,0 +1,110 @@
+use anyhow::{bail, Context, Result};
+use clap::{Args, Subcommand};
+use std::io;
+use std::time::Duration;
+
+use crate::cpm::{CpmFs, FileId, FilenameMode, LsMode};
+use crate::error::{ErrorKind, Failure};
+use crate::file_arg::{FileArg, DEFAULT_USER};
+use crate::util::thousands;
+use crate::xmodem::{self, Protocol};
+
+#[derive(Args, Clone)]
+pub struct SerialArgs {
+    /// serial port, e.g. /dev/ttyUSB0 or COM1
+    #[arg(long)]
+    port: String,
+    /// baud rate
+    #[arg(long, default_value_t = 9600)]
+    baud: u32,
+    /// transfer protocol
+    #[arg(long, value_enum, default_value_t = Protocol::Xmodem)]
+    protocol: Protocol,
+    #[command(subcommand)]
+    pub command: SerialCommands,
+}
+
+#[derive(Subcommand, Clone)]
+pub enum SerialCommands {
+    /// Send a file from the image
+    Send {
+        /// file on the image (:NAME.EXT or N:NAME.EXT)
+        file: FileArg,
+    },
+    /// Receive a file onto the image
+    Receive {
+        /// destination on the image (:NAME.EXT or N:NAME.EXT); with YMODEM it may be just
+        /// a user area (: or N:), the sender's file name is used then
+        file: FileArg,
+    },
+}
+
+impl SerialArgs {
+    pub fn modifies_image(&self) -> bool {
+        matches!(self.command, SerialCommands::Receive { .. })
+    }
+}
+
+pub fn serial(fs: &mut CpmFs, args: SerialArgs) -> Result<()> {
+    let mut port = serialport::new(&args.port, args.baud)
+        .timeout(Duration::from_secs(1))
+        .open()
+        .map_err(io::Error::from)
+        .with_context(|| format!("Can't open serial port {}", args.port))?;
+
+    match &args.command {
+        SerialCommands::Send { file } => {
+            let FileArg::Image {
+                owner,
+                name: Some(name),
+            } = file
+            else {
+                bail!("File to send must be on the image (:NAME.EXT or N:NAME.EXT).");
+            };
+            let user = owner.unwrap_or(DEFAULT_USER);
+            let name = name.to_ascii_uppercase();
+            let Some(f) = fs
+                .list_files(LsMode::OwnedBy(user))?
+                .into_iter()
+                .find(|f| f.name == name)
+            else {
+                bail!(Failure::new(
+                    ErrorKind::NoMatch,
+                    format!("File {}:{} not found.", user, name)
+                ));
+            };
+            let mut data = Vec::with_capacity(f.size);
+            fs.read_file(&f, &mut data, false)?;
+
+            println!(
+                "Sending {}:{} ({} bytes), start the receiver...",
+                user,
+                f.name,
+                thousands(data.len())
+            );
+            xmodem::send(&mut port, args.protocol, &f.name, &data)?;
+            println!("Done.");
+        }
+        SerialCommands::Receive { file } => {
+            let FileArg::Image { owner, name } = file else {
+                bail!("Destination must be on the image (:NAME.EXT, : or N:).");
+            };
+            if name.is_none() && args.protocol == Protocol::Xmodem {
+                bail!("XMODEM doesn't transfer file names, destination must include one (:NAME.EXT).");
+            }
+
+            println!("Waiting for the sender...");
+            let (sent_name, data) = xmodem::receive(&mut port, args.protocol)?;
+            let name = name.clone().or(sent_name).context("No file name received")?;
+            let id = FileId::new_with_filename(owner.unwrap_or(DEFAULT_USER), &name, FilenameMode::Normalized)?;
+            fs.write_file(&id, &mut data.as_slice(), false)?;
+            println!(
+                "Received {}:{} ({} bytes).",
+                id.user,
+                id.filename(),
+                thousands(data.len())
+            );
+        }
+    }
+    Ok(())
+}
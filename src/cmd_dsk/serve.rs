@@ -0,0 +1,339 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use serde_json::json;
+use std::fs::File;
+use std::io::Read;
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::cpm::{CpmFs, FileId, FileItem, FilenameMode, LsMode, MAX_USER_ID};
+use crate::error::{error_kind, ErrorKind, Failure};
+use crate::util::html_escape;
+
+#[derive(Args, Clone)]
+pub struct ServeArgs {
+    /// TCP port to listen on
+    #[arg(long, default_value_t = 8080)]
+    port: u16,
+    /// address to listen on, use 0.0.0.0 to make the image available to other machines
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
+    /// don't allow uploading and deleting files
+    #[arg(long)]
+    pub read_only: bool,
+}
+
+/// HTTP response to be sent, and whether the image was modified by the request.
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    modified: bool,
+}
+
+impl Reply {
+    fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+            modified: false,
+        }
+    }
+
+    fn json(value: serde_json::Value) -> Self {
+        Self::new(200, "application/json", value.to_string())
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::new(status, "application/json", json!({ "error": message }).to_string())
+    }
+
+    fn modified(self, status: u16) -> Self {
+        Self {
+            status,
+            modified: true,
+            ..self
+        }
+    }
+}
+
+/// Serves the image over HTTP: a JSON API under /api and an HTML index at /.
+/// Modifications are saved to the image file right away.
+pub fn serve(fs: &mut CpmFs, image: &mut File, args: ServeArgs) -> Result<()> {
+    let addr = format!("{}:{}", args.bind, args.port);
+    let server = Server::http(&addr).map_err(|e| anyhow!("Can't listen on {}: {}", addr, e))?;
+    println!("Serving on http://{}/ (Ctrl-C to stop)", addr);
+
+    for mut request in server.incoming_requests() {
+        let (method, url) = (request.method().clone(), request.url().to_string());
+        let reply = handle(fs, &method, &url, request.as_reader(), args.read_only);
+        if reply.modified {
+            fs.save(image).context("Error saving image file")?;
+        }
+        eprintln!("{} {} {}", method, url, reply.status);
+
+        let header = Header::from_bytes("Content-Type", reply.content_type).expect("Invalid header");
+        let response = Response::from_data(reply.body)
+            .with_status_code(reply.status)
+            .with_header(header);
+        if let Err(e) = request.respond(response) {
+            eprintln!("Error sending response: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn handle(fs: &mut CpmFs, method: &Method, url: &str, body: &mut dyn Read, read_only: bool) -> Reply {
+    route(fs, method, url, body, read_only).unwrap_or_else(|e| {
+        let status = match error_kind(&e) {
+            ErrorKind::NoMatch => 404,
+            ErrorKind::Exists => 409,
+            ErrorKind::DiskFull => 507,
+            ErrorKind::Usage => 400,
+            _ => 500,
+        };
+        Reply::error(status, &format!("{:#}", e))
+    })
+}
+
+fn route(fs: &mut CpmFs, method: &Method, url: &str, mut body: &mut dyn Read, read_only: bool) -> Result<Reply> {
+    let path = url.split('?').next().unwrap_or_default();
+    let segments = path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect::<Result<Vec<_>>>()?;
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    let reply = match (method, segments.as_slice()) {
+        (Method::Get, [""]) => Reply::new(200, "text/html; charset=utf-8", index(fs, read_only)?),
+        (Method::Get, ["api", "info"]) => {
+            let (dir_entries, free_dents) = fs.dir_slots();
+            Reply::json(json!({
+                "label": fs.label(),
+                "block_size": fs.block_size(),
+                "blocks": fs.num_blocks(),
+                "free_blocks": fs.free_blocks(),
+                "dir_entries": dir_entries,
+                "free_dir_entries": free_dents,
+            }))
+        }
+        (Method::Get, ["api", "files"]) => {
+            let files: Vec<_> = sorted_files(fs)?
+                .iter()
+                .map(|f| json!({ "user": f.user, "name": f.name, "size": f.size, "blocks": f.block_list.len() }))
+                .collect();
+            Reply::json(json!(files))
+        }
+        (Method::Get, ["api", "files", user, name]) => {
+            let file = find_file(fs, user, name)?;
+            let mut data = Vec::with_capacity(file.size);
+            fs.read_file(&file, &mut data, false)?;
+            Reply::new(200, "application/octet-stream", data)
+        }
+        (Method::Put | Method::Delete, ["api", "files", _, _]) if read_only => {
+            Reply::error(403, "Image is served read-only")
+        }
+        (Method::Put, ["api", "files", user, name]) => {
+            let id = FileId::new_with_filename(parse_user(user)?, name, FilenameMode::Normalized)
+                .context(Failure::new(ErrorKind::Usage, format!("Invalid file name {}", name)))?;
+            // the body goes straight to the disk, which takes no more of it than there's room for
+            fs.write_file(&id, &mut body, false)?;
+            let file = find_file(fs, user, &id.filename())?;
+            Reply::json(json!({ "user": id.user, "name": file.name, "size": file.size })).modified(201)
+        }
+        (Method::Delete, ["api", "files", user, name]) => {
+            let file = find_file(fs, user, name)?;
+            fs.delete_file(&file)?;
+            Reply::new(204, "application/json", "").modified(204)
+        }
+        (_, ["api", "files", ..]) | (_, [""]) => Reply::error(405, "Method not allowed"),
+        _ => Reply::error(404, "Not found"),
+    };
+    Ok(reply)
+}
+
+fn sorted_files(fs: &CpmFs) -> Result<Vec<FileItem>> {
+    let mut files = fs.list_files(LsMode::All)?;
+    files.sort_by(FileItem::listing_cmp);
+    Ok(files)
+}
+
+fn parse_user(user: &str) -> Result<u8> {
+    match user.parse::<u8>() {
+        Ok(user) if user <= MAX_USER_ID => Ok(user),
+        _ => bail!(Failure::new(ErrorKind::Usage, format!("Invalid user {}", user))),
+    }
+}
+
+fn find_file(fs: &CpmFs, user: &str, name: &str) -> Result<FileItem> {
+    let user = parse_user(user)?;
+    let name = name.to_ascii_uppercase();
+    match fs
+        .list_files(LsMode::OwnedBy(user))?
+        .into_iter()
+        .find(|f| f.name == name)
+    {
+        Some(file) => Ok(file),
+        None => bail!(Failure::new(
+            ErrorKind::NoMatch,
+            format!("File {}:{} not found", user, name)
+        )),
+    }
+}
+
+fn index(fs: &CpmFs, read_only: bool) -> Result<String> {
+    let mut rows = String::new();
+    for f in sorted_files(fs)? {
+        let user = f.user.unwrap_or_default();
+        let url = format!("/api/files/{}/{}", user, percent_encode(&f.name));
+        let delete = if read_only {
+            String::new()
+        } else {
+            format!("<button onclick=\"del('{}')\">delete</button>", url)
+        };
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            user,
+            url,
+            html_escape(&f.name),
+            f.size,
+            delete
+        ));
+    }
+
+    let title = html_escape(&fs.label().unwrap_or_else(|| "Disk image".to_string()));
+    let upload = if read_only { "" } else { UPLOAD_FORM };
+    Ok(format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head><body>\n\
+         <h1>{title}</h1>\n<p>{} blocks free ({} bytes)</p>\n\
+         <table>\n<tr><th>User</th><th>Name</th><th>Size</th><th></th></tr>\n{rows}</table>\n{upload}</body></html>\n",
+        fs.free_blocks(),
+        fs.free_blocks() * fs.block_size(),
+    ))
+}
+
+const UPLOAD_FORM: &str = r#"<form id="upload"><input type="file" id="file"> user <input id="user" value="0" size="2"> <button>upload</button></form>
+<script>
+async function check(r) { if (!r.ok) alert((await r.json()).error); location.reload(); }
+async function del(url) { if (confirm('Delete ' + decodeURIComponent(url) + '?')) check(await fetch(url, {method: 'DELETE'})); }
+document.getElementById('upload').onsubmit = async (e) => {
+  e.preventDefault();
+  const f = document.getElementById('file').files[0];
+  if (f) check(await fetch('/api/files/' + document.getElementById('user').value + '/' + encodeURIComponent(f.name), {method: 'PUT', body: f}));
+};
+</script>
+"#;
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+/// Decodes %XX escapes, a malformed escape or a name that isn't UTF-8 is the client's error.
+fn percent_decode(s: &str) -> Result<String> {
+    let invalid = || Failure::new(ErrorKind::Usage, format!("Invalid URL escape in {}", s));
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'%' {
+            let hex = s
+                .get(idx + 1..idx + 3)
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .ok_or_else(invalid)?;
+            out.push(u8::from_str_radix(hex, 16)?);
+            idx += 3;
+        } else {
+            out.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    Ok(String::from_utf8(out).map_err(|_| invalid())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::handle;
+    use crate::cpm::CpmVersion;
+    use crate::profile::Profile;
+    use std::io::Read;
+    use tiny_http::Method;
+
+    #[test]
+    fn test_api() {
+        let mut fs = Profile::find("junior").unwrap().format(CpmVersion::V3).unwrap();
+        let mut body: &[u8] = b"hello";
+        let reply = handle(&mut fs, &Method::Put, "/api/files/1/%24hi.txt", &mut body, false);
+        assert_eq!((reply.status, reply.modified), (201, true));
+        assert_eq!(
+            String::from_utf8(reply.body).unwrap(),
+            r#"{"name":"$HI.TXT","size":128,"user":1}"#
+        );
+
+        let reply = handle(&mut fs, &Method::Get, "/api/files", &mut &b""[..], false);
+        assert_eq!(
+            String::from_utf8(reply.body).unwrap(),
+            r#"[{"blocks":1,"name":"$HI.TXT","size":128,"user":1}]"#
+        );
+        let reply = handle(&mut fs, &Method::Get, "/api/files/1/$hi.txt", &mut &b""[..], false);
+        assert_eq!(reply.body[0..5], *b"hello");
+        let reply = handle(&mut fs, &Method::Get, "/", &mut &b""[..], false);
+        assert!(String::from_utf8(reply.body)
+            .unwrap()
+            .contains("/api/files/1/%24HI.TXT"));
+
+        // errors
+        let mut body: &[u8] = b"again";
+        assert_eq!(
+            handle(&mut fs, &Method::Put, "/api/files/1/$HI.TXT", &mut body, false).status,
+            409
+        );
+        assert_eq!(
+            handle(&mut fs, &Method::Get, "/api/files/2/$HI.TXT", &mut &b""[..], false).status,
+            404
+        );
+        assert_eq!(
+            handle(&mut fs, &Method::Get, "/api/files/16/X", &mut &b""[..], false).status,
+            400
+        );
+        assert_eq!(
+            handle(&mut fs, &Method::Delete, "/api/files/1/$HI.TXT", &mut &b""[..], true).status,
+            403
+        );
+        assert_eq!(
+            handle(&mut fs, &Method::Get, "/nothing", &mut &b""[..], false).status,
+            404
+        );
+        for url in [
+            "/api/files/1/%ZZ",
+            "/api/files/1/X%2",
+            "/api/files/1/%+1",
+            "/api/files/1/%FF",
+        ] {
+            assert_eq!(
+                handle(&mut fs, &Method::Get, url, &mut &b""[..], false).status,
+                400,
+                "{}",
+                url
+            );
+        }
+
+        // larger than the free space: the disk is left as it was
+        let mut body = std::io::repeat(0xE5).take(400 * 2048);
+        let reply = handle(&mut fs, &Method::Put, "/api/files/1/BIG", &mut body, false);
+        assert_eq!((reply.status, reply.modified), (507, false));
+        assert_eq!(fs.free_blocks(), 350);
+
+        let reply = handle(&mut fs, &Method::Delete, "/api/files/1/$HI.TXT", &mut &b""[..], false);
+        assert_eq!((reply.status, reply.modified), (204, true));
+        assert_eq!(fs.free_blocks(), 351);
+    }
+}
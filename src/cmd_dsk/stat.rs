@@ -0,0 +1,59 @@
+use anyhow::Result;
+use clap::Args;
+
+use super::dir::decode_slot;
+use super::find_file;
+use crate::cpm::{CpmFs, FileItem, Timestamp};
+use crate::file_arg::DEFAULT_USER;
+use crate::util::{hexdump, thousands};
+
+#[derive(Args, Clone)]
+pub struct StatArgs {
+    /// user number of the file (default 0)
+    #[arg(short, long)]
+    user: Option<u8>,
+    /// hexdump the raw directory entries of the file, one per extent, with their slot numbers
+    #[arg(long)]
+    raw: bool,
+    /// file to show
+    file: String,
+}
+
+/// Shows the details of a file: size, blocks, extents, flags and time stamps.
+pub fn stat(fs: &CpmFs, args: StatArgs) -> Result<()> {
+    let file = find_file(fs, args.user.unwrap_or(DEFAULT_USER), &args.file)?;
+    let extents = fs.file_extents(&file);
+    println!("{}:{}", file.user.unwrap_or_default(), file.name);
+    println!(
+        "  Size:    {} bytes ({} stored)",
+        thousands(file.size),
+        thousands(fs.stored_size(&file))
+    );
+    println!("  Blocks:  {} in {} extents", file.block_list.len(), extents.len());
+    println!("  Flags:   {}", file.flags());
+    if let Some(times) = fs.file_times(&file)? {
+        let show = |t: Option<Timestamp>| t.map_or("-".to_string(), |t| t.to_string());
+        println!("  Created: {}", show(times.created));
+        println!("  Updated: {}", show(times.updated));
+    }
+
+    if args.raw {
+        dump_extents(fs, &file)?;
+    }
+    Ok(())
+}
+
+/// Hexdumps the directory slots of the file's extents, each with its fields decoded.
+fn dump_extents(fs: &CpmFs, file: &FileItem) -> Result<()> {
+    let wide_blocks = fs.wide_blocks();
+    for e in fs.file_extents(file) {
+        let data = fs.read_dir_slot(e.slot)?;
+        println!();
+        println!("Slot {} (extent {})", e.slot, e.extent);
+        for line in hexdump(&data, e.slot * 32) {
+            println!("{}", line);
+        }
+        println!("      {}", decode_slot(&data, wide_blocks));
+    }
+    Ok(())
+}
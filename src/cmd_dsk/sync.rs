@@ -0,0 +1,268 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use super::reload_image_fs;
+use crate::cpm::{CpmFs, FileId, FileItem, FilenameMode, LsMode, RECORD_SIZE};
+use crate::file_arg::DEFAULT_USER;
+use crate::util::safe_filename;
+
+#[derive(Args, Clone)]
+pub struct SyncArgs {
+    /// user area on the image (default 0)
+    #[arg(short, long)]
+    user: Option<u8>,
+    /// copy from the image to the local directory, rather than the other way round
+    #[arg(long)]
+    pub from_image: bool,
+    /// delete files that don't exist on the source side
+    #[arg(long)]
+    delete: bool,
+    /// only show what would be done, don't modify anything
+    #[arg(short = 'n', long, conflicts_with = "watch")]
+    pub dry_run: bool,
+    /// keep running and sync again every --interval seconds (the image must not be modified by
+    /// other programs meanwhile, unless syncing from the image)
+    #[arg(short, long)]
+    watch: bool,
+    /// seconds between checks in watch mode
+    #[arg(long, default_value_t = 2)]
+    interval: u64,
+    /// local directory
+    local_dir: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Add,
+    Update,
+    Delete,
+}
+
+/// A single sync step, for a file identified by its CP/M name.
+struct Change {
+    action: Action,
+    name: String,
+    image: Option<FileItem>,
+    local: Option<PathBuf>,
+}
+
+/// Mirrors a local directory into a user area on the image (or the other way round), copying
+/// new and changed files. Changes are detected by size, then by content.
+pub fn sync(fs: &mut CpmFs, image: &mut File, args: SyncArgs) -> Result<()> {
+    loop {
+        let changes = plan(fs, &args)?;
+        for change in &changes {
+            let letter = match change.action {
+                Action::Add => 'A',
+                Action::Update => 'U',
+                Action::Delete => 'D',
+            };
+            println!("{} {}", letter, change.name);
+            if !args.dry_run {
+                apply(fs, &args, change).with_context(|| format!("Can't sync {}", change.name))?;
+            }
+        }
+
+        if !args.watch {
+            let count = |action| changes.iter().filter(|c| c.action == action).count();
+            println!(
+                "{}{} added, {} updated, {} deleted.",
+                if args.dry_run { "Dry run: " } else { "" },
+                count(Action::Add),
+                count(Action::Update),
+                count(Action::Delete)
+            );
+            return Ok(());
+        }
+
+        if !changes.is_empty() && !args.from_image {
+            fs.save(image).context("Error saving image file")?;
+        }
+        thread::sleep(Duration::from_secs(args.interval));
+        if args.from_image {
+            // pick up changes made by other programs, e.g. an emulator
+            *fs = reload_image_fs(image, fs).context("Error reloading image file")?;
+        }
+    }
+}
+
+fn plan(fs: &CpmFs, args: &SyncArgs) -> Result<Vec<Change>> {
+    let user = args.user.unwrap_or(DEFAULT_USER);
+    let mut entries = vec![];
+    for entry in fs::read_dir(&args.local_dir).with_context(|| format!("Can't read {}", args.local_dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            entries.push(entry.path());
+        }
+    }
+    // names differing only in case map to the same CP/M name, the first one (by name) is synced
+    entries.sort();
+    let mut local: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for path in entries {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        match FileId::new_with_filename(user, &file_name, FilenameMode::Normalized) {
+            Ok(id) => match local.get(&id.filename()) {
+                Some(first) => eprintln!(
+                    "Warning: skipping {}, its CP/M name {} is taken by {}.",
+                    file_name,
+                    id.filename(),
+                    first.file_name().unwrap_or_default().to_string_lossy()
+                ),
+                None => {
+                    local.insert(id.filename(), path);
+                }
+            },
+            Err(_) if !args.from_image => eprintln!("Warning: skipping {}, not a valid CP/M name.", file_name),
+            Err(_) => {}
+        }
+    }
+    let image: BTreeMap<String, FileItem> = fs
+        .list_files(LsMode::OwnedBy(user))?
+        .into_iter()
+        .map(|f| (f.name.clone(), f))
+        .collect();
+
+    let mut changes = vec![];
+    let change = |action, name: &String| Change {
+        action,
+        name: name.clone(),
+        image: image.get(name).cloned(),
+        local: local.get(name).cloned(),
+    };
+    let (sources, targets): (Vec<&String>, Vec<&String>) = if args.from_image {
+        (image.keys().collect(), local.keys().collect())
+    } else {
+        (local.keys().collect(), image.keys().collect())
+    };
+    for name in &sources {
+        match (image.get(*name), local.get(*name)) {
+            (Some(f), Some(path)) if differs(fs, f, path)? => changes.push(change(Action::Update, name)),
+            (Some(_), Some(_)) => {}
+            _ => changes.push(change(Action::Add, name)),
+        }
+    }
+    if args.delete {
+        for name in targets.into_iter().filter(|name| !sources.contains(name)) {
+            changes.push(change(Action::Delete, name));
+        }
+    }
+    Ok(changes)
+}
+
+/// Compares the image file with the local one, both padded to whole records.
+fn differs(fs: &CpmFs, file: &FileItem, path: &Path) -> Result<bool> {
+    let mut local = fs::read(path).with_context(|| format!("Can't read {}", path.display()))?;
+    let size = local.len().next_multiple_of(RECORD_SIZE);
+    if size != file.size.next_multiple_of(RECORD_SIZE) {
+        return Ok(true);
+    }
+    let mut data = Vec::with_capacity(size);
+    fs.read_file(file, &mut data, false)?;
+    local.resize(size, 0);
+    data.resize(size, 0);
+    Ok(local != data)
+}
+
+fn apply(fs: &mut CpmFs, args: &SyncArgs, change: &Change) -> Result<()> {
+    let user = args.user.unwrap_or(DEFAULT_USER);
+    if args.from_image {
+        let path = match &change.local {
+            Some(path) => path.clone(),
+            None => args.local_dir.join(safe_filename(&change.name)?),
+        };
+        match (&change.action, &change.image) {
+            (Action::Delete, _) => fs::remove_file(&path)?,
+            (_, Some(f)) => {
+                let mut lf = File::create(&path).with_context(|| format!("Can't create {}", path.display()))?;
+                fs.read_file(f, &mut lf, false)?;
+            }
+            (_, None) => unreachable!(),
+        }
+    } else {
+        if let Some(f) = &change.image {
+            fs.delete_file(f)?;
+        }
+        if let Some(path) = &change.local {
+            if change.action != Action::Delete {
+                let id = FileId::new_with_filename(user, &change.name, FilenameMode::Normalized)?;
+                let mut lf = File::open(path).with_context(|| format!("Can't open {}", path.display()))?;
+                fs.write_file(&id, &mut lf, false)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, plan, Action, SyncArgs};
+    use crate::cpm::{CpmVersion, LsMode};
+    use crate::profile::Profile;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_sync_to_image() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_sync");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.asm"), b"org 100h").unwrap();
+        std::fs::write(dir.join("notes"), b"todo").unwrap();
+        std::fs::write(dir.join("not valid.txt"), b"skipped").unwrap();
+        // same CP/M name as main.asm, which comes later
+        std::fs::write(dir.join("MAIN.asm"), b"clash").unwrap();
+
+        let mut fs = Profile::find("junior").unwrap().format(CpmVersion::V3).unwrap();
+        let args = SyncArgs {
+            user: Some(1),
+            from_image: false,
+            delete: true,
+            dry_run: false,
+            watch: false,
+            interval: 0,
+            local_dir: dir.clone(),
+        };
+        let sync = |fs: &mut _| {
+            let changes = plan(fs, &args).unwrap();
+            for c in &changes {
+                apply(fs, &args, c).unwrap();
+            }
+            changes.into_iter().map(|c| (c.action, c.name)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            sync(&mut fs),
+            vec![
+                (Action::Add, "MAIN.ASM".to_string()),
+                (Action::Add, "NOTES.".to_string())
+            ]
+        );
+        assert!(sync(&mut fs).is_empty());
+
+        let files = fs.list_files(LsMode::OwnedBy(1)).unwrap();
+        let mut data = vec![];
+        fs.read_file(&files[0], &mut data, false).unwrap();
+        assert_eq!(data[0..5], *b"clash");
+        std::fs::remove_file(dir.join("MAIN.asm")).unwrap();
+
+        // content change of the same size is detected too
+        std::fs::write(dir.join("main.asm"), b"org 200h").unwrap();
+        std::fs::remove_file(dir.join("notes")).unwrap();
+        assert_eq!(
+            sync(&mut fs),
+            vec![
+                (Action::Update, "MAIN.ASM".to_string()),
+                (Action::Delete, "NOTES.".to_string())
+            ]
+        );
+        let files = fs.list_files(LsMode::OwnedBy(1)).unwrap();
+        assert_eq!(files.len(), 1);
+        let mut data = vec![];
+        fs.read_file(&files[0], &mut data, false).unwrap();
+        assert_eq!(data[0..8], *b"org 200h");
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::fs::File;
+use std::io::Write;
+use std::ops::Range;
+
+use crate::cpm::{CpmFs, FileItem, LsMode};
+use crate::error::{ErrorKind, Failure};
+use crate::util::thousands;
+
+#[derive(Args, Clone)]
+pub struct UndeleteArgs {
+    /// Name of the deleted file
+    name: String,
+    /// User to bring the file back as
+    #[arg(short, long, default_value_t = 0)]
+    user: u8,
+    /// Rather than restoring the directory entry, write the part of the file before the first
+    /// reused block to this local file (for files whose blocks other files partially hold now)
+    #[arg(long, value_name = "OUT")]
+    pub partial: Option<String>,
+}
+
+/// Brings a deleted file back, or with --partial saves what's left of it locally.
+pub fn undelete(fs: &mut CpmFs, args: UndeleteArgs) -> Result<()> {
+    let name = args.name.to_ascii_uppercase();
+    let Some(file) = fs.list_files(LsMode::DeletedOnly)?.into_iter().find(|f| f.name == name) else {
+        bail!(Failure::new(
+            ErrorKind::NoMatch,
+            format!("No deleted file {} found.", name)
+        ));
+    };
+
+    match &args.partial {
+        Some(out) => recover_partial(fs, &file, out),
+        None => {
+            fs.undelete_file(&file, args.user)?;
+            println!(
+                "{} undeleted as {}:{}, {} bytes.",
+                file.name,
+                args.user,
+                file.name,
+                thousands(file.size)
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Writes the intact prefix of the deleted file to a local file, listing the byte ranges lost
+/// to the reused blocks.
+fn recover_partial(fs: &CpmFs, file: &FileItem, out: &str) -> Result<()> {
+    let mut data = vec![];
+    fs.read_file(file, &mut data, false)?;
+    let reused = reused_ranges(file, &fs.allocated_blocks(file), fs.block_size());
+    let intact = reused.first().map_or(data.len(), |r| r.start);
+    if intact == 0 && !data.is_empty() {
+        bail!(Failure::new(
+            ErrorKind::Filesystem,
+            format!(
+                "The first block of {} holds another file now, nothing to recover.",
+                file.name
+            )
+        ));
+    }
+
+    let mut f = File::create(out).with_context(|| format!("Can't create {}", out))?;
+    f.write_all(&data[..intact])?;
+    println!(
+        "{} of {} bytes of {} recovered to {}.",
+        thousands(intact),
+        thousands(data.len()),
+        file.name,
+        out
+    );
+    for range in &reused {
+        println!(
+            "  bytes {}-{} are in reused blocks",
+            thousands(range.start),
+            thousands(range.end - 1)
+        );
+    }
+    Ok(())
+}
+
+/// Byte ranges of the file stored in the reused blocks, adjacent blocks merged.
+fn reused_ranges(file: &FileItem, reused: &[u16], block_size: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for (idx, block) in file.block_list.iter().enumerate() {
+        let range = idx * block_size..((idx + 1) * block_size).min(file.size);
+        if !reused.contains(block) || range.is_empty() {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ranges.push(range),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reused_ranges;
+    use crate::cpm::{CpmFs, CpmVersion, FileItem, LsMode};
+    use crate::profile::Profile;
+    use std::fs::File;
+
+    #[test]
+    fn test_reused_ranges() {
+        let file = FileItem {
+            user: None,
+            name: "GAME.BIN".to_string(),
+            size: 4500,
+            block_list: vec![10, 11, 12, 13, 14],
+            extents: 1,
+            read_only: false,
+            system_file: false,
+            archived: false,
+        };
+        assert!(reused_ranges(&file, &[], 1024).is_empty());
+        assert_eq!(reused_ranges(&file, &[11, 12, 14], 1024), [1024..3072, 4096..4500]);
+    }
+
+    #[test]
+    fn test_undelete() {
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V3);
+        let mut fs = CpmFs::load(&mut File::open("tests/03.dsk").unwrap(), params).unwrap();
+        let deleted = fs.list_files(LsMode::DeletedOnly).unwrap();
+        let file = |name: &str| deleted.iter().find(|f| f.name == name).unwrap();
+        // two empty deleted files of the same name
+        assert!(fs.undelete_file(file("$$$.SUB"), 0).is_err());
+        assert!(fs.undelete_file(file("BIOS.BAK"), 0).is_err());
+
+        let free = file("PCSYSJA5.NET");
+        fs.undelete_file(free, 3).unwrap();
+        let restored = fs.list_files(LsMode::OwnedBy(3)).unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].name, free.name);
+        assert_eq!(restored[0].block_list, free.block_list);
+        assert_eq!(fs.allocated_blocks(free), free.block_list);
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+use crate::cpm::{CpmFs, FileItem, LsMode};
+use crate::output::Listing;
+use crate::util::thousands;
+
+/// Files stored in a user area.
+#[derive(Debug, Default, PartialEq)]
+struct UserArea {
+    files: usize,
+    bytes: usize,
+    blocks: usize,
+}
+
+/// Lists the user areas holding any files, with the number of files, bytes and blocks of each.
+pub fn users(fs: &CpmFs) -> Result<()> {
+    let areas = user_areas(&fs.list_files(LsMode::All)?);
+    if areas.is_empty() {
+        println!("No files.");
+        return Ok(());
+    }
+
+    let mut listing = Listing::new(&["User", "Files", "Bytes", "Blocks"]);
+    for (user, area) in &areas {
+        listing.add_row(
+            vec![
+                user.to_string(),
+                area.files.to_string(),
+                thousands(area.bytes),
+                area.blocks.to_string(),
+            ],
+            None,
+        );
+    }
+    listing.print()?;
+
+    let files: usize = areas.values().map(|a| a.files).sum();
+    println!("{} files in {} user areas.", files, areas.len());
+    Ok(())
+}
+
+fn user_areas(files: &[FileItem]) -> BTreeMap<u8, UserArea> {
+    let mut areas: BTreeMap<u8, UserArea> = BTreeMap::new();
+    for f in files {
+        let Some(user) = f.user else {
+            continue;
+        };
+        let area = areas.entry(user).or_default();
+        area.files += 1;
+        area.bytes += f.size;
+        area.blocks += f.block_list.len();
+    }
+    areas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{user_areas, UserArea};
+    use crate::cpm::{CpmFs, CpmVersion, FileId, FilenameMode, LsMode};
+    use crate::profile::Profile;
+    use std::fs::File;
+
+    #[test]
+    fn test_user_areas() {
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V3);
+        let mut fs = CpmFs::load(&mut File::open("tests/03.dsk").unwrap(), params).unwrap();
+        let all = fs.list_files(LsMode::All).unwrap();
+        let id = FileId::new_with_filename(5, "HIDDEN.TXT", FilenameMode::Normalized).unwrap();
+        fs.write_file(&id, &mut [0x41u8; 3000].as_slice(), false).unwrap();
+
+        let areas = user_areas(&fs.list_files(LsMode::Deleted).unwrap());
+        assert_eq!(areas.keys().copied().collect::<Vec<_>>(), [0, 5]);
+        assert_eq!(areas[&0].files, all.len());
+        assert_eq!(
+            areas[&5],
+            UserArea {
+                files: 1,
+                bytes: 3072,
+                blocks: 2
+            }
+        );
+    }
+}
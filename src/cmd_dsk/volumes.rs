@@ -0,0 +1,211 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use super::{find_file, image_file_name, load_fs};
+use crate::cpm::{CpmFs, CpmVersion, FileId, FileItem, FilenameMode, LsMode};
+use crate::error::{ErrorKind, Failure};
+use crate::file_arg::FileArg;
+use crate::profile::Profile;
+use crate::util::thousands;
+
+#[derive(Args, Clone)]
+pub struct SplitArgs {
+    /// file to split, e.g. :BIGFILE.DAT or 3:BIGFILE.DAT
+    file: FileArg,
+    /// images receiving the parts, in order (missing ones are created, of the same format)
+    #[arg(long, num_args = 1.., required = true)]
+    volumes: Vec<String>,
+}
+
+#[derive(Args, Clone)]
+pub struct JoinArgs {
+    /// file to create from the parts, e.g. :BIGFILE.DAT or 3:BIGFILE.DAT
+    file: FileArg,
+    /// images holding the parts, in any order
+    #[arg(long, num_args = 1.., required = true)]
+    volumes: Vec<String>,
+}
+
+/// Splits the file across several images, each getting a single part (NAME.001, NAME.002, ...)
+/// as large as its free space allows. Nothing is written unless all the parts fit.
+pub fn split(fs: &CpmFs, profile: &Profile, version: CpmVersion, args: SplitArgs) -> Result<()> {
+    let (user, name) = image_file_name(&args.file)?;
+    let file = find_file(fs, user, &name)?;
+    let mut data = Vec::with_capacity(file.size);
+    fs.read_file(&file, &mut data, false)?;
+
+    // plan the parts first, so a missing volume doesn't leave the others half written
+    let mut parts = vec![];
+    let mut offset = 0;
+    for volume in &args.volumes {
+        if offset == data.len() {
+            break;
+        }
+        let (vf, vfs) = open_volume(volume, profile, version)?;
+        let size = vfs.free_space().min(data.len() - offset);
+        if size == 0 {
+            eprintln!("Skipping {}, no free space.", volume);
+            continue;
+        }
+        let id = part_id(user, &name, parts.len() + 1)?;
+        if vfs.file_exists(&id) {
+            bail!(Failure::new(
+                ErrorKind::Exists,
+                format!("File {}:{} already exists on {}", user, id.filename(), volume)
+            ));
+        }
+        parts.push((volume, vf, vfs, id, offset..offset + size));
+        offset += size;
+    }
+    if offset < data.len() {
+        bail!(Failure::new(
+            ErrorKind::DiskFull,
+            format!(
+                "{} doesn't fit, {} bytes left over, more volumes needed",
+                name,
+                thousands(data.len() - offset)
+            )
+        ));
+    }
+
+    let num_parts = parts.len();
+    for (volume, mut vf, mut vfs, id, range) in parts {
+        vfs.write_file(&id, &mut &data[range.clone()], false)?;
+        vfs.save(&mut vf).with_context(|| format!("Error saving {}", volume))?;
+        println!(
+            "{}:{} -> {}, {} bytes",
+            user,
+            id.filename(),
+            volume,
+            thousands(range.len())
+        );
+    }
+    println!("{} split into {} parts.", name, num_parts);
+    Ok(())
+}
+
+/// Joins the parts (NAME.001, NAME.002, ...) found on the volumes into a single file.
+pub fn join(fs: &mut CpmFs, profile: &Profile, version: CpmVersion, args: JoinArgs) -> Result<()> {
+    let (user, name) = image_file_name(&args.file)?;
+    let id = FileId::new_with_filename(user, &name, FilenameMode::Normalized)?;
+
+    let volumes = args
+        .volumes
+        .iter()
+        .map(|volume| {
+            let mut f = File::open(volume).with_context(|| format!("Can't open image file {}", volume))?;
+            Ok((volume, load_fs(&mut f, volume, profile.params(version))?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // (part number, volume, file)
+    let mut parts: Vec<(usize, &str, &CpmFs, FileItem)> = vec![];
+    for (volume, vfs) in &volumes {
+        for f in vfs.list_files(LsMode::OwnedBy(user))? {
+            let Some(n) = part_number(&name, &f.name) else {
+                continue;
+            };
+            if let Some((_, other, _, _)) = parts.iter().find(|p| p.0 == n) {
+                bail!(Failure::new(
+                    ErrorKind::Filesystem,
+                    format!("Part {} found on both {} and {}", f.name, other, volume)
+                ));
+            }
+            parts.push((n, volume, vfs, f));
+        }
+    }
+    parts.sort_by_key(|p| p.0);
+    if parts.is_empty() {
+        bail!(Failure::new(
+            ErrorKind::NoMatch,
+            format!("No parts of {}:{} found.", user, name)
+        ));
+    }
+    if let Some(missing) = (1..=parts.len()).zip(&parts).find(|(n, p)| *n != p.0).map(|(n, _)| n) {
+        bail!(Failure::new(
+            ErrorKind::Filesystem,
+            format!("Part {} of {} is missing", missing, name)
+        ));
+    }
+
+    let mut data = vec![];
+    for (_, volume, vfs, f) in &parts {
+        vfs.read_file(f, &mut data, false)
+            .with_context(|| format!("Can't read {} from {}", f.name, volume))?;
+    }
+    fs.write_file(&id, &mut data.as_slice(), false)?;
+    println!(
+        "{} parts joined into {}:{}, {} bytes.",
+        parts.len(),
+        user,
+        id.filename(),
+        thousands(data.len())
+    );
+    Ok(())
+}
+
+/// Returns the ID of the n-th part: the file's name with the part number as the extension.
+fn part_id(user: u8, name: &str, n: usize) -> Result<FileId> {
+    let base = name.split('.').next().unwrap_or(name);
+    FileId::new_with_filename(user, &format!("{}.{:03}", base, n), FilenameMode::Normalized)
+}
+
+/// Returns the part number, if the file name is a part of the given file.
+fn part_number(name: &str, file_name: &str) -> Option<usize> {
+    let base = name.split('.').next().unwrap_or(name);
+    let (part_base, ext) = file_name.split_once('.')?;
+    if part_base != base || ext.len() != 3 || !ext.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    ext.parse().ok().filter(|&n| n > 0)
+}
+
+/// Opens the volume for writing, creating a new (formatted) image if it doesn't exist.
+fn open_volume(volume: &str, profile: &Profile, version: CpmVersion) -> Result<(File, CpmFs)> {
+    if !Path::new(volume).exists() {
+        let f = File::create(volume).with_context(|| format!("Can't create image file {}", volume))?;
+        return Ok((f, profile.format(version)?));
+    }
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(volume)
+        .with_context(|| format!("Can't open image file {}", volume))?;
+    let fs = load_fs(&mut f, volume, profile.params(version))?;
+    Ok((f, fs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{part_id, part_number};
+    use crate::cmd_dsk::{dsk, parse_args};
+    use crate::error::{error_kind, ErrorKind};
+
+    #[test]
+    fn test_part_names() {
+        assert_eq!(part_id(0, "BIGFILE.DAT", 1).unwrap().filename(), "BIGFILE.001");
+        assert_eq!(part_id(2, "README", 12).unwrap().filename(), "README.012");
+        assert_eq!(part_number("BIGFILE.DAT", "BIGFILE.002"), Some(2));
+        assert_eq!(part_number("BIGFILE.DAT", "BIGFILE.DAT"), None);
+        assert_eq!(part_number("BIGFILE.DAT", "BIGFILE2.001"), None);
+        assert_eq!(part_number("BIGFILE.DAT", "BIGFILE.000"), None);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let volume = "tests/out_v1.dsk";
+        std::fs::copy("tests/03.dsk", volume).unwrap();
+        let before = std::fs::read(volume).unwrap();
+        for args in [
+            &["tests/03.dsk", "split", ":BDOSI.MAC", "--volumes", volume][..],
+            &["tests/03.dsk", "reformat", "--to", "junior", "tests/out_v2.dsk"],
+        ] {
+            let err = dsk(parse_args(args), true).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::Usage, "{:?}", args);
+        }
+        assert_eq!(std::fs::read(volume).unwrap(), before);
+        assert!(!std::path::Path::new("tests/out_v2.dsk").exists());
+    }
+}
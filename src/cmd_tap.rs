@@ -1,12 +1,24 @@
-use crate::speccy_files::{SpeccyFile, SpeccyFileType};
-use anyhow::{bail, Result};
+use crate::error::{ErrorKind, Failure};
+use crate::speccy_files::{SpeccyFile, SpeccyFileHeader, SpeccyFileType, HEADER_SIZE};
+use crate::tape::{checksum_ok, decode, is_tzx, read_tape, render, write_tap_block, Speed};
+use crate::util::{parse_number, parse_ranges};
+use crate::wav::{read_wav, write_wav};
+use anyhow::{bail, Context, Result};
 use clap::{Args, Subcommand};
-use std::io::Write;
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Args)]
 pub struct TapArgs {
     /// The disk image file
     pub tap_file: String,
+    /// Report checksum mismatches as warnings, rather than failing
+    #[arg(long, global = true)]
+    pub lenient: bool,
+    /// Rewrite correct checksums into the .tap file first
+    #[arg(long, global = true)]
+    pub fix_checksums: bool,
 
     #[command(subcommand)]
     pub command: TapCommands,
@@ -20,6 +32,26 @@ pub enum TapCommands {
     Extract(ExtractArgs),
     /// Extract all files from the .tap file
     Explode(ExplodeArgs),
+    /// Render the tape (.tap or .tzx) as audio, to load on a real Spectrum through the EAR socket
+    Towav(TowavArgs),
+    /// Decode a standard speed tape recording (WAV) into the .tap file, checking the block checksums
+    Fromwav(FromwavArgs),
+    /// Concatenate .tap files (or blocks of them, given by a list file) into the .tap file
+    Merge(MergeArgs),
+    /// Rename a file in the .tap file, rewriting its header
+    Rename(RenameArgs),
+    /// Change the type, autostart line, load address or variables offset in a file header
+    Setparam(SetparamArgs),
+}
+
+impl TapCommands {
+    /// Returns true for commands which write the .tap file.
+    fn writes_tape(&self) -> bool {
+        matches!(
+            self,
+            TapCommands::Fromwav(_) | TapCommands::Merge(_) | TapCommands::Rename(_) | TapCommands::Setparam(_)
+        )
+    }
 }
 
 #[derive(Args)]
@@ -27,8 +59,9 @@ pub struct ExtractArgs {
     /// Index of the file to extract
     #[arg(short, long)]
     pub index: usize,
-    /// Output file name
-    pub output_file: String,
+    /// Output file name, defaults to the name of the file made safe, with the type extension
+    /// (not overwriting existing files)
+    pub output_file: Option<String>,
     /// Extract only the raw header bytes
     #[arg(long = "header", conflicts_with = "only_data")]
     pub only_header: bool,
@@ -42,21 +75,110 @@ pub struct ExtractArgs {
 
 #[derive(Args)]
 pub struct ExplodeArgs {
-    /// Prefix for output file names
-    pub prefix: String,
+    /// Prefix for output file names (e.g. out/ to write them to a directory), followed by the
+    /// file index, required unless using the names
+    #[arg(required_unless_present = "use_names")]
+    pub prefix: Option<String>,
+    /// Name the output files after the names on the tape, made safe and unique
+    #[arg(long)]
+    pub use_names: bool,
+    /// Explode only files of the type (can be repeated)
+    #[arg(long, value_enum)]
+    pub filter: Vec<SpeccyFileType>,
+}
+
+#[derive(Args)]
+pub struct TowavArgs {
+    /// Output WAV file name
+    pub output_file: String,
+    /// Tape speed, turbo needs a turbo loader on the Spectrum
+    #[arg(long, value_enum, default_value_t = Speed::Std)]
+    pub speed: Speed,
+    /// Sample rate
+    #[arg(long, default_value_t = 44100)]
+    pub rate: u32,
+}
+
+#[derive(Args)]
+pub struct FromwavArgs {
+    /// Input WAV file name (8 or 16 bit PCM)
+    pub input_file: String,
+    /// Leave out blocks with a bad checksum, rather than keeping them with a warning
+    #[arg(long)]
+    pub skip_bad: bool,
+}
+
+#[derive(Args)]
+pub struct MergeArgs {
+    /// Input .tap files, in order
+    #[arg(required_unless_present = "list")]
+    pub input_files: Vec<String>,
+    /// List file with a .tap file per line, optionally followed by the blocks to take
+    /// (e.g. "game.tap 2-3"), relative to the list file; # starts a comment
+    #[arg(long, conflicts_with = "input_files")]
+    pub list: Option<PathBuf>,
 }
 
-pub fn tap(args: TapArgs) -> Result<()> {
+#[derive(Args)]
+pub struct RenameArgs {
+    /// Index of the file to rename (as listed by info)
+    #[arg(short, long)]
+    pub index: usize,
+    /// New name, up to 10 characters
+    pub new_name: String,
+}
+
+#[derive(Args)]
+pub struct SetparamArgs {
+    /// Index of the file to change (as listed by info)
+    #[arg(short, long)]
+    pub index: usize,
+    /// New file type
+    #[arg(long = "type", value_enum)]
+    pub file_type: Option<SpeccyFileType>,
+    /// Autostart line (Basic only), none to disable autorun
+    #[arg(long, conflicts_with = "load_addr")]
+    pub autostart: Option<String>,
+    /// Load address (Code only)
+    #[arg(long, value_parser = parse_number)]
+    pub load_addr: Option<usize>,
+    /// Offset of the variables from the program start (Basic only)
+    #[arg(long, value_parser = parse_number)]
+    pub vars_offset: Option<usize>,
+}
+
+pub fn tap(args: TapArgs, read_only: bool) -> Result<()> {
+    if read_only && (args.command.writes_tape() || args.fix_checksums) {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            "The command would write the tape file, not allowed with --read-only."
+        ));
+    }
+    if args.fix_checksums {
+        if matches!(args.command, TapCommands::Fromwav(_) | TapCommands::Merge(_)) {
+            bail!(Failure::new(
+                ErrorKind::Usage,
+                "--fix-checksums repairs an existing .tap file, fromwav and merge create it"
+            ));
+        }
+        fix_checksums(&args.tap_file)?;
+    }
+    let lenient = args.lenient;
     match args.command {
-        TapCommands::Info => info(&args.tap_file),
-        TapCommands::Extract(ext_args) => extract(&args.tap_file, ext_args),
-        TapCommands::Explode(exp_args) => explode(&args.tap_file, exp_args),
+        TapCommands::Info => info(&args.tap_file, lenient),
+        TapCommands::Extract(ext_args) => extract(&args.tap_file, ext_args, lenient),
+        TapCommands::Explode(exp_args) => explode(&args.tap_file, exp_args, lenient),
+        TapCommands::Towav(wav_args) => towav(&args.tap_file, wav_args),
+        TapCommands::Fromwav(wav_args) => fromwav(&args.tap_file, wav_args),
+        TapCommands::Merge(merge_args) => merge(&args.tap_file, merge_args),
+        TapCommands::Rename(rename_args) => rename(&args.tap_file, rename_args),
+        TapCommands::Setparam(param_args) => setparam(&args.tap_file, param_args),
     }
 }
 
-fn info(fname: &str) -> Result<()> {
+fn info(fname: &str, lenient: bool) -> Result<()> {
     let mut tap_file = std::fs::File::open(fname)?;
-    let entries = SpeccyFile::load_tap_file(&mut tap_file)?;
+    let entries = SpeccyFile::load_tap_file(&mut tap_file, lenient)?;
 
     for (idx, entry) in entries.iter().enumerate() {
         println!("{idx}: \"{}\"", entry.name());
@@ -74,31 +196,41 @@ fn info(fname: &str) -> Result<()> {
             SpeccyFile::Code(c) => {
                 println!("    load address: 0x{:04X}", c.load_address())
             }
-            SpeccyFile::NumArray(n) => {
+            SpeccyFile::NumArray(_) => {
                 println!("    num array - TODO")
             }
-            SpeccyFile::StrArray(s) => {
+            SpeccyFile::StrArray(_) => {
                 println!("    string array - TODO")
             }
-            _ => {}
         }
         println!();
     }
     Ok(())
 }
 
-fn extract(fname: &str, args: ExtractArgs) -> Result<()> {
+fn extract(fname: &str, args: ExtractArgs, lenient: bool) -> Result<()> {
     if args.only_header && args.only_data {
         bail!("--header and --data are mutually exclusive");
     }
     let mut tap_file = std::fs::File::open(fname)?;
-    let mut entries = SpeccyFile::load_tap_file(&mut tap_file)?;
+    let mut entries = SpeccyFile::load_tap_file(&mut tap_file, lenient)?;
     if args.index >= entries.len() {
         bail!("Invalid file index");
     }
 
     let entry = &mut entries[args.index];
-    let mut out_file = std::fs::File::create(args.output_file)?;
+    let out_name = match args.output_file {
+        Some(name) => name,
+        None => {
+            let mut used: HashSet<String> = std::fs::read_dir(".")?
+                .filter_map(|e| Some(e.ok()?.file_name().to_string_lossy().to_lowercase()))
+                .collect();
+            let name = entry.file_name(&mut used);
+            println!("{}: {} -> {}", args.index, entry.name(), name);
+            name
+        }
+    };
+    let mut out_file = std::fs::File::create(out_name)?;
 
     if let SpeccyFile::Program(ref mut p) = entry {
         if args.no_autorun {
@@ -118,13 +250,34 @@ fn extract(fname: &str, args: ExtractArgs) -> Result<()> {
     Ok(())
 }
 
-fn explode(fname: &str, args: ExplodeArgs) -> Result<()> {
+fn explode(fname: &str, args: ExplodeArgs, lenient: bool) -> Result<()> {
     let mut tap_file = std::fs::File::open(fname)?;
-    let entries = SpeccyFile::load_tap_file(&mut tap_file)?;
+    let entries = SpeccyFile::load_tap_file(&mut tap_file, lenient)?;
 
+    let prefix = args.prefix.unwrap_or_default();
+    let mut used = HashSet::new();
+    if args.use_names {
+        // names taken in the target directory, so existing files aren't overwritten
+        let (dir, name_prefix) = match prefix.rsplit_once(['/', std::path::MAIN_SEPARATOR]) {
+            Some(("", name_prefix)) => ("/", name_prefix),
+            Some(split) => split,
+            None => (".", prefix.as_str()),
+        };
+        let name_prefix = name_prefix.to_lowercase();
+        used = std::fs::read_dir(dir)?
+            .filter_map(|e| Some(e.ok()?.file_name().to_string_lossy().to_lowercase()))
+            .filter_map(|name| Some(name.strip_prefix(&name_prefix)?.to_string()))
+            .collect();
+    }
     for (idx, entry) in entries.iter().enumerate() {
-        let ext = entry.file_type().extension();
-        let out_name = format!("{}{:02}.{}", args.prefix, idx, ext);
+        if !args.filter.is_empty() && !args.filter.contains(&entry.file_type()) {
+            continue;
+        }
+        let out_name = if args.use_names {
+            format!("{}{}", prefix, entry.file_name(&mut used))
+        } else {
+            format!("{}{:02}.{}", prefix, idx, entry.file_type().extension())
+        };
         let mut out_file = std::fs::File::create(&out_name)?;
         entry.write_header(&mut out_file)?;
         entry.write_raw_data(&mut out_file)?;
@@ -133,3 +286,437 @@ fn explode(fname: &str, args: ExplodeArgs) -> Result<()> {
 
     Ok(())
 }
+
+fn towav(fname: &str, args: TowavArgs) -> Result<()> {
+    let data = std::fs::read(fname).with_context(|| format!("Can't read {}", fname))?;
+    let mut blocks = read_tape(&data)?;
+    for block in &mut blocks {
+        block.set_speed(args.speed);
+    }
+
+    let samples = render(&blocks, args.rate);
+    let out_file =
+        std::fs::File::create(&args.output_file).with_context(|| format!("Can't create {}", args.output_file))?;
+    write_wav(&mut BufWriter::new(out_file), args.rate, &samples)?;
+    let seconds = samples.len() / args.rate as usize;
+    println!(
+        "{} blocks, {}:{:02} of audio written to {}",
+        blocks.len(),
+        seconds / 60,
+        seconds % 60,
+        args.output_file
+    );
+    Ok(())
+}
+
+fn fromwav(fname: &str, args: FromwavArgs) -> Result<()> {
+    let data = std::fs::read(&args.input_file).with_context(|| format!("Can't read {}", args.input_file))?;
+    let (rate, samples) = read_wav(&data)?;
+    let blocks = decode(&samples, rate);
+    if blocks.is_empty() {
+        bail!("No standard speed tape blocks found in {}", args.input_file);
+    }
+
+    let mut out = BufWriter::new(std::fs::File::create(fname).with_context(|| format!("Can't create {}", fname))?);
+    let mut written = 0;
+    for (idx, bytes) in blocks.iter().enumerate() {
+        let desc = describe_block(bytes);
+        if !checksum_ok(bytes) {
+            eprintln!("Warning: block {} ({}) has a bad checksum", idx, desc);
+            if args.skip_bad {
+                continue;
+            }
+        }
+        println!("{}: {}", idx, desc);
+        write_tap_block(&mut out, bytes)?;
+        written += 1;
+    }
+    out.flush()?;
+    println!("{} of {} blocks written to {}", written, blocks.len(), fname);
+    Ok(())
+}
+
+/// Headers with what they describe, other blocks with their flag and data length.
+fn describe_block(bytes: &[u8]) -> String {
+    match SpeccyFileHeader::from_bytes(bytes.get(1..).unwrap_or_default()) {
+        Some(header) if bytes[0] == 0x00 && bytes.len() == 19 => format!("header, {}", header),
+        _ => format!(
+            "flag 0x{:02X}, {} bytes",
+            bytes.first().copied().unwrap_or_default(),
+            bytes.len().saturating_sub(2)
+        ),
+    }
+}
+
+/// A .tap file to merge, with the blocks to take from it (all if None).
+#[derive(Debug, PartialEq)]
+struct MergeSource {
+    path: PathBuf,
+    blocks: Option<String>,
+}
+
+/// Parses the list file, with the paths relative to base.
+fn parse_merge_list(list: &str, base: &Path) -> Vec<MergeSource> {
+    list.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (path, blocks) = match line.rsplit_once(char::is_whitespace) {
+                Some((path, blocks)) if blocks.chars().all(|c| c.is_ascii_digit() || c == ',' || c == '-') => {
+                    (path.trim_end(), Some(blocks.to_string()))
+                }
+                _ => (line, None),
+            };
+            MergeSource {
+                path: base.join(path),
+                blocks,
+            }
+        })
+        .collect()
+}
+
+fn merge(fname: &str, args: MergeArgs) -> Result<()> {
+    let sources = match &args.list {
+        Some(list) => {
+            let text = std::fs::read_to_string(list).with_context(|| format!("Can't read {}", list.display()))?;
+            parse_merge_list(&text, list.parent().unwrap_or(Path::new("")))
+        }
+        None => args
+            .input_files
+            .iter()
+            .map(|f| MergeSource {
+                path: PathBuf::from(f),
+                blocks: None,
+            })
+            .collect(),
+    };
+
+    // all inputs are read and checked before the output gets created, it can be one of them
+    let mut merged = vec![];
+    for source in &sources {
+        let name = source.path.display();
+        let data = std::fs::read(&source.path).with_context(|| format!("Can't read {}", name))?;
+        let blocks = read_tape(&data).with_context(|| format!("Invalid tape {}", name))?;
+        if blocks.is_empty() {
+            bail!("{} has no blocks", name);
+        }
+        let indexes = match &source.blocks {
+            Some(ranges) => parse_ranges(ranges, 0, blocks.len() - 1).with_context(|| format!("Blocks of {}", name))?,
+            None => (0..blocks.len()).collect(),
+        };
+        for idx in indexes {
+            if !checksum_ok(&blocks[idx].bytes) {
+                eprintln!("Warning: block {} of {} has a bad checksum", idx, name);
+            }
+            merged.push((name.to_string(), idx, blocks[idx].bytes.clone()));
+        }
+    }
+
+    let mut out = BufWriter::new(std::fs::File::create(fname).with_context(|| format!("Can't create {}", fname))?);
+    for (idx, (name, source_idx, bytes)) in merged.iter().enumerate() {
+        write_tap_block(&mut out, bytes)?;
+        println!("{}: {} ({} block {})", idx, describe_block(bytes), name, source_idx);
+    }
+    out.flush()?;
+    println!(
+        "{} blocks from {} files written to {}",
+        merged.len(),
+        sources.len(),
+        fname
+    );
+    Ok(())
+}
+
+/// Reads the blocks of a .tap file to edit it, refusing TZX files, their timing and other
+/// blocks wouldn't survive the rewrite.
+fn load_tap_blocks(fname: &str) -> Result<Vec<Vec<u8>>> {
+    let data = std::fs::read(fname).with_context(|| format!("Can't read {}", fname))?;
+    if is_tzx(&data) {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            format!("{} is a TZX file, only .tap files can be edited", fname)
+        ));
+    }
+    Ok(read_tape(&data)?.into_iter().map(|block| block.bytes).collect())
+}
+
+fn save_tap_blocks(fname: &str, blocks: &[Vec<u8>]) -> Result<()> {
+    let mut out = BufWriter::new(std::fs::File::create(fname).with_context(|| format!("Can't create {}", fname))?);
+    for bytes in blocks {
+        write_tap_block(&mut out, bytes)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Changes the header of the file at the index (as listed by info), fixing the checksum of
+/// the header block, and writes the .tap file back.
+fn edit_header(
+    fname: &str,
+    index: usize,
+    edit: impl FnOnce(&mut [u8; HEADER_SIZE]) -> Result<()>,
+) -> Result<SpeccyFileHeader> {
+    let mut blocks = load_tap_blocks(fname)?;
+    let Some(block) = blocks
+        .iter_mut()
+        .filter(|bytes| bytes.len() == HEADER_SIZE + 2 && bytes[0] == 0x00)
+        .nth(index)
+    else {
+        bail!(Failure::new(
+            ErrorKind::NoMatch,
+            format!("No file {} in {}", index, fname)
+        ));
+    };
+
+    let mut header: [u8; HEADER_SIZE] = block[1..=HEADER_SIZE].try_into()?;
+    edit(&mut header)?;
+    block[1..=HEADER_SIZE].copy_from_slice(&header);
+    block[HEADER_SIZE + 1] = block[..=HEADER_SIZE].iter().fold(0, |acc, &b| acc ^ b);
+    save_tap_blocks(fname, &blocks)?;
+    SpeccyFileHeader::from_bytes(&header).context("Invalid header")
+}
+
+/// Rewrites the checksum of every block with a wrong one, for tapes with good data saved
+/// with bad checksums.
+fn fix_checksums(fname: &str) -> Result<()> {
+    let mut blocks = load_tap_blocks(fname)?;
+    let mut fixed = 0;
+    for (idx, bytes) in blocks.iter_mut().enumerate() {
+        let Some((&mut checksum, rest)) = bytes.split_last_mut() else {
+            continue;
+        };
+        let correct = rest.iter().fold(0, |acc, &b| acc ^ b);
+        if checksum != correct {
+            println!(
+                "Block {} ({}): checksum 0x{:02X} fixed to 0x{:02X}",
+                idx,
+                describe_block(bytes),
+                checksum,
+                correct
+            );
+            *bytes.last_mut().unwrap() = correct;
+            fixed += 1;
+        }
+    }
+    if fixed > 0 {
+        save_tap_blocks(fname, &blocks)?;
+    }
+    println!("{} checksums fixed in {}", fixed, fname);
+    Ok(())
+}
+
+fn rename(fname: &str, args: RenameArgs) -> Result<()> {
+    if args.new_name.len() > 10 || !args.new_name.bytes().all(|b| (0x20..0x7F).contains(&b)) {
+        bail!(Failure::new(
+            ErrorKind::Usage,
+            format!("Invalid name {}, up to 10 printable ASCII characters", args.new_name)
+        ));
+    }
+    let header = edit_header(fname, args.index, |header| {
+        header[1..11].copy_from_slice(format!("{:<10}", args.new_name).as_bytes());
+        Ok(())
+    })?;
+    println!("{}: {}", args.index, header);
+    Ok(())
+}
+
+fn setparam(fname: &str, args: SetparamArgs) -> Result<()> {
+    let usage = |msg: String| Failure::new(ErrorKind::Usage, msg);
+    if args.file_type.is_none() && args.autostart.is_none() && args.load_addr.is_none() && args.vars_offset.is_none() {
+        bail!(usage(
+            "Nothing to change, give --type, --autostart, --load-addr or --vars-offset".to_string()
+        ));
+    }
+    // a line above 9999 disables autorun, 0x8000 is what the ROM saves then
+    let autostart = match args.autostart.as_deref() {
+        Some("none") => Some(0x8000),
+        Some(line) => match parse_number(line)? {
+            line @ 0..=9999 => Some(line as u16),
+            _ => bail!(usage(format!("Invalid autostart line {}, up to 9999", line))),
+        },
+        None => None,
+    };
+    let word = |n: usize, what: &str| -> Result<u16> {
+        u16::try_from(n).map_err(|_| usage(format!("Invalid {} {}, up to 0xFFFF", what, n)).into())
+    };
+    let load_addr = args.load_addr.map(|n| word(n, "load address")).transpose()?;
+    let vars_offset = args.vars_offset.map(|n| word(n, "variables offset")).transpose()?;
+
+    let header = edit_header(fname, args.index, |header| {
+        if let Some(file_type) = args.file_type {
+            header[0] = file_type as u8;
+        }
+        let is_program = header[0] == SpeccyFileType::Program as u8;
+        if (autostart.is_some() || vars_offset.is_some()) && !is_program {
+            bail!(usage(
+                "--autostart and --vars-offset are for Basic programs only".to_string()
+            ));
+        }
+        if load_addr.is_some() && header[0] != SpeccyFileType::Code as u8 {
+            bail!(usage("--load-addr is for Code files only".to_string()));
+        }
+        if let Some(param1) = autostart.or(load_addr) {
+            header[13..15].copy_from_slice(&param1.to_le_bytes());
+        }
+        if let Some(param2) = vars_offset {
+            header[15..17].copy_from_slice(&param2.to_le_bytes());
+        }
+        Ok(())
+    })?;
+    println!("{}: {}", args.index, header);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        explode, fix_checksums, parse_merge_list, rename, setparam, ExplodeArgs, MergeSource, RenameArgs, SetparamArgs,
+    };
+    use crate::speccy_files::{SpeccyFile, SpeccyFileType};
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_parse_merge_list() {
+        let list =
+            "# compilation\nloader.tap\nside 2.tap\n\ngames/jet set.tap 2-3  # the code only\ngames/jet set.tap 0,1\n";
+        let source = |path: &str, blocks: Option<&str>| MergeSource {
+            path: PathBuf::from(path),
+            blocks: blocks.map(str::to_string),
+        };
+        assert_eq!(
+            parse_merge_list(list, Path::new("tapes")),
+            [
+                source("tapes/loader.tap", None),
+                source("tapes/side 2.tap", None),
+                source("tapes/games/jet set.tap", Some("2-3")),
+                source("tapes/games/jet set.tap", Some("0,1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename() {
+        let tap = "tests/out_rename.tap";
+        std::fs::copy("tests/jetset.tap", tap).unwrap();
+        let args = |index, name: &str| RenameArgs {
+            index,
+            new_name: name.to_string(),
+        };
+        rename(tap, args(1, "JSW code")).unwrap();
+        assert!(rename(tap, args(2, "JSW")).is_err());
+        assert!(rename(tap, args(0, "Jet Set Willy")).is_err());
+
+        // the checksums get checked on loading
+        let files = SpeccyFile::load_tap_file(&mut File::open(tap).unwrap(), false).unwrap();
+        assert_eq!(files[0].name(), "Jetset1");
+        assert_eq!(files[1].name(), "JSW code");
+        assert_eq!(files[1].size(), 32768);
+    }
+
+    #[test]
+    fn test_setparam() {
+        let tap = "tests/out_setparam.tap";
+        std::fs::copy("tests/jetset.tap", tap).unwrap();
+        let args = |index| SetparamArgs {
+            index,
+            file_type: None,
+            autostart: None,
+            load_addr: None,
+            vars_offset: None,
+        };
+        setparam(
+            tap,
+            SetparamArgs {
+                autostart: Some("none".to_string()),
+                ..args(0)
+            },
+        )
+        .unwrap();
+        setparam(
+            tap,
+            SetparamArgs {
+                load_addr: Some(0x6000),
+                ..args(1)
+            },
+        )
+        .unwrap();
+        assert!(setparam(tap, args(1)).is_err());
+        assert!(setparam(
+            tap,
+            SetparamArgs {
+                autostart: Some("10".to_string()),
+                ..args(1)
+            }
+        )
+        .is_err());
+
+        let files = SpeccyFile::load_tap_file(&mut File::open(tap).unwrap(), false).unwrap();
+        let SpeccyFile::Program(program) = &files[0] else {
+            panic!("not a program");
+        };
+        assert_eq!(program.get_autostart_line(), None);
+        let SpeccyFile::Code(code) = &files[1] else {
+            panic!("not code");
+        };
+        assert_eq!(code.load_address(), 0x6000);
+
+        setparam(
+            tap,
+            SetparamArgs {
+                file_type: Some(SpeccyFileType::Program),
+                autostart: Some("1".to_string()),
+                vars_offset: Some(0),
+                ..args(1)
+            },
+        )
+        .unwrap();
+        let files = SpeccyFile::load_tap_file(&mut File::open(tap).unwrap(), false).unwrap();
+        assert_eq!(files[1].file_type(), SpeccyFileType::Program);
+    }
+
+    #[test]
+    fn test_fix_checksums() {
+        let tap = "tests/out_checksums.tap";
+        let mut data = std::fs::read("tests/jetset.tap").unwrap();
+        // the checksums of the first header and of the last data block
+        data[20] ^= 0x01;
+        *data.last_mut().unwrap() ^= 0x80;
+        std::fs::write(tap, &data).unwrap();
+        assert!(SpeccyFile::load_tap_file(&mut File::open(tap).unwrap(), false).is_err());
+        let files = SpeccyFile::load_tap_file(&mut File::open(tap).unwrap(), true).unwrap();
+        assert_eq!(files.len(), 2);
+
+        fix_checksums(tap).unwrap();
+        assert_eq!(std::fs::read(tap).unwrap(), std::fs::read("tests/jetset.tap").unwrap());
+    }
+
+    #[test]
+    fn test_explode_names() {
+        let dir = "tests/out_explode/";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir).unwrap();
+        let args = |filter| ExplodeArgs {
+            prefix: Some(dir.to_string()),
+            use_names: true,
+            filter,
+        };
+        explode("tests/jetset.tap", args(vec![SpeccyFileType::Code]), false).unwrap();
+        explode("tests/jetset.tap", args(vec![]), false).unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        // files of the first run are kept
+        assert_eq!(names, ["Jetset1.prg", "Jetset2.cod", "Jetset2~1.cod"]);
+
+        let args = ExplodeArgs {
+            prefix: Some(format!("{}x_", dir)),
+            use_names: true,
+            filter: vec![SpeccyFileType::Code],
+        };
+        explode("tests/jetset.tap", args, false).unwrap();
+        assert!(std::path::Path::new(dir).join("x_Jetset2.cod").exists());
+    }
+}
@@ -0,0 +1,92 @@
+use clap::ValueEnum;
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// When to color the output.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ColorChoice {
+    /// if stdout is a terminal and NO_COLOR isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+/// Highlights of the listings and reports.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Style {
+    /// deleted directory entries
+    Deleted,
+    /// system files, hidden from the CP/M DIR listing
+    System,
+    /// executables (.COM)
+    Executable,
+    /// differences found by comparisons
+    Mismatch,
+}
+
+impl Style {
+    /// The prettytable style specification.
+    pub fn spec(self) -> &'static str {
+        match self {
+            Style::Deleted => "Fr",
+            Style::System => "FD",
+            Style::Executable => "bFg",
+            Style::Mismatch => "bFr",
+        }
+    }
+
+    fn ansi(self) -> &'static str {
+        match self {
+            Style::Deleted => "31",
+            Style::System => "90",
+            Style::Executable => "1;32",
+            Style::Mismatch => "1;31",
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turns the colors on or off for the rest of the run.
+pub fn init(choice: ColorChoice) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    ENABLED.store(resolve(choice, no_color, io::stdout().is_terminal()), Ordering::Relaxed);
+}
+
+fn resolve(choice: ColorChoice, no_color: bool, terminal: bool) -> bool {
+    match choice {
+        ColorChoice::Auto => terminal && !no_color,
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns the text wrapped in the escape sequences of the style, or as is if colors are off.
+pub fn paint(text: impl Display, style: Style) -> String {
+    if enabled() {
+        format!("\x1b[{}m{}\x1b[0m", style.ansi(), text)
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{paint, resolve, ColorChoice, Style};
+
+    #[test]
+    fn test_resolve() {
+        assert!(resolve(ColorChoice::Auto, false, true));
+        assert!(!resolve(ColorChoice::Auto, true, true));
+        assert!(!resolve(ColorChoice::Auto, false, false));
+        assert!(resolve(ColorChoice::Always, true, false));
+        assert!(!resolve(ColorChoice::Never, false, true));
+        // colors are off unless initialized
+        assert_eq!(paint(42, Style::Mismatch), "42");
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{Context, Result};
+use fast_glob::glob_match;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::charset::Charset;
+use crate::error::{ErrorKind, Failure};
+
+/// User configuration, e.g.:
+///
+/// ```toml
+/// default_format = "junior"
+///
+/// [[rule]]
+/// files = ["*.TXT", "*.PAS", "*.SUB"]
+/// text = true
+/// charset = "latin2"
+///
+/// [[rule]]
+/// files = ["*.COM", "*.COD"]
+/// text = false
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// disk format used when none is given with --disk-format or $JUDIM_FORMAT
+    pub default_format: Option<String>,
+    /// transfer rules of get, put and cp, the first matching one applies
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<TransferRule>,
+}
+
+/// Transfer mode of files matching any of the globs (case-insensitive).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TransferRule {
+    pub files: Vec<String>,
+    pub text: bool,
+    pub charset: Option<Charset>,
+}
+
+impl TransferRule {
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.to_ascii_uppercase();
+        self.files
+            .iter()
+            .any(|glob| glob_match(glob.to_ascii_uppercase(), &name))
+    }
+}
+
+impl Config {
+    /// Loads the config file, if there is one.
+    pub fn load() -> Result<Config> {
+        match Self::path() {
+            Some(path) if path.exists() => {
+                let text = fs::read_to_string(&path).with_context(|| format!("Can't read {}", path.display()))?;
+                Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+            }
+            _ => Ok(Config::default()),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Config> {
+        toml::from_str(text).map_err(|e| Failure::new(ErrorKind::Usage, e.to_string()).into())
+    }
+
+    /// Config file location: $JUDIM_CONFIG, $XDG_CONFIG_HOME/judim/config.toml,
+    /// ~/.config/judim/config.toml, or %APPDATA%\judim\config.toml on Windows.
+    fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os("JUDIM_CONFIG").filter(|p| !p.is_empty()) {
+            return Some(PathBuf::from(path));
+        }
+        let dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+        Some(dir.join("judim").join("config.toml"))
+    }
+}
+
+/// Disk format to use when --disk-format isn't given: $JUDIM_FORMAT, or default_format of the
+/// config file.
+pub fn default_format() -> Result<Option<String>> {
+    match env::var("JUDIM_FORMAT") {
+        Ok(format) if !format.is_empty() => Ok(Some(format)),
+        _ => Ok(Config::load()?.default_format),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::charset::Charset;
+
+    #[test]
+    fn test_rules() {
+        let config = Config::parse(
+            r#"
+[[rule]]
+files = ["*.TXT", "*.pas"]
+text = true
+charset = "latin2"
+
+[[rule]]
+files = ["*.COM", "*.cod"]
+text = false
+"#,
+        )
+        .unwrap();
+        let rule = |name| config.rules.iter().find(|r| r.matches(name));
+        assert!(rule("READ.ME").is_none());
+        assert_eq!(
+            rule("prog.pas").map(|r| (r.text, r.charset)),
+            Some((true, Some(Charset::Latin2)))
+        );
+        assert_eq!(rule("GAME.COD").map(|r| (r.text, r.charset)), Some((false, None)));
+
+        assert!(Config::parse("[[rule]]\nfiles = []\ntext = 1\n").is_err());
+        assert!(Config::parse("").unwrap().rules.is_empty());
+    }
+
+    #[test]
+    fn test_default_format() {
+        assert_eq!(
+            Config::parse("default_format = \"pcw\"\n")
+                .unwrap()
+                .default_format
+                .as_deref(),
+            Some("pcw")
+        );
+        assert!(Config::parse("").unwrap().default_format.is_none());
+        assert!(Config::parse("default_format = 3\n").is_err());
+    }
+}
@@ -1,6 +1,10 @@
 mod cpm_fs;
 mod dir_entry;
+mod dpb;
 mod file_id;
+mod timestamp;
 
-pub use cpm_fs::{CpmFs, FileItem, LsMode, Params};
-pub use file_id::MAX_USER_ID;
+pub use cpm_fs::{CpmFs, CpmVersion, EntryStatus, FileItem, LsMode, Params, Placement, RECORD_SIZE};
+pub use dpb::Dpb;
+pub use file_id::{FileId, FilenameMode, MAX_USER_ID};
+pub use timestamp::Timestamp;
@@ -1,20 +1,38 @@
-use crate::cpm::dir_entry::{CpmDirEntry, BLOCKS_PER_EXTENT};
-use crate::cpm::file_id::FileId;
-use crate::dsk::DskImage;
+use crate::cpm::dir_entry::CpmDirEntry;
+use crate::cpm::dpb::Dpb;
+use crate::cpm::file_id::{FileId, FilenameMode, LABEL_USER, TIMESTAMPS_USER};
+use crate::cpm::timestamp::FileTimes;
 use crate::dsk::CHS;
+use crate::dsk::{DiskBackend, DskImage};
+use crate::error::{ErrorKind, Failure, Location};
+use crate::stats;
 use anyhow::{bail, Context, Result};
-use std::cmp::min;
-use std::collections::HashMap;
+use clap::ValueEnum;
+use std::cmp::{min, Ordering};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, Write};
 
 pub const RECORD_SIZE: usize = 128;
 
+/// CP/M version, determines how the directory entries are interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum CpmVersion {
+    /// CP/M 2.2 (and CP/J): strict 7-bit names, no label or date stamp entries
+    #[value(name = "2.2")]
+    V22,
+    /// CP/M Plus: F1'-F4' attributes, label and date stamps, last record byte count (S1)
+    #[value(name = "3")]
+    V3,
+}
+
 /// CP/M filesystem parameters
 #[derive(Clone, Copy, Debug)]
 pub struct Params {
     /// sectors per track (CP/M format requires uniform formatting)
     pub sectors_per_track: u8,
+    /// ID of the first sector of a track, the others are numbered consecutively
+    pub first_sector_id: u8,
     /// tracks (not cylinders!) at the beginning used for booting
     pub reserved_tracks: u8,
     /// size of a sector in bytes
@@ -23,6 +41,351 @@ pub struct Params {
     pub sectors_per_block: u8,
     /// number of blocks reserved for the file directory entries
     pub dir_blocks: u8,
+    /// directory entries semantics
+    pub version: CpmVersion,
+}
+
+/// Directory entry size in bytes.
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Largest allocation block CP/M supports (BSH 7).
+const MAX_BLOCK_SIZE: usize = 16384;
+
+impl Params {
+    /// Starts building parameters, `build()` checks they make a valid CP/M filesystem.
+    pub fn builder() -> ParamsBuilder {
+        ParamsBuilder {
+            params: Params {
+                sectors_per_track: 0,
+                first_sector_id: 1,
+                reserved_tracks: 0,
+                sector_size: 512,
+                sectors_per_block: 0,
+                dir_blocks: 0,
+                version: CpmVersion::V3,
+            },
+            block_size: 0,
+            num_tracks: 0,
+        }
+    }
+
+    /// Checks the combination makes a filesystem CP/M can handle, on a disk with the number of
+    /// tracks (all sides).
+    pub fn validate(&self, num_tracks: u16) -> Result<()> {
+        let sector_size = self.sector_size as usize;
+        if sector_size < RECORD_SIZE || !sector_size.is_power_of_two() {
+            bail!(
+                "Invalid sector size {}, must be a power of two, at least 128",
+                sector_size
+            );
+        }
+        if self.sectors_per_track == 0 {
+            bail!("Invalid number of sectors per track 0");
+        }
+        let block_size = self.block_size();
+        if !(1024..=MAX_BLOCK_SIZE).contains(&block_size) || !block_size.is_power_of_two() {
+            bail!(
+                "Invalid block size {}, must be a power of two from 1K to 16K",
+                block_size
+            );
+        }
+        // AL0/AL1 mark at most 16 directory blocks
+        if !(1..=16).contains(&self.dir_blocks) {
+            bail!(
+                "Invalid number of directory blocks {}, must be 1 to 16",
+                self.dir_blocks
+            );
+        }
+        if num_tracks <= self.reserved_tracks as u16 {
+            bail!(
+                "Invalid number of reserved tracks {}, the disk has {} tracks",
+                self.reserved_tracks,
+                num_tracks
+            );
+        }
+        if self.num_blocks(num_tracks) <= self.dir_blocks as u16 {
+            bail!("Disk of {} tracks too small, no room for the directory", num_tracks);
+        }
+        // an extent maps 16K, a directory entry holding more (EXM > 0) isn't supported
+        let num_blocks = self.num_blocks(num_tracks);
+        if block_size == 1024 && num_blocks > 256 {
+            bail!(
+                "Invalid block size 1K for {} blocks, 16-bit block numbers need at least 2K blocks",
+                num_blocks
+            );
+        }
+        if self.exm(num_blocks) != 0 {
+            bail!(
+                "Unsupported block size {} for {} blocks, directory entries would map more than 16K (EXM {})",
+                block_size,
+                num_blocks,
+                self.exm(num_blocks)
+            );
+        }
+        Ok(())
+    }
+
+    /// Allocation block size in bytes.
+    pub fn block_size(&self) -> usize {
+        self.sector_size as usize * self.sectors_per_block as usize
+    }
+
+    /// Size of the system area (reserved tracks) in bytes.
+    pub fn system_area_size(&self) -> usize {
+        self.reserved_tracks as usize * self.sectors_per_track as usize * self.sector_size as usize
+    }
+
+    /// Number of blocks on a disk with the given number of tracks (all sides), the reserved
+    /// tracks don't belong to any block. The tracks must outnumber the reserved ones, see
+    /// [`Params::validate`].
+    pub fn num_blocks(&self, num_tracks: u16) -> u16 {
+        let num_tracks = num_tracks - self.reserved_tracks as u16;
+        (num_tracks * self.sectors_per_track as u16) / self.sectors_per_block as u16
+    }
+
+    /// Number of directory entries.
+    pub fn dir_entries(&self) -> usize {
+        self.dir_blocks as usize * self.block_size() / DIR_ENTRY_SIZE
+    }
+
+    /// Number of sectors holding the directory.
+    pub fn dir_sectors(&self) -> u16 {
+        self.dir_blocks as u16 * self.sectors_per_block as u16
+    }
+
+    /// 128-byte records per block.
+    pub fn records_per_block(&self) -> usize {
+        self.block_size() / RECORD_SIZE
+    }
+
+    /// 128-byte records per sector.
+    fn records_per_sector(&self) -> usize {
+        self.sector_size as usize / RECORD_SIZE
+    }
+
+    /// 128-byte records per track (SPT).
+    pub fn spt(&self) -> u16 {
+        (self.sectors_per_track as usize * self.records_per_sector()) as u16
+    }
+
+    /// Block shift factor (BSH), log2 of the records per block.
+    pub fn bsh(&self) -> u8 {
+        self.records_per_block().trailing_zeros() as u8
+    }
+
+    /// Block mask (BLM), records per block - 1.
+    pub fn blm(&self) -> u8 {
+        (self.records_per_block() - 1) as u8
+    }
+
+    /// Extent mask (EXM) of a filesystem with the number of blocks: 16K per extent, but 16-bit
+    /// block numbers halve the number of blocks per entry.
+    pub fn exm(&self, num_blocks: u16) -> u8 {
+        let exm = if num_blocks <= 256 {
+            self.block_size() / 1024 - 1
+        } else {
+            self.block_size() / 2048 - 1
+        };
+        exm as u8
+    }
+
+    /// Number of the last directory entry (DRM).
+    pub fn drm(&self) -> u16 {
+        (self.dir_entries() - 1) as u16
+    }
+
+    /// Directory blocks allocation bitmap (AL0 is the high byte).
+    pub fn al(&self) -> u16 {
+        !(0xFFFFu16 >> self.dir_blocks)
+    }
+
+    /// Size of the directory check vector (CKS), removable media check every directory record.
+    pub fn cks(&self) -> u16 {
+        (self.dir_entries() / 4) as u16
+    }
+
+    /// Number of reserved tracks (OFF).
+    pub fn off(&self) -> u16 {
+        self.reserved_tracks as u16
+    }
+
+    /// Physical record shift factor (PSH, CP/M Plus only).
+    pub fn psh(&self) -> u8 {
+        self.records_per_sector().trailing_zeros() as u8
+    }
+
+    /// Physical record mask (PHM, CP/M Plus only).
+    pub fn phm(&self) -> u8 {
+        (self.records_per_sector() - 1) as u8
+    }
+}
+
+/// Builds [`Params`], see [`Params::builder`]. The first sector ID defaults to 1, the sector
+/// size to 512 bytes, there are no reserved tracks unless given. The number of tracks of the
+/// disk is only used for validation.
+pub struct ParamsBuilder {
+    params: Params,
+    /// block size in bytes, converted to sectors once the sector size is known
+    block_size: usize,
+    /// tracks of the disk (all sides)
+    num_tracks: u16,
+}
+
+impl ParamsBuilder {
+    pub fn sectors_per_track(mut self, sectors_per_track: u8) -> Self {
+        self.params.sectors_per_track = sectors_per_track;
+        self
+    }
+
+    pub fn reserved_tracks(mut self, reserved_tracks: u8) -> Self {
+        self.params.reserved_tracks = reserved_tracks;
+        self
+    }
+
+    pub fn sector_size(mut self, sector_size: u16) -> Self {
+        self.params.sector_size = sector_size;
+        self
+    }
+
+    /// Allocation block size in bytes, a multiple of the sector size.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn dir_blocks(mut self, dir_blocks: u8) -> Self {
+        self.params.dir_blocks = dir_blocks;
+        self
+    }
+
+    pub fn version(mut self, version: CpmVersion) -> Self {
+        self.params.version = version;
+        self
+    }
+
+    /// Tracks of the disk, all sides.
+    pub fn num_tracks(mut self, num_tracks: u16) -> Self {
+        self.num_tracks = num_tracks;
+        self
+    }
+
+    pub fn build(self) -> Result<Params> {
+        let mut params = self.params;
+        let sector_size = params.sector_size.max(1) as usize;
+        if !self.block_size.is_multiple_of(sector_size) || self.block_size / sector_size > u8::MAX as usize {
+            bail!(
+                "Invalid block size {}, must be a multiple of the sector size {}",
+                self.block_size,
+                sector_size
+            );
+        }
+        params.sectors_per_block = (self.block_size / sector_size) as u8;
+        params.validate(self.num_tracks)?;
+        Ok(params)
+    }
+}
+
+/// A directory entry of a file, i.e. one extent.
+#[derive(Clone, Debug)]
+pub struct FileExtent {
+    /// directory slot of the entry
+    pub slot: usize,
+    /// extent number
+    pub extent: u16,
+    /// number of 128-byte records in the extent
+    pub record_count: u8,
+    pub blocks: Vec<u16>,
+}
+
+/// Where writes allocate blocks, see [`CpmFs::set_placement`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Placement {
+    /// block the file must start at
+    pub first_block: Option<u16>,
+    /// the file must occupy consecutive blocks
+    pub contiguous: bool,
+}
+
+/// Kind of a directory slot, see [`DirEntryView::status`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryStatus {
+    /// an extent of a file
+    File,
+    /// a deleted extent, with a plausible block list
+    Deleted,
+    /// never used, or nothing worth recovering
+    Unused,
+    /// CP/M Plus directory label
+    Label,
+    /// CP/M Plus date stamps (SFCB) of the 3 preceding slots
+    Timestamps,
+    /// any other user byte
+    Other,
+}
+
+/// Read-only view of a raw directory entry, see [`CpmFs::dir_entries`].
+pub struct DirEntryView<'a> {
+    slot: usize,
+    entry: &'a CpmDirEntry,
+    status: EntryStatus,
+}
+
+impl DirEntryView<'_> {
+    /// directory slot number
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    pub fn status(&self) -> EntryStatus {
+        self.status
+    }
+
+    /// user and name as stored, the user byte is 0xE5 for unused entries
+    pub fn file_id(&self) -> FileId {
+        self.entry.file_id
+    }
+
+    pub fn extent(&self) -> u16 {
+        self.entry.extent
+    }
+
+    /// number of 128-byte records in the extent
+    pub fn record_count(&self) -> u8 {
+        self.entry.record_count
+    }
+
+    /// allocated blocks, without the trailing zeros
+    pub fn blocks(&self) -> Vec<u16> {
+        self.entry.blocks()
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.entry.read_only
+    }
+
+    pub fn system_file(&self) -> bool {
+        self.entry.system_file
+    }
+
+    pub fn archived(&self) -> bool {
+        self.entry.archived
+    }
+
+    /// F1'-F4' attributes (CP/M Plus only), bit 0 is F1'
+    pub fn attributes(&self) -> u8 {
+        self.entry.attributes
+    }
+}
+
+/// Directory slots freed by [`CpmFs::compact_directory`].
+#[derive(Debug, Default, PartialEq)]
+pub struct CompactStats {
+    /// deleted file entries, wiped
+    pub deleted: usize,
+    /// label entries other than the first one
+    pub labels: usize,
+    /// date stamp (SFCB) slots, when the label doesn't enable date stamps
+    pub stamps: usize,
 }
 
 pub enum LsMode {
@@ -30,8 +393,12 @@ pub enum LsMode {
     All,
     /// List only files owned bya  given user.
     OwnedBy(u8),
+    /// List only files owned by any of the given users.
+    OwnedByAny(Vec<u8>),
     /// List all files, included deleted ones.
     Deleted,
+    /// List only (likely) deleted files.
+    DeletedOnly,
 }
 
 /// Filesystem file list element.
@@ -45,29 +412,63 @@ pub struct FileItem {
     pub size: usize,
     /// list of the blocks (LBAs) occupied by the file
     pub block_list: Vec<u16>,
+    /// number of directory entries (extents) of the file
+    pub extents: usize,
+    /// read-only flag (of the first extent)
+    pub read_only: bool,
+    /// system file flag (of the first extent)
+    pub system_file: bool,
+    /// archived flag (of the first extent)
+    pub archived: bool,
+}
+
+impl FileItem {
+    /// Returns the R/O, SYS and ARC flags in a compact form, e.g. "RS-".
+    pub fn flags(&self) -> String {
+        [(self.read_only, 'R'), (self.system_file, 'S'), (self.archived, 'A')]
+            .iter()
+            .map(|&(set, c)| if set { c } else { '-' })
+            .collect()
+    }
+
+    /// Collation of listings: by user, deleted files (no user) last, then by name, byte-wise
+    /// (i.e. alphabetically, as names are upper case ASCII). Use with a stable sort, so that
+    /// deleted files of the same name keep the directory order.
+    pub fn listing_cmp(&self, other: &FileItem) -> Ordering {
+        (self.user.is_none(), self.user, &self.name).cmp(&(other.user.is_none(), other.user, &other.name))
+    }
 }
 
 pub struct CpmFs {
     params: Params,
-    disk: DskImage,
+    disk: Box<dyn DiskBackend>,
     /// total number of filesystem blocks
     num_blocks: u16,
     /// raw directory entries (all, including unused ones)
     dir_entries: Vec<CpmDirEntry>,
     /// used logical blocks (LBA as index, true for used block)
     used_blocks: Vec<bool>,
+    /// allocate never used blocks and directory entries before the ones of deleted files
+    preserve_deleted: bool,
+    placement: Placement,
+    /// directory oddities found when loading, which don't stop the filesystem from working
+    diagnostics: Vec<String>,
 }
 
 impl CpmFs {
-    pub fn load(f: &mut File, params: Params) -> Result<CpmFs> {
-        // TODO: validate params ?
+    pub fn load(f: &mut (impl Read + Seek), params: Params) -> Result<CpmFs> {
+        Self::from_disk(Box::new(DskImage::load(f)?), params)
+    }
 
-        let disk = DskImage::load(f)?;
-        let dir_entries = Self::read_directory(&disk, &params)?;
+    /// Creates the filesystem on top of an already loaded (or freshly formatted) disk image,
+    /// in any container format.
+    pub fn from_disk(disk: Box<dyn DiskBackend>, params: Params) -> Result<CpmFs> {
+        params.validate(disk.num_cylinders() as u16 * disk.num_sides() as u16)?;
+        let dir_entries = stats::time("directory parse", || Self::read_directory(disk.as_ref(), &params))?;
 
-        let num_blocks = (disk.num_cylinders() as u16 * disk.num_sides() as u16 * params.sectors_per_track as u16)
-            / params.sectors_per_block as u16;
-        let used_blocks = Self::calc_used_blocks(num_blocks, &dir_entries)?;
+        let num_blocks = params.num_blocks(disk.num_cylinders() as u16 * disk.num_sides() as u16);
+        let used_blocks = Self::calc_used_blocks(num_blocks, params.dir_blocks, &dir_entries)?;
+        let diagnostics = Self::diagnose(&params, num_blocks, &dir_entries);
 
         Ok(CpmFs {
             params,
@@ -75,235 +476,1048 @@ impl CpmFs {
             num_blocks,
             dir_entries,
             used_blocks,
+            preserve_deleted: false,
+            placement: Placement::default(),
+            diagnostics,
         })
     }
 
+    /// Returns the directory oddities found when loading the image: suspicious record counts,
+    /// unusual flags, deleted entries not taken for deleted files. None of them is an error.
+    pub fn diagnostics(&self) -> &[String] {
+        &self.diagnostics
+    }
+
+    /// Makes writes allocate never used blocks and directory entries first, recycling the ones
+    /// of deleted files only when there's no other choice, so they can still be recovered.
+    pub fn set_preserve_deleted(&mut self, preserve_deleted: bool) {
+        self.preserve_deleted = preserve_deleted;
+    }
+
+    /// Constrains the blocks of the files written next, writes fail if they can't be placed so.
+    pub fn set_placement(&mut self, placement: Placement) {
+        self.placement = placement;
+    }
+
+    /// Writes the whole image (including the directory) back to a given file.
+    pub fn save(&mut self, f: &mut File) -> Result<()> {
+        self.write_directory()?;
+        self.disk.save(f)
+    }
+
+    /// Lists the files, in the directory order (of their first directory entries).
     pub fn list_files(&self, mode: LsMode) -> Result<Vec<FileItem>> {
         let mut file_entries: HashMap<FileId, Vec<&CpmDirEntry>> = HashMap::new();
         let valid_block_range = self.params.dir_blocks as u16..self.num_blocks;
 
-        let condition = |de: &&CpmDirEntry| match mode {
+        let condition = |de: &&CpmDirEntry| match &mode {
             LsMode::All => de.used(),
             LsMode::Deleted => de.used() || de.likely_deleted(&valid_block_range),
-            LsMode::OwnedBy(num) => de.owner() == Some(num),
+            LsMode::DeletedOnly => de.likely_deleted(&valid_block_range),
+            LsMode::OwnedBy(num) => de.owner() == Some(*num),
+            LsMode::OwnedByAny(users) => de.owner().is_some_and(|u| users.contains(&u)),
         };
 
-        // group all the extends belonging to each file
+        // group all the extends belonging to each file, remembering the directory order
+        let mut order = Vec::new();
         for e in self.dir_entries.iter().filter(condition) {
-            file_entries.entry(e.file_id).or_insert_with(Vec::new).push(e);
+            file_entries
+                .entry(e.file_id)
+                .or_insert_with(|| {
+                    order.push(e.file_id);
+                    Vec::new()
+                })
+                .push(e);
         }
 
-        // TODO: use map() ?
         let mut files: Vec<FileItem> = Vec::with_capacity(file_entries.len());
-        for (_, v) in file_entries.iter_mut() {
+        for id in order {
+            let v = file_entries.get_mut(&id).expect("Grouped file missing");
             let first = v[0];
 
             v.sort_unstable_by_key(|e| e.extent);
-            let block_list = self
-                .blocks_from_sorted_extents(v)
-                .with_context(|| format!("File '{}' entry invalid.", first.file_name()))?;
+            let block_list = match self.blocks_from_sorted_extents(v) {
+                Ok(block_list) => block_list,
+                // several deleted files with the same name get mixed up, they can't be validated
+                Err(_) if first.owner().is_none() => v.iter().flat_map(|e| e.blocks()).collect(),
+                Err(e) => {
+                    // the entries are borrowed from the directory, the position of the first is its slot
+                    let slot = self
+                        .dir_entries
+                        .iter()
+                        .position(|e| std::ptr::eq(e, first))
+                        .unwrap_or(0);
+                    return Err(e
+                        .context(Self::slot_location(self.disk.as_ref(), &self.params, slot))
+                        .context(Failure::new(
+                            ErrorKind::Filesystem,
+                            format!("File '{}' entry invalid.", first.file_name()),
+                        )));
+                }
+            };
+
+            let mut size: usize = v.iter().map(|e| e.extent_size()).sum();
+            // CP/M Plus may store the number of bytes used in the last record
+            let last = v[v.len() - 1];
+            if self.params.version == CpmVersion::V3 && last.record_count > 0 && last.last_record_bytes > 0 {
+                size -= RECORD_SIZE - (last.last_record_bytes as usize).min(RECORD_SIZE);
+            }
 
             files.push(FileItem {
                 user: first.owner(),
                 name: first.file_name(),
-                size: v.iter().map(|e| e.extent_size()).sum(),
+                size,
                 block_list,
+                extents: v.len(),
+                read_only: first.read_only,
+                system_file: first.system_file,
+                archived: first.archived,
             })
         }
 
         Ok(files)
     }
 
-    pub fn read_file(&self, file: &FileItem, w: &mut impl Write, text_mode: bool) -> Result<()> {
-        let block_size = self.block_size();
-        let mut buf = vec![0; block_size];
+    /// Iterates over all directory slots, in the directory order, for analyses of the raw entries.
+    pub fn dir_entries(&self) -> impl Iterator<Item = DirEntryView<'_>> {
+        let valid_block_range = self.params.dir_blocks as u16..self.num_blocks;
+        self.dir_entries.iter().enumerate().map(move |(slot, entry)| {
+            let status = match entry.file_id.user {
+                _ if entry.used() => EntryStatus::File,
+                _ if entry.likely_deleted(&valid_block_range) => EntryStatus::Deleted,
+                0xE5 => EntryStatus::Unused,
+                LABEL_USER => EntryStatus::Label,
+                TIMESTAMPS_USER => EntryStatus::Timestamps,
+                _ => EntryStatus::Other,
+            };
+            DirEntryView { slot, entry, status }
+        })
+    }
+
+    /// Reads the file contents, returns the number of bytes written.
+    ///
+    /// Fails if the blocks allocated to the file can't hold its size (as given by record counts).
+    pub fn read_file(&self, file: &FileItem, w: &mut impl Write, text_mode: bool) -> Result<usize> {
+        if self.stored_size(file) < file.size {
+            bail!(Failure::new(
+                ErrorKind::Filesystem,
+                format!(
+                    "File {} is truncated: {} bytes recorded, only {} stored in {} blocks.",
+                    file.name,
+                    file.size,
+                    self.stored_size(file),
+                    file.block_list.len()
+                )
+            ));
+        }
 
         let mut size_left = file.size;
         for block in &file.block_list {
-            self.read_block(*block, &mut buf)?;
-
-            // All chunks are of block_size bytes, except the last one,
-            // which can be shorter.
-            let chunk_size = min(size_left, block_size);
-            let chunk = &buf[0..chunk_size];
-
-            // In text mode we trim the file at first ^Z (0x1A) character.
-            if text_mode {
-                // It should happen in the last chunk, but it makes little sense checking that.
-                // Just write the bytes up to (not including) ^Z and return.
-                if let Some(trim_at) = chunk.iter().position(|&a| a == 0x1A) {
-                    w.write_all(&chunk[0..trim_at])?;
-                    return Ok(());
+            for sector in self.block_sectors(*block) {
+                if size_left == 0 {
+                    return Ok(file.size);
                 }
+                // Sectors are written straight from the image, the last chunk can be shorter.
+                let sector = sector?;
+                let chunk_size = min(size_left, sector.len());
+                let chunk = &sector[0..chunk_size];
+
+                // In text mode we trim the file at first ^Z (0x1A) character.
+                if text_mode {
+                    // It should happen in the last chunk, but it makes little sense checking that.
+                    // Just write the bytes up to (not including) ^Z and return.
+                    if let Some(trim_at) = chunk.iter().position(|&a| a == 0x1A) {
+                        w.write_all(&chunk[0..trim_at])?;
+                        return Ok(file.size - size_left + trim_at);
+                    }
+                }
+
+                w.write_all(chunk)?;
+                size_left -= chunk_size;
             }
+        }
+        Ok(file.size)
+    }
+
+    /// Returns CP/M Plus time stamps of the file, if the directory has them.
+    ///
+    /// Stamps are kept in every 4th directory slot (SFCB), for the 3 entries preceding it,
+    /// the first extent of the file is the one stamped.
+    pub fn file_times(&self, file: &FileItem) -> Result<Option<FileTimes>> {
+        let Some(slot) = self
+            .dir_entries
+            .iter()
+            .position(|e| e.used() && e.owner() == file.user && e.file_name() == file.name && e.extent == 0)
+        else {
+            return Ok(None);
+        };
 
-            w.write_all(&buf[0..chunk_size])?;
-            size_left -= chunk_size;
+        let sfcb_slot = slot | 3;
+        if slot == sfcb_slot || self.dir_entries[sfcb_slot].file_id.user != TIMESTAMPS_USER {
+            return Ok(None);
         }
-        assert_eq!(size_left, 0);
-        Ok(())
+        // note: SFCB is read from the disk, as dir entries don't keep all the raw bytes
+        let sfcb = self.read_dir_slot(sfcb_slot)?;
+        Ok(Some(FileTimes::from_sfcb(&sfcb, slot % 4)))
     }
 
-    pub fn write_file(&mut self, id: &FileId, file: &mut File, text_mode: bool) -> Result<()> {
-        file.seek(SeekFrom::Start(0))?;
-        let file_size = file.metadata()?.len() as usize;
-        let block_size = self.block_size();
+    /// Returns the blocks of the file which are currently allocated (to any file).
+    ///
+    /// For a deleted file it tells which of its blocks have been reused since.
+    pub fn allocated_blocks(&self, file: &FileItem) -> Vec<u16> {
+        file.block_list
+            .iter()
+            .copied()
+            .filter(|&b| self.used_blocks.get(b as usize).copied().unwrap_or(true))
+            .collect()
+    }
 
-        let num_blocks = file_size.div_ceil(block_size);
-        let num_dents = num_blocks.div_ceil(BLOCKS_PER_EXTENT);
-        let blocks = self.get_free_blocks(num_blocks)?;
-        let dents = self.get_free_dents(num_dents)?;
+    /// Returns the directory entries of the file, in extent order.
+    pub fn file_extents(&self, file: &FileItem) -> Vec<FileExtent> {
+        let mut extents: Vec<FileExtent> = self
+            .dir_entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.used() && e.owner() == file.user && e.file_name() == file.name)
+            .map(|(slot, e)| FileExtent {
+                slot,
+                extent: e.extent,
+                record_count: e.record_count,
+                blocks: e.blocks(),
+            })
+            .collect();
+        extents.sort_by_key(|e| e.extent);
+        extents
+    }
+
+    /// Returns the number of bytes the file's blocks can hold.
+    pub fn stored_size(&self, file: &FileItem) -> usize {
+        file.block_list.len() * self.block_size()
+    }
+
+    /// Writes a new file to the filesystem, returns the list of allocated blocks.
+    ///
+    /// In text mode the file is terminated with ^Z, unless it ends at the record boundary.
+    /// The last record is padded with ^Z (text mode) or zeros.
+    pub fn write_file(&mut self, id: &FileId, file: &mut impl Read, text_mode: bool) -> Result<Vec<u16>> {
+        if self.file_exists(id) {
+            bail!(Failure::new(
+                ErrorKind::Exists,
+                format!("File {} already exists", id.filename())
+            ));
+        }
+        if let Some(first) = self.placement.first_block.filter(|&b| b >= self.num_blocks) {
+            bail!(Failure::new(
+                ErrorKind::Usage,
+                format!(
+                    "Block {} out of range, the disk has blocks 0-{}",
+                    first,
+                    self.num_blocks - 1
+                )
+            ));
+        }
 
-        // files are so small here, that we can read them at once
-        let mut buf = vec![0; file_size];
-        file.read_exact(&mut buf)?;
-        for (chunk, block) in buf.chunks_mut(block_size).zip(&blocks) {
-            // we terminate text files in the last block, unless it's a block boundary
-            // (it's not needed in such case, block size is always a multiple of record size)
-            if text_mode && chunk.len() < block_size {
-                chunk[chunk.len()] = 0x1A;
+        // The source is read in block-sized chunks, no more of them than there are free blocks.
+        // The blocks and directory entries are found before anything is written, so a failed
+        // write leaves the filesystem unchanged, including the free blocks of deleted files.
+        let block_size = self.block_size();
+        let padding = if text_mode { 0x1A } else { 0x00 };
+        let available = self.free_blocks();
+        let mut chunks: Vec<Vec<u8>> = vec![];
+        loop {
+            let mut buf = Vec::with_capacity(block_size);
+            file.by_ref().take(block_size as u64).read_to_end(&mut buf)?;
+            if buf.is_empty() {
+                break;
+            }
+            if chunks.len() == available {
+                // read the rest, to report how many blocks the file needs
+                let rest = io::copy(file, &mut io::sink())? as usize;
+                bail!(Failure::new(
+                    ErrorKind::DiskFull,
+                    format!(
+                        "Not enough free blocks: {} available, {} required",
+                        available,
+                        (chunks.len() * block_size + buf.len() + rest).div_ceil(block_size)
+                    )
+                ));
             }
+            buf.resize(buf.len().next_multiple_of(RECORD_SIZE), padding);
+            chunks.push(buf);
+        }
+        let free = if self.placement.contiguous {
+            self.contiguous_free_blocks(chunks.len())?
+        } else {
+            self.free_blocks_in_order()
+        };
+        if let Some(first) = self.placement.first_block {
+            if free.first() != Some(&first) {
+                bail!(Failure::new(
+                    ErrorKind::DiskFull,
+                    format!("Block {} is not free", first)
+                ));
+            }
+        }
+        // note: even an empty file needs a directory entry
+        let blocks_per_extent = self.blocks_per_extent();
+        let num_dents = chunks.len().div_ceil(blocks_per_extent).max(1);
+        let dents = self.get_free_dents(num_dents)?;
 
-            self.write_block(*block, chunk)?;
+        let blocks = free[..chunks.len()].to_vec();
+        let mut size = 0;
+        for (buf, &block) in chunks.iter().zip(&blocks) {
+            self.write_block(block, buf)?;
+            size += buf.len();
+        }
+        for block in &blocks {
             self.used_blocks[*block as usize] = true;
         }
 
-        let mut size_left = file_size;
-        let max_bytes_per_extent = block_size * BLOCKS_PER_EXTENT;
-        for ((extent_idx, &dir_entry), blocks) in dents.iter().enumerate().zip(blocks.chunks(BLOCKS_PER_EXTENT)) {
+        let mut size_left = size;
+        let max_bytes_per_extent = block_size * blocks_per_extent;
+        for (extent_idx, &dir_entry) in dents.iter().enumerate() {
             let size = min(size_left, max_bytes_per_extent);
             size_left -= size;
 
             let records = size.div_ceil(RECORD_SIZE);
-            self.dir_entries[dir_entry] = CpmDirEntry::new(*id, extent_idx as u16, records as u8, blocks);
+            let extent_blocks = blocks.chunks(blocks_per_extent).nth(extent_idx).unwrap_or(&[]);
+            self.dir_entries[dir_entry] =
+                CpmDirEntry::new(*id, extent_idx as u16, records as u8, extent_blocks, self.wide_blocks());
         }
 
-        Ok(())
+        Ok(blocks)
     }
 
-    pub fn block_size(&self) -> usize {
-        self.params.sector_size as usize * self.params.sectors_per_block as usize
+    /// Returns true if there's a file with a given ID.
+    pub fn file_exists(&self, id: &FileId) -> bool {
+        self.dir_entries.iter().any(|e| e.used() && e.file_id == *id)
     }
 
-    pub fn read_block(&self, block: u16, buf: &mut [u8]) -> Result<()> {
-        let first_lsi = block * self.params.sectors_per_block as u16;
-        let sides = self.disk.num_sides();
-        let sect_size = self.params.sector_size as usize;
-        for i in 0..self.params.sectors_per_block {
-            let chs = Self::lsi_to_chs(&self.params, sides, first_lsi + i as u16);
-            let buf_offs = i as usize * self.params.sector_size as usize;
-            buf[buf_offs..buf_offs + sect_size].copy_from_slice(self.disk.sector_as_slice(chs)?);
+    /// Creates a zero-length file, i.e. a single directory entry without any blocks.
+    pub fn create_empty_file(&mut self, id: &FileId) -> Result<()> {
+        if self.file_exists(id) {
+            bail!(Failure::new(
+                ErrorKind::Exists,
+                format!("File {} already exists", id.filename())
+            ));
         }
+        let slot = self.get_free_dents(1)?[0];
+        self.dir_entries[slot] = CpmDirEntry::new(*id, 0, 0, &[], self.wide_blocks());
         Ok(())
     }
 
-    pub fn write_block(&mut self, block: u16, buf: &[u8]) -> Result<()> {
-        let first_lsi = block * self.params.sectors_per_block as u16;
-        let sides = self.disk.num_sides();
-        let sect_size = self.params.sector_size as usize;
-        assert!(buf.len() <= self.params.sectors_per_block as usize * sect_size);
+    /// Deletes a file, i.e. marks all its directory entries as unused and frees its blocks.
+    pub fn delete_file(&mut self, file: &FileItem) -> Result<()> {
+        if file.user.is_none() {
+            bail!("File {} is already deleted", file.name);
+        }
 
-        for (i, chunk) in buf.chunks(sect_size).enumerate() {
-            let chs = Self::lsi_to_chs(&self.params, sides, first_lsi + i as u16);
-            let sect = self.disk.sector_as_slice_mut(chs)?;
-            sect[0..chunk.len()].copy_from_slice(chunk);
+        let mut found = false;
+        for e in self
+            .dir_entries
+            .iter_mut()
+            .filter(|e| e.used() && e.owner() == file.user && e.file_name() == file.name)
+        {
+            for b in e.blocks() {
+                self.used_blocks[b as usize] = false;
+            }
+            e.file_id.user = 0xE5;
+            found = true;
+        }
+
+        if !found {
+            bail!("File {} not found", file.name);
         }
         Ok(())
     }
 
-    fn blocks_from_sorted_extents(&self, extents: &mut Vec<&CpmDirEntry>) -> Result<Vec<u16>> {
-        let records_per_sector = self.params.sector_size as usize / RECORD_SIZE;
-        let records_per_extent = self.params.sectors_per_block as usize * records_per_sector * BLOCKS_PER_EXTENT;
+    /// Sets or clears the R/O flag in all the directory entries of the file.
+    pub fn set_read_only(&mut self, id: &FileId, read_only: bool) -> Result<()> {
+        let mut found = false;
+        for e in self.dir_entries.iter_mut().filter(|e| e.used() && e.file_id == *id) {
+            e.read_only = read_only;
+            found = true;
+        }
 
-        for (idx, e) in extents.iter().enumerate() {
-            // ensure extents are numbered 0..n-1
-            if e.extent as usize != idx {
-                bail!("Inconsistent extent index (expected {}, found {}).", idx, e.extent);
-            }
-            // ensure all extents but the last are fully filled
-            if idx < extents.len() - 1 && (e.record_count as usize) < records_per_extent {
-                bail!(
-                    "Extent {} is too small ({} records, {} expected).",
-                    idx,
-                    e.record_count,
-                    records_per_extent
-                );
-            }
+        if !found {
+            bail!("File {} not found", id.filename());
+        }
+        Ok(())
+    }
+
+    /// Sets the R/O, SYS and ARC flags in all the directory entries of the file to those of
+    /// another file, e.g. the one it was copied from.
+    pub fn copy_flags(&mut self, id: &FileId, from: &FileItem) -> Result<()> {
+        let mut found = false;
+        for e in self.dir_entries.iter_mut().filter(|e| e.used() && e.file_id == *id) {
+            e.read_only = from.read_only;
+            e.system_file = from.system_file;
+            e.archived = from.archived;
+            found = true;
         }
 
-        let block_list = extents.iter().map(|e| e.blocks()).flatten().collect();
-        Ok(block_list)
+        if !found {
+            bail!("File {} not found", id.filename());
+        }
+        Ok(())
     }
 
-    fn get_free_blocks(&self, count: usize) -> Result<Vec<u16>> {
-        let blocks: Vec<u16> = self
-            .used_blocks
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, used)| if !used { Some(idx as u16) } else { None })
-            .take(count)
+    /// Brings a deleted file back as the user's, restoring its directory entries and marking
+    /// its blocks used. Fails if any of the blocks hold other files now.
+    pub fn undelete_file(&mut self, file: &FileItem, user: u8) -> Result<()> {
+        if file.user.is_some() {
+            bail!("File {} is not deleted", file.name);
+        }
+        let reused = self.allocated_blocks(file).len();
+        if reused > 0 {
+            bail!(Failure::new(
+                ErrorKind::Filesystem,
+                format!(
+                    "File {} can't be undeleted, {} of its {} blocks hold other files now",
+                    file.name,
+                    reused,
+                    file.block_list.len()
+                )
+            ));
+        }
+        let id = FileId::new_with_filename(user, &file.name, FilenameMode::AsIs)?;
+        if self.file_exists(&id) {
+            bail!(Failure::new(
+                ErrorKind::Exists,
+                format!("File {}:{} already exists", user, file.name)
+            ));
+        }
+
+        let valid_block_range = self.params.dir_blocks as u16..self.num_blocks;
+        let slots: Vec<usize> = (0..self.dir_entries.len())
+            .filter(|&slot| {
+                let e = &self.dir_entries[slot];
+                e.likely_deleted(&valid_block_range) && e.file_name() == file.name
+            })
             .collect();
-        if blocks.len() < count {
-            bail!("Not enough free blocks: {} available, {} required", blocks.len(), count);
+        let mut extents: Vec<u16> = slots.iter().map(|&slot| self.dir_entries[slot].extent).collect();
+        extents.sort_unstable();
+        extents.dedup();
+        if extents.len() < slots.len() {
+            bail!(Failure::new(
+                ErrorKind::Filesystem,
+                format!(
+                    "Several deleted files are named {}, they can't be told apart",
+                    file.name
+                )
+            ));
         }
 
-        Ok(blocks)
+        for slot in slots {
+            let e = &mut self.dir_entries[slot];
+            for b in e.blocks() {
+                self.used_blocks[b as usize] = true;
+            }
+            e.file_id.user = user;
+        }
+        Ok(())
     }
 
-    fn get_free_dents(&self, count: usize) -> Result<Vec<usize>> {
-        let dents: Vec<usize> = self
+    /// Renames the file (possibly moving it to another user), keeping its flags and blocks.
+    pub fn rename_file(&mut self, file: &FileItem, id: &FileId) -> Result<()> {
+        if file.user.is_none() {
+            bail!("File {} is deleted", file.name);
+        }
+        if self.file_exists(id) {
+            bail!(Failure::new(
+                ErrorKind::Exists,
+                format!("File {}:{} already exists", id.user, id.filename())
+            ));
+        }
+
+        let mut found = false;
+        for e in self
             .dir_entries
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, d)| if !d.used() { Some(idx) } else { None })
-            .take(count)
-            .collect();
-        if dents.len() < count {
-            bail!(
-                "Not enough free directory entries: {} available, {} required",
-                dents.len(),
-                count
-            );
+            .iter_mut()
+            .filter(|e| e.used() && e.owner() == file.user && e.file_name() == file.name)
+        {
+            e.file_id = *id;
+            found = true;
         }
 
-        Ok(dents)
+        if !found {
+            bail!("File {} not found", file.name);
+        }
+        Ok(())
     }
 
-    /// Converts a logical sector index to a CHS sector address.
-    fn lsi_to_chs(params: &Params, sides: u8, lsi: u16) -> CHS {
-        let track = lsi / params.sectors_per_track as u16 + params.reserved_tracks as u16;
-        // note: +1, because sector IDs start from 1
-        let sector = (lsi % params.sectors_per_track as u16) as u8 + 1;
+    /// Sorts the directory in place: the label first, then file entries by user, name and extent,
+    /// then everything else (unused and deleted entries included) in the original order.
+    /// Returns the number of slots whose contents changed.
+    ///
+    /// Raw slots are moved, so deleted entries stay recoverable. If the directory has date stamps,
+    /// every 4th slot (SFCB) stays in place, the stamps move with their entries.
+    pub fn sort_directory(&mut self) -> Result<usize> {
+        self.write_directory()?;
+        let slots = self.dir_entries.len();
+        let raw = (0..slots)
+            .map(|slot| self.read_dir_slot(slot))
+            .collect::<Result<Vec<_>>>()?;
+        let stamped = raw.iter().any(|r| r[0] == TIMESTAMPS_USER);
+        let is_sfcb = |slot: usize| stamped && slot % 4 == 3;
+        if stamped && (0..slots).any(|slot| is_sfcb(slot) != (raw[slot][0] == TIMESTAMPS_USER)) {
+            bail!(Failure::new(
+                ErrorKind::Filesystem,
+                "Date stamps (SFCB) are not in every 4th directory slot, can't sort the directory."
+            ));
+        }
+        // stamps of an entry are 10 bytes in the following SFCB, at offset 1 + 10 * (slot % 4)
+        let stamps_range = |slot: usize| 1 + (slot % 4) * 10..11 + (slot % 4) * 10;
+
+        let mut order: Vec<usize> = (0..slots).filter(|&slot| !is_sfcb(slot)).collect();
+        // note: stable sort, so the order of other entries is kept
+        order.sort_by_key(|&slot| {
+            let e = &self.dir_entries[slot];
+            match e.file_id.user {
+                LABEL_USER => (0, None),
+                _ if e.used() => (1, Some((e.file_id.user, e.file_name(), e.extent))),
+                _ => (2, None),
+            }
+        });
+
+        let mut sorted = raw.clone();
+        let targets = (0..slots).filter(|&slot| !is_sfcb(slot));
+        for (slot, src) in targets.zip(order) {
+            sorted[slot] = raw[src];
+            if stamped {
+                sorted[slot | 3][stamps_range(slot)].copy_from_slice(&raw[src | 3][stamps_range(src)]);
+            }
+        }
+
+        let mut changed = 0;
+        for (slot, data) in sorted.iter().enumerate() {
+            if *data != raw[slot] {
+                self.write_dir_slot(slot, data)?;
+                changed += 1;
+            }
+        }
+        self.dir_entries = Self::read_directory(self.disk.as_ref(), &self.params)?;
+        Ok(changed)
+    }
+
+    /// Frees directory slots for good: deleted entries are overwritten with 0xE5 (so they can't
+    /// be recovered anymore), extra label entries are removed, and so are the date stamp slots
+    /// (SFCB) unless the label enables date stamps. Stamps of unused entries are cleared.
+    pub fn compact_directory(&mut self) -> Result<CompactStats> {
+        self.write_directory()?;
+        let slots = self.dir_entries.len();
+        let mut raw = (0..slots)
+            .map(|slot| self.read_dir_slot(slot))
+            .collect::<Result<Vec<_>>>()?;
+        let original = raw.clone();
+        let mut stats = CompactStats::default();
+
+        let label = raw.iter().position(|r| r[0] == LABEL_USER);
+        for r in raw.iter_mut().skip(label.map_or(0, |l| l + 1)) {
+            if r[0] == LABEL_USER {
+                *r = [0xE5; 32];
+                stats.labels += 1;
+            }
+        }
+        // note: extent byte of a label entry holds label flags, 0x70 are create, update and access stamps
+        let stamps_enabled = label.is_some_and(|l| raw[l][12] & 0x70 != 0);
+        for r in raw.iter_mut() {
+            match r[0] {
+                0xE5 if *r != [0xE5; 32] => {
+                    *r = [0xE5; 32];
+                    stats.deleted += 1;
+                }
+                TIMESTAMPS_USER if !stamps_enabled => {
+                    *r = [0xE5; 32];
+                    stats.stamps += 1;
+                }
+                _ => {}
+            }
+        }
+        // stamps of unused entries, in the SFCB following them
+        for slot in (0..slots).filter(|&slot| slot % 4 != 3) {
+            if raw[slot][0] == 0xE5 && raw[slot | 3][0] == TIMESTAMPS_USER {
+                raw[slot | 3][1 + (slot % 4) * 10..11 + (slot % 4) * 10].fill(0);
+            }
+        }
+
+        for slot in 0..slots {
+            if raw[slot] != original[slot] {
+                self.write_dir_slot(slot, &raw[slot])?;
+            }
+        }
+        self.dir_entries = Self::read_directory(self.disk.as_ref(), &self.params)?;
+        Ok(stats)
+    }
+
+    /// Returns the disk label (CP/M Plus directory label entry), if there's one.
+    pub fn label(&self) -> Option<String> {
+        self.dir_entries.iter().find(|e| e.is_label()).map(|e| {
+            let name = String::from_utf8_lossy(&e.file_id.name).trim_end().to_string();
+            let ext = e.file_id.extension.map(|b| b & 0x7F);
+            let ext = String::from_utf8_lossy(&ext).trim_end().to_string();
+            if ext.is_empty() {
+                name
+            } else {
+                format!("{}.{}", name, ext)
+            }
+        })
+    }
+
+    /// Sets (or removes, if None) the disk label.
+    ///
+    /// Label follows 8.3 file name rules, extension is optional. Existing label entry
+    /// is reused, otherwise a free directory entry is allocated.
+    pub fn set_label(&mut self, label: Option<&str>) -> Result<()> {
+        if self.params.version == CpmVersion::V22 {
+            bail!("Disk labels are not supported by CP/M 2.2");
+        }
+        let slot = self.dir_entries.iter().position(|e| e.is_label());
+        let Some(label) = label else {
+            if let Some(slot) = slot {
+                self.dir_entries[slot].file_id.user = 0xE5;
+            }
+            return Ok(());
+        };
+
+        let mut id = FileId::new_with_filename(0, label, FilenameMode::Normalized).context("Invalid label")?;
+        id.user = LABEL_USER;
+
+        let slot = match slot {
+            Some(slot) => slot,
+            None => self.get_free_dents(1)?[0],
+        };
+        // note: extent byte of a label entry holds label flags, bit 0 means "label exists"
+        self.dir_entries[slot] = CpmDirEntry::new(id, 0x01, 0, &[], self.wide_blocks());
+        Ok(())
+    }
+
+    /// Returns the total number of the filesystem blocks.
+    pub fn num_blocks(&self) -> u16 {
+        self.num_blocks
+    }
+
+    /// Returns true if directory entries hold 16-bit block numbers (more than 256 blocks).
+    pub fn wide_blocks(&self) -> bool {
+        CpmDirEntry::wide_blocks(self.num_blocks)
+    }
+
+    fn blocks_per_extent(&self) -> usize {
+        CpmDirEntry::blocks_per_extent(self.wide_blocks())
+    }
+
+    /// Returns the number of bytes a new file can have, limited by free blocks and directory entries.
+    pub fn free_space(&self) -> usize {
+        let (_, free_dents) = self.dir_slots();
+        self.free_blocks().min(free_dents * self.blocks_per_extent()) * self.block_size()
+    }
+
+    /// Returns the number of free (not allocated) blocks.
+    pub fn free_blocks(&self) -> usize {
+        self.used_blocks.iter().filter(|used| !**used).count()
+    }
+
+    /// Returns the number of directory slots, and the number of the free ones.
+    pub fn dir_slots(&self) -> (usize, usize) {
+        let free = self.dir_entries.iter().filter(|e| e.is_free()).count();
+        (self.dir_entries.len(), free)
+    }
+
+    /// Returns the Disk Parameter Block describing this filesystem.
+    pub fn dpb(&self) -> Dpb {
+        Dpb::new(&self.params, self.num_blocks)
+    }
+
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    pub fn disk(&self) -> &dyn DiskBackend {
+        self.disk.as_ref()
+    }
+
+    /// Returns the size (in bytes) of the system area, i.e. reserved tracks.
+    pub fn system_area_size(&self) -> usize {
+        self.params.system_area_size()
+    }
+
+    /// Reads the whole system area (reserved tracks), sector by sector.
+    pub fn read_system_area(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.system_area_size());
+        for chs in self.system_area_sectors() {
+            data.extend_from_slice(self.disk.sector_as_slice(chs)?);
+        }
+        Ok(data)
+    }
+
+    /// Writes data to the system area (reserved tracks), starting from the first sector.
+    ///
+    /// Bytes past the end of data are left untouched.
+    pub fn write_system_area(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() > self.system_area_size() {
+            bail!(
+                "System image too large: {} bytes, {} available",
+                data.len(),
+                self.system_area_size()
+            );
+        }
+
+        let sectors: Vec<CHS> = self.system_area_sectors().collect();
+        for (chunk, chs) in data.chunks(self.params.sector_size as usize).zip(sectors) {
+            let sect = self.disk.sector_as_slice_mut(chs)?;
+            sect[0..chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+
+    pub fn sector_size(&self) -> usize {
+        self.params.sector_size as usize
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.params.block_size()
+    }
+
+    /// Changes the number of disk cylinders, growing or shrinking the filesystem.
+    ///
+    /// Shrinking fails if any of the blocks to be removed is in use.
+    pub fn resize(&mut self, num_cylinders: u8) -> Result<()> {
+        let num_tracks = num_cylinders as u16 * self.disk.num_sides() as u16;
+        if num_tracks <= self.params.reserved_tracks as u16 {
+            bail!("Too few cylinders, no room for the filesystem");
+        }
+        let num_blocks = self.params.num_blocks(num_tracks);
+        if num_blocks <= self.params.dir_blocks as u16 {
+            bail!("Too few cylinders, no room for the directory");
+        }
+        if CpmDirEntry::wide_blocks(num_blocks) != self.wide_blocks() {
+            bail!("Can't resize across 256 blocks, the size of block numbers in the directory would change");
+        }
+
+        let used: Vec<u16> = (num_blocks..self.num_blocks)
+            .filter(|&b| self.used_blocks[b as usize])
+            .collect();
+        if !used.is_empty() {
+            bail!(Failure::new(
+                ErrorKind::DiskFull,
+                format!(
+                    "Can't shrink the image, {} blocks beyond the new size are in use (first: {})",
+                    used.len(),
+                    used[0]
+                )
+            ));
+        }
+
+        self.disk.resize(num_cylinders)?;
+        self.used_blocks.resize(num_blocks as usize, false);
+        self.num_blocks = num_blocks;
+        Ok(())
+    }
+
+    /// Returns true if the block is allocated (including directory blocks).
+    pub fn block_is_used(&self, block: u16) -> bool {
+        self.used_blocks[block as usize]
+    }
+
+    /// Returns true if any of the block's sectors is missing or marked with FDC errors in the image.
+    pub fn block_is_bad(&self, block: u16) -> bool {
+        let first_lsi = block * self.params.sectors_per_block as u16;
+        let sides = self.disk.num_sides();
+        (0..self.params.sectors_per_block as u16).any(|i| {
+            !self
+                .disk
+                .sector_ok(Self::lsi_to_chs(&self.params, sides, first_lsi + i))
+        })
+    }
+
+    /// Returns the addresses of the block's sectors, in logical order.
+    pub fn block_chs(&self, block: u16) -> Vec<CHS> {
+        let first_lsi = block * self.params.sectors_per_block as u16;
+        let sides = self.disk.num_sides();
+        (0..self.params.sectors_per_block as u16)
+            .map(|i| Self::lsi_to_chs(&self.params, sides, first_lsi + i))
+            .collect()
+    }
+
+    /// Returns the block stored (partly) in the sector, None for sectors of the system tracks
+    /// and ones beyond the last block.
+    pub fn chs_block(&self, chs: CHS) -> Option<u16> {
+        let sectors_per_track = self.params.sectors_per_track as u16;
+        let reserved_tracks = self.params.reserved_tracks as u16;
+        let track = chs.cylinder as u16 * self.disk.num_sides() as u16 + chs.head as u16;
+        let sector = chs.sector.checked_sub(self.params.first_sector_id)? as u16;
+        if track < reserved_tracks || sector >= sectors_per_track {
+            return None;
+        }
+        let block = ((track - reserved_tracks) * sectors_per_track + sector) / self.params.sectors_per_block as u16;
+        (block < self.num_blocks).then_some(block)
+    }
+
+    /// Returns the sectors of the block in logical order, borrowed from the image.
+    pub fn block_sectors(&self, block: u16) -> impl Iterator<Item = Result<&[u8]>> + '_ {
+        let first_lsi = block * self.params.sectors_per_block as u16;
+        let sides = self.disk.num_sides();
+        (0..self.params.sectors_per_block as u16).map(move |i| {
+            self.disk
+                .sector_as_slice(Self::lsi_to_chs(&self.params, sides, first_lsi + i))
+        })
+    }
+
+    pub fn write_block(&mut self, block: u16, buf: &[u8]) -> Result<()> {
+        let first_lsi = block * self.params.sectors_per_block as u16;
+        let sides = self.disk.num_sides();
+        let sect_size = self.params.sector_size as usize;
+        assert!(buf.len() <= self.block_size());
+
+        for (i, chunk) in buf.chunks(sect_size).enumerate() {
+            let chs = Self::lsi_to_chs(&self.params, sides, first_lsi + i as u16);
+            let sect = self.disk.sector_as_slice_mut(chs)?;
+            sect[0..chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(())
+    }
+
+    fn blocks_from_sorted_extents(&self, extents: &[&CpmDirEntry]) -> Result<Vec<u16>> {
+        let records_per_extent = self.params.records_per_block() * self.blocks_per_extent();
+
+        for (idx, e) in extents.iter().enumerate() {
+            // ensure extents are numbered 0..n-1
+            if e.extent as usize != idx {
+                bail!("Inconsistent extent index (expected {}, found {}).", idx, e.extent);
+            }
+            // ensure all extents but the last are fully filled
+            if idx < extents.len() - 1 && (e.record_count as usize) < records_per_extent {
+                bail!(
+                    "Extent {} is too small ({} records, {} expected).",
+                    idx,
+                    e.record_count,
+                    records_per_extent
+                );
+            }
+        }
+
+        let block_list = extents.iter().flat_map(|e| e.blocks()).collect();
+        Ok(block_list)
+    }
+
+    /// Returns all free blocks, in the order they should be allocated.
+    fn free_blocks_in_order(&self) -> Vec<u16> {
+        let mut blocks: Vec<u16> = self
+            .used_blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, used)| if !used { Some(idx as u16) } else { None })
+            .collect();
+        if self.preserve_deleted {
+            let valid_block_range = self.params.dir_blocks as u16..self.num_blocks;
+            let deleted: HashSet<u16> = self
+                .dir_entries
+                .iter()
+                .filter(|e| e.likely_deleted(&valid_block_range))
+                .flat_map(|e| e.blocks())
+                .collect();
+            // stable sort, so blocks stay in order within both groups
+            blocks.sort_by_key(|b| deleted.contains(b));
+        }
+        if let Some(first) = self.placement.first_block {
+            // the rest keep their order
+            if let Some(pos) = blocks.iter().position(|&b| b == first) {
+                blocks[..=pos].rotate_right(1);
+            }
+        }
+        blocks
+    }
 
+    /// Returns the first run of free blocks long enough (or the one at the placement's first
+    /// block).
+    fn contiguous_free_blocks(&self, count: usize) -> Result<Vec<u16>> {
+        let free = |b: usize| self.used_blocks.get(b).is_some_and(|used| !used);
+        let starts: Vec<usize> = match self.placement.first_block {
+            Some(first) => vec![first as usize],
+            None => (0..self.used_blocks.len()).collect(),
+        };
+        match starts.into_iter().find(|&start| (start..start + count).all(free)) {
+            Some(start) => Ok((start..start + count).map(|b| b as u16).collect()),
+            None => bail!(Failure::new(
+                ErrorKind::DiskFull,
+                format!("No {} contiguous free blocks", count)
+            )),
+        }
+    }
+
+    fn get_free_dents(&self, count: usize) -> Result<Vec<usize>> {
+        let mut dents: Vec<usize> = self
+            .dir_entries
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, d)| if d.is_free() { Some(idx) } else { None })
+            .collect();
+        if self.preserve_deleted {
+            let valid_block_range = self.params.dir_blocks as u16..self.num_blocks;
+            dents.sort_by_key(|&idx| self.dir_entries[idx].likely_deleted(&valid_block_range));
+        }
+        dents.truncate(count);
+        if dents.len() < count {
+            bail!(Failure::new(
+                ErrorKind::DiskFull,
+                format!(
+                    "Not enough free directory entries: {} available, {} required",
+                    dents.len(),
+                    count
+                )
+            ));
+        }
+
+        Ok(dents)
+    }
+
+    /// Returns addresses of all the system area sectors, in order.
+    fn system_area_sectors(&self) -> impl Iterator<Item = CHS> {
+        let params = self.params;
+        let sides = self.disk.num_sides();
+        let num_sectors = params.reserved_tracks as u16 * params.sectors_per_track as u16;
+        (0..num_sectors).map(move |idx| {
+            let track = idx / params.sectors_per_track as u16;
+            let sector = (idx % params.sectors_per_track as u16) as u8;
+            Self::track_sector_to_chs(&params, sides, track, sector)
+        })
+    }
+
+    /// Converts a logical sector index to a CHS sector address.
+    fn lsi_to_chs(params: &Params, sides: u8, lsi: u16) -> CHS {
+        let track = lsi / params.sectors_per_track as u16 + params.reserved_tracks as u16;
+        let sector = (lsi % params.sectors_per_track as u16) as u8;
+        Self::track_sector_to_chs(params, sides, track, sector)
+    }
+
+    /// Converts absolute track number and 0-based sector index to a CHS sector address.
+    fn track_sector_to_chs(params: &Params, sides: u8, track: u16, sector: u8) -> CHS {
         let cylinder = (track / sides as u16) as u8;
         let head = (track % sides as u16) as u8;
-        CHS { cylinder, head, sector }
+        CHS {
+            cylinder,
+            head,
+            sector: params.first_sector_id + sector,
+        }
     }
 
-    fn read_directory(disk: &DskImage, params: &Params) -> Result<Vec<CpmDirEntry>> {
-        let num_sectors = params.dir_blocks as u16 * params.sectors_per_block as u16;
-        let total_slots = num_sectors * params.sector_size / 32;
-        let mut entries = Vec::with_capacity(total_slots as usize);
+    /// Reads the raw 32-byte directory slot, as stored on the disk.
+    pub fn read_dir_slot(&self, slot: usize) -> Result<[u8; 32]> {
+        let sector_size = self.params.sector_size as usize;
+        let lsi = (slot * 32 / sector_size) as u16;
+        let offset = slot * 32 % sector_size;
+        let sector = self
+            .disk
+            .sector_as_slice(Self::lsi_to_chs(&self.params, self.disk.num_sides(), lsi))?;
+        Ok(sector[offset..offset + 32].try_into().unwrap())
+    }
+
+    /// Writes the raw 32-byte directory slot to the disk (the parsed entries are not updated).
+    fn write_dir_slot(&mut self, slot: usize, data: &[u8; 32]) -> Result<()> {
+        let sector_size = self.params.sector_size as usize;
+        let lsi = (slot * 32 / sector_size) as u16;
+        let offset = slot * 32 % sector_size;
+        let sector = self
+            .disk
+            .sector_as_slice_mut(Self::lsi_to_chs(&self.params, self.disk.num_sides(), lsi))?;
+        sector[offset..offset + 32].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_directory(&mut self) -> Result<()> {
+        let sector_size = self.params.sector_size as usize;
+        let sides = self.disk.num_sides();
+        for (slot, entry) in self.dir_entries.iter().enumerate() {
+            let lsi = (slot * 32 / sector_size) as u16;
+            let offset = slot * 32 % sector_size;
+            let sector = self
+                .disk
+                .sector_as_slice_mut(Self::lsi_to_chs(&self.params, sides, lsi))?;
+            entry.to_bytes((&mut sector[offset..offset + 32]).try_into().unwrap());
+        }
+        Ok(())
+    }
+
+    fn read_directory(disk: &dyn DiskBackend, params: &Params) -> Result<Vec<CpmDirEntry>> {
+        let num_blocks = params.num_blocks(disk.num_cylinders() as u16 * disk.num_sides() as u16);
+        let wide_blocks = CpmDirEntry::wide_blocks(num_blocks);
+        let num_sectors = params.dir_sectors();
+        let mut entries = Vec::with_capacity(params.dir_entries());
 
         let sides = disk.num_sides();
         // note: it starts from logical sector 0
         for lsi in 0..num_sectors {
-            let sector = disk.sector_as_slice(Self::lsi_to_chs(params, sides, lsi))?;
+            let chs = Self::lsi_to_chs(params, sides, lsi);
+            let sector = disk.sector_as_slice(chs)?;
 
-            let sector_entries: Vec<CpmDirEntry> = sector
-                .chunks(32)
-                .map(|chunk| CpmDirEntry::from_bytes(chunk.try_into().unwrap()))
-                .collect::<Result<Vec<_>>>()?;
-            entries.extend(sector_entries);
+            for chunk in sector.chunks(32) {
+                let entry = CpmDirEntry::from_bytes(chunk.try_into().unwrap(), params.version, wide_blocks)
+                    .with_context(|| Self::slot_location(disk, params, entries.len()))?;
+                entries.push(entry);
+            }
         }
         Ok(entries)
     }
 
-    fn calc_used_blocks(num_blocks: u16, dir_entries: &Vec<CpmDirEntry>) -> Result<Vec<bool>> {
+    /// Returns where the directory slot is stored, for error reports.
+    fn slot_location(disk: &dyn DiskBackend, params: &Params, slot: usize) -> Location {
+        let sector_size = params.sector_size as usize;
+        let chs = Self::lsi_to_chs(params, disk.num_sides(), (slot * 32 / sector_size) as u16);
+        Location {
+            chs: Some(chs),
+            offset: disk.sector_offset(chs).map(|offset| offset + slot * 32 % sector_size),
+            slot: Some(slot),
+        }
+    }
+
+    fn diagnose(params: &Params, num_blocks: u16, dir_entries: &[CpmDirEntry]) -> Vec<String> {
+        let valid_block_range = params.dir_blocks as u16..num_blocks;
+        let block_size = params.block_size();
+        let mut diagnostics = vec![];
+        for (slot, e) in dir_entries.iter().enumerate() {
+            let what = format!("slot {}: {} extent {}", slot, e.file_name(), e.extent);
+            if e.used() {
+                let blocks = e.blocks();
+                if e.record_count > 0x80 {
+                    diagnostics.push(format!("{} has record count {}, over 128", what, e.record_count));
+                } else if e.extent_size() > blocks.len() * block_size {
+                    diagnostics.push(format!(
+                        "{} has {} records, more than its {} blocks hold",
+                        what,
+                        e.record_count,
+                        blocks.len()
+                    ));
+                }
+                if params.version == CpmVersion::V3 && e.last_record_bytes as usize > RECORD_SIZE {
+                    diagnostics.push(format!("{} has byte count {}, over 128", what, e.last_record_bytes));
+                }
+                let attributes: Vec<String> = (0..4)
+                    .filter(|idx| e.attributes & 1 << idx != 0)
+                    .map(|idx| format!("F{}'", idx + 1))
+                    .collect();
+                if !attributes.is_empty() {
+                    diagnostics.push(format!("{} has attributes {} set", what, attributes.join(" ")));
+                }
+            } else if e.is_free() && e.file_id.name != [0xE5; 8] && !e.likely_deleted(&valid_block_range) {
+                diagnostics.push(format!(
+                    "{} is deleted, but its blocks are out of range, it's not taken for a deleted file",
+                    what
+                ));
+            }
+        }
+        diagnostics
+    }
+
+    fn calc_used_blocks(num_blocks: u16, dir_blocks: u8, dir_entries: &[CpmDirEntry]) -> Result<Vec<bool>> {
         let mut used_blocks = vec![false; num_blocks as usize];
+        // directory blocks are always allocated
+        used_blocks[0..dir_blocks as usize].fill(true);
         for e in dir_entries.iter().filter(|e| e.used()) {
             for b in e.blocks() {
                 if b != 0 {
+                    if b >= num_blocks {
+                        bail!("Block {} out of range", b)
+                    }
                     if used_blocks[b as usize] {
                         bail!("Block {} used more than once", b)
                     }
@@ -317,25 +1531,646 @@ impl CpmFs {
 
 #[cfg(test)]
 mod tests {
-    use crate::cpm::cpm_fs::LsMode::All;
-    use crate::cpm::cpm_fs::{CpmFs, Params};
+    use crate::cpm::cpm_fs::LsMode::{self, All, OwnedBy};
+    use crate::cpm::cpm_fs::{CompactStats, CpmFs, CpmVersion, EntryStatus, FileItem, Params, Placement};
+    use crate::cpm::dir_entry::CpmDirEntry;
+    use crate::cpm::file_id::{FileId, FilenameMode, LABEL_USER, TIMESTAMPS_USER};
+    use crate::dsk::DskImage;
+    use crate::dsk::CHS;
+    use crate::error::{error_kind, ErrorKind, Location};
     use std::fs::File;
+    use std::io::Read;
     use std::path::PathBuf;
 
-    #[test]
-    fn test_load_save_dsk() {
+    const PARAMS: Params = Params {
+        sectors_per_track: 9,
+        first_sector_id: 1,
+        reserved_tracks: 2,
+        sector_size: 512,
+        sectors_per_block: 4,
+        dir_blocks: 4,
+        version: CpmVersion::V3,
+    };
+
+    fn load_test_image() -> CpmFs {
         let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/03.dsk");
         let mut file = File::open(path).unwrap();
+        CpmFs::load(&mut file, PARAMS).unwrap()
+    }
 
-        let params = Params {
-            sectors_per_track: 9,
-            reserved_tracks: 2,
-            sector_size: 512,
-            sectors_per_block: 4,
-            dir_blocks: 4,
-        };
-        let fs = CpmFs::load(&mut file, params).unwrap();
+    #[test]
+    fn test_load_save_dsk() {
+        let mut fs = load_test_image();
         let files = fs.list_files(All).unwrap();
         dbg!(&files);
+
+        // saving unmodified filesystem must produce identical image
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_fs.dsk");
+        let mut file = File::create(&path).unwrap();
+        fs.save(&mut file).unwrap();
+        let original = std::fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/03.dsk")).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn test_label() {
+        let mut fs = load_test_image();
+        assert_eq!(fs.label(), None);
+        let (_, free) = fs.dir_slots();
+
+        fs.set_label(Some("junior")).unwrap();
+        assert_eq!(fs.label(), Some("JUNIOR".to_string()));
+        fs.set_label(Some("disk.01")).unwrap();
+        assert_eq!(fs.label(), Some("DISK.01".to_string()));
+        assert_eq!(fs.dir_slots().1, free - 1);
+        assert!(fs.set_label(Some("too long label")).is_err());
+
+        // label must survive save & load
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_label.dsk");
+        fs.save(&mut File::create(&path).unwrap()).unwrap();
+        let mut fs = CpmFs::load(&mut File::open(&path).unwrap(), PARAMS).unwrap();
+        assert_eq!(fs.label(), Some("DISK.01".to_string()));
+        assert_eq!(fs.list_files(All).unwrap().len(), 64);
+
+        fs.set_label(None).unwrap();
+        assert_eq!(fs.label(), None);
+        assert_eq!(fs.dir_slots().1, free);
+    }
+
+    #[test]
+    fn test_create_empty_file() {
+        let mut fs = load_test_image();
+        let id = FileId::new_with_filename(3, "flag.$$$", FilenameMode::Normalized).unwrap();
+        assert!(!fs.file_exists(&id));
+
+        fs.create_empty_file(&id).unwrap();
+        assert!(fs.file_exists(&id));
+        assert!(fs.create_empty_file(&id).is_err());
+
+        let files = fs.list_files(OwnedBy(3)).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "FLAG.$$$");
+        assert_eq!(files[0].size, 0);
+        assert!(files[0].block_list.is_empty());
+    }
+
+    #[test]
+    fn test_delete_file() {
+        let mut fs = load_test_image();
+        let free = fs.free_blocks();
+        let files = fs.list_files(All).unwrap();
+        let bdos = files.iter().find(|f| f.name == "BDOS.MAC").unwrap();
+
+        fs.delete_file(bdos).unwrap();
+        assert_eq!(fs.free_blocks(), free + bdos.block_list.len());
+        assert!(!fs.list_files(All).unwrap().iter().any(|f| f.name == "BDOS.MAC"));
+        assert!(fs.delete_file(bdos).is_err());
+    }
+
+    #[test]
+    fn test_block_sectors() {
+        let fs = load_test_image();
+        let files = fs.list_files(All).unwrap();
+        let bdos = files.iter().find(|f| f.name == "BDOS.MAC").unwrap();
+
+        let mut data = vec![];
+        fs.read_file(bdos, &mut data, false).unwrap();
+        let sectors: Vec<u8> = bdos
+            .block_list
+            .iter()
+            .flat_map(|&b| fs.block_sectors(b).map(|s| s.unwrap().to_vec()))
+            .flatten()
+            .collect();
+        assert_eq!(sectors.len(), bdos.block_list.len() * fs.block_size());
+        assert_eq!(&sectors[0..data.len()], data);
+
+        for &b in &bdos.block_list {
+            assert!(fs.block_chs(b).into_iter().all(|chs| fs.chs_block(chs) == Some(b)));
+        }
+        let system = CHS {
+            cylinder: 0,
+            head: 0,
+            sector: 1,
+        };
+        assert_eq!(fs.chs_block(system), None);
+    }
+
+    #[test]
+    fn test_rename_file() {
+        let mut fs = load_test_image();
+        let files = fs.list_files(OwnedBy(0)).unwrap();
+        let bdos = files.iter().find(|f| f.name == "BDOS.MAC").unwrap();
+        let id = FileId::new_with_filename(2, "bdos3.mac", FilenameMode::Normalized).unwrap();
+        fs.rename_file(bdos, &id).unwrap();
+
+        let renamed = fs.list_files(OwnedBy(2)).unwrap();
+        assert_eq!(renamed.len(), 1);
+        assert_eq!(renamed[0].name, "BDOS3.MAC");
+        assert_eq!(renamed[0].block_list, bdos.block_list);
+        assert_eq!(fs.list_files(OwnedBy(0)).unwrap().len(), files.len() - 1);
+
+        // target must not exist
+        let bios = files.iter().find(|f| f.name == "BIOS.MAC").unwrap();
+        assert!(fs.rename_file(bios, &id).is_err());
+    }
+
+    #[test]
+    fn test_file_times() {
+        let mut fs = load_test_image();
+        let first = fs.dir_entries[0].file_name();
+        let file = fs
+            .list_files(OwnedBy(0))
+            .unwrap()
+            .into_iter()
+            .find(|f| f.name == first)
+            .unwrap();
+        assert_eq!(fs.file_times(&file).unwrap(), None);
+
+        // turn slot 3 into SFCB, stamping the file in slot 0
+        let mut sfcb = [0u8; 32];
+        sfcb[0] = TIMESTAMPS_USER;
+        sfcb[5..9].copy_from_slice(&[0x80, 0x0E, 0x23, 0x59]);
+        let chs = CpmFs::lsi_to_chs(&fs.params, fs.disk.num_sides(), 0);
+        fs.disk.sector_as_slice_mut(chs).unwrap()[96..128].copy_from_slice(&sfcb);
+        fs.dir_entries[3].file_id.user = TIMESTAMPS_USER;
+
+        let times = fs.file_times(&file).unwrap().unwrap();
+        assert_eq!(times.created, None);
+        assert_eq!(times.updated.unwrap().to_string(), "1988-02-29 23:59");
+    }
+
+    #[test]
+    fn test_sort_directory() {
+        let mut fs = load_test_image();
+        let contents = |fs: &CpmFs| -> Vec<(FileItem, Vec<u8>)> {
+            let mut files = fs.list_files(LsMode::Deleted).unwrap();
+            files.sort_by(FileItem::listing_cmp);
+            files
+                .into_iter()
+                .map(|f| {
+                    let mut data = vec![];
+                    fs.read_file(&f, &mut data, false).unwrap();
+                    (f, data)
+                })
+                .collect()
+        };
+        let before = contents(&fs);
+
+        assert!(fs.sort_directory().unwrap() > 0);
+        let used = fs.dir_entries.iter().filter(|e| e.used()).count();
+        assert!(fs.dir_entries[0..used].iter().all(|e| e.used()));
+        let keys: Vec<_> = fs.dir_entries[0..used]
+            .iter()
+            .map(|e| (e.file_id.user, e.file_name(), e.extent))
+            .collect();
+        assert!(keys.is_sorted());
+        let after = contents(&fs);
+        assert_eq!(after.len(), before.len());
+        for ((a, data_a), (b, data_b)) in before.iter().zip(&after) {
+            assert_eq!((&a.name, a.user, &a.block_list), (&b.name, b.user, &b.block_list));
+            assert_eq!(data_a, data_b);
+        }
+        assert_eq!(fs.sort_directory().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sort_stamped_directory() {
+        let disk = DskImage::format(80, 2, 512, &[1, 2, 3, 4, 5, 6, 7, 8, 9], 0x2A, 0xE5);
+        let mut fs = CpmFs::from_disk(Box::new(disk), PARAMS).unwrap();
+        let (slots, _) = fs.dir_slots();
+        let mut sfcb = [0u8; 32];
+        sfcb[0] = TIMESTAMPS_USER;
+        for slot in (3..slots).step_by(4) {
+            fs.write_dir_slot(slot, &sfcb).unwrap();
+        }
+        fs.dir_entries = CpmFs::read_directory(fs.disk.as_ref(), &fs.params).unwrap();
+
+        // C, B and A in slots 0-2, each updated on another day
+        for (idx, name) in ["c.txt", "b.txt", "a.txt"].iter().enumerate() {
+            let id = FileId::new_with_filename(0, name, FilenameMode::Normalized).unwrap();
+            fs.write_file(&id, &mut [0x55u8; 100].as_slice(), false).unwrap();
+            sfcb[5 + idx * 10] = idx as u8 + 1;
+        }
+        fs.write_directory().unwrap();
+        fs.write_dir_slot(3, &sfcb).unwrap();
+        fs.dir_entries = CpmFs::read_directory(fs.disk.as_ref(), &fs.params).unwrap();
+        let times = |fs: &CpmFs| -> Vec<_> {
+            let mut files = fs.list_files(All).unwrap();
+            files.sort_by(FileItem::listing_cmp);
+            files.iter().map(|f| fs.file_times(f).unwrap()).collect()
+        };
+        let before = times(&fs);
+        assert!(before.iter().all(|t| t.is_some_and(|t| t.updated.is_some())));
+
+        assert_eq!(fs.sort_directory().unwrap(), 3);
+        assert_eq!(fs.dir_entries[0].file_name(), "A.TXT");
+        assert_eq!(fs.dir_entries[3].file_id.user, TIMESTAMPS_USER);
+        assert_eq!(times(&fs), before);
+
+        // a file entry where an SFCB is expected
+        fs.dir_entries[7].file_id = FileId::new_with_filename(0, "d.txt", FilenameMode::Normalized).unwrap();
+        assert!(fs.sort_directory().is_err());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let disk = DskImage::format(80, 2, 512, &[1, 2, 3, 4, 5, 6, 7, 8, 9], 0x2A, 0xE5);
+        let mut fs = CpmFs::from_disk(Box::new(disk), PARAMS).unwrap();
+        assert!(fs.diagnostics().is_empty());
+
+        // F1' set, 0x90 records in blocks 10 and 11
+        let mut odd = [0u8; 32];
+        odd[1..12].copy_from_slice(b"ODD     TXT");
+        odd[1] |= 0x80;
+        odd[15] = 0x90;
+        odd[16..20].copy_from_slice(&[10, 0, 11, 0]);
+        fs.write_dir_slot(0, &odd).unwrap();
+        // records without blocks
+        odd[12] = 1;
+        odd[15] = 0x10;
+        odd[16..20].fill(0);
+        fs.write_dir_slot(1, &odd).unwrap();
+        let mut deleted = [0xE5u8; 32];
+        deleted[1..12].copy_from_slice(b"GONE    TXT");
+        deleted[12..32].fill(0);
+        deleted[16..18].copy_from_slice(&[0x00, 0x10]);
+        fs.write_dir_slot(2, &deleted).unwrap();
+
+        let fs = CpmFs::from_disk(fs.disk, PARAMS).unwrap();
+        assert_eq!(
+            fs.diagnostics(),
+            [
+                "slot 0: ODD.TXT extent 0 has record count 144, over 128",
+                "slot 0: ODD.TXT extent 0 has attributes F1' set",
+                "slot 1: ODD.TXT extent 1 has 16 records, more than its 0 blocks hold",
+                "slot 1: ODD.TXT extent 1 has attributes F1' set",
+                "slot 2: GONE.TXT extent 0 is deleted, but its blocks are out of range, it's not taken for a deleted file",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_location() {
+        let disk = DskImage::format(80, 2, 512, &[1, 2, 3, 4, 5, 6, 7, 8, 9], 0x2A, 0xE5);
+        let mut fs = CpmFs::from_disk(Box::new(disk), PARAMS).unwrap();
+        let mut entry = [0u8; 32];
+        entry[1..12].copy_from_slice(b"BAD     TXT");
+        // a gap in the block list
+        entry[16..20].copy_from_slice(&[10, 0, 0, 0]);
+        entry[20] = 11;
+        fs.write_dir_slot(5, &entry).unwrap();
+
+        let e = CpmFs::from_disk(fs.disk, PARAMS).err().unwrap();
+        let location = e.downcast_ref::<Location>().unwrap();
+        // the header block, two tracks of 9 sectors with their info blocks, the info block
+        assert_eq!(location.offset, Some(256 + 2 * (256 + 9 * 512) + 256 + 5 * 32));
+        assert_eq!(
+            location.to_string(),
+            "directory slot 5, sector C1/H0/R1, image offset 0x28a0"
+        );
+
+        let fs = load_test_image();
+        let chs = CHS {
+            cylinder: 0,
+            head: 0,
+            sector: 10,
+        };
+        let e = fs.disk.sector_as_slice(chs).err().unwrap();
+        assert_eq!(e.to_string(), "sector C0/H0/R10, image offset 0x100");
+    }
+
+    #[test]
+    fn test_compact_directory() {
+        let mut fs = load_test_image();
+        let files = fs.list_files(All).unwrap();
+
+        // a second label and an SFCB, while the label doesn't enable stamps
+        fs.set_label(Some("disk")).unwrap();
+        let empty = fs.dir_entries.iter().rposition(|e| e.is_free()).unwrap();
+        fs.dir_entries[empty] = CpmDirEntry::new(fs.dir_entries[0].file_id, 0, 0, &[], true);
+        fs.dir_entries[empty].file_id.user = LABEL_USER;
+        fs.dir_entries[empty - 1].file_id.user = TIMESTAMPS_USER;
+        let (_, free) = fs.dir_slots();
+        let deleted = fs
+            .dir_entries
+            .iter()
+            .enumerate()
+            .filter(|(slot, e)| e.is_free() && fs.read_dir_slot(*slot).unwrap() != [0xE5; 32])
+            .count();
+        assert!(deleted > 0);
+
+        let stats = fs.compact_directory().unwrap();
+        assert_eq!(
+            stats,
+            CompactStats {
+                deleted,
+                labels: 1,
+                stamps: 1
+            }
+        );
+        assert_eq!(fs.dir_slots().1, free + 2);
+        assert_eq!(fs.label(), Some("DISK".to_string()));
+        assert!(fs.list_files(LsMode::DeletedOnly).unwrap().is_empty());
+        assert_eq!(fs.list_files(All).unwrap().len(), files.len());
+        assert_eq!(fs.compact_directory().unwrap(), CompactStats::default());
+    }
+
+    #[test]
+    fn test_deleted_only() {
+        let mut fs = load_test_image();
+        let deleted = fs.list_files(LsMode::DeletedOnly).unwrap();
+        assert!(deleted.iter().all(|f| f.user.is_none()));
+        assert!(deleted.iter().any(|f| f.name == "$$$.SUB"));
+
+        let pip = fs
+            .list_files(OwnedBy(0))
+            .unwrap()
+            .into_iter()
+            .find(|f| f.name == "PIP.COM")
+            .unwrap();
+        assert_eq!(fs.allocated_blocks(&pip), pip.block_list);
+        fs.delete_file(&pip).unwrap();
+        let deleted = fs.list_files(LsMode::DeletedOnly).unwrap();
+        let pip = deleted.iter().find(|f| f.name == "PIP.COM").unwrap();
+        assert!(fs.allocated_blocks(pip).is_empty());
+    }
+
+    #[test]
+    fn test_dir_entries() {
+        let fs = load_test_image();
+        let (slots, free) = fs.dir_slots();
+        assert_eq!(fs.dir_entries().count(), slots);
+        assert!(fs.dir_entries().enumerate().all(|(idx, e)| e.slot() == idx));
+
+        let count = |status| fs.dir_entries().filter(|e| e.status() == status).count();
+        let files = fs.list_files(All).unwrap();
+        assert_eq!(count(EntryStatus::File), files.iter().map(|f| f.extents).sum::<usize>());
+        assert_eq!(count(EntryStatus::Deleted) + count(EntryStatus::Unused), free);
+        assert!(count(EntryStatus::Deleted) > 0);
+
+        let pip = files.iter().find(|f| f.name == "PIP.COM").unwrap();
+        let extents: Vec<_> = fs
+            .dir_entries()
+            .filter(|e| e.status() == EntryStatus::File && e.file_id().filename() == "PIP.COM")
+            .collect();
+        assert_eq!(extents.len(), pip.extents);
+        assert_eq!(
+            extents.iter().flat_map(|e| e.blocks()).collect::<Vec<_>>(),
+            pip.block_list
+        );
+        assert_eq!(extents[0].read_only(), pip.read_only);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut fs = load_test_image();
+        let free = fs.free_blocks();
+        fs.resize(81).unwrap();
+        assert_eq!(fs.num_blocks(), 360);
+        assert_eq!(fs.free_blocks(), free + 5);
+
+        // blocks near the end are in use
+        assert!(fs.resize(70).is_err());
+        assert_eq!(fs.num_blocks(), 360);
+        fs.resize(80).unwrap();
+        assert_eq!(fs.free_blocks(), free);
+    }
+
+    #[test]
+    fn test_read_truncated_file() {
+        let fs = load_test_image();
+        let mut file = fs.list_files(OwnedBy(0)).unwrap().remove(0);
+        file.size = fs.stored_size(&file) + 1;
+        let mut out = Vec::new();
+        assert!(fs.read_file(&file, &mut out, false).is_err());
+        assert!(out.is_empty());
+
+        file.size -= 1;
+        assert_eq!(fs.read_file(&file, &mut out, false).unwrap(), file.size);
+    }
+
+    #[test]
+    fn test_write_file() {
+        let mut fs = load_test_image();
+        let free = fs.free_blocks();
+        let (_, free_dents) = fs.dir_slots();
+
+        // 40000 bytes: 20 blocks, 3 extents
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_write.bin");
+        std::fs::write(&path, &data).unwrap();
+        let id = FileId::new_with_filename(1, "data.bin", FilenameMode::Normalized).unwrap();
+        let blocks = fs.write_file(&id, &mut File::open(&path).unwrap(), false).unwrap();
+        assert_eq!(blocks.len(), 20);
+        assert_eq!(fs.free_blocks(), free - 20);
+        assert_eq!(fs.dir_slots().1, free_dents - 3);
+        assert!(fs.write_file(&id, &mut File::open(&path).unwrap(), false).is_err());
+
+        let files = fs.list_files(OwnedBy(1)).unwrap();
+        assert_eq!(files[0].size, 40064);
+        assert_eq!(files[0].extents, 3);
+        assert_eq!(files[0].flags(), "---");
+        assert_eq!(files[0].block_list, blocks);
+        let mut out = Vec::new();
+        assert_eq!(fs.read_file(&files[0], &mut out, false).unwrap(), 40064);
+        assert_eq!(out[0..40000], data);
+        assert!(out[40000..].iter().all(|&b| b == 0));
+
+        // text mode adds ^Z
+        std::fs::write(&path, b"hello").unwrap();
+        let id = FileId::new_with_filename(2, "hello.txt", FilenameMode::Normalized).unwrap();
+        fs.write_file(&id, &mut File::open(&path).unwrap(), true).unwrap();
+        let files = fs.list_files(OwnedBy(2)).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(fs.read_file(&files[0], &mut out, true).unwrap(), 5);
+        assert_eq!(out, b"hello");
+
+        // empty file still gets a directory entry
+        std::fs::write(&path, []).unwrap();
+        let id = FileId::new_with_filename(1, "empty", FilenameMode::Normalized).unwrap();
+        assert!(fs
+            .write_file(&id, &mut File::open(&path).unwrap(), false)
+            .unwrap()
+            .is_empty());
+        assert!(fs.file_exists(&id));
+
+        // a non-seekable source which doesn't fit leaves the filesystem unchanged
+        let free = fs.free_blocks();
+        let id = FileId::new_with_filename(1, "big.bin", FilenameMode::Normalized).unwrap();
+        let mut src = std::io::repeat(0x55).take((free as u64 + 1) * fs.block_size() as u64);
+        let err = fs.write_file(&id, &mut src, false).unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::DiskFull);
+        assert!(err.to_string().contains(&format!("{} required", free + 1)));
+        assert_eq!(fs.free_blocks(), free);
+        assert!(!fs.file_exists(&id));
+    }
+
+    #[test]
+    fn test_listing_order() {
+        let fs = load_test_image();
+        let names = |files: &[FileItem]| files.iter().map(|f| (f.user, f.name.clone())).collect::<Vec<_>>();
+
+        // directory order doesn't change between calls
+        let files = fs.list_files(LsMode::Deleted).unwrap();
+        assert_eq!(names(&files), names(&fs.list_files(LsMode::Deleted).unwrap()));
+
+        let mut sorted = files.clone();
+        sorted.sort_by(FileItem::listing_cmp);
+        assert_ne!(names(&files), names(&sorted));
+        assert!(sorted.windows(2).all(|w| w[0].listing_cmp(&w[1]).is_le()));
+        assert_eq!(sorted.first().map(|f| f.user), Some(Some(0)));
+        assert_eq!(sorted.last().map(|f| f.user), Some(None));
+    }
+
+    #[test]
+    fn test_preserve_deleted() {
+        let mut fs = load_test_image();
+        fs.set_preserve_deleted(true);
+        let deleted = fs.list_files(LsMode::DeletedOnly).unwrap();
+        let recoverable: Vec<_> = deleted.iter().filter(|f| fs.allocated_blocks(f).is_empty()).collect();
+        assert!(!recoverable.is_empty());
+
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_preserve.bin");
+        std::fs::write(&path, vec![0x55u8; 8192]).unwrap();
+        let id = FileId::new_with_filename(3, "new.bin", FilenameMode::Normalized).unwrap();
+        let blocks = fs.write_file(&id, &mut File::open(&path).unwrap(), false).unwrap();
+        assert!(blocks.iter().all(|b| !deleted.iter().any(|f| f.block_list.contains(b))));
+        assert_eq!(fs.list_files(LsMode::DeletedOnly).unwrap().len(), deleted.len());
+        for f in &recoverable {
+            assert!(fs.allocated_blocks(f).is_empty());
+        }
+
+        // writes failing for lack of blocks or directory entries don't touch the deleted files
+        let contents = |fs: &CpmFs| -> Vec<u8> {
+            let blocks = recoverable.iter().flat_map(|f| f.block_list.iter());
+            blocks
+                .flat_map(|&b| fs.block_sectors(b).flat_map(|s| s.unwrap().to_vec()))
+                .collect()
+        };
+        let before = contents(&fs);
+        let big = vec![0x55u8; (fs.free_blocks() + 1) * fs.block_size()];
+        let id = FileId::new_with_filename(3, "big.bin", FilenameMode::Normalized).unwrap();
+        let err = fs.write_file(&id, &mut big.as_slice(), false).unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::DiskFull);
+        assert_eq!(contents(&fs), before);
+
+        let mut n = 0;
+        while fs.dir_slots().1 > 0 {
+            let id = FileId::new_with_filename(4, &format!("e{}", n), FilenameMode::Normalized).unwrap();
+            fs.create_empty_file(&id).unwrap();
+            n += 1;
+        }
+        let free = fs.free_blocks();
+        let id = FileId::new_with_filename(3, "small.bin", FilenameMode::Normalized).unwrap();
+        let err = fs
+            .write_file(&id, &mut &big[..free * fs.block_size()], false)
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::DiskFull);
+        assert!(err.to_string().contains("directory entries"));
+        assert_eq!(contents(&fs), before);
+        assert_eq!(fs.free_blocks(), free);
+    }
+
+    #[test]
+    fn test_placement() {
+        let mut fs = load_test_image();
+        let free: Vec<u16> = (0..fs.num_blocks()).filter(|&b| !fs.block_is_used(b)).collect();
+        let data = vec![0x55u8; 3 * fs.block_size()];
+
+        let first = free[free.len() / 2];
+        fs.set_placement(Placement {
+            first_block: Some(first),
+            contiguous: false,
+        });
+        let id = FileId::new_with_filename(0, "at.bin", FilenameMode::Normalized).unwrap();
+        let blocks = fs.write_file(&id, &mut data.as_slice(), false).unwrap();
+        assert_eq!(blocks[0], first);
+        assert!(blocks[1] < first);
+        let id = FileId::new_with_filename(0, "used.bin", FilenameMode::Normalized).unwrap();
+        let err = fs.write_file(&id, &mut data.as_slice(), false).unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::DiskFull);
+        for contiguous in [false, true] {
+            fs.set_placement(Placement {
+                first_block: Some(400),
+                contiguous,
+            });
+            let err = fs.write_file(&id, &mut data.as_slice(), false).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::Usage);
+            assert_eq!(err.to_string(), "Block 400 out of range, the disk has blocks 0-354");
+        }
+
+        fs.set_placement(Placement {
+            first_block: None,
+            contiguous: true,
+        });
+        let id = FileId::new_with_filename(0, "run.bin", FilenameMode::Normalized).unwrap();
+        let blocks = fs.write_file(&id, &mut data.as_slice(), false).unwrap();
+        assert!(blocks.windows(2).all(|w| w[1] == w[0] + 1));
+        let huge = vec![0u8; fs.free_blocks() * fs.block_size()];
+        let id = FileId::new_with_filename(0, "huge.bin", FilenameMode::Normalized).unwrap();
+        assert!(fs.write_file(&id, &mut huge.as_slice(), false).is_err());
+        assert!(!fs.file_exists(&id));
+    }
+
+    #[test]
+    fn test_system_area() {
+        let mut fs = load_test_image();
+        assert_eq!(fs.system_area_size(), 2 * 9 * 512);
+
+        let system = fs.read_system_area().unwrap();
+        assert_eq!(system.len(), fs.system_area_size());
+        assert_eq!(system[0..3], [0xC3, 0xF3, 0xBA]);
+
+        fs.write_system_area(&[1, 2, 3]).unwrap();
+        let modified = fs.read_system_area().unwrap();
+        assert_eq!(modified[0..3], [1, 2, 3]);
+        assert_eq!(modified[3..], system[3..]);
+
+        assert!(fs.write_system_area(&vec![0; fs.system_area_size() + 1]).is_err());
+    }
+
+    #[test]
+    fn test_params_builder() {
+        let params = Params::builder()
+            .sectors_per_track(9)
+            .reserved_tracks(1)
+            .block_size(1024)
+            .dir_blocks(2)
+            .version(CpmVersion::V22)
+            .num_tracks(40)
+            .build()
+            .unwrap();
+        assert_eq!((params.sectors_per_block, params.first_sector_id), (2, 1));
+        assert_eq!((params.bsh(), params.blm(), params.exm(175)), (3, 7, 0));
+        assert_eq!(
+            (params.drm(), params.al(), params.cks(), params.off()),
+            (63, 0xC000, 16, 1)
+        );
+        assert_eq!(params.num_blocks(40), 175);
+        assert_eq!(params.system_area_size(), 9 * 512);
+
+        let builder = || {
+            Params::builder()
+                .sectors_per_track(9)
+                .block_size(1024)
+                .dir_blocks(2)
+                .num_tracks(20)
+        };
+        assert!(builder().build().is_ok());
+        // blocks smaller than 1K, not a whole number of sectors, too many directory blocks
+        assert!(builder().block_size(512).build().is_err());
+        assert!(builder().block_size(3000).build().is_err());
+        assert!(builder().sector_size(1024).block_size(1536).build().is_err());
+        assert!(builder().dir_blocks(17).build().is_err());
+        assert!(builder().sector_size(100).build().is_err());
+        assert!(builder().sectors_per_track(0).build().is_err());
+        // no tracks left for the filesystem, or the directory
+        assert!(builder().reserved_tracks(20).build().is_err());
+        assert!(builder().reserved_tracks(19).dir_blocks(9).build().is_err());
+        assert!(builder().reserved_tracks(19).build().is_ok());
+        // EXM > 0: 2K blocks on up to 256 blocks, 1K blocks beyond them
+        assert!(builder().block_size(2048).build().is_err());
+        assert!(builder().block_size(2048).num_tracks(160).build().is_ok());
+        assert!(builder().num_tracks(80).build().is_err());
     }
 }
@@ -1,15 +1,15 @@
-use crate::cpm::file_id::FileId;
+use crate::cpm::cpm_fs::CpmVersion;
+use crate::cpm::file_id::{FileId, LABEL_USER, MAX_USER_ID, TIMESTAMPS_USER};
 use anyhow::{bail, Result};
 use std::ops::Range;
 
-pub const BLOCKS_PER_EXTENT: usize = 8;
+pub const MAX_BLOCKS_PER_EXTENT: usize = 16;
 
 /// CpmDirEntry structure represents a directory entry as stored
 /// in the CP/M filesystem directory.
 ///
 /// Note: depending on the size of the filesystem, DirEntry
-/// can store either 16 * u8 or 8 * u16 block numbers. This
-/// implementation hardcodes the second case.
+/// stores either 16 * u8 (up to 256 blocks) or 8 * u16 block numbers.
 pub struct CpmDirEntry {
     pub file_id: FileId,
     /// extent number, used for files spanning more than one dir entry
@@ -17,61 +17,106 @@ pub struct CpmDirEntry {
     /// file size expressed as number of 128-byte records
     pub record_count: u8,
     /// block numbers
-    blocks: [u16; BLOCKS_PER_EXTENT],
+    blocks: [u16; MAX_BLOCKS_PER_EXTENT],
+    /// 16-bit block numbers
+    wide_blocks: bool,
     /// read-only flag
     pub read_only: bool,
     /// system file flag
     pub system_file: bool,
     /// archived file flag
     pub archived: bool,
+    /// F1'-F4' attributes (CP/M Plus only), bit 0 is F1'
+    pub attributes: u8,
+    /// S1 byte, number of bytes used in the last record (CP/M Plus only, 0 means all)
+    pub last_record_bytes: u8,
 }
 
 impl CpmDirEntry {
-    pub fn from_bytes(data: &[u8; 32]) -> Result<CpmDirEntry> {
-        let file_id_bytes = &data[0..12].try_into().unwrap();
-        let file_id = FileId::from_bytes(file_id_bytes)?;
+    /// Returns the number of blocks an entry holds.
+    pub fn blocks_per_extent(wide_blocks: bool) -> usize {
+        if wide_blocks {
+            MAX_BLOCKS_PER_EXTENT / 2
+        } else {
+            MAX_BLOCKS_PER_EXTENT
+        }
+    }
+
+    /// Returns true if the filesystem of that many blocks needs 16-bit block numbers.
+    pub fn wide_blocks(num_blocks: u16) -> bool {
+        num_blocks > 256
+    }
+
+    pub fn from_bytes(data: &[u8; 32], version: CpmVersion, wide_blocks: bool) -> Result<CpmDirEntry> {
+        let mut file_id_bytes: [u8; 12] = data[0..12].try_into().unwrap();
+
+        // CP/M Plus uses MSBs of the first 4 name characters as attributes
+        let mut attributes = 0;
+        if version == CpmVersion::V3 && data[0] <= MAX_USER_ID {
+            for (idx, b) in file_id_bytes[1..5].iter_mut().enumerate() {
+                if *b & 0x80 != 0 {
+                    attributes |= 1 << idx;
+                    *b &= 0x7F;
+                }
+            }
+        }
+        let file_id = FileId::from_bytes(&file_id_bytes)?;
+        if version == CpmVersion::V22 && (file_id.user == LABEL_USER || file_id.user == TIMESTAMPS_USER) {
+            bail!(
+                "CP/M Plus directory entry (0x{:02X}) in CP/M 2.2 directory",
+                file_id.user
+            );
+        }
 
         let (x_h, x_l) = (data[14] as u16, data[12] as u16);
         let extent = (x_h << 8) + x_l;
         let record_count = data[15];
 
         let block_bytes = &data[16..32];
-        let mut blocks = [0u16; BLOCKS_PER_EXTENT];
-        for (i, chunk) in block_bytes.chunks_exact(2).enumerate() {
-            blocks[i] = u16::from_le_bytes([chunk[0], chunk[1]]);
+        let mut blocks = [0u16; MAX_BLOCKS_PER_EXTENT];
+        if wide_blocks {
+            for (i, chunk) in block_bytes.chunks_exact(2).enumerate() {
+                blocks[i] = u16::from_le_bytes([chunk[0], chunk[1]]);
+            }
+        } else {
+            for (i, &b) in block_bytes.iter().enumerate() {
+                blocks[i] = b as u16;
+            }
         }
 
         // Note: only check validity for actually used entries! Still we want
         // to keep the info for unsued (possibly deleted) entries.
-        if file_id.user != 0xE5 {
-            if !Self::has_only_trailing_zeros(&blocks) {
-                bail!(
-                    "Invalid block list for {} extent {}: {:?}",
-                    file_id.filename(),
-                    extent,
-                    blocks
-                );
-            }
+        if file_id.user <= MAX_USER_ID && !Self::has_only_trailing_zeros(&blocks) {
+            bail!(
+                "Invalid block list for {} extent {}: {:?}",
+                file_id.filename(),
+                extent,
+                blocks
+            );
         }
 
-        let read_only = file_id.extension[0] & 0x80 != 0;
-        let system_file = file_id.extension[1] & 0x80 != 0;
-        let archived = file_id.extension[2] & 0x80 != 0;
+        // note: FileId masks the flags out of the extension, use raw bytes
+        let read_only = data[9] & 0x80 != 0;
+        let system_file = data[10] & 0x80 != 0;
+        let archived = data[11] & 0x80 != 0;
 
         Ok(CpmDirEntry {
             file_id,
             extent,
             record_count,
             blocks,
+            wide_blocks,
             read_only,
             system_file,
             archived,
+            attributes,
+            last_record_bytes: data[13],
         })
     }
 
-    pub fn new(file_id: FileId, extent: u16, record_count: u8, blocks: &[u16]) -> CpmDirEntry {
-        assert!(blocks.len() <= BLOCKS_PER_EXTENT);
-        let mut blocks_array = [0u16; BLOCKS_PER_EXTENT];
+    pub fn new(file_id: FileId, extent: u16, record_count: u8, blocks: &[u16], wide_blocks: bool) -> CpmDirEntry {
+        assert!(blocks.len() <= Self::blocks_per_extent(wide_blocks));
+        let mut blocks_array = [0u16; MAX_BLOCKS_PER_EXTENT];
         blocks_array[0..blocks.len()].copy_from_slice(blocks);
 
         CpmDirEntry {
@@ -79,9 +124,47 @@ impl CpmDirEntry {
             extent,
             record_count,
             blocks: blocks_array,
+            wide_blocks,
             read_only: false,
             system_file: false,
             archived: false,
+            attributes: 0,
+            last_record_bytes: 0,
+        }
+    }
+
+    /// Serialize the entry back (in place) to a given 32-byte directory slot.
+    ///
+    /// As with FileId, only the first byte is set for unused entries.
+    pub fn to_bytes(&self, data: &mut [u8; 32]) {
+        self.file_id.to_bytes(&mut data[0..12]);
+        if self.is_free() {
+            return;
+        }
+
+        for (idx, flag) in [self.read_only, self.system_file, self.archived].iter().enumerate() {
+            if *flag {
+                data[9 + idx] |= 0x80;
+            }
+        }
+        for idx in 0..4 {
+            if self.attributes & (1 << idx) != 0 {
+                data[1 + idx] |= 0x80;
+            }
+        }
+
+        data[12] = (self.extent & 0xFF) as u8;
+        data[13] = self.last_record_bytes;
+        data[14] = (self.extent >> 8) as u8;
+        data[15] = self.record_count;
+        if self.wide_blocks {
+            for (chunk, block) in data[16..32].chunks_exact_mut(2).zip(self.blocks) {
+                chunk.copy_from_slice(&block.to_le_bytes());
+            }
+        } else {
+            for (b, block) in data[16..32].iter_mut().zip(self.blocks) {
+                *b = block as u8;
+            }
         }
     }
 
@@ -100,8 +183,19 @@ impl CpmDirEntry {
         self.file_id.filename()
     }
 
+    /// Returns true for entries describing files (as opposed to unused or special entries).
     pub fn used(&self) -> bool {
-        self.file_id.user != 0xE5
+        self.file_id.user <= MAX_USER_ID
+    }
+
+    /// Returns true for unused (including deleted) entries.
+    pub fn is_free(&self) -> bool {
+        self.file_id.user == 0xE5
+    }
+
+    /// Returns true for CP/M Plus directory label entry.
+    pub fn is_label(&self) -> bool {
+        self.file_id.user == LABEL_USER
     }
 
     pub fn owner(&self) -> Option<u8> {
@@ -127,3 +221,79 @@ impl CpmDirEntry {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CpmDirEntry;
+    use crate::cpm::cpm_fs::CpmVersion::{V22, V3};
+
+    #[test]
+    fn test_to_bytes_roundtrip() {
+        let bytes =
+            *b"\x03FOO     P\xC1S\x01\x55\x00\x80\x10\x00\x11\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        let entry = CpmDirEntry::from_bytes(&bytes, V3, true).unwrap();
+        assert_eq!(entry.extent, 1);
+        assert_eq!(entry.record_count, 0x80);
+        assert_eq!(entry.blocks(), vec![0x10, 0x111]);
+        assert!(entry.system_file);
+
+        let mut out = [0xAA; 32];
+        entry.to_bytes(&mut out);
+        assert_eq!(out, bytes);
+
+        // 8-bit block numbers of small disks
+        assert!(CpmDirEntry::from_bytes(&bytes, V3, false).is_err());
+        let mut bytes = bytes;
+        bytes[16..32].copy_from_slice(&[0x10, 0x11, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let entry = CpmDirEntry::from_bytes(&bytes, V3, false).unwrap();
+        assert_eq!(entry.blocks(), vec![0x10, 0x11, 0x12]);
+        let mut out = [0xAA; 32];
+        entry.to_bytes(&mut out);
+        assert_eq!(out, bytes);
+    }
+
+    #[test]
+    fn test_version_semantics() {
+        let bytes =
+            *b"\x00\xC6OO     COM\x00\x20\x00\x01\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert!(CpmDirEntry::from_bytes(&bytes, V22, true).is_err());
+
+        let entry = CpmDirEntry::from_bytes(&bytes, V3, true).unwrap();
+        assert_eq!(entry.file_name(), "FOO.COM");
+        assert_eq!(entry.attributes, 0x01);
+        assert_eq!(entry.last_record_bytes, 0x20);
+        let mut out = [0u8; 32];
+        entry.to_bytes(&mut out);
+        assert_eq!(out, bytes);
+
+        let mut label = [0u8; 32];
+        label[0..12].copy_from_slice(b"\x20JUNIOR     ");
+        assert!(CpmDirEntry::from_bytes(&label, V22, true).is_err());
+        assert!(CpmDirEntry::from_bytes(&label, V3, true).is_ok());
+    }
+
+    #[test]
+    fn test_label_entry() {
+        let mut bytes = [0u8; 32];
+        bytes[0..12].copy_from_slice(b"\x20JUNIOR  \xB1\xB2\xB3");
+        bytes[12] = 0x71;
+        bytes[16..32].copy_from_slice(&[0xFF; 16]);
+        let entry = CpmDirEntry::from_bytes(&bytes, V3, true).unwrap();
+        assert!(entry.is_label());
+        assert!(!entry.used());
+        assert!(!entry.is_free());
+
+        let mut out = [0u8; 32];
+        entry.to_bytes(&mut out);
+        assert_eq!(out, bytes);
+    }
+
+    #[test]
+    fn test_to_bytes_unused() {
+        let entry = CpmDirEntry::from_bytes(&[0xE5; 32], V3, true).unwrap();
+        let mut out = [0x11; 32];
+        entry.to_bytes(&mut out);
+        assert_eq!(out[0], 0xE5);
+        assert!(out[1..].iter().all(|&b| b == 0x11));
+    }
+}
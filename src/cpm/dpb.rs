@@ -0,0 +1,96 @@
+use crate::cpm::cpm_fs::{CpmVersion, Params};
+
+/// CP/M Disk Parameter Block, the BIOS description of the disk format.
+///
+/// All the values are derived from the filesystem parameters.
+#[derive(Debug, PartialEq)]
+pub struct Dpb {
+    /// 128-byte records per track
+    pub spt: u16,
+    /// block shift factor, log2(block size / 128)
+    pub bsh: u8,
+    /// block mask, block size / 128 - 1
+    pub blm: u8,
+    /// extent mask
+    pub exm: u8,
+    /// number of the last block
+    pub dsm: u16,
+    /// number of the last directory entry
+    pub drm: u16,
+    /// directory blocks allocation bitmap (AL0 is the high byte)
+    pub al: u16,
+    /// size of the directory check vector
+    pub cks: u16,
+    /// number of reserved tracks
+    pub off: u16,
+    /// physical record shift factor (CP/M Plus only)
+    pub psh: u8,
+    /// physical record mask (CP/M Plus only)
+    pub phm: u8,
+}
+
+impl Dpb {
+    pub fn new(params: &Params, num_blocks: u16) -> Self {
+        Self {
+            spt: params.spt(),
+            bsh: params.bsh(),
+            blm: params.blm(),
+            exm: params.exm(num_blocks),
+            dsm: num_blocks - 1,
+            drm: params.drm(),
+            al: params.al(),
+            cks: params.cks(),
+            off: params.off(),
+            psh: params.psh(),
+            phm: params.phm(),
+        }
+    }
+
+    /// Serializes the DPB as expected by the BIOS: 15 bytes for CP/M 2.2, 17 bytes for CP/M Plus.
+    pub fn to_bytes(&self, version: CpmVersion) -> Vec<u8> {
+        let mut data = Vec::with_capacity(17);
+        data.extend_from_slice(&self.spt.to_le_bytes());
+        data.extend_from_slice(&[self.bsh, self.blm, self.exm]);
+        data.extend_from_slice(&self.dsm.to_le_bytes());
+        data.extend_from_slice(&self.drm.to_le_bytes());
+        data.extend_from_slice(&self.al.to_be_bytes());
+        data.extend_from_slice(&self.cks.to_le_bytes());
+        data.extend_from_slice(&self.off.to_le_bytes());
+        if version == CpmVersion::V3 {
+            data.extend_from_slice(&[self.psh, self.phm]);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dpb;
+    use crate::cpm::cpm_fs::CpmVersion::{V22, V3};
+    use crate::cpm::cpm_fs::Params;
+
+    #[test]
+    fn test_junior_dpb() {
+        let params = Params {
+            sectors_per_track: 9,
+            first_sector_id: 1,
+            reserved_tracks: 2,
+            sector_size: 512,
+            sectors_per_block: 4,
+            dir_blocks: 4,
+            version: V22,
+        };
+        let dpb = Dpb::new(&params, 355);
+        assert_eq!(dpb.spt, 36);
+        assert_eq!((dpb.bsh, dpb.blm, dpb.exm), (4, 15, 0));
+        assert_eq!((dpb.dsm, dpb.drm), (354, 255));
+        assert_eq!(dpb.al, 0xF000);
+        assert_eq!((dpb.cks, dpb.off), (64, 2));
+
+        assert_eq!(
+            dpb.to_bytes(V22),
+            vec![36, 0, 4, 15, 0, 0x62, 0x01, 0xFF, 0x00, 0xF0, 0x00, 64, 0, 2, 0]
+        );
+        assert_eq!(dpb.to_bytes(V3)[15..], [2, 3]);
+    }
+}
@@ -14,6 +14,10 @@ pub enum FilenameMode {
 }
 
 pub const MAX_USER_ID: u8 = 15;
+/// "User" byte of CP/M Plus directory label entry.
+pub const LABEL_USER: u8 = 0x20;
+/// "User" byte of CP/M Plus date stamps entry (SFCB).
+pub const TIMESTAMPS_USER: u8 = 0x21;
 pub const MAX_NAME_LEN: usize = 8;
 pub const MAX_EXT_LEN: usize = 3;
 
@@ -81,18 +85,17 @@ impl FileId {
         id.name.copy_from_slice(name);
         id.extension.copy_from_slice(extension);
 
-        // Note: perform this validation only for non-deleted entries.
-        // Deleted ones might not be valid, or might be all 0xE5.
-        if user != 0xE5 {
+        // Note: perform this validation only for file entries. Deleted ones might not be valid,
+        // or might be all 0xE5. Label and date stamps entries have their own layout.
+        if user <= MAX_USER_ID {
             // note: name is not used for flags, so it should be ASCII without trimming
             id.extension.iter_mut().for_each(|b| *b &= 0x7F);
 
-            if user > MAX_USER_ID {
-                bail!("invalid user ID: {}", user);
-            }
             if !ValidNameRe.is_match(&id.name) || !ValidExtRe.is_match(&id.extension) {
                 bail!("invalid name: {:?}.{:?}", name, extension);
             }
+        } else if user != 0xE5 && user != LABEL_USER && user != TIMESTAMPS_USER {
+            bail!("invalid user ID: {}", user);
         }
 
         Ok(id)
@@ -103,7 +106,7 @@ impl FileId {
     /// Note: for deleted entries we only set the first byte, leaving everything else
     /// untouched. This is to preserve deleted entries as is when serializing the whole image
     /// back to dsk file.
-    pub fn to_bytes(&self, bytes: &mut [u8]) {
+    pub fn to_bytes(self, bytes: &mut [u8]) {
         bytes[0] = self.user;
         if self.user != 0xE5 {
             bytes[1..1 + MAX_NAME_LEN].copy_from_slice(&self.name);
@@ -117,21 +120,36 @@ impl FileId {
         format!("{}.{}", name.trim_end(), extension.trim_end())
     }
 
-    fn parse_filename(filename: &str) -> Option<(&str, &str)> {
-        // make sure it's a valid 8.3 name
-        if let Some(parts) = filename.split_once('.') {
-            // ensure it is at most 8 + 3 ASCII characters
-            if parts.1.contains('.')
-                || parts.0.len() > MAX_NAME_LEN
-                || parts.1.len() > MAX_EXT_LEN
-                || !(parts.0.is_ascii() && parts.1.is_ascii())
-            {
-                return None;
-            }
+    /// Returns a numbered variant of the ID (e.g. GAME1.COM for n = 1), used to avoid name clashes.
+    ///
+    /// The name is shortened if needed, to fit 8 characters. Returns None if the number is too long.
+    pub fn numbered(&self, n: usize) -> Option<FileId> {
+        let suffix = n.to_string();
+        if suffix.len() > MAX_NAME_LEN {
+            return None;
+        }
+        let name_len = self.name.iter().position(|&b| b == 0x20).unwrap_or(MAX_NAME_LEN);
+        let keep = name_len.min(MAX_NAME_LEN - suffix.len());
+
+        let mut id = *self;
+        id.name[keep..keep + suffix.len()].copy_from_slice(suffix.as_bytes());
+        id.name[keep + suffix.len()..].fill(0x20);
+        Some(id)
+    }
 
-            return Some(parts);
+    fn parse_filename(filename: &str) -> Option<(&str, &str)> {
+        // make sure it's a valid 8.3 name, extension is optional
+        let parts = filename.split_once('.').unwrap_or((filename, ""));
+        // ensure it is at most 8 + 3 ASCII characters
+        if parts.1.contains('.')
+            || parts.0.len() > MAX_NAME_LEN
+            || parts.1.len() > MAX_EXT_LEN
+            || !(parts.0.is_ascii() && parts.1.is_ascii())
+        {
+            return None;
         }
-        None
+
+        Some(parts)
     }
 
     fn str_to_padded_bytes(dst: &mut [u8], n: &str, mode: FilenameMode) {
@@ -157,6 +175,18 @@ mod tests {
         assert_eq!(id.extension, *b"Pas");
     }
 
+    #[test]
+    fn test_numbered() {
+        let id = FileId::new_with_filename(3, "game.com", Normalized).unwrap();
+        assert_eq!(id.numbered(1).unwrap().filename(), "GAME1.COM");
+        assert_eq!(id.numbered(12).unwrap().user, 3);
+
+        let id = FileId::new_with_filename(0, "longname.txt", Normalized).unwrap();
+        assert_eq!(id.numbered(7).unwrap().filename(), "LONGNAM7.TXT");
+        assert_eq!(id.numbered(123).unwrap().filename(), "LONGN123.TXT");
+        assert!(id.numbered(123456789).is_none());
+    }
+
     #[test]
     fn test_new_valid_case_norm() {
         let id = FileId::new_with_filename(1, "FoO.Pas", Normalized).unwrap();
@@ -165,6 +195,14 @@ mod tests {
         assert_eq!(id.extension, *b"PAS");
     }
 
+    #[test]
+    fn test_new_without_extension() {
+        let id = FileId::new_with_filename(0, "sign1", Normalized).unwrap();
+        assert_eq!(id.name, *b"SIGN1   ");
+        assert_eq!(id.extension, *b"   ");
+        assert_eq!(id, FileId::new_with_filename(0, "SIGN1.", Normalized).unwrap());
+    }
+
     #[test]
     fn test_new_invalid_name() {
         assert!(FileId::new_with_filename(1, "a.b.c", FilenameMode::Normalized).is_err());
@@ -199,7 +237,7 @@ mod tests {
     fn test_to_bytes_deleted() {
         let mut id = FileId::new_with_filename(3, "FoO.Pas", Normalized).unwrap();
         id.user = 0xE5;
-        let mut bytes = b"0123456789AB".clone();
+        let mut bytes = *b"0123456789AB";
         id.to_bytes(&mut bytes);
         assert_eq!(bytes, *b"\xE5123456789AB");
     }
@@ -209,6 +247,16 @@ mod tests {
         assert!(FileId::from_bytes(b"A123456789AB").is_err());
     }
 
+    #[test]
+    fn test_from_bytes_special_entries() {
+        // label entry
+        let id = FileId::from_bytes(b"\x20MY DISK    ").unwrap();
+        assert_eq!(id.user, 0x20);
+        // date stamps entry is binary data, must be kept as is
+        let id = FileId::from_bytes(b"\x21\x01\x02\x03\x04\x05\x06\x07\x08\x89\x8A\x8B").unwrap();
+        assert_eq!(id.extension, [0x89, 0x8A, 0x8B]);
+    }
+
     #[test]
     fn test_from_bytes_valid_case() {
         let id = FileId::from_bytes(b"\x00TesT    zX ");
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Days between 1970-01-01 and 1977-12-31 (CP/M day 0).
+const CPM_EPOCH_DAYS: u64 = 2921;
+
+/// CP/M Plus date stamp: days since 1977-12-31 (day 1 is 1978-01-01), hour and minute in BCD.
+///
+/// CP/M has no notion of time zones, stamps are treated as UTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timestamp {
+    pub days: u16,
+    pub hour: u8,
+    pub minute: u8,
+}
+
+impl Timestamp {
+    /// Parses 4 bytes of a stamp, returns None for unset (all zero days) ones.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let days = u16::from_le_bytes([data[0], data[1]]);
+        if days == 0 {
+            return None;
+        }
+        Some(Self {
+            days,
+            hour: from_bcd(data[2]),
+            minute: from_bcd(data[3]),
+        })
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        let secs = (CPM_EPOCH_DAYS + self.days as u64) * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60;
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// Returns the date as (year, month, day).
+    pub fn date(self) -> (u16, u8, u8) {
+        let (y, m, d) = civil_from_days(CPM_EPOCH_DAYS + self.days as u64);
+        (y as u16, m as u8, d as u8)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (y, m, d) = civil_from_days(CPM_EPOCH_DAYS + self.days as u64);
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}", y, m, d, self.hour, self.minute)
+    }
+}
+
+/// Time stamps of a single file, as stored in the date stamp (SFCB) directory entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileTimes {
+    /// creation or access time, depending on the directory label settings
+    pub created: Option<Timestamp>,
+    pub updated: Option<Timestamp>,
+}
+
+impl FileTimes {
+    /// Parses the stamps of the idx-th (0..3) entry from the raw SFCB entry.
+    ///
+    /// The SFCB holds 10 bytes per each of the 3 preceding entries, starting at offset 1:
+    /// create/access stamp, update stamp, password mode and a reserved byte.
+    pub fn from_sfcb(data: &[u8; 32], idx: usize) -> Self {
+        let stamps = &data[1 + idx * 10..1 + idx * 10 + 8];
+        Self {
+            created: Timestamp::from_bytes(&stamps[0..4]),
+            updated: Timestamp::from_bytes(&stamps[4..8]),
+        }
+    }
+}
+
+fn from_bcd(b: u8) -> u8 {
+    (b >> 4) * 10 + (b & 0x0F)
+}
+
+/// Converts days since 1970-01-01 to (year, month, day), see Howard Hinnant's date algorithms.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileTimes, Timestamp};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(Timestamp::from_bytes(&[0, 0, 0x12, 0x30]), None);
+
+        let ts = Timestamp::from_bytes(&[1, 0, 0x12, 0x30]).unwrap();
+        assert_eq!(ts.to_string(), "1978-01-01 12:30");
+        assert_eq!(ts.to_system_time(), UNIX_EPOCH + Duration::from_secs(252_505_800));
+
+        // 1988-02-29 is day 3712
+        let ts = Timestamp::from_bytes(&[0x80, 0x0E, 0x23, 0x59]).unwrap();
+        assert_eq!(ts.to_string(), "1988-02-29 23:59");
+    }
+
+    #[test]
+    fn test_from_sfcb() {
+        let mut sfcb = [0u8; 32];
+        sfcb[0] = 0x21;
+        sfcb[15..19].copy_from_slice(&[1, 0, 0x08, 0x15]);
+        let times = FileTimes::from_sfcb(&sfcb, 1);
+        assert_eq!(times.created, None);
+        assert_eq!(times.updated.unwrap().to_string(), "1978-01-01 08:15");
+        assert_eq!(FileTimes::from_sfcb(&sfcb, 0), FileTimes::default());
+    }
+}
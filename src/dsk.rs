@@ -1,5 +1,10 @@
+mod backend;
 mod image;
+mod raw;
 mod structs;
 
+pub use backend::DiskBackend;
 pub use image::DskImage;
 pub use image::CHS;
+pub use raw::RawImage;
+pub use structs::SectorInfo;
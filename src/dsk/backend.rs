@@ -0,0 +1,83 @@
+use anyhow::Result;
+use std::fs::File;
+
+use super::image::{DskImage, CHS};
+
+/// Sector level access to a disk image container, all the filesystem needs. New container
+/// formats only have to implement it.
+pub trait DiskBackend {
+    fn num_cylinders(&self) -> u8;
+
+    fn num_sides(&self) -> u8;
+
+    fn sector_as_slice(&self, chs: CHS) -> Result<&[u8]>;
+
+    fn sector_as_slice_mut(&mut self, chs: CHS) -> Result<&mut [u8]>;
+
+    /// Returns the position of the sector's data in the image file, None if there's no such sector.
+    fn sector_offset(&self, chs: CHS) -> Option<usize>;
+
+    /// Returns true if the sector exists and was read without errors. Containers not recording
+    /// read errors only check the sector exists.
+    fn sector_ok(&self, chs: CHS) -> bool {
+        self.sector_as_slice(chs).is_ok()
+    }
+
+    /// Changes the number of cylinders, new ones are formatted like the last existing one.
+    fn resize(&mut self, num_cylinders: u8) -> Result<()>;
+
+    /// Writes the whole image to the file, truncating it.
+    fn save(&self, f: &mut File) -> Result<()>;
+
+    /// Returns deviations from the container format found while loading the image.
+    fn warnings(&self) -> &[String] {
+        &[]
+    }
+
+    /// Returns the DSK image, for information specific to the DSK container.
+    fn as_dsk(&self) -> Option<&DskImage> {
+        None
+    }
+}
+
+impl DiskBackend for DskImage {
+    fn num_cylinders(&self) -> u8 {
+        self.num_cylinders()
+    }
+
+    fn num_sides(&self) -> u8 {
+        self.num_sides()
+    }
+
+    fn sector_as_slice(&self, chs: CHS) -> Result<&[u8]> {
+        self.sector_as_slice(chs)
+    }
+
+    fn sector_as_slice_mut(&mut self, chs: CHS) -> Result<&mut [u8]> {
+        self.sector_as_slice_mut(chs)
+    }
+
+    fn sector_offset(&self, chs: CHS) -> Option<usize> {
+        self.sector_offset(chs)
+    }
+
+    fn sector_ok(&self, chs: CHS) -> bool {
+        self.sector_ok(chs)
+    }
+
+    fn resize(&mut self, num_cylinders: u8) -> Result<()> {
+        self.resize(num_cylinders)
+    }
+
+    fn save(&self, f: &mut File) -> Result<()> {
+        self.save(f)
+    }
+
+    fn warnings(&self) -> &[String] {
+        self.warnings()
+    }
+
+    fn as_dsk(&self) -> Option<&DskImage> {
+        Some(self)
+    }
+}
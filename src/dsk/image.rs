@@ -1,10 +1,16 @@
-use super::structs::{DskFileHeader, TrackInfo};
-use anyhow::{anyhow, bail, Result};
+use super::structs::{block_padding, DskFileHeader, SectorInfo, TrackInfo, DSK_SIGNATURE, MAX_TRACKS, MAX_TRACK_SIZE};
+use anyhow::{anyhow, bail, Context, Result};
 use binrw::{BinReaderExt, BinWrite};
+use std::fmt;
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use crate::error::Location;
 
 /// CHS encapsulates cylinder/head/sector address
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct CHS {
     /// cylinder number, 0 based
     pub cylinder: u8,
@@ -14,26 +20,95 @@ pub struct CHS {
     pub sector: u8,
 }
 
+impl fmt::Display for CHS {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "C{}/H{}/R{}", self.cylinder, self.head, self.sector)
+    }
+}
+
 pub struct DskImage {
     header: DskFileHeader,
     tracks: Vec<DskImageTrack>,
+    /// data of all sectors, track after track, as stored in the image
+    data: Vec<u8>,
+    /// data following the last track (e.g. an offset info block), kept as is
+    trailer: Vec<u8>,
+    /// deviations from the format tolerated while loading
+    warnings: Vec<String>,
 }
 
 impl DskImage {
-    pub fn load(f: &mut File) -> Result<Self> {
-        let header: DskFileHeader = f.read_le()?;
-        let mut tracks = Vec::with_capacity((header.num_cylinders * header.num_sides) as usize);
+    /// Loads the image. Quirks of real-world writers (odd signature or creator, track size
+    /// table not matching the actual track layout) are tolerated and reported by `warnings()`.
+    pub fn load(f: &mut (impl Read + Seek)) -> Result<Self> {
+        let mut header: DskFileHeader = f.read_le()?;
+        let mut warnings = vec![];
+        if header.signature != DSK_SIGNATURE {
+            warnings.push(format!(
+                "non-standard signature {:?}",
+                String::from_utf8_lossy(&header.signature)
+            ));
+            header.signature = DSK_SIGNATURE;
+        }
+        if !header
+            .name_of_creator
+            .iter()
+            .all(|&b| b == 0 || b == b' ' || b.is_ascii_graphic())
+        {
+            warnings.push("creator name is not ASCII text".to_string());
+        }
+        let mut tracks = Vec::with_capacity(header.track_sizes.len());
+        // track sizes include the 256 bytes track info block
+        let data_size = header
+            .track_sizes
+            .iter()
+            .map(|&s| (s as usize).saturating_sub(256))
+            .sum();
+        let mut data = Vec::with_capacity(data_size);
 
+        // track offset according to the track size table, the first one follows the header block
+        let mut declared_pos = 256;
         for c in 0..header.num_cylinders {
             for h in 0..header.num_sides {
-                let idx = c * header.num_sides + h;
+                let idx = c as usize * header.num_sides as usize + h as usize;
+                let declared_size = header.track_sizes[idx] as u64;
+                // unformatted track, stored without a track info block
+                if declared_size == 0 {
+                    tracks.push(DskImageTrack::new(TrackInfo::unformatted(c, h), &mut data));
+                    continue;
+                }
 
-                let file_pos = f.seek(SeekFrom::Current(0))?;
-                let track: DskImageTrack = DskImageTrack::load(f)?;
-                let loaded_bytes = f.seek(SeekFrom::Current(0))? - file_pos;
-                if loaded_bytes != 256 * header.track_sizes[idx as usize] as u64 {
-                    bail!("Track {} size invalid", idx);
+                // some writers don't pad the tracks to what the size table says, so if there's
+                // no track at the declared offset, try right after the previous one
+                let previous_end = f.stream_position()?;
+                let arena_len = data.len();
+                f.seek(SeekFrom::Start(declared_pos))?;
+                let (track, file_pos) = match DskImageTrack::load(f, &mut data) {
+                    Ok(track) => (track, declared_pos),
+                    Err(_) if previous_end != declared_pos => {
+                        data.truncate(arena_len);
+                        f.seek(SeekFrom::Start(previous_end))?;
+                        let track =
+                            DskImageTrack::load(f, &mut data).with_context(|| format!("Can't load track {}", idx))?;
+                        warnings.push(format!(
+                            "track {} found at offset {:#x}, not at {:#x}",
+                            idx, previous_end, declared_pos
+                        ));
+                        (track, previous_end)
+                    }
+                    Err(e) => return Err(e.context(format!("Can't load track {}", idx))),
+                };
+                let loaded_bytes = (f.stream_position()? - file_pos).next_multiple_of(256);
+                if loaded_bytes != declared_size {
+                    warnings.push(format!(
+                        "track {} takes {} bytes, {} declared",
+                        idx, loaded_bytes, declared_size
+                    ));
+                    // saved images get the size of what's actually stored
+                    header.track_sizes[idx] = loaded_bytes as u32;
                 }
+                // sizes of the following tracks are relative to where this one really is
+                declared_pos = file_pos + declared_size;
 
                 if track.header.cylinder_number != c || track.header.side_number != h {
                     bail!("Invalid track order");
@@ -43,15 +118,121 @@ impl DskImage {
             }
         }
 
-        Ok(Self { header, tracks })
+        let mut trailer = vec![];
+        f.read_to_end(&mut trailer)?;
+
+        Ok(Self {
+            header,
+            tracks,
+            data,
+            trailer,
+            warnings,
+        })
+    }
+
+    /// Returns the rest of the header block after the track size table, normally zeros.
+    pub fn header_extension(&self) -> &[u8] {
+        &self.header.extension
+    }
+
+    /// Returns data stored after the last track (e.g. an offset info block), normally empty.
+    pub fn trailer(&self) -> &[u8] {
+        &self.trailer
+    }
+
+    /// Returns deviations from the DSK format found while loading the image.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Creates a freshly formatted image, all tracks having the same layout.
+    ///
+    /// Sector IDs are given in the physical order (i.e. with interleave applied),
+    /// all sectors are filled with the filler byte. The sector size is a power of two, 128 bytes
+    /// at least.
+    pub fn format(num_cylinders: u8, num_sides: u8, sector_size: u16, sector_ids: &[u8], gap3: u8, filler: u8) -> Self {
+        let mut tracks = Vec::with_capacity(num_cylinders as usize * num_sides as usize);
+        let mut data = vec![];
+        for c in 0..num_cylinders {
+            for h in 0..num_sides {
+                let header = TrackInfo::new(c, h, sector_size, sector_ids, gap3, filler);
+                tracks.push(DskImageTrack::new(header, &mut data));
+            }
+        }
+
+        // track info block (256 bytes) plus the sector data, in 256 bytes units
+        let track_size = 256 * (1 + (sector_size as u32 * sector_ids.len() as u32).div_ceil(256));
+        let header = DskFileHeader::new(num_cylinders, num_sides, track_size);
+        Self {
+            header,
+            tracks,
+            data,
+            trailer: vec![],
+            warnings: vec![],
+        }
+    }
+
+    /// Changes the number of cylinders: new ones are formatted like the last existing one,
+    /// removed ones are dropped with all their data.
+    pub fn resize(&mut self, num_cylinders: u8) -> Result<()> {
+        if num_cylinders == 0 {
+            bail!("Image must have at least one cylinder");
+        }
+        let num_sides = self.header.num_sides;
+        if num_cylinders as usize * num_sides as usize > MAX_TRACKS {
+            bail!("DSK image can't have more than {} tracks", MAX_TRACKS);
+        }
+        let template = &self.tracks.last().context("Image has no tracks")?.header;
+        let sector_ids: Vec<u8> = template.sectors.iter().map(|s| s.sector_id).collect();
+        let (sector_size, gap3, filler) = (template.sector_size, template.gap3_length, template.filler_byte);
+        let track_size = *self.header.track_sizes.last().unwrap();
+
+        self.tracks.truncate(num_cylinders as usize * num_sides as usize);
+        let data_end = self.tracks.last().map_or(0, |t| t.offset + t.data_size());
+        self.data.truncate(data_end);
+        for c in self.header.num_cylinders..num_cylinders {
+            for h in 0..num_sides {
+                let header = TrackInfo::new(c, h, sector_size, &sector_ids, gap3, filler);
+                self.tracks.push(DskImageTrack::new(header, &mut self.data));
+            }
+        }
+
+        let num_tracks = num_cylinders as usize * num_sides as usize;
+        self.header.track_sizes.resize(num_tracks, track_size);
+        self.header.extension.resize(block_padding(0x34 + num_tracks), 0);
+        self.header.num_cylinders = num_cylinders;
+        // whatever follows the tracks may describe their layout, which is gone now
+        self.trailer.clear();
+        Ok(())
     }
 
     pub fn save(&self, f: &mut File) -> Result<()> {
+        if self.tracks.len() > MAX_TRACKS {
+            bail!("DSK image can't have more than {} tracks", MAX_TRACKS);
+        }
+        if let Some(idx) = self.header.track_sizes.iter().position(|&s| s > MAX_TRACK_SIZE) {
+            bail!(
+                "Track {} too large for the DSK format: {} bytes, at most {}",
+                idx,
+                self.header.track_sizes[idx],
+                MAX_TRACK_SIZE
+            );
+        }
         f.seek(SeekFrom::Start(0))?;
         self.header.write_le(f)?;
-        for track in &self.tracks {
-            track.save(f)?;
+        for (track, &size) in self.tracks.iter().zip(&self.header.track_sizes) {
+            if size == 0 {
+                continue;
+            }
+            track.header.write_le(f)?;
+            f.write_all(&self.data[track.offset..track.offset + track.data_size()])?;
+            // track blocks take whole 256 bytes units, e.g. with an odd number of 128 bytes sectors
+            f.write_all(&vec![0; block_padding(track.data_size())])?;
         }
+        f.write_all(&self.trailer)?;
+        // the image might have shrunk
+        let end = f.stream_position()?;
+        f.set_len(end)?;
         Ok(())
     }
 
@@ -63,6 +244,29 @@ impl DskImage {
         self.header.num_sides
     }
 
+    /// Iterates over the tracks, cylinder by cylinder, side by side.
+    pub fn tracks(&self) -> impl Iterator<Item = TrackView<'_>> {
+        let sides = self.header.num_sides;
+        self.tracks.iter().enumerate().map(move |(idx, track)| TrackView {
+            cylinder: (idx / sides as usize) as u8,
+            head: (idx % sides as usize) as u8,
+            track,
+            data: &self.data,
+        })
+    }
+
+    /// Returns the sector's metadata, None if there's no such sector.
+    pub fn sector_info(&self, chs: CHS) -> Option<&SectorInfo> {
+        let track = &self.tracks[self.ch_to_track_index(chs.cylinder, chs.head).ok()?];
+        track.sector_idx(chs.sector).map(|idx| &track.header.sectors[idx])
+    }
+
+    /// Returns IDs of the track's sectors, in the physical order.
+    pub fn sector_ids(&self, cylinder: u8, head: u8) -> Result<Vec<u8>> {
+        let track = self.ch_to_track_index(cylinder, head)?;
+        Ok(self.tracks[track].header.sectors.iter().map(|s| s.sector_id).collect())
+    }
+
     fn ch_to_track_index(&self, cylinder: u8, head: u8) -> Result<usize> {
         if head >= self.header.num_sides {
             bail!("Invalid head (side) number: {}", head);
@@ -71,43 +275,146 @@ impl DskImage {
             bail!("Invalid cylinder number: {}", cylinder);
         }
 
-        Ok((cylinder * self.header.num_sides + head) as usize)
+        Ok(cylinder as usize * self.header.num_sides as usize + head as usize)
     }
 
-    pub fn sector_as_slice(&self, chs: CHS) -> Result<&[u8]> {
-        let track = self.ch_to_track_index(chs.cylinder, chs.head)?;
-        self.tracks[track]
-            .sector_as_slice(chs.sector)
+    /// Returns the position of the track in the image file (as saved): after the header block
+    /// and the preceding tracks, each with its info block.
+    fn track_offset(&self, track: usize) -> usize {
+        256 + self.header.track_sizes[..track]
+            .iter()
+            .map(|&s| s as usize)
+            .sum::<usize>()
+    }
+
+    /// Returns the position of the sector's data in the image file, None if there's no such sector.
+    pub fn sector_offset(&self, chs: CHS) -> Option<usize> {
+        let track_idx = self.ch_to_track_index(chs.cylinder, chs.head).ok()?;
+        let track = &self.tracks[track_idx];
+        let idx = track.sector_idx(chs.sector)?;
+        Some(self.track_offset(track_idx) + 256 + idx * track.header.sector_size as usize)
+    }
+
+    /// Returns the position of the sector's data in the arena.
+    fn sector_range(&self, chs: CHS) -> Result<Range<usize>> {
+        let location = |offset| Location {
+            chs: Some(chs),
+            offset,
+            slot: None,
+        };
+        let track_idx = self.ch_to_track_index(chs.cylinder, chs.head).context(location(None))?;
+        let track = &self.tracks[track_idx];
+        let idx = track
+            .sector_idx(chs.sector)
             .ok_or(anyhow!("Sector not found"))
+            .context(location(Some(self.track_offset(track_idx))))?;
+        let sector_size = track.header.sector_size as usize;
+        let start = track.offset + idx * sector_size;
+        Ok(start..start + sector_size)
+    }
+
+    pub fn sector_as_slice(&self, chs: CHS) -> Result<&[u8]> {
+        let range = self.sector_range(chs)?;
+        Ok(&self.data[range])
+    }
+
+    /// Returns true if the sector exists and was read without FDC errors.
+    pub fn sector_ok(&self, chs: CHS) -> bool {
+        self.sector_info(chs).is_some_and(|s| s.fdc_st1 == 0 && s.fdc_st2 == 0)
     }
 
     pub fn sector_as_slice_mut(&mut self, chs: CHS) -> Result<&mut [u8]> {
-        let track = self.ch_to_track_index(chs.cylinder, chs.head)?;
-        self.tracks[track]
-            .sector_as_slice_mut(chs.sector)
-            .ok_or(anyhow!("Sector not found"))
+        let range = self.sector_range(chs)?;
+        Ok(&mut self.data[range])
+    }
+}
+
+/// Read-only view of a track, see [`DskImage::tracks`].
+pub struct TrackView<'a> {
+    cylinder: u8,
+    head: u8,
+    track: &'a DskImageTrack,
+    data: &'a [u8],
+}
+
+impl<'a> TrackView<'a> {
+    /// cylinder number, 0 based (the track's position in the image)
+    pub fn cylinder(&self) -> u8 {
+        self.cylinder
+    }
+
+    pub fn head(&self) -> u8 {
+        self.head
+    }
+
+    /// metadata of the sectors, in the physical order
+    pub fn sectors(&self) -> &'a [SectorInfo] {
+        &self.track.header.sectors
+    }
+
+    /// rest of the track info block after the sector list, normally zeros
+    pub fn extension(&self) -> &'a [u8] {
+        &self.track.header.extension
+    }
+
+    /// sectors with their data, in the physical order
+    pub fn sector_data(&self) -> impl Iterator<Item = (&'a SectorInfo, &'a [u8])> + 'a {
+        // unformatted tracks have neither sectors nor a sector size
+        let sector_size = (self.track.header.sector_size as usize).max(1);
+        let data = &self.data[self.track.offset..self.track.offset + self.track.data_size()];
+        self.track.header.sectors.iter().zip(data.chunks_exact(sector_size))
     }
 }
 
+/// Marks sector IDs not present on the track (a track has at most 255 sectors).
+const NO_SECTOR: u8 = 0xFF;
+
 struct DskImageTrack {
     header: TrackInfo,
-    /// data of all track sectors, as stored in the image
-    sector_data: Vec<u8>,
+    /// offset of the track's sector data in the image arena
+    offset: usize,
     /// maps sector ID (R in uPD765 parlance) to sector index in the track image
-    sector_index: [Option<usize>; 256],
+    sector_index: [u8; 256],
 }
 
 impl DskImageTrack {
-    fn load(f: &mut File) -> Result<Self> {
+    /// Creates the track, appending its sectors (filled with the filler byte) to the arena.
+    fn new(header: TrackInfo, data: &mut Vec<u8>) -> Self {
+        let sector_index = Self::index_sectors(&header).expect("sector IDs must be unique");
+        let offset = data.len();
+        let track = DskImageTrack {
+            header,
+            offset,
+            sector_index,
+        };
+        data.resize(offset + track.data_size(), track.header.filler_byte);
+        track
+    }
+
+    /// Loads the track, appending its sector data to the arena.
+    fn load(f: &mut (impl Read + Seek), data: &mut Vec<u8>) -> Result<Self> {
         let header: TrackInfo = f.read_le()?;
+        let sector_index = Self::index_sectors(&header)?;
+
+        let offset = data.len();
+        let track = DskImageTrack {
+            header,
+            offset,
+            sector_index,
+        };
+        data.resize(offset + track.data_size(), 0);
+        f.read_exact(&mut data[offset..])?;
+        Ok(track)
+    }
 
-        let mut sector_index = [None; 256];
+    fn index_sectors(header: &TrackInfo) -> Result<[u8; 256]> {
+        let mut sector_index = [NO_SECTOR; 256];
         for (idx, s) in header.sectors.iter().enumerate() {
             if s.sector_size != header.sector_size {
                 bail!("Variable sector size not supported");
             }
 
-            if let Some(_) = sector_index[s.sector_id as usize] {
+            if sector_index[s.sector_id as usize] != NO_SECTOR {
                 bail!(
                     "sector ID {} on the track c={}, h={} is not unique",
                     s.cylinder,
@@ -115,42 +422,26 @@ impl DskImageTrack {
                     header.side_number
                 );
             }
-            sector_index[s.sector_id as usize] = Some(idx);
+            sector_index[s.sector_id as usize] = idx as u8;
         }
-
-        let buffer_size = header.sector_size as usize * header.num_sectors as usize;
-        let mut sector_data = vec![0; buffer_size];
-        f.read_exact(sector_data.as_mut_slice())?;
-
-        Ok(DskImageTrack {
-            header,
-            sector_data,
-            sector_index,
-        })
-    }
-
-    fn save(&self, f: &mut File) -> Result<()> {
-        self.header.write_le(f)?;
-        f.write_all(&self.sector_data)?;
-        Ok(())
+        Ok(sector_index)
     }
 
-    fn sector_as_slice(&self, sector_id: u8) -> Option<&[u8]> {
-        let sector_size = self.header.sector_size as usize;
-        self.sector_index[sector_id as usize]
-            .map(|i| &self.sector_data[i as usize * sector_size..(i + 1) as usize * sector_size])
+    fn data_size(&self) -> usize {
+        self.header.sector_size as usize * self.header.num_sectors as usize
     }
 
-    fn sector_as_slice_mut(&mut self, sector_id: u8) -> Option<&mut [u8]> {
-        let sector_size = self.header.sector_size as usize;
-        self.sector_index[sector_id as usize]
-            .map(|i| &mut self.sector_data[i as usize * sector_size..(i + 1) as usize * sector_size])
+    fn sector_idx(&self, sector_id: u8) -> Option<usize> {
+        let idx = self.sector_index[sector_id as usize];
+        (idx != NO_SECTOR).then_some(idx as usize)
     }
 }
+
 #[cfg(test)]
 mod tests {
-    use crate::dsk::image::DskImage;
+    use crate::dsk::image::{DskImage, CHS};
     use std::fs::File;
+    use std::io::Cursor;
     use std::path::PathBuf;
 
     #[test]
@@ -164,4 +455,233 @@ mod tests {
         let mut file = File::create(path).unwrap();
         image.save(&mut file).unwrap();
     }
+
+    #[test]
+    fn test_format() {
+        let image = DskImage::format(40, 1, 512, &[1, 6, 2, 7, 3, 8, 4, 9, 5], 0x2A, 0xE5);
+        assert_eq!(image.num_cylinders(), 40);
+        assert_eq!(image.num_sides(), 1);
+        assert_eq!(image.header.track_sizes, vec![19 * 256; 40]);
+
+        let chs = CHS {
+            cylinder: 39,
+            head: 0,
+            sector: 9,
+        };
+        assert!(image.sector_as_slice(chs).unwrap().iter().all(|&b| b == 0xE5));
+
+        // sector marked with CRC error in the data field
+        let mut image = image;
+        assert!(image.sector_ok(CHS {
+            cylinder: 0,
+            head: 0,
+            sector: 6
+        }));
+        image.tracks[0].header.sectors[1].fdc_st2 = 0x20;
+        assert!(!image.sector_ok(CHS {
+            cylinder: 0,
+            head: 0,
+            sector: 6
+        }));
+        assert!(!image.sector_ok(CHS {
+            cylinder: 0,
+            head: 0,
+            sector: 10
+        }));
+
+        // shrink and grow back, data of the dropped cylinders is lost
+        image
+            .sector_as_slice_mut(CHS {
+                cylinder: 39,
+                head: 0,
+                sector: 1,
+            })
+            .unwrap()[0] = 0;
+        image.resize(30).unwrap();
+        assert_eq!(image.header.track_sizes.len(), 30);
+        image.resize(40).unwrap();
+        assert_eq!(image.num_cylinders(), 40);
+        assert_eq!(image.header.track_sizes, vec![19 * 256; 40]);
+        assert_eq!(
+            image
+                .sector_as_slice(CHS {
+                    cylinder: 39,
+                    head: 0,
+                    sector: 1
+                })
+                .unwrap()[0],
+            0xE5
+        );
+
+        // saved image loads back
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_format.dsk");
+        image.save(&mut File::create(&path).unwrap()).unwrap();
+        let image = DskImage::load(&mut File::open(&path).unwrap()).unwrap();
+        assert_eq!(image.num_cylinders(), 40);
+    }
+
+    #[test]
+    fn test_tracks() {
+        let mut image = DskImage::format(2, 2, 512, &[1, 3, 2], 0x2A, 0xE5);
+        let chs = CHS {
+            cylinder: 1,
+            head: 0,
+            sector: 3,
+        };
+        image.sector_as_slice_mut(chs).unwrap()[0] = 0x42;
+
+        let tracks: Vec<_> = image.tracks().map(|t| (t.cylinder(), t.head())).collect();
+        assert_eq!(tracks, [(0, 0), (0, 1), (1, 0), (1, 1)]);
+
+        let track = image.tracks().nth(2).unwrap();
+        let ids: Vec<u8> = track.sector_data().map(|(info, _)| info.sector_id).collect();
+        assert_eq!(ids, [1, 3, 2]);
+        let (_, data) = track.sector_data().nth(1).unwrap();
+        assert_eq!((data.len(), data[0]), (512, 0x42));
+
+        assert_eq!(image.sector_info(chs).map(|s| s.sector_id), Some(3));
+        assert!(image.sector_info(CHS { sector: 4, ..chs }).is_none());
+    }
+
+    #[test]
+    fn test_load_quirks() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_quirks.dsk");
+        DskImage::format(2, 1, 512, &[1, 2], 0x2A, 0xE5)
+            .save(&mut File::create(&path).unwrap())
+            .unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        data[8..21].copy_from_slice(b" DSK (v2)\r\n  ");
+        // the size table says track 0 is padded to 1024 bytes, but track 1 follows right after it
+        data[0x34] = 4;
+
+        let image = DskImage::load(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(image.warnings().len(), 3);
+        assert!(image.warnings()[1].contains("track 0 takes 1280 bytes, 1024 declared"));
+        assert!(image.warnings()[2].contains("track 1 found at offset 0x600, not at 0x500"));
+        assert_eq!(image.tracks().count(), 2);
+
+        image.save(&mut File::create(&path).unwrap()).unwrap();
+        let image = DskImage::load(&mut File::open(&path).unwrap()).unwrap();
+        assert!(image.warnings().is_empty());
+
+        data[0..8].copy_from_slice(b"MV - CPC");
+        assert!(DskImage::load(&mut Cursor::new(&data)).is_err());
+    }
+
+    #[test]
+    fn test_unformatted_tracks() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/03.dsk");
+        let original = std::fs::read(path).unwrap();
+        let track_size = 19 * 256;
+        let track_start = |idx: usize| 256 + idx * track_size;
+        let chs = |cylinder, head| CHS {
+            cylinder,
+            head,
+            sector: 1,
+        };
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_unformatted.dsk");
+
+        // last track dropped, a middle one marked unformatted
+        for idx in [159, 3] {
+            let mut data = original.clone();
+            data[0x34 + idx] = 0;
+            data.drain(track_start(idx)..track_start(idx + 1));
+
+            let image = DskImage::load(&mut Cursor::new(&data)).unwrap();
+            assert!(image.warnings().is_empty());
+            assert_eq!(image.tracks().count(), 160);
+            let track = image.tracks().nth(idx).unwrap();
+            assert_eq!((track.cylinder() as usize, track.head() as usize), (idx / 2, idx % 2));
+            assert!(track.sectors().is_empty());
+            assert_eq!(track.sector_data().count(), 0);
+            let (c, h) = ((idx / 2) as u8, (idx % 2) as u8);
+            assert!(image.sector_as_slice(chs(c, h)).is_err());
+            // the other tracks keep their data, the following ones move up in the file
+            assert_eq!(
+                image.sector_as_slice(chs(79, 0)).unwrap(),
+                &original[track_start(158) + 256..track_start(158) + 768]
+            );
+            let moved = if idx < 158 { 157 } else { 158 };
+            assert_eq!(image.sector_offset(chs(79, 0)), Some(track_start(moved) + 256));
+
+            image.save(&mut File::create(&path).unwrap()).unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_large_tracks() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_large.dsk");
+        let last_byte = |image: &DskImage, sector_size: usize| {
+            image
+                .sector_as_slice(CHS {
+                    cylinder: 1,
+                    head: 1,
+                    sector: 2,
+                })
+                .unwrap()[sector_size - 1]
+        };
+
+        // seven 8K sectors (N=6) plus the track info make the largest track of such sectors,
+        // 128 bytes ones take the track block up to 256 bytes units
+        for (sector_size, ids) in [
+            (8192, &[1, 2, 3, 4, 5, 6, 7][..]),
+            (1024, &[1, 2, 3]),
+            (128, &[1, 2, 3]),
+        ] {
+            let mut image = DskImage::format(2, 2, sector_size, ids, 0x2A, 0xE5);
+            image
+                .sector_as_slice_mut(CHS {
+                    cylinder: 1,
+                    head: 1,
+                    sector: 2,
+                })
+                .unwrap()[sector_size as usize - 1] = 0x42;
+            image.save(&mut File::create(&path).unwrap()).unwrap();
+            let image = DskImage::load(&mut File::open(&path).unwrap()).unwrap();
+            assert!(image.warnings().is_empty());
+            assert_eq!(image.tracks().next().unwrap().sectors()[0].sector_size, sector_size);
+            assert_eq!(last_byte(&image, sector_size as usize), 0x42);
+        }
+
+        let image = DskImage::format(1, 1, 8192, &[1, 2, 3, 4, 5, 6, 7, 8], 0x2A, 0xE5);
+        assert!(image.save(&mut File::create(&path).unwrap()).is_err());
+
+        let mut image = DskImage::format(100, 2, 512, &[1], 0x2A, 0xE5);
+        image.save(&mut File::create(&path).unwrap()).unwrap();
+        assert_eq!(
+            DskImage::load(&mut File::open(&path).unwrap())
+                .unwrap()
+                .tracks()
+                .count(),
+            200
+        );
+        assert!(image.resize(103).is_err());
+    }
+
+    #[test]
+    fn test_extensions() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_ext.dsk");
+        DskImage::format(2, 1, 512, &[1, 2], 0x2A, 0xE5)
+            .save(&mut File::create(&path).unwrap())
+            .unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        data[0xF0] = 0x11;
+        // track 1 info block: unused bytes, data rate and recording mode, extension data
+        data[0x600 + 0x0C] = 0x22;
+        data[0x600 + 0x12..0x600 + 0x14].copy_from_slice(&[1, 2]);
+        data[0x600 + 0x80] = 0x33;
+        data.extend_from_slice(b"Offset-Info\r\n\x00\x01");
+        std::fs::write(&path, &data).unwrap();
+
+        let image = DskImage::load(&mut File::open(&path).unwrap()).unwrap();
+        assert!(image.warnings().is_empty());
+        assert_eq!(image.header_extension()[0xF0 - 0x36], 0x11);
+        assert_eq!(image.trailer(), b"Offset-Info\r\n\x00\x01");
+        let extensions: Vec<bool> = image.tracks().map(|t| t.extension().contains(&0x33)).collect();
+        assert_eq!(extensions, [false, true]);
+
+        image.save(&mut File::create(&path).unwrap()).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
 }
@@ -0,0 +1,225 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use super::backend::DiskBackend;
+use super::image::CHS;
+use crate::error::Location;
+
+/// Byte the sectors of tracks added by resize are filled with.
+const FILLER: u8 = 0xE5;
+
+/// Raw sector dump without any container metadata: tracks one after another (cylinder by
+/// cylinder, side by side, or all tracks of a side before the next one), sectors in the order
+/// of their IDs, starting from 1 unless set otherwise.
+pub struct RawImage {
+    num_cylinders: u8,
+    num_sides: u8,
+    sectors_per_track: u8,
+    first_sector_id: u8,
+    sector_size: usize,
+    /// tracks of side 0 first, then side 1 ones
+    sides_first: bool,
+    data: Vec<u8>,
+}
+
+impl RawImage {
+    /// Loads the image, the number of cylinders follows from its size.
+    pub fn load(f: &mut (impl Read + Seek), num_sides: u8, sectors_per_track: u8, sector_size: u16) -> Result<Self> {
+        Self::load_with_order(f, num_sides, sectors_per_track, sector_size, false)
+    }
+
+    /// Loads an image with all tracks of a side stored before the next side.
+    pub fn load_sides_first(
+        f: &mut (impl Read + Seek),
+        num_sides: u8,
+        sectors_per_track: u8,
+        sector_size: u16,
+    ) -> Result<Self> {
+        Self::load_with_order(f, num_sides, sectors_per_track, sector_size, true)
+    }
+
+    fn load_with_order(
+        f: &mut (impl Read + Seek),
+        num_sides: u8,
+        sectors_per_track: u8,
+        sector_size: u16,
+        sides_first: bool,
+    ) -> Result<Self> {
+        let mut data = vec![];
+        f.seek(SeekFrom::Start(0))?;
+        f.read_to_end(&mut data)?;
+
+        let cylinder_size = num_sides as usize * sectors_per_track as usize * sector_size as usize;
+        let num_cylinders = data.len() / cylinder_size;
+        if data.is_empty() || !data.len().is_multiple_of(cylinder_size) || num_cylinders > u8::MAX as usize {
+            bail!(
+                "Raw image size {} is not a whole number of {} bytes cylinders",
+                data.len(),
+                cylinder_size
+            );
+        }
+        Ok(Self {
+            num_cylinders: num_cylinders as u8,
+            num_sides,
+            sectors_per_track,
+            first_sector_id: 1,
+            sector_size: sector_size as usize,
+            sides_first,
+            data,
+        })
+    }
+
+    /// Sets the ID of the first sector of a track, e.g. 0xC1 for CPC Data disks.
+    pub fn set_first_sector_id(&mut self, first_sector_id: u8) {
+        self.first_sector_id = first_sector_id;
+    }
+
+    fn sector_range(&self, chs: CHS) -> Result<Range<usize>> {
+        let index = chs.sector.wrapping_sub(self.first_sector_id);
+        if chs.cylinder >= self.num_cylinders || chs.head >= self.num_sides || index >= self.sectors_per_track {
+            return Err(anyhow!("Sector not found")).context(Location {
+                chs: Some(chs),
+                offset: None,
+                slot: None,
+            });
+        }
+        let track = if self.sides_first {
+            chs.head as usize * self.num_cylinders as usize + chs.cylinder as usize
+        } else {
+            chs.cylinder as usize * self.num_sides as usize + chs.head as usize
+        };
+        let start = (track * self.sectors_per_track as usize + index as usize) * self.sector_size;
+        Ok(start..start + self.sector_size)
+    }
+}
+
+impl DiskBackend for RawImage {
+    fn num_cylinders(&self) -> u8 {
+        self.num_cylinders
+    }
+
+    fn num_sides(&self) -> u8 {
+        self.num_sides
+    }
+
+    fn sector_as_slice(&self, chs: CHS) -> Result<&[u8]> {
+        let range = self.sector_range(chs)?;
+        Ok(&self.data[range])
+    }
+
+    fn sector_as_slice_mut(&mut self, chs: CHS) -> Result<&mut [u8]> {
+        let range = self.sector_range(chs)?;
+        Ok(&mut self.data[range])
+    }
+
+    fn sector_offset(&self, chs: CHS) -> Option<usize> {
+        self.sector_range(chs).ok().map(|range| range.start)
+    }
+
+    fn resize(&mut self, num_cylinders: u8) -> Result<()> {
+        if num_cylinders == 0 {
+            bail!("Image must have at least one cylinder");
+        }
+        let track_size = self.sectors_per_track as usize * self.sector_size;
+        if self.sides_first {
+            // every side grows or shrinks separately
+            let side_size = self.num_cylinders as usize * track_size;
+            let new_side_size = num_cylinders as usize * track_size;
+            let mut data = Vec::with_capacity(self.num_sides as usize * new_side_size);
+            for side in self.data.chunks(side_size) {
+                data.extend_from_slice(&side[..side_size.min(new_side_size)]);
+                data.resize(data.len() + new_side_size.saturating_sub(side_size), FILLER);
+            }
+            self.data = data;
+        } else {
+            self.data
+                .resize(num_cylinders as usize * self.num_sides as usize * track_size, FILLER);
+        }
+        self.num_cylinders = num_cylinders;
+        Ok(())
+    }
+
+    fn save(&self, f: &mut File) -> Result<()> {
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&self.data)?;
+        f.set_len(self.data.len() as u64)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawImage;
+    use crate::cpm::{CpmFs, CpmVersion, LsMode};
+    use crate::dsk::{DiskBackend, DskImage, CHS};
+    use crate::profile::Profile;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_raw_image() {
+        let mut data = vec![0u8; 2 * 2 * 9 * 512];
+        // cylinder 1, head 0, sector 3
+        data[(2 * 9 + 2) * 512] = 0x42;
+        let mut image = RawImage::load(&mut Cursor::new(&data), 2, 9, 512).unwrap();
+        assert_eq!((image.num_cylinders(), image.num_sides()), (2, 2));
+        let chs = CHS {
+            cylinder: 1,
+            head: 0,
+            sector: 3,
+        };
+        assert_eq!(image.sector_as_slice(chs).unwrap()[0], 0x42);
+        assert!(image.sector_ok(chs));
+        assert!(!image.sector_ok(CHS { sector: 10, ..chs }));
+        assert!(image.sector_as_slice(CHS { sector: 0, ..chs }).is_err());
+
+        image.resize(3).unwrap();
+        assert_eq!(image.sector_as_slice(CHS { cylinder: 2, ..chs }).unwrap()[0], 0xE5);
+
+        image.set_first_sector_id(0xC1);
+        assert_eq!(image.sector_as_slice(CHS { sector: 0xC3, ..chs }).unwrap()[0], 0x42);
+        assert!(!image.sector_ok(chs));
+
+        assert!(RawImage::load(&mut Cursor::new(&data[..1000]), 2, 9, 512).is_err());
+    }
+
+    #[test]
+    fn test_raw_image_sides_first() {
+        let mut data = vec![0u8; 2 * 2 * 9 * 512];
+        // cylinder 0, head 1, sector 1
+        data[2 * 9 * 512] = 0x42;
+        let mut image = RawImage::load_sides_first(&mut Cursor::new(&data), 2, 9, 512).unwrap();
+        let chs = CHS {
+            cylinder: 0,
+            head: 1,
+            sector: 1,
+        };
+        assert_eq!(image.sector_as_slice(chs).unwrap()[0], 0x42);
+
+        image.resize(3).unwrap();
+        assert_eq!(image.sector_as_slice(chs).unwrap()[0], 0x42);
+        assert_eq!(image.sector_as_slice(CHS { cylinder: 2, ..chs }).unwrap()[0], 0xE5);
+        image.resize(1).unwrap();
+        assert_eq!(image.sector_as_slice(chs).unwrap()[0], 0x42);
+    }
+
+    #[test]
+    fn test_raw_filesystem() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/03.dsk");
+        let dsk = DskImage::load(&mut File::open(path).unwrap()).unwrap();
+        let mut raw = vec![];
+        for t in dsk.tracks() {
+            let mut sectors: Vec<_> = t.sector_data().collect();
+            sectors.sort_by_key(|(info, _)| info.sector_id);
+            sectors.iter().for_each(|(_, data)| raw.extend_from_slice(data));
+        }
+
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V3);
+        let image = RawImage::load(&mut Cursor::new(raw), 2, 9, 512).unwrap();
+        let fs = CpmFs::from_disk(Box::new(image), params).unwrap();
+        assert_eq!(fs.list_files(LsMode::All).unwrap().len(), 64);
+    }
+}
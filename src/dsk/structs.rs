@@ -7,22 +7,77 @@ use binrw::binrw;
 // - https://cpctech.cpc-live.com/docs/extdsk.html
 // - https://sinclair.wiki.zxnet.co.uk/wiki/DSK_format
 
+/// Signature of the extended DSK image, some writers vary the text after "EXTENDED".
+pub const DSK_SIGNATURE: [u8; 34] = *b"EXTENDED CPC DSK File\r\nDisk-Info\r\n";
+
+/// Number of tracks whose sizes fit in the 256 bytes header block.
+pub const MAX_TRACKS: usize = 256 - 0x34;
+
+/// Largest track size the track size table can express (255 units of 256 bytes).
+pub const MAX_TRACK_SIZE: u32 = 255 * 256;
+
 #[binrw]
 #[brw(little)]
-#[brw(magic = b"EXTENDED CPC DSK File\r\nDisk-Info\r\n")]
 pub struct DskFileHeader {
+    #[br(assert(signature.starts_with(b"EXTENDED"), "not an extended DSK image"))]
+    pub signature: [u8; 34],
     /// Name of the program that created the file, ASCII, zero-padded
     pub name_of_creator: [u8; 14],
     /// Number fo the disk's cylinders
     pub num_cylinders: u8,
     /// Number fo the disk's sides
+    #[br(assert(num_cylinders as usize * num_sides as usize <= MAX_TRACKS, "too many tracks"))]
     pub num_sides: u8,
     _unused: [u8; 2],
-    /// Sizes of consecutive track info blocks in 256 bytes units.
-    /// Note: I don't know how to convert [u8] to [u16] with binrw.
-    #[br(count = num_cylinders * num_sides, align_after=256)]
+    /// Sizes of consecutive track blocks (track info included) in bytes, stored in 256 bytes
+    /// units, so at most MAX_TRACK_SIZE. Unformatted tracks have size 0 and no track block.
+    #[br(count = num_cylinders as usize * num_sides as usize)]
+    #[br(map = |sizes: Vec<u8>| sizes.into_iter().map(|s| s as u32 * 256).collect())]
+    #[bw(map = |sizes: &Vec<u32>| sizes.iter().map(|s| s.div_ceil(256) as u8).collect::<Vec<u8>>())]
+    pub track_sizes: Vec<u32>,
+    /// Rest of the header block: zeros, or extension data of some writers, kept as is
+    #[br(count = block_padding(0x34 + track_sizes.len()))]
     #[bw(align_after = 256)]
-    pub track_sizes: Vec<u8>,
+    pub extension: Vec<u8>,
+}
+
+/// Largest sector size code (N) whose size fits in 16 bits.
+const MAX_SECTOR_SIZE_CODE: u8 = 8;
+
+fn sector_size_from_code(code: u8) -> Result<u16, String> {
+    if code > MAX_SECTOR_SIZE_CODE {
+        return Err(format!("sector size code {} not supported", code));
+    }
+    Ok(128 << code)
+}
+
+/// Returns the uPD765 N code of the sector size (a power of two, 128 bytes at least).
+fn sector_size_code(sector_size: u16) -> u8 {
+    (sector_size / 128).trailing_zeros() as u8
+}
+
+/// Returns the number of bytes filling the block of the given length up to 256 bytes boundary.
+pub fn block_padding(len: usize) -> usize {
+    len.next_multiple_of(256) - len
+}
+
+impl DskFileHeader {
+    pub fn new(num_cylinders: u8, num_sides: u8, track_size: u32) -> Self {
+        let mut name_of_creator = [0u8; 14];
+        let creator = concat!("JuDIM ", env!("CARGO_PKG_VERSION")).as_bytes();
+        let len = creator.len().min(name_of_creator.len());
+        name_of_creator[..len].copy_from_slice(&creator[..len]);
+
+        Self {
+            signature: DSK_SIGNATURE,
+            name_of_creator,
+            num_cylinders,
+            num_sides,
+            _unused: [0; 2],
+            track_sizes: vec![track_size; num_cylinders as usize * num_sides as usize],
+            extension: vec![0; block_padding(0x34 + num_cylinders as usize * num_sides as usize)],
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -30,17 +85,17 @@ pub struct DskFileHeader {
 #[brw(little)]
 #[brw(magic = b"Track-Info\r\n")]
 pub struct TrackInfo {
+    _unused0: [u8; 4],
     /// Cylinder number, 0-based
-    #[brw(pad_before = 4)]
     pub cylinder_number: u8,
     /// Side number, 0 or 1
     pub side_number: u8,
 
     _unused1: [u8; 2],
 
-    /// Size of the sector (stored as u8 with unit of 256 bytes)
-    #[br(map = |x: u8| x as u16 * 256)]
-    #[bw(map = |x| (x / 256) as u8)]
+    /// Size of the sector (stored as the uPD765 N code, 128 << N bytes)
+    #[br(try_map = sector_size_from_code)]
+    #[bw(map = |&x| sector_size_code(x))]
     pub sector_size: u16,
 
     /// Number of sectors on this particular track (tracks may vary)
@@ -48,12 +103,54 @@ pub struct TrackInfo {
     /// GAP#3 length, as defined in uPD765 datasheet
     pub gap3_length: u8,
 
-    _unused2: u8,
+    /// Filler byte used when the track was formatted
+    pub filler_byte: u8,
 
     /// Metadata of actual sectors
-    #[br(count = num_sectors, align_after=256)]
-    #[bw(align_after = 256)]
+    #[br(count = num_sectors)]
     pub sectors: Vec<SectorInfo>,
+
+    /// Rest of the track info block: zeros, or extension data of some writers, kept as is
+    #[br(count = block_padding(0x18 + 8 * num_sectors as usize))]
+    #[bw(align_after = 256)]
+    pub extension: Vec<u8>,
+}
+
+impl TrackInfo {
+    /// Creates a track header, with sector IDs given in the physical order.
+    pub fn new(cylinder: u8, side: u8, sector_size: u16, sector_ids: &[u8], gap3_length: u8, filler: u8) -> Self {
+        let sectors = sector_ids
+            .iter()
+            .map(|&sector_id| SectorInfo {
+                cylinder,
+                side,
+                sector_id,
+                sector_size,
+                fdc_st1: 0,
+                fdc_st2: 0,
+                actual_data_length: sector_size,
+            })
+            .collect();
+
+        Self {
+            _unused0: [0; 4],
+            cylinder_number: cylinder,
+            side_number: side,
+            _unused1: [0; 2],
+            sector_size,
+            num_sectors: sector_ids.len() as u8,
+            gap3_length,
+            filler_byte: filler,
+            sectors,
+            extension: vec![0; block_padding(0x18 + 8 * sector_ids.len())],
+        }
+    }
+
+    /// Creates the header of an unformatted track, having no sectors. Such tracks take no space
+    /// in the image, their size in the track size table is 0.
+    pub fn unformatted(cylinder: u8, side: u8) -> Self {
+        Self::new(cylinder, side, 0, &[], 0, 0)
+    }
 }
 
 /// SectorInfo contains metadata for a single sector within a track.
@@ -68,9 +165,9 @@ pub struct SectorInfo {
     /// Sector ID, equivalent to R parameter in uPD765 commands
     pub sector_id: u8,
 
-    /// Sector size, equivalent to N parameter in uPD765 commands
-    #[br(map = |x: u8| x as u16 * 256)]
-    #[bw(map = |x| (x / 256) as u8)]
+    /// Sector size, stored as N parameter in uPD765 commands (128 << N bytes)
+    #[br(try_map = sector_size_from_code)]
+    #[bw(map = |&x| sector_size_code(x))]
     pub sector_size: u16,
 
     /// uPD765 Status Register 1 value
@@ -116,7 +213,7 @@ mod tests {
             cylinder: 2,
             side: 1,
             sector_id: 5,
-            sector_size: 1536, // 1536 = 6 * 256
+            sector_size: 8192, // 8192 = 128 << 6
             fdc_st1: 17,
             fdc_st2: 18,
             actual_data_length: 512,
@@ -140,7 +237,7 @@ mod tests {
         assert_eq!(sector_info.cylinder, 2);
         assert_eq!(sector_info.side, 1);
         assert_eq!(sector_info.sector_id, 5);
-        assert_eq!(sector_info.sector_size, 1536);
+        assert_eq!(sector_info.sector_size, 8192);
         assert_eq!(sector_info.fdc_st1, 17);
         assert_eq!(sector_info.fdc_st2, 18);
         assert_eq!(sector_info.actual_data_length, 512);
@@ -199,7 +296,7 @@ mod tests {
         assert_eq!(dsk_header.name_of_creator, *b"CPCDiskXP v2.5");
         assert_eq!(dsk_header.num_cylinders, 80);
         assert_eq!(dsk_header.num_sides, 2);
-        assert_eq!(dsk_header.track_sizes, vec![19; 2 * 80]);
+        assert_eq!(dsk_header.track_sizes, vec![19 * 256; 2 * 80]);
 
         let mut output = Vec::new();
         {
@@ -0,0 +1,125 @@
+use std::fmt;
+use std::io;
+
+use crate::dsk::CHS;
+
+/// Failure classes, reported to the shell as distinct exit codes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// invalid command line or arguments, also any unclassified error
+    Usage,
+    /// no files matched the given names / globs
+    NoMatch,
+    /// the image is corrupt or inconsistent
+    Filesystem,
+    /// local file or image file I/O error
+    Io,
+    /// the target file already exists
+    Exists,
+    /// not enough free blocks or directory entries
+    DiskFull,
+    /// compared images or files differ
+    Differ,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Usage => 1,
+            ErrorKind::NoMatch => 2,
+            ErrorKind::Filesystem => 3,
+            ErrorKind::Io => 4,
+            ErrorKind::Exists => 5,
+            ErrorKind::DiskFull => 6,
+            ErrorKind::Differ => 7,
+        }
+    }
+}
+
+/// Error of a known class, to be used with bail!() or as a context.
+#[derive(Debug)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    message: String,
+}
+
+impl Failure {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Where on the disk an error happened, attached as a context to sector and directory errors so
+/// they can be matched with raw dumps and imaging logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Location {
+    pub chs: Option<CHS>,
+    /// byte offset in the image file: of the data (directory entry) if it was found, of the
+    /// track otherwise
+    pub offset: Option<usize>,
+    /// directory slot
+    pub slot: Option<usize>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(slot) = self.slot {
+            parts.push(format!("directory slot {}", slot));
+        }
+        if let Some(chs) = self.chs {
+            parts.push(format!("sector {}", chs));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("image offset {:#x}", offset));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+impl std::error::Error for Location {}
+
+/// Classifies the error: the outermost Failure wins, then any I/O error in the chain.
+pub fn error_kind(e: &anyhow::Error) -> ErrorKind {
+    if let Some(failure) = e.downcast_ref::<Failure>() {
+        failure.kind
+    } else if e.chain().any(|cause| cause.is::<io::Error>()) {
+        ErrorKind::Io
+    } else {
+        ErrorKind::Usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{error_kind, ErrorKind, Failure};
+    use anyhow::anyhow;
+    use std::io;
+
+    #[test]
+    fn test_error_kind() {
+        let e = anyhow!(Failure::new(ErrorKind::NoMatch, "No files"));
+        assert_eq!(error_kind(&e), ErrorKind::NoMatch);
+        assert_eq!(e.to_string(), "No files");
+
+        let e = anyhow!("Extent too small").context(Failure::new(ErrorKind::Filesystem, "Error loading image"));
+        assert_eq!(error_kind(&e).exit_code(), 3);
+
+        let e = anyhow!(io::Error::other("disk on fire")).context("Can't open image file");
+        assert_eq!(error_kind(&e), ErrorKind::Io);
+
+        let e = anyhow!("something else");
+        assert_eq!(error_kind(&e).exit_code(), 1);
+    }
+}
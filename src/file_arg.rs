@@ -1,24 +1,34 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use fast_glob::glob_match;
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::fmt;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use crate::cpm::MAX_USER_ID;
+use crate::util::parse_ranges;
 
 lazy_static! {
     static ref ImageFileRe: Regex = Regex::new(r"^(?:(\d+):|:)(.*)$").unwrap();
+    static ref ImageGlobRe: Regex = Regex::new(r"^(\*|\d*):(.*)$").unwrap();
 }
 
-const DEFAULT_USER: u8 = 0;
+pub const DEFAULT_USER: u8 = 0;
 
 // FIXME: FileArg could use Option<&str>, but for reasons I don't know yet,
 //   &Path is not Clone. Sticking to owned types for now.
 
 #[derive(Clone, Debug)]
 pub enum FileArg {
-    Local { path: PathBuf },
-    Image { owner: u8, name: Option<String> },
+    Local {
+        path: PathBuf,
+    },
+    /// Note: owner is None if not given explicitly (i.e. :NAME form)
+    Image {
+        owner: Option<u8>,
+        name: Option<String>,
+    },
 }
 
 impl FromStr for FileArg {
@@ -28,15 +38,15 @@ impl FromStr for FileArg {
             // image file (not checking filename syntax at this point, it might
             // be a glob pattern)
             let owner = if let Some(cap) = caps.get(1) {
-                cap.as_str().parse()?
+                let owner = cap.as_str().parse()?;
+                if owner > MAX_USER_ID {
+                    bail!("User ID {} is not in range 0..{}", owner, MAX_USER_ID);
+                }
+                Some(owner)
             } else {
-                DEFAULT_USER
+                None
             };
 
-            if owner > MAX_USER_ID {
-                bail!("User ID {} is not in range 0..{}", owner, MAX_USER_ID);
-            }
-
             // normalize empty name to None, for "dir mode"
             let name = caps[2].trim();
             let name = if name.is_empty() { None } else { Some(name.to_owned()) };
@@ -54,6 +64,7 @@ impl FileArg {
         matches!(self, Self::Local { .. })
     }
 
+    #[allow(dead_code)]
     pub fn is_dir(&self) -> bool {
         match self {
             Self::Local { path } => path.is_dir(),
@@ -61,3 +72,166 @@ impl FileArg {
         }
     }
 }
+
+/// Set of user numbers, given as a list of numbers and ranges, e.g. 0-3,15.
+#[derive(Clone, Debug)]
+pub struct UserList(pub Vec<u8>);
+
+impl FromStr for UserList {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let users = parse_ranges(s, 0, MAX_USER_ID as usize).context("Invalid user list")?;
+        Ok(Self(users.into_iter().map(|u| u as u8).collect()))
+    }
+}
+
+/// Users matched by the prefix of an image glob.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UserMatch {
+    /// No prefix (or an empty one), the user given by --user or the default one
+    Default,
+    /// * prefix, all users
+    Any,
+    /// N prefix
+    User(u8),
+}
+
+/// Image file glob with an optional user prefix: GLOB, N:GLOB, or *:GLOB matching the files
+/// of all users, e.g. *:*.BAK.
+#[derive(Clone, Debug)]
+pub struct ImageGlob {
+    pub users: UserMatch,
+    pub glob: String,
+    /// the glob compiled as a regular expression, used instead of glob matching if set
+    regex: Option<Regex>,
+}
+
+impl FromStr for ImageGlob {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (users, glob) = match ImageGlobRe.captures(s.trim()) {
+            Some(caps) => {
+                let users = match &caps[1] {
+                    "" => UserMatch::Default,
+                    "*" => UserMatch::Any,
+                    user => {
+                        let user = user.parse()?;
+                        if user > MAX_USER_ID {
+                            bail!("User ID {} is not in range 0..{}", user, MAX_USER_ID);
+                        }
+                        UserMatch::User(user)
+                    }
+                };
+                (users, caps[2].to_string())
+            }
+            None => (UserMatch::Default, s.trim().to_string()),
+        };
+        if glob.is_empty() {
+            bail!("File name or glob is missing in {}", s);
+        }
+        Ok(Self {
+            users,
+            glob,
+            regex: None,
+        })
+    }
+}
+
+impl fmt::Display for ImageGlob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.users {
+            UserMatch::Default => write!(f, "{}", self.glob),
+            UserMatch::Any => write!(f, "*:{}", self.glob),
+            UserMatch::User(user) => write!(f, "{}:{}", user, self.glob),
+        }
+    }
+}
+
+impl ImageGlob {
+    /// Takes the pattern as a regular expression rather than a glob (matching anywhere in the
+    /// name, unless anchored), e.g. 2:^(GAME|DEMO)[0-9]\.COM$.
+    pub fn into_regex(self) -> Result<Self> {
+        let regex = Regex::new(&self.glob).with_context(|| format!("Invalid regular expression {}", self.glob))?;
+        Ok(Self {
+            regex: Some(regex),
+            ..self
+        })
+    }
+
+    /// Returns true if the file of the user (None for deleted files) matches, default_user
+    /// standing for the glob without a prefix.
+    pub fn matches(&self, default_user: u8, user: Option<u8>, name: &str) -> bool {
+        let user_matches = match self.users {
+            UserMatch::Default => user == Some(default_user),
+            UserMatch::Any => user.is_some(),
+            UserMatch::User(u) => user == Some(u),
+        };
+        user_matches && self.name_matches(name)
+    }
+
+    /// Returns true if the listed file (None user for deleted files) matches, the glob without
+    /// a prefix matching the files of any user, deleted ones included.
+    pub fn matches_listed(&self, user: Option<u8>, name: &str) -> bool {
+        let user_matches = match self.users {
+            UserMatch::Default => true,
+            UserMatch::Any => user.is_some(),
+            UserMatch::User(u) => user == Some(u),
+        };
+        user_matches && self.name_matches(name)
+    }
+
+    fn name_matches(&self, name: &str) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(name),
+            None => glob_match(&self.glob, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ImageGlob, UserList, UserMatch};
+
+    #[test]
+    fn test_user_list() {
+        assert_eq!("5".parse::<UserList>().unwrap().0, vec![5]);
+        assert_eq!("0-3,15".parse::<UserList>().unwrap().0, vec![0, 1, 2, 3, 15]);
+        assert_eq!("3, 1-2,2".parse::<UserList>().unwrap().0, vec![1, 2, 3]);
+        assert!("3-1".parse::<UserList>().is_err());
+        assert!("0-16".parse::<UserList>().is_err());
+        assert!("a".parse::<UserList>().is_err());
+    }
+
+    #[test]
+    fn test_image_glob() {
+        let glob = |s: &str| s.parse::<ImageGlob>().unwrap();
+        assert_eq!(glob("*.BAK").users, UserMatch::Default);
+        assert_eq!(glob(":*.BAK").users, UserMatch::Default);
+        assert_eq!(glob("*:*.BAK").users, UserMatch::Any);
+        assert_eq!(glob("12:GAME.*").users, UserMatch::User(12));
+        assert_eq!(glob("*:*.BAK").glob, "*.BAK");
+        assert!("16:*".parse::<ImageGlob>().is_err());
+        assert!("*:".parse::<ImageGlob>().is_err());
+
+        assert!(glob("*.BAK").matches(3, Some(3), "A.BAK"));
+        assert!(!glob("*.BAK").matches(0, Some(3), "A.BAK"));
+        assert!(glob("*:*.BAK").matches(0, Some(3), "A.BAK"));
+        assert!(!glob("*:*.BAK").matches(0, None, "A.BAK"));
+        assert!(glob("2:*").matches(0, Some(2), "X.COM"));
+        assert!(!glob("2:*").matches(2, Some(0), "X.COM"));
+        assert_eq!(glob("*:A*").to_string(), "*:A*");
+
+        assert!(glob("*.BAK").matches_listed(Some(3), "A.BAK"));
+        assert!(glob("*.BAK").matches_listed(None, "A.BAK"));
+        assert!(glob("3:*.BAK").matches_listed(Some(3), "A.BAK"));
+        assert!(!glob("3:*.BAK").matches_listed(None, "A.BAK"));
+        assert!(!glob("*:*.BAK").matches_listed(None, "A.BAK"));
+
+        let regex = |s: &str| glob(s).into_regex().unwrap();
+        assert!(regex(r"^(GAME|DEMO)[0-9]\.COM$").matches(0, Some(0), "DEMO3.COM"));
+        assert!(!regex(r"^(GAME|DEMO)[0-9]\.COM$").matches(0, Some(0), "DEMO10.COM"));
+        assert!(regex(r"*:\.BAK$").matches(0, Some(7), "A.BAK"));
+        assert!(regex("MAC").matches(0, Some(0), "BDOS.MAC"));
+        assert!(glob("*.COM").into_regex().is_err());
+    }
+}
@@ -1,20 +1,70 @@
+mod basic;
+mod charset;
 mod cmd_basic;
+mod cmd_build;
+mod cmd_catalog;
+mod cmd_codeinfo;
+mod cmd_dpb;
 mod cmd_dsk;
 mod cmd_tap;
+mod color;
+mod config;
 mod cpm;
 mod dsk;
+mod error;
 mod file_arg;
+mod mdos;
+mod mgt;
+mod output;
+mod profile;
+mod retro_fs;
 mod speccy_files;
+mod stats;
+mod tape;
+mod util;
+mod wav;
+mod xmodem;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use color::ColorChoice;
+use error::{error_kind, ErrorKind};
 use std::process::exit;
 
 #[derive(Parser)]
 #[command(name = "judim")]
 #[command(version)]
 #[command(about = "Junior Disk Image Manager", long_about = None)]
+#[command(after_help = "Exit codes:\n  \
+    0  success\n  \
+    1  usage error (or other, unclassified error)\n  \
+    2  no files matched\n  \
+    3  filesystem error (corrupt or inconsistent image)\n  \
+    4  I/O error\n  \
+    5  target file already exists\n  \
+    6  disk full (no free blocks or directory entries)\n  \
+    7  compared images differ")]
 struct Cli {
+    /// Refuse to run commands that would modify a disk image or write a tape file
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// Color the output: deleted files, system files, executables, differences
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Print listings as tab separated columns rather than tables
+    #[arg(long, global = true)]
+    plain: bool,
+
+    /// Report directory oddities which aren't errors (suspicious record counts, unusual flags, ...)
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Report the time spent loading, parsing and transferring, and the bytes moved (on stderr)
+    #[arg(long, global = true)]
+    stats: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -22,32 +72,71 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Disk image operations
-    #[command(about = "Disk image operations (ls, get, cp)")]
+    #[command(
+        about = "Disk image operations (ls, get, put, cp, rm, undelete, chuser, cmp, users, dedup, info, stat, map, scan, label, boot, mkfs, resize, reformat, imgdiff, hash, browse, serial, serve, sync, export, fsdump, import, interleave, edit, split, join, dir)"
+    )]
     Dsk(cmd_dsk::DskArgs),
 
     /// BASIC file operations
     #[command(about = "BASIC file operations")]
     Basic(cmd_basic::BasicArgs),
 
+    /// Build an image from a manifest
+    #[command(about = "Create a disk image from a manifest (profile, boot binary, label and files)")]
+    Build(cmd_build::BuildArgs),
+
+    /// Catalog many images
+    #[command(about = "Write a JSON catalog of images (metadata, files and checksums), for searching an archive")]
+    Catalog(cmd_catalog::CatalogArgs),
+
     /// TAP file operations
     #[command(about = "TAP file operations")]
     Tap(cmd_tap::TapArgs),
+
+    /// Annotated hexdump of a Code file
+    #[command(
+        about = "Hexdump a Code file at its load address, marking screen and system variable areas and describing the contents"
+    )]
+    Codeinfo(cmd_codeinfo::CodeinfoArgs),
+
+    /// Disk Parameter Block of a format as source code
+    #[command(about = "Print the Disk Parameter Block and skew table of a disk format as assembler, C or Rust source")]
+    Dpb(cmd_dpb::DpbArgs),
 }
 
 fn cli() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        // clap uses 2 for usage errors, which we reserve for "no match"
+        if e.use_stderr() {
+            let _ = e.print();
+            exit(ErrorKind::Usage.exit_code());
+        }
+        e.exit()
+    });
 
-    match cli.command {
-        Commands::Dsk(args) => cmd_dsk::dsk(args),
-        Commands::Basic(args) => cmd_basic::basic(args),
-        Commands::Tap(args) => cmd_tap::tap(args),
+    color::init(cli.color);
+    output::set_plain(cli.plain);
+    output::set_verbose(cli.verbose);
+    if cli.stats {
+        stats::enable();
     }
+    let result = match cli.command {
+        Commands::Dsk(args) => cmd_dsk::dsk(args, cli.read_only),
+        Commands::Basic(args) => cmd_basic::basic(args),
+        Commands::Tap(args) => cmd_tap::tap(args, cli.read_only),
+        Commands::Build(args) => cmd_build::build(args),
+        Commands::Catalog(args) => cmd_catalog::catalog(args),
+        Commands::Codeinfo(args) => cmd_codeinfo::codeinfo(args),
+        Commands::Dpb(args) => cmd_dpb::dpb(args),
+    };
+    stats::report();
+    result
 }
 
 fn main() {
     let result = cli();
     if let Err(e) = result {
         println!("Error: {:?}", e);
-        exit(1);
+        exit(error_kind(&e).exit_code());
     }
 }
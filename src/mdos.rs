@@ -0,0 +1,341 @@
+use anyhow::{bail, Result};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::basic::spectrum_text;
+use crate::dsk::{DiskBackend, DskImage, RawImage, CHS};
+use crate::error::{ErrorKind, Failure};
+use crate::retro_fs::{Attributes, FileStat, FreeSpace, FsFile, FsFileId, RetroFs, Select};
+use crate::speccy_files::{SpeccyFileType, HEADER_SIZE};
+
+// References:
+// - https://sinclair.wiki.zxnet.co.uk/wiki/Didaktik_40/80
+
+const SIDES: u8 = 2;
+const SECTORS_PER_TRACK: u8 = 9;
+const SECTOR_SIZE: u16 = 512;
+/// Logical sectors holding the FAT, 12-bit entries indexed by the logical sector number.
+const FAT_START: usize = 1;
+const FAT_SECTORS: usize = 5;
+/// Logical sectors holding the directory.
+const DIR_START: usize = FAT_START + FAT_SECTORS;
+const DIR_SECTORS: usize = 8;
+const ENTRY_SIZE: usize = 32;
+const NUM_ENTRIES: usize = DIR_SECTORS * SECTOR_SIZE as usize / ENTRY_SIZE;
+const NAME_LEN: usize = 10;
+
+// directory entry layout, the first 17 bytes follow the tape header
+const TYPE: usize = 0;
+const NAME: usize = 1;
+const LENGTH: usize = 11;
+// 13: start address, or autostart line of BASIC programs
+/// program length of BASIC programs, without the variables
+const PARAM2: usize = 15;
+const ATTRIBUTES: usize = 17;
+const FIRST_SECTOR: usize = 30;
+
+/// Type byte of a never used slot, no entries follow it.
+const TYPE_END: u8 = 0;
+const TYPE_DELETED: u8 = 0xE5;
+const HIDDEN: u8 = 0x01;
+const PROTECTED: u8 = 0x02;
+
+// FAT values
+const FAT_FREE: u16 = 0;
+/// Values from this one on end the chain.
+const FAT_LAST: u16 = 0xFF8;
+
+/// MDOS filesystem of Didaktik 40/80 disks (.d40/.d80 images): 128 entries directory after the
+/// FAT, files allocated by single sectors chained in the FAT.
+///
+/// Like on MGT disks, Spectrum files (BASIC, arrays and CODE) are read with the tape header
+/// before the data, made up from the directory entry, other types (e.g. snapshots and sequential
+/// files) raw. The filesystem is read-only, all files belong to user 0.
+pub struct MdosFs {
+    disk: Box<dyn DiskBackend>,
+}
+
+impl MdosFs {
+    /// Loads the filesystem from a DSK image or a raw one, the number of cylinders (40 for D40,
+    /// 80 for D80) follows from its size.
+    pub fn load(f: &mut (impl Read + Seek)) -> Result<MdosFs> {
+        let mut signature = [0u8; 8];
+        f.read_exact(&mut signature)?;
+        f.seek(SeekFrom::Start(0))?;
+        let disk: Box<dyn DiskBackend> = match &signature {
+            b"EXTENDED" => Box::new(DskImage::load(f)?),
+            _ => Box::new(RawImage::load(f, SIDES, SECTORS_PER_TRACK, SECTOR_SIZE)?),
+        };
+        Ok(MdosFs { disk })
+    }
+
+    pub fn disk(&self) -> &dyn DiskBackend {
+        self.disk.as_ref()
+    }
+
+    fn num_sectors(&self) -> usize {
+        self.disk.num_cylinders() as usize * SIDES as usize * SECTORS_PER_TRACK as usize
+    }
+
+    fn sector(&self, lsi: usize) -> Result<&[u8]> {
+        self.disk.sector_as_slice(lsi_chs(lsi))
+    }
+
+    fn entry(&self, slot: usize) -> Result<&[u8]> {
+        let offset = (slot * ENTRY_SIZE) % SECTOR_SIZE as usize;
+        let sector = self.sector(DIR_START + slot * ENTRY_SIZE / SECTOR_SIZE as usize)?;
+        Ok(&sector[offset..offset + ENTRY_SIZE])
+    }
+
+    /// Returns the entries of the files, up to the first never used slot.
+    fn entries(&self) -> Result<Vec<&[u8]>> {
+        let mut entries = vec![];
+        for slot in 0..NUM_ENTRIES {
+            let entry = self.entry(slot)?;
+            match entry[TYPE] {
+                TYPE_END => break,
+                TYPE_DELETED => {}
+                _ => entries.push(entry),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn file_entry(&self, file: &FsFile) -> Result<&[u8]> {
+        match self.entries()?.into_iter().find(|e| entry_name(e) == file.name) {
+            Some(entry) => Ok(entry),
+            None => bail!("File {} not found", file.name),
+        }
+    }
+
+    fn fat(&self) -> Result<Vec<u8>> {
+        let mut fat = Vec::with_capacity(FAT_SECTORS * SECTOR_SIZE as usize);
+        for lsi in FAT_START..DIR_START {
+            fat.extend_from_slice(self.sector(lsi)?);
+        }
+        Ok(fat)
+    }
+
+    /// Returns the logical sectors of the file, following the FAT chain until it ends, leaves
+    /// the disk or loops.
+    fn chain(&self, fat: &[u8], entry: &[u8]) -> Vec<usize> {
+        let mut sectors = vec![];
+        let mut lsi = word(entry, FIRST_SECTOR) as usize;
+        let data_sectors = DIR_START + DIR_SECTORS..self.num_sectors();
+        while data_sectors.contains(&lsi) && !sectors.contains(&lsi) {
+            sectors.push(lsi);
+            match fat_entry(fat, lsi) {
+                next if next >= FAT_LAST || next == FAT_FREE => break,
+                next => lsi = next as usize,
+            }
+        }
+        sectors
+    }
+}
+
+impl RetroFs for MdosFs {
+    fn list(&self, select: Select) -> Result<Vec<FsFile>> {
+        // the type byte of deleted entries is overwritten, there's nothing to recover, and all
+        // files belong to user 0
+        if !select.includes(0) {
+            return Ok(vec![]);
+        }
+        let fat = self.fat()?;
+        Ok(self
+            .entries()?
+            .into_iter()
+            .map(|e| FsFile {
+                user: Some(0),
+                name: entry_name(e),
+                size: file_size(e),
+                attributes: Attributes {
+                    read_only: e[ATTRIBUTES] & PROTECTED != 0,
+                    system: e[ATTRIBUTES] & HIDDEN != 0,
+                    archived: false,
+                },
+                entries: 1,
+                location: self.chain(&fat, e).into_iter().map(|lsi| lsi as u32).collect(),
+            })
+            .collect())
+    }
+
+    fn read(&self, file: &FsFile, w: &mut dyn Write, _text_mode: bool) -> Result<usize> {
+        let entry = self.file_entry(file)?;
+        let header = match speccy_type(entry) {
+            Some(file_type) => tape_header(entry, file_type),
+            None => vec![],
+        };
+        let length = word(entry, LENGTH) as usize;
+        let sectors = self.chain(&self.fat()?, entry);
+        if sectors.len() * (SECTOR_SIZE as usize) < length {
+            bail!(Failure::new(
+                ErrorKind::Filesystem,
+                format!(
+                    "File {} is truncated: {} bytes recorded, only {} stored.",
+                    file.name,
+                    header.len() + length,
+                    header.len() + sectors.len() * SECTOR_SIZE as usize
+                )
+            ));
+        }
+        let size = file.size.min(file_size(entry));
+        let mut data = header;
+        for lsi in sectors {
+            if data.len() >= size {
+                break;
+            }
+            data.extend_from_slice(self.sector(lsi)?);
+        }
+        w.write_all(&data[..size])?;
+        Ok(size)
+    }
+
+    fn write(&mut self, _id: &FsFileId, _r: &mut dyn Read, _text_mode: bool) -> Result<Vec<u32>> {
+        bail!(Failure::new(ErrorKind::Usage, "MDOS disks are read-only"))
+    }
+
+    fn delete(&mut self, _file: &FsFile) -> Result<()> {
+        bail!(Failure::new(ErrorKind::Usage, "MDOS disks are read-only"))
+    }
+
+    fn stat(&self, file: &FsFile) -> Result<FileStat> {
+        let entry = self.file_entry(file)?;
+        let header_len = if speccy_type(entry).is_some() { HEADER_SIZE } else { 0 };
+        Ok(FileStat {
+            stored_size: header_len + self.chain(&self.fat()?, entry).len() * SECTOR_SIZE as usize,
+            updated: None,
+            reused_blocks: 0,
+        })
+    }
+
+    fn free_space(&self) -> FreeSpace {
+        let free_sectors = self
+            .fat()
+            .map(|fat| {
+                (DIR_START + DIR_SECTORS..self.num_sectors())
+                    .filter(|&lsi| fat_entry(&fat, lsi) == FAT_FREE)
+                    .count()
+            })
+            .unwrap_or_default();
+        let free_entries = (0..NUM_ENTRIES)
+            .filter(|&slot| {
+                self.entry(slot)
+                    .is_ok_and(|e| e[TYPE] == TYPE_END || e[TYPE] == TYPE_DELETED)
+            })
+            .count();
+        FreeSpace {
+            blocks: free_sectors,
+            bytes: free_sectors * SECTOR_SIZE as usize,
+            dir_entries: free_entries,
+        }
+    }
+}
+
+fn word(entry: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([entry[pos], entry[pos + 1]])
+}
+
+fn entry_name(entry: &[u8]) -> String {
+    spectrum_text(&entry[NAME..NAME + NAME_LEN]).trim_end().to_string()
+}
+
+/// Spectrum file type of the entry, None for other (e.g. snapshot or sequential) files.
+fn speccy_type(entry: &[u8]) -> Option<SpeccyFileType> {
+    match entry[TYPE] {
+        b'P' => Some(SpeccyFileType::Program),
+        b'N' => Some(SpeccyFileType::NumArray),
+        b'C' => Some(SpeccyFileType::ChrArray),
+        b'B' => Some(SpeccyFileType::Code),
+        _ => None,
+    }
+}
+
+/// Size of the file as read, with the tape header for Spectrum files.
+fn file_size(entry: &[u8]) -> usize {
+    let header_len = if speccy_type(entry).is_some() { HEADER_SIZE } else { 0 };
+    header_len + word(entry, LENGTH) as usize
+}
+
+/// Builds the tape header of a Spectrum file, the directory entry holds all its fields.
+fn tape_header(entry: &[u8], file_type: SpeccyFileType) -> Vec<u8> {
+    [&[file_type as u8], &entry[NAME..PARAM2 + 2]].concat()
+}
+
+/// Returns the 12-bit FAT entry of the logical sector, packed as on FAT12 disks.
+fn fat_entry(fat: &[u8], lsi: usize) -> u16 {
+    let pos = lsi * 3 / 2;
+    if pos + 1 >= fat.len() {
+        return FAT_LAST;
+    }
+    let pair = word(fat, pos);
+    if lsi.is_multiple_of(2) {
+        pair & 0xFFF
+    } else {
+        pair >> 4
+    }
+}
+
+/// Logical sectors go sector by sector, then side by side, then cylinder by cylinder.
+fn lsi_chs(lsi: usize) -> CHS {
+    let track = lsi / SECTORS_PER_TRACK as usize;
+    CHS {
+        cylinder: (track / SIDES as usize) as u8,
+        head: (track % SIDES as usize) as u8,
+        sector: (lsi % SECTORS_PER_TRACK as usize) as u8 + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MdosFs, DIR_START, FAT_START, SECTOR_SIZE};
+    use crate::retro_fs::{RetroFs, Select};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_mdos_fs() {
+        let sector = |lsi: usize| lsi * SECTOR_SIZE as usize;
+        // D40 image, data starts at logical sector 14
+        let mut image = vec![0u8; 40 * 2 * 9 * 512];
+        // CODE file of 600 bytes at 32768, in sectors 14 and 20
+        let dir = sector(DIR_START);
+        image[dir..dir + 17].copy_from_slice(b"Bscreen    \x58\x02\x00\x80\x00\x80");
+        image[dir + 30..dir + 32].copy_from_slice(&14u16.to_le_bytes());
+        // 3 bytes snapshot in sector 15, with its FAT entry missing
+        image[dir + 32] = 0xE5;
+        image[dir + 64..dir + 76].copy_from_slice(b"Sgame      \x03");
+        image[dir + 94] = 15;
+        // 768 bytes recorded, but its only sector is free in the FAT
+        image[dir + 96..dir + 109].copy_from_slice(b"Xbroken    \x00\x03");
+        image[dir + 126] = 16;
+        // FAT12: 14 -> 20, 15 -> end, 20 -> end
+        let fat = sector(FAT_START);
+        image[fat + 21..fat + 24].copy_from_slice(&[0x14, 0xF0, 0xFF]);
+        image[fat + 30..fat + 32].copy_from_slice(&[0xFF, 0x0F]);
+        image[sector(14)..sector(15)].fill(0x11);
+        image[sector(15)..sector(15) + 3].copy_from_slice(b"abc");
+        image[sector(20)..sector(21)].fill(0x22);
+
+        let mut fs = MdosFs::load(&mut Cursor::new(image)).unwrap();
+        let files = fs.list(Select::All).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!((files[0].name.as_str(), files[0].size), ("screen", 17 + 600));
+        assert_eq!(files[0].location, [14, 20]);
+
+        let mut data = vec![];
+        assert_eq!(fs.read(&files[0], &mut data, false).unwrap(), 617);
+        assert_eq!(data[..17], *b"\x03screen    \x58\x02\x00\x80\x00\x80");
+        assert!(data[17..529].iter().all(|&b| b == 0x11));
+        assert!(data[529..].iter().all(|&b| b == 0x22));
+        assert_eq!(fs.stat(&files[0]).unwrap().stored_size, 17 + 1024);
+
+        let mut data = vec![];
+        fs.read(&files[1], &mut data, false).unwrap();
+        assert_eq!(data, b"abc");
+        assert!(fs.read(&files[2], &mut vec![], false).is_err());
+
+        assert_eq!(fs.free_space().dir_entries, 128 - 3);
+        assert_eq!(fs.free_space().blocks, 40 * 2 * 9 - 14 - 3);
+        assert!(fs.list(Select::User(1)).unwrap().is_empty());
+        let id = fs.file_id(0, "new").unwrap();
+        assert!(fs.write(&id, &mut &b""[..], false).is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::basic::spectrum_text;
+use crate::dsk::{DiskBackend, DskImage, RawImage, CHS};
+use crate::error::{ErrorKind, Failure};
+use crate::retro_fs::{Attributes, FileStat, FreeSpace, FsFile, FsFileId, RetroFs, Select};
+use crate::speccy_files::{SpeccyFileHeader, SpeccyFileType, HEADER_SIZE};
+
+// References:
+// - https://sinclair.wiki.zxnet.co.uk/wiki/MGT_filesystem
+
+const SIDES: u8 = 2;
+const SECTORS_PER_TRACK: u8 = 10;
+const SECTOR_SIZE: u16 = 512;
+/// Tracks per side, side 1 tracks are numbered from 128 in the sector links.
+const TRACKS_PER_SIDE: usize = 80;
+const SIDE_1_TRACK: u8 = 128;
+/// Tracks of side 0 holding the catalogue.
+const DIR_TRACKS: usize = 4;
+const ENTRY_SIZE: usize = 256;
+const NUM_ENTRIES: usize = DIR_TRACKS * SECTORS_PER_TRACK as usize * SECTOR_SIZE as usize / ENTRY_SIZE;
+/// Data bytes of a sector, the last 2 link to the next sector of the file.
+const SECTOR_DATA: usize = 510;
+/// Sectors the address map of a directory entry covers (all but the catalogue ones).
+const MAP_SECTORS: usize = 195 * 8;
+const NAME_LEN: usize = 10;
+
+// directory entry layout
+const TYPE: usize = 0;
+const NAME: usize = 1;
+const NUM_SECTORS: usize = 11;
+const FIRST_SECTOR: usize = 13;
+const SECTOR_MAP: usize = 15;
+const OPENTYPE_LENGTH: usize = 210;
+/// Spectrum files: copy of the 9 bytes header preceding the data (type, length, start address,
+/// program length, autostart line).
+const SPECCY_HEADER: usize = 211;
+const SPECCY_HEADER_LEN: usize = 9;
+
+const TYPE_MASK: u8 = 0x3F;
+const HIDDEN: u8 = 0x80;
+const PROTECTED: u8 = 0x40;
+const TYPE_FREE: u8 = 0;
+const TYPE_SCREEN: u8 = 7;
+const TYPE_OPENTYPE: u8 = 10;
+/// Start address of BASIC programs (PROG system variable)
+const BASIC_START: u16 = 0x5CCB;
+
+/// MGT filesystem of +D and DISCiPLE disks: 80 entries catalogue on the first 4 tracks, files
+/// stored in chains of linked sectors.
+///
+/// Files are presented like on the Junior filesystem: Spectrum files (BASIC, arrays, CODE and
+/// SCREEN$) with the 17 bytes tape header before the data, other types raw. New files with a
+/// tape header become Spectrum files, the rest OPENTYPE (data) files. Neither users nor text
+/// mode are known to the filesystem, all files belong to user 0.
+pub struct MgtFs {
+    disk: Box<dyn DiskBackend>,
+}
+
+impl MgtFs {
+    /// Loads the filesystem from a DSK image, or a raw .mgt (cylinder by cylinder) or .img (side by side) one.
+    pub fn load(f: &mut (impl Read + Seek), sides_first: bool) -> Result<MgtFs> {
+        let mut signature = [0u8; 8];
+        f.read_exact(&mut signature)?;
+        f.seek(SeekFrom::Start(0))?;
+        let disk: Box<dyn DiskBackend> = match &signature {
+            b"EXTENDED" => Box::new(DskImage::load(f)?),
+            _ if sides_first => Box::new(RawImage::load_sides_first(f, SIDES, SECTORS_PER_TRACK, SECTOR_SIZE)?),
+            _ => Box::new(RawImage::load(f, SIDES, SECTORS_PER_TRACK, SECTOR_SIZE)?),
+        };
+        Ok(MgtFs { disk })
+    }
+
+    pub fn save(&self, f: &mut File) -> Result<()> {
+        self.disk.save(f)
+    }
+
+    pub fn disk(&self) -> &dyn DiskBackend {
+        self.disk.as_ref()
+    }
+
+    fn entry_location(slot: usize) -> (CHS, usize) {
+        let sector = slot / 2;
+        let chs = CHS {
+            cylinder: (sector / SECTORS_PER_TRACK as usize) as u8,
+            head: 0,
+            sector: (sector % SECTORS_PER_TRACK as usize) as u8 + 1,
+        };
+        (chs, (slot % 2) * ENTRY_SIZE)
+    }
+
+    fn entry(&self, slot: usize) -> Result<&[u8]> {
+        let (chs, offset) = Self::entry_location(slot);
+        Ok(&self.disk.sector_as_slice(chs)?[offset..offset + ENTRY_SIZE])
+    }
+
+    fn entry_mut(&mut self, slot: usize) -> Result<&mut [u8]> {
+        let (chs, offset) = Self::entry_location(slot);
+        Ok(&mut self.disk.sector_as_slice_mut(chs)?[offset..offset + ENTRY_SIZE])
+    }
+
+    /// Returns the slots and entries of the files.
+    fn entries(&self) -> Result<Vec<(usize, &[u8])>> {
+        let mut entries = vec![];
+        for slot in 0..NUM_ENTRIES {
+            let entry = self.entry(slot)?;
+            if entry[TYPE] & TYPE_MASK != TYPE_FREE {
+                entries.push((slot, entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn find_slot(&self, name: &str) -> Result<Option<usize>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .find(|(_, e)| entry_name(e) == name)
+            .map(|(slot, _)| slot))
+    }
+
+    fn file_slot(&self, file: &FsFile) -> Result<usize> {
+        match self.find_slot(&file.name)? {
+            Some(slot) => Ok(slot),
+            None => bail!("File {} not found", file.name),
+        }
+    }
+
+    /// Returns the data of the file's sector chain, at most as many sectors as the entry gives,
+    /// stopping once there are at least limit bytes.
+    fn read_chain(&self, entry: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        let (mut track, mut sector) = (entry[FIRST_SECTOR], entry[FIRST_SECTOR + 1]);
+        for _ in 0..num_sectors(entry) {
+            if (track, sector) == (0, 0) || data.len() >= limit {
+                break;
+            }
+            let chs = link_chs(track, sector);
+            let sector_data = self.disk.sector_as_slice(chs)?;
+            data.extend_from_slice(&sector_data[..SECTOR_DATA]);
+            (track, sector) = (sector_data[SECTOR_DATA], sector_data[SECTOR_DATA + 1]);
+        }
+        Ok(data)
+    }
+
+    /// Returns a map of the used sectors, indexed as the entries' address maps.
+    fn used_sectors(&self) -> Result<Vec<bool>> {
+        let mut used = vec![false; MAP_SECTORS];
+        for (_, entry) in self.entries()? {
+            for idx in map_sectors(entry) {
+                used[idx] = true;
+            }
+        }
+        Ok(used)
+    }
+
+    fn free_sectors(&self) -> Result<Vec<usize>> {
+        Ok(self
+            .used_sectors()?
+            .into_iter()
+            .enumerate()
+            .filter(|&(idx, used)| !used && self.disk.sector_ok(index_chs(idx)))
+            .map(|(idx, _)| idx)
+            .collect())
+    }
+}
+
+impl RetroFs for MgtFs {
+    fn list(&self, select: Select) -> Result<Vec<FsFile>> {
+        // erased entries leave nothing behind, and all files belong to user 0
+        if !select.includes(0) {
+            return Ok(vec![]);
+        }
+        Ok(self
+            .entries()?
+            .into_iter()
+            .map(|(_, e)| FsFile {
+                user: Some(0),
+                name: entry_name(e),
+                size: file_size(e),
+                attributes: Attributes {
+                    read_only: e[TYPE] & PROTECTED != 0,
+                    system: e[TYPE] & HIDDEN != 0,
+                    archived: false,
+                },
+                entries: 1,
+                location: map_sectors(e).map(|idx| idx as u32).collect(),
+            })
+            .collect())
+    }
+
+    fn read(&self, file: &FsFile, w: &mut dyn Write, _text_mode: bool) -> Result<usize> {
+        let entry = self.entry(self.file_slot(file)?)?;
+        let size = file.size.min(file_size(entry));
+        let (header, data_start) = match speccy_type(entry) {
+            Some(file_type) => (tape_header(entry, file_type), SPECCY_HEADER_LEN),
+            None => (vec![], 0),
+        };
+        // a prefix may end within the header
+        let data_end = (data_start + size).saturating_sub(header.len()).max(data_start);
+        let data = self.read_chain(entry, data_end)?;
+        if data.len() < data_end {
+            bail!(Failure::new(
+                ErrorKind::Filesystem,
+                format!(
+                    "File {} is truncated: {} bytes recorded, only {} stored.",
+                    file.name,
+                    size,
+                    (data.len() + header.len()).saturating_sub(data_start)
+                )
+            ));
+        }
+        w.write_all(&header[..size.min(header.len())])?;
+        w.write_all(&data[data_start..data_end])?;
+        Ok(size)
+    }
+
+    fn write(&mut self, id: &FsFileId, r: &mut dyn Read, _text_mode: bool) -> Result<Vec<u32>> {
+        let name = self.file_id(id.user, &id.name)?.name;
+        if self.find_slot(&name)?.is_some() {
+            bail!(Failure::new(ErrorKind::Exists, format!("File {} already exists", name)));
+        }
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+
+        let mut entry = vec![0u8; ENTRY_SIZE];
+        let header = SpeccyFileHeader::from_bytes(&data).filter(|h| HEADER_SIZE + h.length as usize == data.len());
+        let content = match header {
+            Some(h) => {
+                let (start, autostart) = match h.file_type {
+                    SpeccyFileType::Program => (BASIC_START, h.param1),
+                    _ => (h.param1, 0),
+                };
+                let mut content = vec![h.file_type as u8];
+                for word in [h.length, start, h.param2, autostart] {
+                    content.extend_from_slice(&word.to_le_bytes());
+                }
+                entry[TYPE] = h.file_type as u8 + 1;
+                entry[SPECCY_HEADER..SPECCY_HEADER + SPECCY_HEADER_LEN].copy_from_slice(&content);
+                content.extend_from_slice(&data[HEADER_SIZE..]);
+                content
+            }
+            None => {
+                if data.len() > 0xFF_FFFF {
+                    bail!("File {} is too large", name);
+                }
+                entry[TYPE] = TYPE_OPENTYPE;
+                entry[OPENTYPE_LENGTH] = (data.len() >> 16) as u8;
+                entry[OPENTYPE_LENGTH + 1..OPENTYPE_LENGTH + 3].copy_from_slice(&(data.len() as u16).to_le_bytes());
+                data
+            }
+        };
+
+        let Some(slot) =
+            (0..NUM_ENTRIES).find(|&slot| self.entry(slot).is_ok_and(|e| e[TYPE] & TYPE_MASK == TYPE_FREE))
+        else {
+            bail!(Failure::new(ErrorKind::DiskFull, "Catalogue is full"));
+        };
+        let needed = content.len().div_ceil(SECTOR_DATA).max(1);
+        let free = self.free_sectors()?;
+        if free.len() < needed {
+            bail!(Failure::new(
+                ErrorKind::DiskFull,
+                format!(
+                    "Not enough space for {}: {} sectors needed, {} free",
+                    name,
+                    needed,
+                    free.len()
+                )
+            ));
+        }
+
+        let sectors = &free[..needed];
+        let chunks: Vec<&[u8]> = content.chunks(SECTOR_DATA).collect();
+        for (i, &idx) in sectors.iter().enumerate() {
+            let chunk = chunks.get(i).copied().unwrap_or_default();
+            let next = sectors.get(i + 1).map(|&next| index_link(next)).unwrap_or((0, 0));
+            let sector_data = self.disk.sector_as_slice_mut(index_chs(idx))?;
+            sector_data.fill(0);
+            sector_data[..chunk.len()].copy_from_slice(chunk);
+            (sector_data[SECTOR_DATA], sector_data[SECTOR_DATA + 1]) = next;
+            entry[SECTOR_MAP + idx / 8] |= 1 << (idx % 8);
+        }
+        entry[NAME..NAME + NAME_LEN].copy_from_slice(format!("{:<10}", name).as_bytes());
+        entry[NUM_SECTORS..NUM_SECTORS + 2].copy_from_slice(&(needed as u16).to_be_bytes());
+        (entry[FIRST_SECTOR], entry[FIRST_SECTOR + 1]) = index_link(sectors[0]);
+        self.entry_mut(slot)?.copy_from_slice(&entry);
+        Ok(sectors.iter().map(|&idx| idx as u32).collect())
+    }
+
+    fn delete(&mut self, file: &FsFile) -> Result<()> {
+        let slot = self.file_slot(file)?;
+        self.entry_mut(slot)?[TYPE] = TYPE_FREE;
+        Ok(())
+    }
+
+    fn stat(&self, file: &FsFile) -> Result<FileStat> {
+        let entry = self.entry(self.file_slot(file)?)?;
+        let mut stored_size = num_sectors(entry) * SECTOR_DATA;
+        if speccy_type(entry).is_some() {
+            stored_size = (stored_size + HEADER_SIZE).saturating_sub(SPECCY_HEADER_LEN);
+        }
+        Ok(FileStat {
+            stored_size,
+            updated: None,
+            reused_blocks: 0,
+        })
+    }
+
+    /// Names are kept as given (MGT names are case sensitive), up to 10 ASCII characters.
+    fn file_id(&self, user: u8, name: &str) -> Result<FsFileId> {
+        if name.is_empty() || !name.is_ascii() || name.len() > NAME_LEN {
+            bail!(
+                "Invalid file name {}, MGT names have 1 to {} ASCII characters",
+                name,
+                NAME_LEN
+            );
+        }
+        Ok(FsFileId {
+            user,
+            name: name.to_string(),
+        })
+    }
+
+    fn free_space(&self) -> FreeSpace {
+        let free_sectors = self.free_sectors().map(|f| f.len()).unwrap_or_default();
+        let free_entries = (0..NUM_ENTRIES)
+            .filter(|&slot| self.entry(slot).is_ok_and(|e| e[TYPE] & TYPE_MASK == TYPE_FREE))
+            .count();
+        FreeSpace {
+            blocks: free_sectors,
+            bytes: free_sectors * SECTOR_DATA,
+            dir_entries: free_entries,
+        }
+    }
+}
+
+fn entry_name(entry: &[u8]) -> String {
+    spectrum_text(&entry[NAME..NAME + NAME_LEN]).trim_end().to_string()
+}
+
+fn num_sectors(entry: &[u8]) -> usize {
+    u16::from_be_bytes([entry[NUM_SECTORS], entry[NUM_SECTORS + 1]]) as usize
+}
+
+/// Spectrum file type of the entry, None for other (e.g. snapshot or OPENTYPE) files.
+fn speccy_type(entry: &[u8]) -> Option<SpeccyFileType> {
+    match entry[TYPE] & TYPE_MASK {
+        1 => Some(SpeccyFileType::Program),
+        2 => Some(SpeccyFileType::NumArray),
+        3 => Some(SpeccyFileType::ChrArray),
+        4 | TYPE_SCREEN => Some(SpeccyFileType::Code),
+        _ => None,
+    }
+}
+
+/// Size of the file as read: with the tape header for Spectrum files, all sectors' data for
+/// types of unknown length.
+fn file_size(entry: &[u8]) -> usize {
+    let word = |pos: usize| u16::from_le_bytes([entry[pos], entry[pos + 1]]) as usize;
+    if speccy_type(entry).is_some() {
+        HEADER_SIZE + word(SPECCY_HEADER + 1)
+    } else if entry[TYPE] & TYPE_MASK == TYPE_OPENTYPE {
+        ((entry[OPENTYPE_LENGTH] as usize) << 16) + word(OPENTYPE_LENGTH + 1)
+    } else {
+        num_sectors(entry) * SECTOR_DATA
+    }
+}
+
+/// Builds the tape header of a Spectrum file from the directory entry.
+fn tape_header(entry: &[u8], file_type: SpeccyFileType) -> Vec<u8> {
+    let header = &entry[SPECCY_HEADER..SPECCY_HEADER + SPECCY_HEADER_LEN];
+    let (length, start, program_length, autostart) = (&header[1..3], &header[3..5], &header[5..7], &header[7..9]);
+    let param1 = match file_type {
+        SpeccyFileType::Program => autostart,
+        _ => start,
+    };
+    [
+        &[file_type as u8],
+        &entry[NAME..NAME + NAME_LEN],
+        length,
+        param1,
+        program_length,
+    ]
+    .concat()
+}
+
+/// Indexes of the sectors set in the entry's address map.
+fn map_sectors(entry: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    (0..MAP_SECTORS).filter(|idx| entry[SECTOR_MAP + idx / 8] & (1 << (idx % 8)) != 0)
+}
+
+/// Sector of the sector link (track, sector), side 1 tracks numbered from 128.
+fn link_chs(track: u8, sector: u8) -> CHS {
+    CHS {
+        cylinder: track & !SIDE_1_TRACK,
+        head: track / SIDE_1_TRACK,
+        sector,
+    }
+}
+
+/// Sector link of the address map index: bit 0 is track 4 sector 1, side 1 tracks follow side 0.
+fn index_link(idx: usize) -> (u8, u8) {
+    let track = DIR_TRACKS + idx / SECTORS_PER_TRACK as usize;
+    let track = if track < TRACKS_PER_SIDE {
+        track as u8
+    } else {
+        SIDE_1_TRACK + (track - TRACKS_PER_SIDE) as u8
+    };
+    (track, (idx % SECTORS_PER_TRACK as usize) as u8 + 1)
+}
+
+fn index_chs(idx: usize) -> CHS {
+    let (track, sector) = index_link(idx);
+    link_chs(track, sector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MgtFs;
+    use crate::retro_fs::{RetroFs, Select};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_mgt_fs() {
+        let image = vec![0u8; 80 * 2 * 10 * 512];
+        let mut fs = MgtFs::load(&mut Cursor::new(image), false).unwrap();
+        assert_eq!(fs.free_space().blocks, 1560);
+        assert_eq!(fs.free_space().dir_entries, 80);
+
+        // CODE file with a tape header, 1000 bytes at 32768
+        let mut code = vec![3];
+        code.extend_from_slice(b"screen    ");
+        code.extend_from_slice(&[0xE8, 0x03, 0x00, 0x80, 0x00, 0x80]);
+        code.extend((0..1000).map(|i| i as u8));
+        let id = fs.file_id(0, "GAME.COD").unwrap();
+        assert_eq!(fs.write(&id, &mut code.as_slice(), false).unwrap(), [0, 1]);
+        let data = b"plain data".to_vec();
+        let id = fs.file_id(0, "DATA.BIN").unwrap();
+        fs.write(&id, &mut data.as_slice(), false).unwrap();
+        assert!(fs.write(&id, &mut data.as_slice(), false).is_err());
+        assert!(fs.file_id(0, "TOO_LONG.BIN").is_err());
+
+        let files = fs.list(Select::All).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!((files[0].name.as_str(), files[0].size), ("GAME.COD", code.len()));
+        let mut read = vec![];
+        fs.read(&files[0], &mut read, false).unwrap();
+        // the tape header name comes from the catalogue
+        assert_eq!(read[1..11], *b"GAME.COD  ");
+        assert_eq!((read[0], &read[11..]), (code[0], &code[11..]));
+        for len in [5, 17, 20] {
+            assert_eq!(fs.read_prefix(&files[0], len).unwrap(), read[..len]);
+        }
+        // the data is preceded by the 9 bytes header
+        let first = fs.disk().sector_as_slice(super::index_chs(0)).unwrap();
+        assert_eq!(first[..9], [3, 0xE8, 0x03, 0x00, 0x80, 0x00, 0x80, 0x00, 0x00]);
+        assert_eq!(first[510..], [4, 2]);
+
+        let mut read = vec![];
+        fs.read(&files[1], &mut read, false).unwrap();
+        assert_eq!(read, data);
+
+        fs.delete(&files[0]).unwrap();
+        assert_eq!(fs.list(Select::All).unwrap().len(), 1);
+        assert_eq!(fs.free_space().blocks, 1559);
+        assert!(fs.list(Select::User(1)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use prettytable::{format, Cell, Row, Table};
+use ratatui::crossterm::terminal;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::color::{self, Style};
+
+const TAB_WIDTH: usize = 8;
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Prints listings as tab separated columns rather than tables for the rest of the run.
+pub fn set_plain(plain: bool) {
+    PLAIN.store(plain, Ordering::Relaxed);
+}
+
+/// Reports the non-fatal diagnostics of loaded images for the rest of the run.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+/// Rows of columns with titles, printed as a borderless table, or as tab separated columns
+/// in the plain mode and when the table doesn't fit the terminal.
+pub struct Listing {
+    titles: Vec<String>,
+    rows: Vec<(Vec<String>, Option<Style>)>,
+}
+
+impl Listing {
+    pub fn new<S: ToString>(titles: &[S]) -> Self {
+        Self {
+            titles: titles.iter().map(|t| t.to_string()).collect(),
+            rows: vec![],
+        }
+    }
+
+    pub fn add_row(&mut self, cells: Vec<String>, style: Option<Style>) {
+        self.rows.push((cells, style));
+    }
+
+    pub fn print(&self) -> Result<()> {
+        let width = terminal_width();
+        let table = self.table();
+        let fits = width.is_none_or(|w| table.to_string().lines().all(|l| l.chars().count() <= w));
+        if !PLAIN.load(Ordering::Relaxed) && fits {
+            if color::enabled() {
+                table.print_tty(true)?;
+            } else {
+                table.print(&mut io::stdout())?;
+            }
+            return Ok(());
+        }
+
+        for cells in std::iter::once(&self.titles).chain(self.rows.iter().map(|(cells, _)| cells)) {
+            let line = cells.join("\t");
+            match width {
+                Some(w) => println!("{}", truncate(&line, w)),
+                None => println!("{}", line),
+            }
+        }
+        Ok(())
+    }
+
+    fn table(&self) -> Table {
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(Row::new(self.titles.iter().map(|t| Cell::new(t)).collect()));
+        for (cells, style) in &self.rows {
+            let spec = style.map_or("", Style::spec);
+            table.add_row(Row::new(cells.iter().map(|c| Cell::new(c).style_spec(spec)).collect()));
+        }
+        table
+    }
+}
+
+/// Width of the terminal stdout is, None if it isn't one (nothing gets cut then).
+fn terminal_width() -> Option<usize> {
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+    terminal::size().ok().map(|(columns, _)| columns as usize)
+}
+
+/// Cuts the line to the width (with tabs expanded), marking the cut with "...".
+fn truncate(line: &str, width: usize) -> String {
+    let column_after = |column: usize, c: char| {
+        if c == '\t' {
+            (column / TAB_WIDTH + 1) * TAB_WIDTH
+        } else {
+            column + 1
+        }
+    };
+    if line.chars().fold(0, column_after) <= width {
+        return line.to_string();
+    }
+
+    let mut result = String::new();
+    let mut column = 0;
+    for c in line.chars() {
+        column = column_after(column, c);
+        if column > width.saturating_sub(3) {
+            break;
+        }
+        result.push(c);
+    }
+    result + "..."
+}
+
+#[cfg(test)]
+mod tests {
+    use super::truncate;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("0\tBDOS.MAC\t21120", 40), "0\tBDOS.MAC\t21120");
+        // tabs count up to the next tab stop
+        assert_eq!(truncate("0\tBDOS.MAC\t21120", 29), "0\tBDOS.MAC\t21120");
+        assert_eq!(truncate("0\tBDOS.MAC\t21120", 20), "0\tBDOS.MAC...");
+        assert_eq!(truncate("0\tBDOS.MAC\t21120", 12), "0\tB...");
+        assert_eq!(truncate("ABCDEFGHIJ", 8), "ABCDE...");
+    }
+}
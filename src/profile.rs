@@ -0,0 +1,420 @@
+use anyhow::{bail, Result};
+
+use crate::cpm::{CpmFs, CpmVersion, Dpb, Params};
+use crate::dsk::{DiskBackend, DskImage, CHS};
+
+/// Disk format profile: physical layout of a freshly formatted disk, and the filesystem parameters.
+pub struct Profile {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub cylinders: u8,
+    pub sides: u8,
+    /// sector IDs, in the physical order (i.e. with interleave applied)
+    pub sector_ids: &'static [u8],
+    pub gap3: u8,
+    pub filler: u8,
+    pub params: Params,
+    /// the first sector may hold an Amstrad disk specification record, overriding the parameters
+    pub spec_record: bool,
+}
+
+pub const DEFAULT_PROFILE: &str = "junior";
+
+pub const PROFILES: &[Profile] = &[
+    Profile {
+        name: "junior",
+        description: "Junior CP/J, 80 cylinders, 2 sides, 9 x 512 byte sectors, 2K blocks",
+        cylinders: 80,
+        sides: 2,
+        sector_ids: &[1, 6, 2, 7, 3, 8, 4, 9, 5],
+        gap3: 0x2A,
+        filler: 0xE5,
+        params: Params {
+            sectors_per_track: 9,
+            first_sector_id: 1,
+            reserved_tracks: 2,
+            sector_size: 512,
+            sectors_per_block: 4,
+            dir_blocks: 4,
+            // CP/J is CP/M 2.2 compatible, S1 and F1'-F4' carry no CP/M Plus meaning
+            version: CpmVersion::V22,
+        },
+        spec_record: false,
+    },
+    Profile {
+        name: "plus3",
+        description: "Spectrum +3 CF2, 40 cylinders, 1 side, 9 x 512 byte sectors, 1K blocks",
+        cylinders: 40,
+        sides: 1,
+        sector_ids: &[1, 2, 3, 4, 5, 6, 7, 8, 9],
+        gap3: 0x52,
+        filler: 0xE5,
+        params: PCW180_PARAMS,
+        spec_record: true,
+    },
+    Profile {
+        name: "pcw180",
+        description: "Amstrad PCW CF2, same as plus3",
+        cylinders: 40,
+        sides: 1,
+        sector_ids: &[1, 2, 3, 4, 5, 6, 7, 8, 9],
+        gap3: 0x52,
+        filler: 0xE5,
+        params: PCW180_PARAMS,
+        spec_record: true,
+    },
+    Profile {
+        name: "pcw720",
+        description: "Amstrad PCW / Spectrum +3 CF2DD, 80 cylinders, 2 sides, 9 x 512 byte sectors, 2K blocks",
+        cylinders: 80,
+        sides: 2,
+        sector_ids: &[1, 2, 3, 4, 5, 6, 7, 8, 9],
+        gap3: 0x2A,
+        filler: 0xE5,
+        params: Params {
+            sectors_per_track: 9,
+            first_sector_id: 1,
+            reserved_tracks: 1,
+            sector_size: 512,
+            sectors_per_block: 4,
+            dir_blocks: 4,
+            version: CpmVersion::V3,
+        },
+        spec_record: true,
+    },
+    Profile {
+        name: "cpc-data",
+        description: "Amstrad CPC Data, 40 cylinders, 1 side, 9 x 512 byte sectors (IDs 0xC1-0xC9), 1K blocks",
+        cylinders: 40,
+        sides: 1,
+        sector_ids: &[0xC1, 0xC6, 0xC2, 0xC7, 0xC3, 0xC8, 0xC4, 0xC9, 0xC5],
+        gap3: 0x52,
+        filler: 0xE5,
+        params: Params {
+            sectors_per_track: 9,
+            first_sector_id: 0xC1,
+            reserved_tracks: 0,
+            sector_size: 512,
+            sectors_per_block: 2,
+            dir_blocks: 2,
+            version: CpmVersion::V22,
+        },
+        spec_record: false,
+    },
+    Profile {
+        name: "cpc-system",
+        description: "Amstrad CPC System, 40 cylinders, 1 side, 9 x 512 byte sectors (IDs 0x41-0x49), 1K blocks",
+        cylinders: 40,
+        sides: 1,
+        sector_ids: &[0x41, 0x46, 0x42, 0x47, 0x43, 0x48, 0x44, 0x49, 0x45],
+        gap3: 0x52,
+        filler: 0xE5,
+        params: Params {
+            sectors_per_track: 9,
+            first_sector_id: 0x41,
+            reserved_tracks: 2,
+            sector_size: 512,
+            sectors_per_block: 2,
+            dir_blocks: 2,
+            version: CpmVersion::V22,
+        },
+        spec_record: false,
+    },
+];
+
+/// +3 and PCW single sided disks, also what a disk without a specification record is taken for.
+const PCW180_PARAMS: Params = Params {
+    sectors_per_track: 9,
+    first_sector_id: 1,
+    reserved_tracks: 1,
+    sector_size: 512,
+    sectors_per_block: 2,
+    dir_blocks: 2,
+    version: CpmVersion::V3,
+};
+
+impl Profile {
+    pub fn find(name: &str) -> Result<&'static Profile> {
+        match PROFILES.iter().find(|p| p.name.eq_ignore_ascii_case(name)) {
+            Some(profile) => Ok(profile),
+            None => {
+                let known: Vec<String> = PROFILES
+                    .iter()
+                    .map(|p| format!("  {:<10} {}", p.name, p.description))
+                    .collect();
+                bail!("Unknown disk format {}, known ones:\n{}", name, known.join("\n"))
+            }
+        }
+    }
+
+    /// Filesystem parameters, with directory semantics of a given CP/M version (the format's own,
+    /// if None).
+    pub fn params_or_default(&self, version: Option<CpmVersion>) -> Params {
+        self.params(version.unwrap_or(self.params.version))
+    }
+
+    /// Filesystem parameters, with directory semantics of a given CP/M version.
+    pub fn params(&self, version: CpmVersion) -> Params {
+        Params { version, ..self.params }
+    }
+
+    /// Disk Parameter Block of a disk formatted as the profile.
+    pub fn dpb(&self, version: CpmVersion) -> Dpb {
+        let params = self.params(version);
+        Dpb::new(&params, params.num_blocks(self.cylinders as u16 * self.sides as u16))
+    }
+
+    /// Creates an empty filesystem on a freshly formatted disk, with the disk specification record
+    /// if the format has one.
+    pub fn format(&self, version: CpmVersion) -> Result<CpmFs> {
+        let mut disk = DskImage::format(
+            self.cylinders,
+            self.sides,
+            self.params.sector_size,
+            self.sector_ids,
+            self.gap3,
+            self.filler,
+        );
+        if self.spec_record {
+            let first = CHS {
+                cylinder: 0,
+                head: 0,
+                sector: 1,
+            };
+            let record = SpecRecord::from_profile(self).to_bytes(self.gap3);
+            disk.sector_as_slice_mut(first)?[..SpecRecord::SIZE].copy_from_slice(&record);
+        }
+        CpmFs::from_disk(Box::new(disk), self.params(version))
+    }
+}
+
+/// Gap length for reading and writing sectors, in the disk specification record.
+const RW_GAP: u8 = 0x2A;
+
+/// Amstrad disk specification record: the first bytes of the first sector of +3 and PCW disks,
+/// describing the geometry and filesystem parameters.
+///
+/// References:
+/// - https://www.seasip.info/Cpm/amsform.html
+#[derive(Debug, PartialEq)]
+pub struct SpecRecord {
+    /// 0 for +3 and single sided PCW disks, 3 for double sided PCW ones
+    pub format: u8,
+    pub sides: u8,
+    pub tracks: u8,
+    pub sectors_per_track: u8,
+    pub sector_size: u16,
+    pub reserved_tracks: u8,
+    pub block_size: usize,
+    pub dir_blocks: u8,
+}
+
+impl SpecRecord {
+    pub const SIZE: usize = 16;
+    /// CP/M version of the disks having the record (+3DOS and PCW CP/M Plus ones)
+    pub const VERSION: CpmVersion = CpmVersion::V3;
+
+    /// Parses the record, returns None if the bytes don't make a plausible one (e.g. a blank or
+    /// a boot sector), the sides are stored one after another, or the blocks are too large for the
+    /// disk size, making directory entries map more than 16K (neither supported).
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let &[format, sidedness, tracks, sectors_per_track, size_code, reserved_tracks, block_shift, dir_blocks, ..] =
+            data
+        else {
+            return None;
+        };
+        let sides = match sidedness {
+            0 => 1,
+            // alternate sides
+            1 => 2,
+            _ => return None,
+        };
+        if format > 3 || size_code > 3 || !(3..=7).contains(&block_shift) {
+            return None;
+        }
+        let record = SpecRecord {
+            format,
+            sides,
+            tracks,
+            sectors_per_track,
+            sector_size: 128 << size_code,
+            reserved_tracks,
+            block_size: 128 << block_shift,
+            dir_blocks,
+        };
+        let plausible =
+            tracks > 0 && sectors_per_track > 0 && reserved_tracks < tracks && record.params(CpmVersion::V3).is_ok();
+        plausible.then_some(record)
+    }
+
+    /// The record of a disk formatted as the profile.
+    pub fn from_profile(profile: &Profile) -> Self {
+        let params = profile.params;
+        SpecRecord {
+            format: if profile.sides == 1 { 0 } else { 3 },
+            sides: profile.sides,
+            tracks: profile.cylinders,
+            sectors_per_track: params.sectors_per_track,
+            sector_size: params.sector_size,
+            reserved_tracks: params.reserved_tracks,
+            block_size: params.block_size(),
+            dir_blocks: params.dir_blocks,
+        }
+    }
+
+    pub fn to_bytes(&self, gap3: u8) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[..10].copy_from_slice(&[
+            self.format,
+            self.sides - 1,
+            self.tracks,
+            self.sectors_per_track,
+            (self.sector_size / 128).trailing_zeros() as u8,
+            self.reserved_tracks,
+            (self.block_size / 128).trailing_zeros() as u8,
+            self.dir_blocks,
+            RW_GAP,
+            gap3,
+        ]);
+        bytes
+    }
+
+    /// Filesystem parameters, with directory semantics of a given CP/M version. Fails if the
+    /// record describes a filesystem CP/M can't handle.
+    pub fn params(&self, version: CpmVersion) -> Result<Params> {
+        Params::builder()
+            .sectors_per_track(self.sectors_per_track)
+            .reserved_tracks(self.reserved_tracks)
+            .sector_size(self.sector_size)
+            .block_size(self.block_size)
+            .dir_blocks(self.dir_blocks)
+            .version(version)
+            .num_tracks(self.tracks as u16 * self.sides as u16)
+            .build()
+    }
+
+    /// Returns true if the disk has the geometry the record describes.
+    pub fn matches(&self, disk: &dyn DiskBackend) -> bool {
+        let first = CHS {
+            cylinder: 0,
+            head: 0,
+            sector: 1,
+        };
+        disk.num_cylinders() == self.tracks
+            && disk.num_sides() == self.sides
+            && disk
+                .sector_as_slice(first)
+                .is_ok_and(|s| s.len() == self.sector_size as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Profile, SpecRecord, PROFILES};
+    use crate::cmd_dsk::load_image_fs;
+    use crate::cpm::{CpmVersion, FileId, FilenameMode, LsMode};
+    use std::fs::File;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_profiles() {
+        assert!(Profile::find("JUNIOR").is_ok());
+        assert!(Profile::find("unknown").is_err());
+        for profile in PROFILES {
+            profile
+                .params
+                .validate(profile.cylinders as u16 * profile.sides as u16)
+                .unwrap();
+        }
+
+        let fs = Profile::find("junior").unwrap().format(CpmVersion::V22).unwrap();
+        assert_eq!(fs.num_blocks(), 355);
+        assert_eq!(fs.free_blocks(), 351);
+        let fs = Profile::find("pcw720").unwrap().format(CpmVersion::V3).unwrap();
+        assert_eq!(fs.dpb().dsm, 356);
+        assert_eq!(Profile::find("pcw720").unwrap().dpb(CpmVersion::V3), fs.dpb());
+        let fs = Profile::find("plus3").unwrap().format(CpmVersion::V3).unwrap();
+        assert_eq!((fs.dpb().dsm, fs.dpb().drm), (174, 63));
+        let record = SpecRecord::from_bytes(&fs.read_system_area().unwrap()).unwrap();
+        assert_eq!(record, SpecRecord::from_profile(Profile::find("plus3").unwrap()));
+
+        // sectors numbered from 0xC1, files still land in the first data sector
+        let mut fs = Profile::find("cpc-data").unwrap().format(CpmVersion::V22).unwrap();
+        assert_eq!((fs.dpb().dsm, fs.dpb().drm), (179, 63));
+        assert_eq!(fs.block_chs(0)[0].sector, 0xC1);
+        let id = FileId::new_with_filename(0, "TEST.BAS", FilenameMode::Normalized).unwrap();
+        fs.write_file(&id, &mut &b"10 PRINT"[..], false).unwrap();
+        let file = fs.list_files(LsMode::All).unwrap().remove(0);
+        let mut data = vec![];
+        fs.read_file(&file, &mut data, false).unwrap();
+        assert_eq!(data.len(), 128);
+        let fs = Profile::find("cpc-system").unwrap().format(CpmVersion::V22).unwrap();
+        assert_eq!(fs.system_area_size(), 2 * 9 * 512);
+    }
+
+    #[test]
+    fn test_spec_record() {
+        // PCW 720K
+        let record = SpecRecord::from_bytes(&[3, 1, 80, 9, 2, 1, 4, 4, 0x2A, 0x52, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!((record.sides, record.tracks, record.block_size), (2, 80, 2048));
+        let params = record.params(CpmVersion::V3).unwrap();
+        assert_eq!((params.sectors_per_block, params.dir_blocks), (4, 4));
+
+        assert!(SpecRecord::from_bytes(&[0xE5; 16]).is_none());
+        // successive sides
+        assert!(SpecRecord::from_bytes(&[3, 2, 80, 9, 2, 1, 4, 4, 0x2A, 0x52, 0, 0, 0, 0, 0, 0]).is_none());
+        assert!(SpecRecord::from_bytes(&[0, 0, 40]).is_none());
+        // +3 geometry with 2K blocks: 87 blocks, EXM 1
+        assert!(SpecRecord::from_bytes(&[0, 0, 40, 9, 2, 1, 4, 2, 0x2A, 0x52, 0, 0, 0, 0, 0, 0]).is_none());
+        assert!(SpecRecord::from_bytes(&[0, 0, 40, 9, 2, 1, 3, 2, 0x2A, 0x52, 0, 0, 0, 0, 0, 0]).is_some());
+    }
+
+    #[test]
+    fn test_load_with_spec_record() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_profile");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plus3.dsk");
+        let mut fs = Profile::find("plus3").unwrap().format(CpmVersion::V3).unwrap();
+        fs.save(&mut File::create(&path).unwrap()).unwrap();
+
+        // no format given, the record wins over the junior default
+        let fs = load_image_fs(&mut File::open(&path).unwrap(), None, None).unwrap();
+        assert_eq!((fs.params().sectors_per_block, fs.params().reserved_tracks), (2, 1));
+        assert_eq!(fs.params().version, CpmVersion::V3);
+        // a given format without records ignores it: junior's 2K blocks on the 40 tracks make
+        // 85 blocks, with extents of 32K (EXM 1), which isn't supported
+        let junior = Profile::find("junior").unwrap();
+        let Err(err) = load_image_fs(&mut File::open(&path).unwrap(), Some(junior), Some(CpmVersion::V3)) else {
+            panic!("junior parameters accepted on a +3 disk");
+        };
+        assert!(format!("{:#}", err).contains("EXM 1"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_probe_format() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/03.dsk");
+        let fs = load_image_fs(&mut File::open(&path).unwrap(), None, None).unwrap();
+        assert_eq!(fs.params().reserved_tracks, 2);
+        // CP/J disks are read with CP/M 2.2 semantics, unless asked otherwise
+        assert_eq!(fs.params().version, CpmVersion::V22);
+        assert_eq!(fs.list_files(LsMode::All).unwrap().len(), 64);
+
+        // sector IDs tell the CPC formats apart
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_profile");
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["cpc-data", "cpc-system"] {
+            let path = dir.join(format!("{}.dsk", name));
+            let mut fs = Profile::find(name).unwrap().format(CpmVersion::V22).unwrap();
+            let id = FileId::new_with_filename(0, "TEST.BAS", FilenameMode::Normalized).unwrap();
+            fs.write_file(&id, &mut &b"10 PRINT"[..], false).unwrap();
+            fs.save(&mut File::create(&path).unwrap()).unwrap();
+
+            let fs = load_image_fs(&mut File::open(&path).unwrap(), None, None).unwrap();
+            let profile = Profile::find(name).unwrap();
+            assert_eq!(fs.params().first_sector_id, profile.params.first_sector_id);
+            assert_eq!(fs.list_files(LsMode::All).unwrap()[0].name, "TEST.BAS");
+        }
+
+        assert!(load_image_fs(&mut std::io::Cursor::new(vec![0x42; 1000]), None, None).is_err());
+    }
+}
@@ -0,0 +1,381 @@
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::SystemTime;
+
+use crate::cpm::{CpmFs, FileId, FileItem, FilenameMode, LsMode};
+
+/// File level access to a filesystem, the file commands (ls, get, put, cp, rm) are written against it,
+/// so filesystems other than CP/M only have to implement it.
+///
+/// The files are described by the types below, which carry nothing filesystem specific: each
+/// implementation converts them from/to its own directory structures.
+pub trait RetroFs {
+    /// Lists the selected files, in the directory order.
+    fn list(&self, select: Select) -> Result<Vec<FsFile>>;
+
+    /// Reads the file contents, returns the number of bytes written. Only the first file.size
+    /// bytes are read, a smaller size reads a prefix of the file.
+    fn read(&self, file: &FsFile, w: &mut dyn Write, text_mode: bool) -> Result<usize>;
+
+    /// Reads the first len bytes of the file (all of it if shorter), in binary mode, without
+    /// touching the blocks past them.
+    fn read_prefix(&self, file: &FsFile, len: usize) -> Result<Vec<u8>> {
+        let prefix = FsFile {
+            size: file.size.min(len),
+            ..file.clone()
+        };
+        let mut data = Vec::with_capacity(prefix.size);
+        self.read(&prefix, &mut data, false)?;
+        Ok(data)
+    }
+
+    /// Writes a new file, returns the allocation units (blocks, sectors) it got.
+    fn write(&mut self, id: &FsFileId, r: &mut dyn Read, text_mode: bool) -> Result<Vec<u32>>;
+
+    fn delete(&mut self, file: &FsFile) -> Result<()>;
+
+    fn stat(&self, file: &FsFile) -> Result<FileStat>;
+
+    /// Returns the ID a new file with the name gets, the name normalized as the filesystem stores
+    /// it. Fails if the name is not valid on the filesystem.
+    fn file_id(&self, user: u8, name: &str) -> Result<FsFileId> {
+        Ok(FsFileId {
+            user,
+            name: name.to_string(),
+        })
+    }
+
+    /// Returns a numbered variant of the ID (e.g. GAME1.COM for n = 1), to avoid name clashes.
+    /// None if there's no valid name with the number.
+    fn numbered(&self, id: &FsFileId, n: usize) -> Option<FsFileId> {
+        let (base, ext) = match id.name.rsplit_once('.') {
+            Some((base, ext)) => (base, format!(".{}", ext)),
+            None => (id.name.as_str(), String::new()),
+        };
+        self.file_id(id.user, &format!("{}{}{}", base, n, ext)).ok()
+    }
+
+    /// Sets or clears the read-only flag of a file, a no-op for filesystems without one.
+    fn set_read_only(&mut self, _id: &FsFileId, _read_only: bool) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the disk label, None if there's none (or the filesystem has no labels).
+    fn label(&self) -> Option<String> {
+        None
+    }
+
+    fn free_space(&self) -> FreeSpace;
+}
+
+/// Which files to list.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Select {
+    /// files of all users, but not deleted files
+    All,
+    /// files of a single user
+    User(u8),
+    /// files of any of the users
+    Users(Vec<u8>),
+    /// all files, deleted ones included
+    WithDeleted,
+    /// only (likely) deleted files
+    DeletedOnly,
+}
+
+impl Select {
+    /// Returns true if the existing files of the user are selected.
+    pub fn includes(&self, user: u8) -> bool {
+        match self {
+            Select::All | Select::WithDeleted => true,
+            Select::User(u) => *u == user,
+            Select::Users(users) => users.contains(&user),
+            Select::DeletedOnly => false,
+        }
+    }
+}
+
+/// A file as listed by a filesystem.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsFile {
+    /// user owning the file (0 on filesystems without users), None for deleted files
+    pub user: Option<u8>,
+    /// file name, as stored
+    pub name: String,
+    /// size in bytes
+    pub size: usize,
+    pub attributes: Attributes,
+    /// number of directory entries the file takes
+    pub entries: usize,
+    /// allocation units (blocks, sectors) holding the data, in the file order, numbered as the
+    /// filesystem does
+    pub location: Vec<u32>,
+}
+
+impl FsFile {
+    /// Returns the R/O, SYS and ARC flags in a compact form, e.g. "RS-".
+    pub fn flags(&self) -> String {
+        self.attributes.flags()
+    }
+
+    /// Collation of listings: by user, deleted files (no user) last, then by name, byte-wise.
+    /// Use with a stable sort, so that deleted files of the same name keep the directory order.
+    pub fn listing_cmp(&self, other: &FsFile) -> Ordering {
+        (self.user.is_none(), self.user, &self.name).cmp(&(other.user.is_none(), other.user, &other.name))
+    }
+}
+
+/// File attributes, those a filesystem doesn't have are never set.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Attributes {
+    pub read_only: bool,
+    /// hidden from directory listings
+    pub system: bool,
+    pub archived: bool,
+}
+
+impl Attributes {
+    /// Returns the R/O, SYS and ARC flags in a compact form, e.g. "RS-".
+    pub fn flags(&self) -> String {
+        [(self.read_only, 'R'), (self.system, 'S'), (self.archived, 'A')]
+            .iter()
+            .map(|&(set, c)| if set { c } else { '-' })
+            .collect()
+    }
+}
+
+/// Name of a file to create, or to look for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsFileId {
+    pub user: u8,
+    pub name: String,
+}
+
+impl fmt::Display for FsFileId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.user, self.name)
+    }
+}
+
+/// File details beyond what the listing has.
+pub struct FileStat {
+    /// number of bytes the file's blocks can hold, less than its size if the file is truncated
+    pub stored_size: usize,
+    /// last modification time, None if the filesystem doesn't keep it
+    pub updated: Option<SystemTime>,
+    /// blocks of a deleted file allocated to other files since, 0 for existing files
+    pub reused_blocks: usize,
+}
+
+impl FileStat {
+    /// How much of a deleted file with the number of blocks other files have left.
+    pub fn recoverability(&self, blocks: usize) -> Recoverability {
+        match self.reused_blocks {
+            0 => Recoverability::Free,
+            reused if reused < blocks => Recoverability::PartiallyReused,
+            _ => Recoverability::Reused,
+        }
+    }
+}
+
+/// State of the blocks of a deleted file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recoverability {
+    /// none of the blocks is used by other files, the file can be brought back whole
+    Free,
+    /// some of the blocks hold other files now
+    PartiallyReused,
+    /// all the blocks hold other files now, nothing is left
+    Reused,
+}
+
+/// Space left for new files.
+pub struct FreeSpace {
+    pub blocks: usize,
+    pub bytes: usize,
+    pub dir_entries: usize,
+}
+
+impl RetroFs for CpmFs {
+    fn list(&self, select: Select) -> Result<Vec<FsFile>> {
+        let mode = match select {
+            Select::All => LsMode::All,
+            Select::User(user) => LsMode::OwnedBy(user),
+            Select::Users(users) => LsMode::OwnedByAny(users),
+            Select::WithDeleted => LsMode::Deleted,
+            Select::DeletedOnly => LsMode::DeletedOnly,
+        };
+        Ok(self.list_files(mode)?.into_iter().map(FsFile::from).collect())
+    }
+
+    fn read(&self, file: &FsFile, mut w: &mut dyn Write, text_mode: bool) -> Result<usize> {
+        self.read_file(&file.into(), &mut w, text_mode)
+    }
+
+    fn write(&mut self, id: &FsFileId, mut r: &mut dyn Read, text_mode: bool) -> Result<Vec<u32>> {
+        let blocks = self.write_file(&cpm_id(id)?, &mut r, text_mode)?;
+        Ok(blocks.into_iter().map(u32::from).collect())
+    }
+
+    fn delete(&mut self, file: &FsFile) -> Result<()> {
+        self.delete_file(&file.into())
+    }
+
+    fn stat(&self, file: &FsFile) -> Result<FileStat> {
+        let file = FileItem::from(file);
+        Ok(FileStat {
+            stored_size: self.stored_size(&file),
+            updated: self
+                .file_times(&file)?
+                .and_then(|t| t.updated)
+                .map(|t| t.to_system_time()),
+            reused_blocks: match file.user {
+                Some(_) => 0,
+                None => self.allocated_blocks(&file).len(),
+            },
+        })
+    }
+
+    fn file_id(&self, user: u8, name: &str) -> Result<FsFileId> {
+        Ok(FileId::new_with_filename(user, name, FilenameMode::Normalized)?.into())
+    }
+
+    fn numbered(&self, id: &FsFileId, n: usize) -> Option<FsFileId> {
+        cpm_id(id).ok()?.numbered(n).map(FsFileId::from)
+    }
+
+    fn set_read_only(&mut self, id: &FsFileId, read_only: bool) -> Result<()> {
+        self.set_read_only(&cpm_id(id)?, read_only)
+    }
+
+    fn label(&self) -> Option<String> {
+        self.label()
+    }
+
+    fn free_space(&self) -> FreeSpace {
+        FreeSpace {
+            blocks: self.free_blocks(),
+            bytes: self.free_blocks() * self.block_size(),
+            dir_entries: self.dir_slots().1,
+        }
+    }
+}
+
+impl From<FileItem> for FsFile {
+    fn from(f: FileItem) -> Self {
+        FsFile {
+            user: f.user,
+            name: f.name,
+            size: f.size,
+            attributes: Attributes {
+                read_only: f.read_only,
+                system: f.system_file,
+                archived: f.archived,
+            },
+            entries: f.extents,
+            location: f.block_list.into_iter().map(u32::from).collect(),
+        }
+    }
+}
+
+/// The CP/M item of a file listed by CpmFs, whose blocks all fit 16 bits.
+impl From<&FsFile> for FileItem {
+    fn from(f: &FsFile) -> Self {
+        FileItem {
+            user: f.user,
+            name: f.name.clone(),
+            size: f.size,
+            block_list: f.location.iter().map(|&b| b as u16).collect(),
+            extents: f.entries,
+            read_only: f.attributes.read_only,
+            system_file: f.attributes.system,
+            archived: f.attributes.archived,
+        }
+    }
+}
+
+impl From<FileId> for FsFileId {
+    fn from(id: FileId) -> Self {
+        FsFileId {
+            user: id.user,
+            name: id.filename(),
+        }
+    }
+}
+
+/// Converts the ID, the name already normalized by CpmFs::file_id().
+fn cpm_id(id: &FsFileId) -> Result<FileId> {
+    FileId::new_with_filename(id.user, &id.name, FilenameMode::AsIs)
+}
+
+/// Returns the file with the ID, None if there's none (deleted files don't count).
+pub fn find_file(fs: &dyn RetroFs, id: &FsFileId) -> Result<Option<FsFile>> {
+    Ok(fs.list(Select::User(id.user))?.into_iter().find(|f| f.name == id.name))
+}
+
+/// Returns true if the file exists (i.e. isn't deleted).
+pub fn file_exists(fs: &dyn RetroFs, id: &FsFileId) -> Result<bool> {
+    Ok(find_file(fs, id)?.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_exists, FileStat, Recoverability, RetroFs, Select};
+    use crate::cpm::{CpmFs, CpmVersion};
+    use crate::profile::Profile;
+    use std::fs::File;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_cpm_fs() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/03.dsk");
+        let params = Profile::find("junior").unwrap().params(CpmVersion::V3);
+        let mut cpm_fs = CpmFs::load(&mut File::open(path).unwrap(), params).unwrap();
+        let fs: &mut dyn RetroFs = &mut cpm_fs;
+
+        let id = fs.file_id(5, "hello.txt").unwrap();
+        assert_eq!(id.name, "HELLO.TXT");
+        assert!(!file_exists(fs, &id).unwrap());
+        let blocks = fs.write(&id, &mut &b"Hello"[..], true).unwrap();
+        assert!(file_exists(fs, &id).unwrap());
+
+        let file = fs.list(Select::User(5)).unwrap().remove(0);
+        assert_eq!(file.name, "HELLO.TXT");
+        assert_eq!(file.location, blocks);
+        let stat = fs.stat(&file).unwrap();
+        assert!(stat.stored_size >= file.size);
+        let mut data = vec![];
+        assert_eq!(fs.read(&file, &mut data, true).unwrap(), 5);
+        assert_eq!(data, b"Hello");
+        assert_eq!(fs.read_prefix(&file, 3).unwrap(), b"Hel");
+        assert_eq!(fs.read_prefix(&file, file.size + 1).unwrap().len(), file.size);
+
+        assert!(!file.attributes.read_only);
+        fs.set_read_only(&id, true).unwrap();
+        assert!(fs.list(Select::Users(vec![4, 5])).unwrap()[0].attributes.read_only);
+        assert_eq!(fs.numbered(&id, 12).unwrap().name, "HELLO12.TXT");
+
+        fs.delete(&file).unwrap();
+        assert!(!file_exists(fs, &id).unwrap());
+        assert!(fs
+            .list(Select::DeletedOnly)
+            .unwrap()
+            .iter()
+            .any(|f| f.name == "HELLO.TXT"));
+    }
+
+    #[test]
+    fn test_recoverability() {
+        let stat = |reused_blocks| FileStat {
+            stored_size: 0,
+            updated: None,
+            reused_blocks,
+        };
+        assert_eq!(stat(0).recoverability(0), Recoverability::Free);
+        assert_eq!(stat(0).recoverability(4), Recoverability::Free);
+        assert_eq!(stat(1).recoverability(4), Recoverability::PartiallyReused);
+        assert_eq!(stat(4).recoverability(4), Recoverability::Reused);
+    }
+}
@@ -1,16 +1,21 @@
 use anyhow::{bail, Error};
 use binrw::BinReaderExt;
 use binrw::{binrw, BinWriterExt};
+use clap::ValueEnum;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs::File;
-use std::io::{Cursor, Read, Write};
+use std::io::{self, Cursor, Read, Seek, Write};
+
+use crate::basic::spectrum_text;
+use crate::util::{safe_filename, unique_filename};
 
 // References:
 // - https://sinclair.wiki.zxnet.co.uk/wiki/Spectrum_tape_interface
 // - https://sinclair.wiki.zxnet.co.uk/wiki/TAP_format
 
 /// Type of ZX Spectrum file
-#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, ValueEnum)]
 #[binrw]
 #[brw(repr=u8)]
 pub enum SpeccyFileType {
@@ -61,7 +66,18 @@ pub struct SpeccyFileHeader {
     pub param2: u16,
 }
 
+/// Size of the ZX Spectrum file header, as stored on tape and at the start of Junior disk files.
+pub const HEADER_SIZE: usize = 17;
+
 impl SpeccyFileHeader {
+    /// Parses the header at the start of a file, if it looks like one: valid type and printable name.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || !data[1..11].iter().all(|b| (0x20..0x7F).contains(b)) {
+            return None;
+        }
+        Cursor::new(&data[..HEADER_SIZE]).read_le().ok()
+    }
+
     pub fn name(&self) -> &[u8] {
         let end = self
             .name
@@ -73,6 +89,24 @@ impl SpeccyFileHeader {
     }
 }
 
+impl fmt::Display for SpeccyFileHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} \"{}\"", self.file_type, spectrum_text(self.name()))?;
+        match self.file_type {
+            SpeccyFileType::Program if self.param1 < 0x4000 => write!(f, ", autostart {}", self.param1),
+            SpeccyFileType::Code => write!(f, ", load 0x{:04X}", self.param1),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Tells if the error comes from reading past the end of the file.
+fn is_truncation(e: &Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+        || e.downcast_ref::<binrw::Error>().is_some_and(binrw::Error::is_eof)
+}
+
 impl SpeccyFile {
     /// Reads a single ZX Spectrum file from a file.
     ///
@@ -80,6 +114,7 @@ impl SpeccyFile {
     /// followed by the file data - as stored on Junior disks. Note: the file might be longer
     /// than data in it due to the way CP/M filesystem works (size is a multiple of 128 bytes
     /// on Junior).
+    #[allow(dead_code)]
     pub fn read(f: &mut File) -> Result<Self, Error> {
         let header: SpeccyFileHeader = f.read_le()?;
         let mut data: Vec<u8> = vec![0; header.length as usize];
@@ -90,13 +125,16 @@ impl SpeccyFile {
 
     /// Reads a single ZX Spectrum file from a tape file.
     ///
-    /// It returns Some(None), if f was at the end already.
-    pub fn read_from_tap(f: &mut File) -> Result<Option<Self>, Error> {
+    /// It returns Some(None), if f was at the end already. When lenient, checksum mismatches
+    /// are reported as warnings rather than errors.
+    pub fn read_from_tap(f: &mut File, lenient: bool) -> Result<Option<Self>, Error> {
         // before the actual header there are always 3 bytes of size (17 bytes) and
         // 00 flag indicating header
         let mut size_and_flag = [0u8; 3];
-        if Self::read_up_to(f, &mut size_and_flag)? == 0 {
-            return Ok(None);
+        match Self::read_up_to(f, &mut size_and_flag)? {
+            0 => return Ok(None),
+            3 => {}
+            _ => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
         }
         if size_and_flag != *b"\x13\x00\x00" {
             bail!(
@@ -114,7 +152,11 @@ impl SpeccyFile {
         let header_checksum = header_bytes.iter().fold(0u8, |acc, &b| acc ^ b);
         let expected_checksum: u8 = f.read_le()?;
         if expected_checksum != header_checksum {
-            bail!("Header checksum mismatch: {} {}", expected_checksum, header_checksum);
+            let msg = format!("Header checksum mismatch: {} {}", expected_checksum, header_checksum);
+            if !lenient {
+                bail!(msg);
+            }
+            eprintln!("Warning: {}", msg);
         }
 
         // TODO: it might make more sense do diss binrw and let SpeccyFileHeader work with bytes
@@ -135,7 +177,11 @@ impl SpeccyFile {
         // checksum includes flag byte!
         let actual_checksum = data.iter().fold(0u8, |acc, &b| acc ^ b) ^ 0xFF;
         if actual_checksum != expected_checksum {
-            bail!("Checksum mismatch");
+            let name = spectrum_text(header.name());
+            if !lenient {
+                bail!("Checksum mismatch in the data of \"{}\"", name);
+            }
+            eprintln!("Warning: checksum mismatch in the data of \"{}\"", name);
         }
 
         let f = Self::from_header_and_data(header, data)?;
@@ -143,10 +189,29 @@ impl SpeccyFile {
     }
 
     /// Loads all Speccy files from a given .tap file handle.
-    pub fn load_tap_file(f: &mut File) -> Result<Vec<Self>, Error> {
+    ///
+    /// A tape cut off in the middle of a file gives the complete files before it, with a
+    /// warning telling where the damage starts.
+    pub fn load_tap_file(f: &mut File, lenient: bool) -> Result<Vec<Self>, Error> {
         let mut files: Vec<Self> = Vec::new();
-        while let Some(file) = Self::read_from_tap(f)? {
-            files.push(file);
+        loop {
+            let offset = f.stream_position()?;
+            match Self::read_from_tap(f, lenient) {
+                Ok(Some(file)) => files.push(file),
+                Ok(None) => break,
+                Err(e) if is_truncation(&e) => {
+                    eprintln!(
+                        "Warning: the tape is truncated, file {} at offset {} is cut off at offset {}; \
+                         {} complete files recovered",
+                        files.len(),
+                        offset,
+                        f.metadata()?.len(),
+                        files.len()
+                    );
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
         }
         Ok(files)
     }
@@ -157,13 +222,43 @@ impl SpeccyFile {
     }
 
     pub fn write_raw_data(&self, f: &mut File) -> Result<(), Error> {
-        f.write_all(&self.data())?;
+        f.write_all(self.data())?;
         Ok(())
     }
 
+    /// The name as printed, with control codes escaped.
     pub fn name(&self) -> String {
-        let raw_name = self.header().name();
-        String::from_utf8_lossy(raw_name).to_string()
+        spectrum_text(self.header().name())
+    }
+
+    /// The name made into a file name with the type extension: letters, digits and a few
+    /// safe characters, spaces as underscores, the rest and the control codes with their
+    /// parameters dropped. Made unique among the used ones (lowercase), with ~N appended.
+    pub fn file_name(&self, used: &mut HashSet<String>) -> String {
+        let mut printable = vec![];
+        let mut params = 0;
+        for &b in self.header().name() {
+            match b {
+                _ if params > 0 => params -= 1,
+                // INK to OVER take a color or a flag, AT and TAB a position
+                0x10..=0x15 => params = 1,
+                0x16 | 0x17 => params = 2,
+                0x00..=0x1F => {}
+                _ => printable.push(b),
+            }
+        }
+        let mut stem = String::new();
+        for c in spectrum_text(&printable).trim().chars() {
+            match c {
+                c if c.is_ascii_alphanumeric() || "-_!()".contains(c) => stem.push(c),
+                ' ' | '.' if !stem.ends_with('_') => stem.push('_'),
+                _ => {}
+            }
+        }
+        let stem = stem.trim_matches('_');
+        let stem = if stem.is_empty() { "unnamed" } else { stem };
+        let name = safe_filename(stem).unwrap_or_else(|_| "unnamed".to_string());
+        unique_filename(format!("{}.{}", name, self.file_type().extension()), used)
     }
 
     pub fn file_type(&self) -> SpeccyFileType {
@@ -289,8 +384,10 @@ impl SFCode {
 
 #[cfg(test)]
 mod tests {
-    use super::{SpeccyFileHeader, SpeccyFileType};
+    use super::{SpeccyFile, SpeccyFileHeader, SpeccyFileType};
     use binrw::BinReaderExt;
+    use std::collections::HashSet;
+    use std::fs::File;
     use std::io::Cursor;
 
     #[test]
@@ -304,4 +401,49 @@ mod tests {
         assert_eq!(h.param1, 16386);
         assert_eq!(h.param2, 20483);
     }
+
+    #[test]
+    fn test_speccy_file_header_from_bytes() {
+        let h = SpeccyFileHeader::from_bytes(b"\x03screen    \x00\x1B\x00\x40\x00\x80rest").unwrap();
+        assert_eq!(h.file_type, SpeccyFileType::Code);
+        assert_eq!(h.to_string(), "Code/bytes \"screen\", load 0x4000");
+        let h = SpeccyFileHeader::from_bytes(b"\x00menu      \x10\x00\x0A\x00\x10\x00").unwrap();
+        assert_eq!(h.to_string(), "BASIC Program \"menu\", autostart 10");
+
+        // invalid type, non-printable name, too short
+        assert!(SpeccyFileHeader::from_bytes(b"\x04screen    \x00\x1B\x00\x40\x00\x80").is_none());
+        assert!(SpeccyFileHeader::from_bytes(b"\x03scr\x00en    \x00\x1B\x00\x40\x00\x80").is_none());
+        assert!(SpeccyFileHeader::from_bytes(b"\x03screen").is_none());
+    }
+
+    #[test]
+    fn test_load_truncated_tap() {
+        let data = std::fs::read("tests/jetset.tap").unwrap();
+        let tap = "tests/out_truncated.tap";
+        // in the data of the second file, in its header and in the length and flag before it
+        for len in [data.len() - 100, 375, 363] {
+            std::fs::write(tap, &data[..len]).unwrap();
+            let files = SpeccyFile::load_tap_file(&mut File::open(tap).unwrap(), false).unwrap();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].name(), "Jetset1");
+        }
+    }
+
+    #[test]
+    fn test_file_name() {
+        let file = |name: &[u8; 10]| {
+            let mut header = b"\x03".to_vec();
+            header.extend(name);
+            header.extend(b"\x00\x00\x00\x80\x00\x80");
+            let header: SpeccyFileHeader = Cursor::new(header).read_le().unwrap();
+            SpeccyFile::from_header_and_data(header, vec![]).unwrap()
+        };
+        let mut used = HashSet::new();
+        assert_eq!(file(b"Jet Set 2 ").file_name(&mut used), "Jet_Set_2.cod");
+        assert_eq!(file(b"jet set 2 ").file_name(&mut used), "jet_set_2~1.cod");
+        assert_eq!(file(b"\x10\x02ELITE/2 ").name(), "\\x10\\x02ELITE/2");
+        assert_eq!(file(b"\x10\x02ELITE/2 ").file_name(&mut used), "ELITE2.cod");
+        assert_eq!(file(b"          ").file_name(&mut used), "unnamed.cod");
+        assert_eq!(file(b"con       ").file_name(&mut used), "%63on.cod");
+    }
 }
@@ -0,0 +1,99 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::util::thousands;
+
+/// Time spent in the phases of a command and the bytes moved, in the order first recorded.
+struct Stats {
+    start: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    counts: Vec<(&'static str, usize)>,
+}
+
+impl Stats {
+    fn lines(&self, total: Duration) -> Vec<String> {
+        let times = self
+            .phases
+            .iter()
+            .copied()
+            .chain([("total", total)])
+            .map(|(phase, time)| format!("  {:<22}{:>10.1} ms", phase, time.as_secs_f64() * 1000.0));
+        let counts = self
+            .counts
+            .iter()
+            .map(|(what, bytes)| format!("  {:<22}{:>10} bytes", what, thousands(*bytes)));
+        times.chain(counts).collect()
+    }
+}
+
+/// None unless enabled, nothing gets recorded then.
+static STATS: Mutex<Option<Stats>> = Mutex::new(None);
+
+/// Starts recording, the total time counts from now.
+pub fn enable() {
+    *STATS.lock().unwrap() = Some(Stats {
+        start: Instant::now(),
+        phases: vec![],
+        counts: vec![],
+    });
+}
+
+/// Runs the function, adding the time it takes to the phase.
+pub fn time<T>(phase: &'static str, f: impl FnOnce() -> T) -> T {
+    if STATS.lock().unwrap().is_none() {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    if let Some(stats) = STATS.lock().unwrap().as_mut() {
+        match stats.phases.iter_mut().find(|(p, _)| *p == phase) {
+            Some((_, time)) => *time += elapsed,
+            None => stats.phases.push((phase, elapsed)),
+        }
+    }
+    result
+}
+
+/// Adds the bytes to the count.
+pub fn count(what: &'static str, bytes: usize) {
+    if let Some(stats) = STATS.lock().unwrap().as_mut() {
+        match stats.counts.iter_mut().find(|(w, _)| *w == what) {
+            Some((_, n)) => *n += bytes,
+            None => stats.counts.push((what, bytes)),
+        }
+    }
+}
+
+/// Prints what was recorded to stderr, if enabled.
+pub fn report() {
+    if let Some(stats) = STATS.lock().unwrap().as_ref() {
+        eprintln!("Stats:");
+        for line in stats.lines(stats.start.elapsed()) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_stats_lines() {
+        let stats = Stats {
+            start: Instant::now(),
+            phases: vec![("image load", Duration::from_micros(12_345))],
+            counts: vec![("image bytes read", 819_456)],
+        };
+        assert_eq!(
+            stats.lines(Duration::from_millis(20)),
+            [
+                "  image load                  12.3 ms",
+                "  total                       20.0 ms",
+                "  image bytes read         819,456 bytes",
+            ]
+        );
+    }
+}
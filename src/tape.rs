@@ -0,0 +1,396 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::io::Write;
+
+use crate::error::{ErrorKind, Failure};
+
+/// Spectrum CPU clock, pulse lengths are given in its T-states.
+pub const CLOCK_HZ: u64 = 3_500_000;
+
+const TZX_SIGNATURE: &[u8] = b"ZXTape!\x1A";
+/// Gap after blocks of a .tap file, the TZX default
+const DEFAULT_PAUSE_MS: u16 = 1000;
+
+/// Pulse lengths (T-states) of a tape block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Timing {
+    pub pilot: u16,
+    pub sync1: u16,
+    pub sync2: u16,
+    pub zero: u16,
+    pub one: u16,
+}
+
+/// What the ROM saves and loads.
+pub const STANDARD: Timing = Timing {
+    pilot: 2168,
+    sync1: 667,
+    sync2: 735,
+    zero: 855,
+    one: 1710,
+};
+
+/// Twice the standard bit rate, for turbo loaders.
+pub const TURBO: Timing = Timing {
+    pilot: 2168,
+    sync1: 667,
+    sync2: 735,
+    zero: 427,
+    one: 855,
+};
+
+const HEADER_PILOT_PULSES: u16 = 8063;
+const DATA_PILOT_PULSES: u16 = 3223;
+
+/// Tape speed of the rendered audio.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    /// ROM timing, loads on any Spectrum
+    Std,
+    /// twice the bit rate and a shorter pilot tone, needs a turbo loader
+    Turbo,
+}
+
+/// A block as saved by the ROM: the flag byte, the data and the checksum, with the timing
+/// to play it at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TapeBlock {
+    pub bytes: Vec<u8>,
+    pub timing: Timing,
+    pub pilot_pulses: u16,
+    /// bits used in the last byte
+    pub used_bits: u8,
+    /// silence after the block
+    pub pause_ms: u16,
+}
+
+impl TapeBlock {
+    /// Block with the ROM timing, the pilot tone long for headers and short for data.
+    pub fn standard(bytes: Vec<u8>, pause_ms: u16) -> Self {
+        let is_header = bytes.first().is_some_and(|&flag| flag < 0x80);
+        Self {
+            bytes,
+            timing: STANDARD,
+            pilot_pulses: if is_header {
+                HEADER_PILOT_PULSES
+            } else {
+                DATA_PILOT_PULSES
+            },
+            used_bits: 8,
+            pause_ms,
+        }
+    }
+
+    /// Switches blocks with the ROM timing to the speed, leaving custom timings as they are.
+    pub fn set_speed(&mut self, speed: Speed) {
+        if speed == Speed::Turbo && self.timing == STANDARD {
+            self.timing = TURBO;
+            self.pilot_pulses /= 4;
+        }
+    }
+
+    /// Pulse lengths in T-states: the pilot tone, two sync pulses and two pulses per bit.
+    pub fn pulses(&self) -> Vec<u16> {
+        let t = &self.timing;
+        let mut pulses = vec![t.pilot; self.pilot_pulses as usize];
+        pulses.extend([t.sync1, t.sync2]);
+        for (idx, &byte) in self.bytes.iter().enumerate() {
+            let bits = if idx + 1 == self.bytes.len() { self.used_bits } else { 8 };
+            for bit in 0..bits {
+                let len = if byte & (0x80 >> bit) != 0 { t.one } else { t.zero };
+                pulses.extend([len, len]);
+            }
+        }
+        pulses
+    }
+}
+
+/// Tells if the XOR of all the bytes, the flag and the checksum included, is zero.
+pub fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0, |acc, &b| acc ^ b) == 0
+}
+
+/// Writes the block as a .tap file has it, after its length.
+pub fn write_tap_block(out: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    out.write_all(&(bytes.len() as u16).to_le_bytes())?;
+    out.write_all(bytes)?;
+    Ok(())
+}
+
+/// Tells TZX files by their signature.
+pub fn is_tzx(data: &[u8]) -> bool {
+    data.starts_with(TZX_SIGNATURE)
+}
+
+/// Reads the blocks of a .tap or a .tzx file (told apart by the TZX signature).
+pub fn read_tape(data: &[u8]) -> Result<Vec<TapeBlock>> {
+    match data.strip_prefix(TZX_SIGNATURE) {
+        Some(rest) => read_tzx(rest),
+        None => read_tap(data),
+    }
+}
+
+fn read_tap(data: &[u8]) -> Result<Vec<TapeBlock>> {
+    let mut blocks = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = u16::from_le_bytes([data[pos], *data.get(pos + 1).unwrap_or(&0)]) as usize;
+        let bytes = data.get(pos + 2..pos + 2 + len).ok_or_else(|| {
+            Failure::new(
+                ErrorKind::Filesystem,
+                format!("Block {} at offset {} is truncated", blocks.len(), pos),
+            )
+        })?;
+        blocks.push(TapeBlock::standard(bytes.to_vec(), DEFAULT_PAUSE_MS));
+        pos += 2 + len;
+    }
+    Ok(blocks)
+}
+
+/// Reads the data blocks of a TZX (after the signature), skipping the informational ones.
+fn read_tzx(data: &[u8]) -> Result<Vec<TapeBlock>> {
+    let word = |pos: usize| u16::from_le_bytes([data[pos], data[pos + 1]]);
+    let truncated = |id: u8, pos: usize| {
+        Failure::new(
+            ErrorKind::Filesystem,
+            format!("TZX block 0x{:02X} at offset {} is truncated", id, pos),
+        )
+    };
+
+    let mut blocks = vec![];
+    // the version follows the signature
+    let mut pos = 2;
+    while pos < data.len() {
+        let id = data[pos];
+        let block = &data[pos + 1..];
+        // fixed part of the block, the length of the variable part is at its end
+        let (fixed, len) = match id {
+            0x10 if block.len() >= 4 => (4, word(pos + 3) as usize),
+            0x11 if block.len() >= 18 => (18, u32::from_le_bytes([block[15], block[16], block[17], 0]) as usize),
+            0x20 => (2, 0),
+            0x21 | 0x30 if !block.is_empty() => (1, block[0] as usize),
+            0x22 => (0, 0),
+            0x32 if block.len() >= 2 => (2, word(pos + 1) as usize),
+            0x10 | 0x11 | 0x21 | 0x30 | 0x32 => bail!(truncated(id, pos)),
+            _ => bail!(Failure::new(
+                ErrorKind::Filesystem,
+                format!("Unsupported TZX block 0x{:02X} at offset {}", id, pos)
+            )),
+        };
+        if block.len() < fixed + len {
+            bail!(truncated(id, pos));
+        }
+        let bytes = block[fixed..fixed + len].to_vec();
+        match id {
+            0x10 => blocks.push(TapeBlock::standard(bytes, word(pos + 1))),
+            0x11 => {
+                let field = |n: usize| word(pos + 1 + n * 2);
+                blocks.push(TapeBlock {
+                    bytes,
+                    timing: Timing {
+                        pilot: field(0),
+                        sync1: field(1),
+                        sync2: field(2),
+                        zero: field(3),
+                        one: field(4),
+                    },
+                    pilot_pulses: field(5),
+                    used_bits: block[12],
+                    pause_ms: word(pos + 14),
+                });
+            }
+            // a pause extends the one of the previous block
+            0x20 => {
+                if let Some(last) = blocks.last_mut() {
+                    last.pause_ms = last.pause_ms.saturating_add(word(pos + 1));
+                }
+            }
+            _ => {}
+        }
+        pos += 1 + fixed + len;
+    }
+    Ok(blocks)
+}
+
+/// Renders the blocks as 8-bit unsigned mono samples at the rate, a square wave flipping
+/// at the end of each pulse.
+pub fn render(blocks: &[TapeBlock], rate: u32) -> Vec<u8> {
+    const LOW: u8 = 0x40;
+    const HIGH: u8 = 0xC0;
+    let mut samples = vec![];
+    let mut t_states: u64 = 0;
+    let mut level = LOW;
+    let mut hold = |samples: &mut Vec<u8>, level: u8, len: u64| {
+        t_states += len;
+        let end = (t_states * rate as u64 / CLOCK_HZ) as usize;
+        samples.resize(end.max(samples.len()), level);
+    };
+
+    for block in blocks {
+        for pulse in block.pulses() {
+            hold(&mut samples, level, pulse as u64);
+            level = if level == LOW { HIGH } else { LOW };
+        }
+        if block.pause_ms > 0 {
+            // the level after the last edge for a millisecond, then silence
+            let ms = CLOCK_HZ / 1000;
+            hold(&mut samples, level, ms);
+            level = LOW;
+            hold(&mut samples, level, (block.pause_ms as u64 - 1) * ms);
+        }
+    }
+    samples
+}
+
+/// Pilot pulses needed before a block is looked for, the ROM wants 256 too
+const MIN_PILOT_PULSES: usize = 256;
+
+/// Decodes the blocks of standard speed found in a recording: finds the pilot tones and
+/// reads the bits that follow the sync pulses, until the pulses stop looking like bits.
+/// Partial bytes at the end of a block are dropped.
+pub fn decode(samples: &[i32], rate: u32) -> Vec<Vec<u8>> {
+    let pulses = pulse_lengths(samples, rate);
+    let is_pilot = |len: u64| (1600..2800).contains(&len);
+    let is_sync = |len: u64| (300..1100).contains(&len);
+    // the ROM tells the bits apart by the length of both pulses, at about 3/4 of a one
+    let zero_or_one = |pair: u64| match pair {
+        1000..2565 => Some(0),
+        2565..4500 => Some(1),
+        _ => None,
+    };
+
+    let mut blocks = vec![];
+    let mut idx = 0;
+    while idx < pulses.len() {
+        let pilot = pulses[idx..].iter().take_while(|&&len| is_pilot(len)).count();
+        if pilot < MIN_PILOT_PULSES {
+            idx += pilot.max(1);
+            continue;
+        }
+        idx += pilot;
+        // the two sync pulses, the first one can merge with the last of the pilot
+        if !pulses.get(idx).is_some_and(|&len| is_sync(len)) {
+            continue;
+        }
+        idx += if pulses.get(idx + 1).is_some_and(|&len| is_sync(len)) {
+            2
+        } else {
+            1
+        };
+
+        let mut bytes = vec![];
+        let (mut byte, mut bits) = (0u8, 0);
+        while idx + 1 < pulses.len() {
+            let Some(bit) = zero_or_one(pulses[idx] + pulses[idx + 1]) else {
+                break;
+            };
+            byte = byte << 1 | bit;
+            bits += 1;
+            if bits == 8 {
+                bytes.push(byte);
+                (byte, bits) = (0, 0);
+            }
+            idx += 2;
+        }
+        // the flag and the checksum at least
+        if bytes.len() >= 2 {
+            blocks.push(bytes);
+        }
+    }
+    blocks
+}
+
+/// Lengths of the pulses in T-states, the time between the signal crossing its average
+/// level, with some hysteresis against noise.
+fn pulse_lengths(samples: &[i32], rate: u32) -> Vec<u64> {
+    if samples.is_empty() {
+        return vec![];
+    }
+    let mean = samples.iter().map(|&s| s as i64).sum::<i64>() / samples.len() as i64;
+    let peak = samples.iter().map(|&s| (s as i64 - mean).abs()).max().unwrap_or(0);
+    let hysteresis = peak / 8;
+
+    let mut pulses = vec![];
+    let mut high = samples[0] as i64 > mean;
+    let mut last_edge = 0;
+    for (idx, &s) in samples.iter().enumerate() {
+        let s = s as i64;
+        if (high && s < mean - hysteresis) || (!high && s > mean + hysteresis) {
+            high = !high;
+            pulses.push((idx - last_edge) as u64 * CLOCK_HZ / rate as u64);
+            last_edge = idx;
+        }
+    }
+    pulses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, read_tape, render, Speed, TapeBlock, STANDARD, TURBO};
+
+    #[test]
+    fn test_read_tape() {
+        let tap = b"\x03\x00\xFF\xAA\x55\x02\x00\x00\x00";
+        let blocks = read_tape(tap).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].bytes, [0xFF, 0xAA, 0x55]);
+        assert_eq!(blocks[0].pilot_pulses, 3223);
+        assert_eq!(blocks[1].pilot_pulses, 8063);
+        assert!(read_tape(&tap[..7]).is_err());
+
+        let mut tzx = b"ZXTape!\x1A\x01\x14".to_vec();
+        tzx.extend(b"\x30\x04demo");
+        tzx.extend(b"\x10\xF4\x01\x03\x00\xFF\xAA\x55");
+        tzx.extend(b"\x11\x78\x08\x9B\x02\xDF\x02\x57\x03\xAE\x06\x00\x01\x06\x00\x00\x02\x00\x00\xFF\x80");
+        tzx.extend(b"\x20\x64\x00");
+        let blocks = read_tape(&tzx).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0], TapeBlock::standard(vec![0xFF, 0xAA, 0x55], 500));
+        assert_eq!(blocks[1].timing, STANDARD);
+        assert_eq!((blocks[1].pilot_pulses, blocks[1].used_bits), (256, 6));
+        assert_eq!(
+            (blocks[1].bytes.as_slice(), blocks[1].pause_ms),
+            (&[0xFF, 0x80][..], 100)
+        );
+        assert!(read_tape(&tzx[..tzx.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn test_pulses() {
+        let mut block = TapeBlock::standard(vec![0xFF, 0x80], 0);
+        block.used_bits = 1;
+        let pulses = block.pulses();
+        assert_eq!(pulses.len(), 3223 + 2 + 18);
+        assert_eq!(pulses[3223..3227], [667, 735, 1710, 1710]);
+        assert_eq!(pulses[pulses.len() - 2..], [1710, 1710]);
+
+        block.set_speed(Speed::Turbo);
+        assert_eq!((block.timing, block.pilot_pulses), (TURBO, 805));
+    }
+
+    #[test]
+    fn test_render() {
+        let block = TapeBlock::standard(vec![0xFF], 1000);
+        let samples = render(&[block], 44100);
+        // 3223 pilot pulses take 2 s, the pause 1 s
+        assert_eq!(samples.len(), 132_504);
+        assert_eq!(&samples[..27], &[0x40; 27]);
+        assert_eq!(samples[28], 0xC0);
+        assert!(samples[samples.len() - 44000..].iter().all(|&s| s == 0x40));
+    }
+
+    #[test]
+    fn test_decode() {
+        let header = b"\x00\x03JETSET    \x00\x80\x00\x80\x00\x80\xAB".to_vec();
+        let data: Vec<u8> = (0..=255).chain([0xFF]).collect();
+        let blocks = [
+            TapeBlock::standard(header.clone(), 1000),
+            TapeBlock::standard(data.clone(), 1000),
+        ];
+        for rate in [22050, 44100, 48000] {
+            let samples: Vec<i32> = render(&blocks, rate).iter().map(|&s| s as i32 - 0x80).collect();
+            assert_eq!(decode(&samples, rate), [header.clone(), data.clone()]);
+        }
+        assert!(decode(&[0; 1000], 44100).is_empty());
+    }
+}
@@ -0,0 +1,305 @@
+use anyhow::{bail, Context, Result};
+use fast_glob::glob_match;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Parses an unsigned number given either as decimal or as hex with 0x prefix.
+pub fn parse_number(s: &str) -> Result<usize> {
+    let s = s.trim();
+    let n = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        usize::from_str_radix(hex, 16)
+    } else {
+        s.parse()
+    };
+    n.with_context(|| format!("Invalid number: {}", s))
+}
+
+/// Parses a comma separated list of numbers and ranges (e.g. 1,3-5) in range min..=max,
+/// returning sorted numbers, without duplicates.
+pub fn parse_ranges(s: &str, min: usize, max: usize) -> Result<Vec<usize>> {
+    let mut numbers = Vec::new();
+    for part in s.split(',').map(str::trim) {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .with_context(|| format!("Invalid number: {}", n.trim()))
+        };
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(part)?, parse(part)?),
+        };
+        if first < min || first > last || last > max {
+            bail!("Invalid range {}, numbers must be in range {}..{}", part, min, max);
+        }
+        numbers.extend(first..=last);
+    }
+    numbers.sort_unstable();
+    numbers.dedup();
+    Ok(numbers)
+}
+
+/// Formats a number with thousands separators, e.g. 12,288.
+pub fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (idx, c) in digits.chars().enumerate() {
+        if idx > 0 && (digits.len() - idx).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Formats a size with a binary unit suffix and one decimal, e.g. 12.5K, bytes below 1K as is.
+pub fn human_size(n: usize) -> String {
+    let mut size = n as f64;
+    let mut unit = "";
+    for next in ["K", "M", "G"] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    let formatted = format!("{:.1}", size);
+    format!("{}{}", formatted.strip_suffix(".0").unwrap_or(&formatted), unit)
+}
+
+/// Formats data as a classic hexdump, 16 bytes per line, with addresses starting at base.
+pub fn hexdump(data: &[u8], base: usize) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let hex = chunk.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| if (0x20..0x7F).contains(&b) { b as char } else { '.' })
+                .collect();
+            format!("{:04X}  {:<47}  |{}|", base + idx * 16, hex, ascii)
+        })
+        .collect()
+}
+
+/// Escapes the HTML special characters of the text.
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Formats bytes (e.g. a digest) as lowercase hex.
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Wildcard characters of globs.
+pub const WILDCARDS: [char; 3] = ['*', '?', '['];
+
+/// Returns local files matching the glob, sorted. Any path component may contain wildcards,
+/// ** matches any number of directories.
+pub fn glob_files(pattern: &str) -> Result<Vec<PathBuf>> {
+    let components: Vec<&str> = pattern.split('/').collect();
+    let Some(first_wild) = components.iter().position(|c| c.contains(WILDCARDS)) else {
+        return Ok(if Path::new(pattern).is_file() {
+            vec![PathBuf::from(pattern)]
+        } else {
+            vec![]
+        });
+    };
+    let depth = if pattern.contains("**") {
+        usize::MAX
+    } else {
+        components.len() - first_wild
+    };
+
+    let mut files = vec![];
+    walk_dir(Path::new(&components[..first_wild].join("/")), depth, &mut files)?;
+    files.retain(|path| glob_match(pattern, path.to_string_lossy().as_ref()));
+    files.sort();
+    Ok(files)
+}
+
+/// Collects files of the directory and of its subdirectories, up to the depth.
+fn walk_dir(dir: &Path, depth: usize, files: &mut Vec<PathBuf>) -> Result<()> {
+    let read_dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    for entry in fs::read_dir(read_dir).with_context(|| format!("Can't read directory {}", read_dir.display()))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() && depth > 1 {
+            walk_dir(&path, depth - 1, files)?;
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Device names reserved on Windows, regardless of the extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes a name safe to use as a local file name.
+///
+/// Names containing path separators are rejected. Characters other than letters, digits
+/// and a few harmless punctuation ones are escaped as %XX (so is a leading dot and the first
+/// character of device names like CON), trailing dots are dropped.
+pub fn safe_filename(name: &str) -> Result<String> {
+    if name.contains(['/', '\\', '\0']) {
+        bail!(
+            "Refusing to use {:?} as a file name, it contains a path separator",
+            name
+        );
+    }
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        bail!("Refusing to use an empty file name");
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    let reserved = RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem));
+    let mut out = String::with_capacity(name.len());
+    for (idx, c) in name.chars().enumerate() {
+        let safe = c.is_ascii_alphanumeric() || "-_.!()@^{}~+".contains(c);
+        if safe && !(idx == 0 && (c == '.' || reserved)) {
+            out.push(c);
+        } else {
+            let mut buf = [0u8; 4];
+            c.encode_utf8(&mut buf)
+                .bytes()
+                .for_each(|b| out.push_str(&format!("%{:02X}", b)));
+        }
+    }
+    Ok(out)
+}
+
+/// Makes the name unique among the ones already used, ignoring the case (as case-insensitive
+/// filesystems do), by appending ~N to the stem. The result is added to the used names.
+pub fn unique_filename(name: String, used: &mut HashSet<String>) -> String {
+    let mut unique = name.clone();
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) => (stem, format!(".{}", ext)),
+        None => (name.as_str(), String::new()),
+    };
+    let mut n = 1;
+    while !used.insert(unique.to_lowercase()) {
+        unique = format!("{}~{}{}", stem, n, ext);
+        n += 1;
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        glob_files, hexdump, human_size, parse_number, parse_ranges, safe_filename, thousands, unique_filename,
+    };
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("123").unwrap(), 123);
+        assert_eq!(parse_number("0x1F").unwrap(), 31);
+        assert_eq!(parse_number("0XFF").unwrap(), 255);
+        assert!(parse_number("0xZZ").is_err());
+        assert!(parse_number("-1").is_err());
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_ranges("2", 1, 5).unwrap(), vec![2]);
+        assert_eq!(parse_ranges("4-5, 1,2-3", 1, 5).unwrap(), vec![1, 2, 3, 4, 5]);
+        assert!(parse_ranges("0", 1, 5).is_err());
+        assert!(parse_ranges("3-6", 1, 5).is_err());
+        assert!(parse_ranges("x", 1, 5).is_err());
+    }
+
+    #[test]
+    fn test_thousands() {
+        assert_eq!(thousands(0), "0");
+        assert_eq!(thousands(999), "999");
+        assert_eq!(thousands(12288), "12,288");
+        assert_eq!(thousands(1234567), "1,234,567");
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0");
+        assert_eq!(human_size(1023), "1023");
+        assert_eq!(human_size(2048), "2K");
+        assert_eq!(human_size(12800), "12.5K");
+        assert_eq!(human_size(737280), "720K");
+        assert_eq!(human_size(3 * 1024 * 1024 / 2), "1.5M");
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let lines = hexdump(b"\xC3\x00\x01Hello, world!\x00\x7F", 0x100);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "0100  C3 00 01 48 65 6C 6C 6F 2C 20 77 6F 72 6C 64 21  |...Hello, world!|"
+        );
+        assert_eq!(lines[1], format!("0110  00 7F{}  |..|", " ".repeat(42)));
+    }
+
+    #[test]
+    fn test_safe_filename() {
+        assert_eq!(safe_filename("PIP.COM").unwrap(), "PIP.COM");
+        assert_eq!(safe_filename("$$$.SUB").unwrap(), "%24%24%24.SUB");
+        assert_eq!(safe_filename("IT'S.TXT").unwrap(), "IT%27S.TXT");
+        assert_eq!(safe_filename("SIGN1.").unwrap(), "SIGN1");
+        assert_eq!(safe_filename(".HIDDEN").unwrap(), "%2EHIDDEN");
+        assert_eq!(safe_filename("con.txt").unwrap(), "%63on.txt");
+        assert_eq!(safe_filename("CONFIG.SYS").unwrap(), "CONFIG.SYS");
+        assert_eq!(safe_filename("\u{1}ż").unwrap(), "%01%C5%BC");
+        assert!(safe_filename("../ETC").is_err());
+        assert!(safe_filename("A\\B").is_err());
+        assert!(safe_filename("..").is_err());
+    }
+
+    #[test]
+    fn test_unique_filename() {
+        let mut used = HashSet::new();
+        assert_eq!(unique_filename("READ.ME".to_string(), &mut used), "READ.ME");
+        assert_eq!(unique_filename("read.me".to_string(), &mut used), "read~1.me");
+        assert_eq!(unique_filename("Read.Me".to_string(), &mut used), "Read~2.Me");
+        assert_eq!(unique_filename("README".to_string(), &mut used), "README");
+        assert_eq!(unique_filename("readme".to_string(), &mut used), "readme~1");
+    }
+
+    #[test]
+    fn test_glob_files() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/out_glob");
+        std::fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        for name in ["a.dsk", "b.tap", "sub/c.dsk", "sub/deeper/d.dsk"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let base = dir.display().to_string();
+        let names = |glob: &str| -> Vec<String> {
+            glob_files(&format!("{}/{}", base, glob))
+                .unwrap()
+                .iter()
+                .map(|p| p.strip_prefix(&dir).unwrap().display().to_string())
+                .collect()
+        };
+
+        assert_eq!(names("*.dsk"), ["a.dsk"]);
+        assert_eq!(names("*/*.dsk"), ["sub/c.dsk"]);
+        assert_eq!(names("**/*.dsk"), ["a.dsk", "sub/c.dsk", "sub/deeper/d.dsk"]);
+        assert_eq!(names("s?b/**/*.dsk"), ["sub/c.dsk", "sub/deeper/d.dsk"]);
+        assert_eq!(names("b.tap"), ["b.tap"]);
+        assert!(names("*.img").is_empty());
+    }
+}
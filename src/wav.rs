@@ -0,0 +1,95 @@
+use anyhow::{bail, Result};
+use std::io::Write;
+
+use crate::error::{ErrorKind, Failure};
+
+/// Writes 8-bit unsigned mono samples as a PCM WAV file.
+pub fn write_wav(out: &mut impl Write, rate: u32, samples: &[u8]) -> Result<()> {
+    let data_len = samples.len() as u32;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM, one channel
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&rate.to_le_bytes())?;
+    // bytes per second, bytes per sample frame, bits per sample
+    out.write_all(&rate.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&8u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    out.write_all(samples)?;
+    Ok(())
+}
+
+/// Reads the samples of a PCM WAV file (8 or 16 bits), the channels mixed down to mono and
+/// centered on zero, with the sample rate.
+pub fn read_wav(data: &[u8]) -> Result<(u32, Vec<i32>)> {
+    let invalid = |msg: &str| Failure::new(ErrorKind::Usage, format!("Not a PCM WAV file: {}", msg));
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        bail!(invalid("no RIFF WAVE header"));
+    }
+
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+        // a truncated recording still has its samples
+        let chunk = &data[pos + 8..(pos + 8 + len).min(data.len())];
+        match id {
+            b"fmt " if chunk.len() >= 16 => {
+                let word = |n: usize| u16::from_le_bytes([chunk[n], chunk[n + 1]]);
+                if word(0) != 1 {
+                    bail!(invalid("compressed"));
+                }
+                let rate = u32::from_le_bytes(chunk[4..8].try_into()?);
+                format = Some((word(2) as usize, rate, word(14)));
+            }
+            b"data" => {
+                let Some((channels, rate, bits)) = format else {
+                    bail!(invalid("data before the format"));
+                };
+                if !(bits == 8 || bits == 16) || channels == 0 {
+                    bail!(invalid(&format!("{} bit samples in {} channels", bits, channels)));
+                }
+                let sample = |frame: &[u8], ch: usize| match bits {
+                    8 => (frame[ch] as i32 - 0x80) << 8,
+                    _ => i16::from_le_bytes([frame[ch * 2], frame[ch * 2 + 1]]) as i32,
+                };
+                let frame_len = channels * bits as usize / 8;
+                let samples = chunk
+                    .chunks_exact(frame_len)
+                    .map(|frame| (0..channels).map(|ch| sample(frame, ch)).sum::<i32>() / channels as i32)
+                    .collect();
+                return Ok((rate, samples));
+            }
+            _ => {}
+        }
+        // chunks are padded to even lengths
+        pos += 8 + len + len % 2;
+    }
+    bail!(invalid("no data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_wav, write_wav};
+
+    #[test]
+    fn test_wav() {
+        let mut wav = vec![];
+        write_wav(&mut wav, 22050, &[0x80, 0xC0, 0x40]).unwrap();
+        assert_eq!(wav.len(), 47);
+        assert_eq!(read_wav(&wav).unwrap(), (22050, vec![0, 0x4000, -0x4000]));
+
+        // 16 bit stereo, with a chunk before the format
+        let mut wav = b"RIFF\0\0\0\0WAVELIST\x03\0\0\0abc\0".to_vec();
+        wav.extend(b"fmt \x10\0\0\0\x01\0\x02\0\x44\xAC\0\0\x10\xB1\x02\0\x04\0\x10\0");
+        wav.extend(b"data\x08\0\0\0\x00\x10\x00\x30\x00\xF0\x00\xF0");
+        assert_eq!(read_wav(&wav).unwrap(), (44100, vec![0x2000, -0x1000]));
+        assert!(read_wav(b"RIFF\0\0\0\0WAVE").is_err());
+    }
+}
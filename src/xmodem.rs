@@ -0,0 +1,332 @@
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::io::{self, Read, Write};
+
+// References:
+// - http://pauillac.inria.fr/~doligez/zmodem/ymodem.txt (XMODEM/YMODEM protocol reference)
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// sent by the receiver instead of NAK to request CRC-16 mode
+const CRC_MODE: u8 = b'C';
+/// ^Z, pads the last block
+const PAD: u8 = 0x1A;
+
+const BLOCK_SIZE: usize = 128;
+const MAX_RETRIES: usize = 10;
+/// Number of port read timeouts to wait for the other side to start the transfer.
+const START_RETRIES: usize = 60;
+/// Receiver falls back to checksum mode after this many unanswered CRC requests (XMODEM only).
+const CRC_ATTEMPTS: usize = 3;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, ValueEnum)]
+pub enum Protocol {
+    /// XMODEM, 128 byte blocks, checksum or CRC
+    Xmodem,
+    /// YMODEM batch, file name and size sent in block 0
+    Ymodem,
+}
+
+/// Sends a single file. The port is expected to time out on reads (after a second or so).
+pub fn send(port: &mut (impl Read + Write), protocol: Protocol, name: &str, data: &[u8]) -> Result<()> {
+    let mut crc = wait_for_start(port)?;
+    if protocol == Protocol::Ymodem {
+        let header = format!("{}\0{}", name.to_ascii_lowercase(), data.len());
+        if header.len() > BLOCK_SIZE {
+            bail!("File name too long: {}", name);
+        }
+        send_block(port, 0, header.as_bytes(), true)?;
+        crc = wait_for_start(port)?;
+    }
+
+    for (idx, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+        send_block(port, (idx + 1) as u8, chunk, crc)?;
+    }
+    send_eot(port)?;
+
+    if protocol == Protocol::Ymodem {
+        // empty block 0 ends the batch
+        wait_for_start(port)?;
+        send_block(port, 0, &[], true)?;
+    }
+    Ok(())
+}
+
+/// Receives a single file, returns its name (YMODEM only) and data. XMODEM data is padded to
+/// whole blocks, YMODEM one is truncated to the size sent by the sender.
+pub fn receive(port: &mut (impl Read + Write), protocol: Protocol) -> Result<(Option<String>, Vec<u8>)> {
+    let mut header = None;
+    if protocol == Protocol::Ymodem {
+        let (_, block) = start_receive(port, false)?;
+        let Received::Block(0, data) = block else {
+            cancel(port)?;
+            bail!("Expected YMODEM header block");
+        };
+        let fields: Vec<&[u8]> = data.splitn(2, |&b| b == 0).collect();
+        if fields[0].is_empty() {
+            port.write_all(&[ACK])?;
+            bail!("Sender has no files to send");
+        }
+        let name = String::from_utf8_lossy(fields[0]).to_string();
+        let size = fields
+            .get(1)
+            .and_then(|f| f.split(|&b| b == b' ' || b == 0).next())
+            .and_then(|f| std::str::from_utf8(f).ok())
+            .and_then(|f| f.parse::<usize>().ok());
+        header = Some((name, size));
+        port.write_all(&[ACK])?;
+    }
+
+    let (crc, mut block) = start_receive(port, protocol == Protocol::Xmodem)?;
+    let mut data = Vec::new();
+    let mut expected = 1u8;
+    let mut errors = 0;
+    loop {
+        match block {
+            Received::Block(n, block_data) if n == expected => {
+                data.extend_from_slice(&block_data);
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                port.write_all(&[ACK])?;
+            }
+            // our ACK got lost, sender repeats the block
+            Received::Block(n, _) if n == expected.wrapping_sub(1) => port.write_all(&[ACK])?,
+            Received::Block(n, _) => {
+                cancel(port)?;
+                bail!("Block {} out of sequence, expected {}", n, expected);
+            }
+            Received::Eot => {
+                port.write_all(&[ACK])?;
+                break;
+            }
+            Received::Cancel => bail!("Transfer cancelled by the sender"),
+            Received::Bad | Received::Timeout => {
+                errors += 1;
+                if errors > MAX_RETRIES {
+                    cancel(port)?;
+                    bail!("Too many errors, transfer aborted");
+                }
+                purge(port)?;
+                port.write_all(&[NAK])?;
+            }
+        }
+        port.flush()?;
+        block = receive_block(port, crc)?;
+    }
+
+    let Some((name, size)) = header else {
+        return Ok((None, data));
+    };
+    if let Some(size) = size {
+        data.truncate(size);
+    }
+    // end of the batch, we only take the first file
+    let (_, block) = start_receive(port, false)?;
+    match block {
+        Received::Block(0, header) if header[0] == 0 => port.write_all(&[ACK])?,
+        _ => {
+            cancel(port)?;
+            eprintln!("Warning: only the first file of the batch was received.");
+        }
+    }
+    Ok((Some(name), data))
+}
+
+/// CRC-16/XMODEM (polynomial 0x1021, initial value 0).
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &b| {
+        (0..8).fold(crc ^ ((b as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+enum Received {
+    Block(u8, Vec<u8>),
+    Eot,
+    Cancel,
+    Bad,
+    Timeout,
+}
+
+/// Waits for the receiver to request the transfer, returns true for CRC mode.
+fn wait_for_start(port: &mut (impl Read + Write)) -> Result<bool> {
+    for _ in 0..START_RETRIES {
+        match read_byte(port)? {
+            Some(CRC_MODE) => return Ok(true),
+            Some(NAK) => return Ok(false),
+            Some(CAN) => bail!("Transfer cancelled by the receiver"),
+            _ => {}
+        }
+    }
+    bail!("Timeout waiting for the receiver")
+}
+
+/// Sends a block, padded to the block size, until it is acknowledged.
+fn send_block(port: &mut (impl Read + Write), num: u8, data: &[u8], crc: bool) -> Result<()> {
+    let mut packet = vec![SOH, num, !num];
+    packet.extend_from_slice(data);
+    packet.resize(3 + BLOCK_SIZE, if num == 0 { 0 } else { PAD });
+    if crc {
+        packet.extend_from_slice(&crc16(&packet[3..]).to_be_bytes());
+    } else {
+        packet.push(checksum(&packet[3..]));
+    }
+
+    for _ in 0..MAX_RETRIES {
+        port.write_all(&packet)?;
+        port.flush()?;
+        if wait_for_ack(port)? {
+            return Ok(());
+        }
+    }
+    bail!("Block {} not acknowledged, transfer aborted", num)
+}
+
+fn send_eot(port: &mut (impl Read + Write)) -> Result<()> {
+    for _ in 0..MAX_RETRIES {
+        port.write_all(&[EOT])?;
+        port.flush()?;
+        if wait_for_ack(port)? {
+            return Ok(());
+        }
+    }
+    bail!("End of transfer not acknowledged")
+}
+
+/// Returns true if the receiver acknowledged, false on NAK or timeout. Other bytes (e.g. stale
+/// CRC mode requests) are skipped.
+fn wait_for_ack(port: &mut impl Read) -> Result<bool> {
+    loop {
+        match read_byte(port)? {
+            Some(ACK) => return Ok(true),
+            Some(CAN) => bail!("Transfer cancelled by the receiver"),
+            Some(NAK) | None => return Ok(false),
+            Some(_) => {}
+        }
+    }
+}
+
+/// Requests the transfer until the first block arrives, returns the mode (true for CRC)
+/// and the block.
+fn start_receive(port: &mut (impl Read + Write), checksum_fallback: bool) -> Result<(bool, Received)> {
+    for attempt in 0..START_RETRIES {
+        let crc = !checksum_fallback || attempt < CRC_ATTEMPTS;
+        port.write_all(&[if crc { CRC_MODE } else { NAK }])?;
+        port.flush()?;
+        match receive_block(port, crc)? {
+            Received::Timeout => {}
+            Received::Bad => purge(port)?,
+            block => return Ok((crc, block)),
+        }
+    }
+    bail!("Timeout waiting for the sender")
+}
+
+fn receive_block(port: &mut impl Read, crc: bool) -> Result<Received> {
+    let size = match read_byte(port)? {
+        Some(SOH) => BLOCK_SIZE,
+        Some(STX) => 1024,
+        Some(EOT) => return Ok(Received::Eot),
+        Some(CAN) => return Ok(Received::Cancel),
+        Some(_) => return Ok(Received::Bad),
+        None => return Ok(Received::Timeout),
+    };
+
+    let mut packet = vec![0u8; 2 + size + if crc { 2 } else { 1 }];
+    for b in packet.iter_mut() {
+        match read_byte(port)? {
+            Some(byte) => *b = byte,
+            None => return Ok(Received::Bad),
+        }
+    }
+    let (num, data) = (packet[0], &packet[2..2 + size]);
+    let check = &packet[2 + size..];
+    let valid = if crc {
+        crc16(data).to_be_bytes() == check
+    } else {
+        checksum(data) == check[0]
+    };
+    if packet[1] != !num || !valid {
+        return Ok(Received::Bad);
+    }
+    Ok(Received::Block(num, data.to_vec()))
+}
+
+/// Arithmetic checksum of the original XMODEM.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn cancel(port: &mut impl Write) -> Result<()> {
+    port.write_all(&[CAN, CAN])?;
+    port.flush()?;
+    Ok(())
+}
+
+/// Discards incoming data until the line is quiet.
+fn purge(port: &mut impl Read) -> Result<()> {
+    while read_byte(port)?.is_some() {}
+    Ok(())
+}
+
+/// Reads a single byte, None on timeout.
+fn read_byte(port: &mut impl Read) -> Result<Option<u8>> {
+    let mut b = [0u8];
+    match port.read(&mut b) {
+        Ok(1) => Ok(Some(b[0])),
+        Ok(_) => Ok(None),
+        Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc16, receive, send, Protocol};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    fn transfer(protocol: Protocol, data: Vec<u8>) -> (Option<String>, Vec<u8>) {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        for s in [&a, &b] {
+            s.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        }
+        let sender = thread::spawn(move || send(&mut a, protocol, "HELLO.COM", &data).unwrap());
+        let received = receive(&mut b, protocol).unwrap();
+        sender.join().unwrap();
+        received
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 253) as u8).collect();
+
+        let (name, received) = transfer(Protocol::Xmodem, data.clone());
+        assert_eq!(name, None);
+        assert_eq!(received.len(), 1024);
+        assert_eq!(received[..1000], data);
+        assert!(received[1000..].iter().all(|&b| b == 0x1A));
+
+        let (name, received) = transfer(Protocol::Ymodem, data.clone());
+        assert_eq!(name.as_deref(), Some("hello.com"));
+        assert_eq!(received, data);
+
+        let (_, received) = transfer(Protocol::Ymodem, vec![]);
+        assert!(received.is_empty());
+    }
+}